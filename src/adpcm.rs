@@ -0,0 +1,453 @@
+//! CD-ROM XA ADPCM audio decoder.
+//!
+//! XA audio is stored in Mode 2 Form 2 sectors flagged as audio in
+//! their subheader. The 2324 bytes of user data contain 18 "sound
+//! groups" of 128 bytes followed by 20 unused bytes. Each sound group
+//! starts with 16 bytes of parameters followed by 28 words containing
+//! the samples of 8 (4 bit samples) or 4 (8 bit samples) "sound
+//! units" of 28 samples each. In stereo the sound units alternate
+//! between the left and right channel.
+//!
+//! Each sound unit has its own shift and prediction filter. The
+//! filters use the two previous output samples of the channel, so
+//! the decoder state must be kept from one sector to the next.
+//!
+//! Several audio streams are usually interleaved on the disc, they
+//! are identified by the file and channel numbers of the subheader.
+
+use CdError;
+
+use sector::{Sector, XaSubHeader, XaAudioCoding, AUDIO, FORM2};
+
+/// Decoder for an XA ADPCM audio stream
+#[derive(Debug, Clone)]
+pub struct XaDecoder {
+    /// File and channel numbers of the stream to decode, or `None`
+    /// to decode every audio sector
+    stream: Option<(u8, u8)>,
+    /// Previous samples of the left (or mono) and right channels
+    state: [ChannelState; 2],
+}
+
+impl XaDecoder {
+    /// Create a decoder for every audio sector, regardless of their
+    /// file and channel numbers
+    pub fn new() -> XaDecoder {
+        XaDecoder {
+            stream: None,
+            state: [ChannelState::new(); 2],
+        }
+    }
+
+    /// Create a decoder for the stream with the given `file` and
+    /// `channel` numbers. Sectors belonging to other streams are
+    /// ignored.
+    pub fn for_stream(file: u8, channel: u8) -> XaDecoder {
+        XaDecoder {
+            stream: Some((file, channel)),
+            state: [ChannelState::new(); 2],
+        }
+    }
+
+    /// Reset the filter state, used when seeking in the stream
+    pub fn reset(&mut self) {
+        self.state = [ChannelState::new(); 2];
+    }
+
+    /// Decode the audio in `sector`. Returns `Ok(None)` if it's not an
+    /// audio sector or if it belongs to another stream.
+    pub fn decode_sector(&mut self,
+                         sector: &Sector) -> Result<Option<XaAudio>,
+                                                    CdError> {
+        let subheader =
+            match sector.mode2_xa_subheader() {
+                Ok(s) => s,
+                // Not a CD-ROM XA sector
+                Err(CdError::BadFormat) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+
+        let payload = try!(sector.mode2_xa_payload());
+
+        self.decode(&subheader, payload)
+    }
+
+    /// Decode the audio in the user data `payload` of a Mode 2 Form 2
+    /// sector using its `subheader`. This can be used for sectors not
+    /// available through `Sector::mode2_xa_payload` (CD-i for
+    /// instance). Returns `Ok(None)` if it's not an audio sector or if
+    /// it belongs to another stream, `CdError::BadFormat` if the
+    /// payload is too short or the coding information is invalid.
+    pub fn decode(&mut self,
+                  subheader: &XaSubHeader,
+                  payload: &[u8]) -> Result<Option<XaAudio>, CdError> {
+        if !subheader.submode().contains(AUDIO | FORM2) {
+            return Ok(None);
+        }
+
+        if let Some((file, channel)) = self.stream {
+            if subheader.file_number() != file ||
+                subheader.channel_number() != channel {
+                return Ok(None);
+            }
+        }
+
+        let coding =
+            match subheader.audio_coding() {
+                Some(c) => c,
+                None => return Err(CdError::BadFormat),
+            };
+
+        if payload.len() < SOUND_GROUPS * 128 {
+            return Err(CdError::BadFormat);
+        }
+
+        let samples = self.decode_groups(coding, payload);
+
+        Ok(Some(XaAudio {
+            coding: coding,
+            samples: samples,
+        }))
+    }
+
+    /// Decode the 18 sound groups at the beginning of `payload`
+    fn decode_groups(&mut self,
+                     coding: XaAudioCoding,
+                     payload: &[u8]) -> Vec<i16> {
+        let units = if coding.bits_per_sample == 4 { 8 } else { 4 };
+        let channels = if coding.stereo { 2 } else { 1 };
+
+        let mut samples = Vec::with_capacity(SOUND_GROUPS * units * 28);
+
+        for group in payload[..SOUND_GROUPS * 128].chunks(128) {
+            // The sound units of a channel are decoded one after the
+            // other, in stereo the samples of both channels are
+            // interleaved in the output
+            let mut decoded = [[0i16; 28]; 8];
+
+            for unit in 0..units {
+                let params = group[4 + unit];
+                let state = &mut self.state[unit % channels];
+
+                for (i, s) in decoded[unit].iter_mut().enumerate() {
+                    let raw =
+                        if units == 8 {
+                            let b = group[16 + i * 4 + unit / 2];
+
+                            // Low nibble first
+                            let nibble =
+                                if unit % 2 == 0 { b & 0xf } else { b >> 4 };
+
+                            ((nibble as u16) << 12) as i16
+                        } else {
+                            ((group[16 + i * 4 + unit] as u16) << 8) as i16
+                        };
+
+                    *s = state.decode(raw, params);
+                }
+            }
+
+            if channels == 1 {
+                for unit in 0..units {
+                    samples.extend_from_slice(&decoded[unit]);
+                }
+            } else {
+                for pair in 0..units / 2 {
+                    let (left, right) =
+                        (&decoded[pair * 2], &decoded[pair * 2 + 1]);
+
+                    for i in 0..28 {
+                        samples.push(left[i]);
+                        samples.push(right[i]);
+                    }
+                }
+            }
+        }
+
+        samples
+    }
+}
+
+/// Filter state of a single channel
+#[derive(Debug, Clone, Copy)]
+struct ChannelState {
+    /// Previous output sample
+    old: i32,
+    /// Output sample before `old`
+    older: i32,
+}
+
+impl ChannelState {
+    fn new() -> ChannelState {
+        ChannelState {
+            old: 0,
+            older: 0,
+        }
+    }
+
+    /// Decode a single `sample` (left-aligned in 16 bits) using the
+    /// shift and filter in `params`
+    fn decode(&mut self, sample: i16, params: u8) -> i16 {
+        let mut shift = params & 0xf;
+
+        // Reserved values, the PlayStation treats them as 9
+        if shift > 12 {
+            shift = 9;
+        }
+
+        let filter = ((params >> 4) & 3) as usize;
+
+        let sample = (sample >> shift) as i32;
+
+        let prediction =
+            (self.old * FILTER_POS[filter] +
+             self.older * FILTER_NEG[filter] + 32) >> 6;
+
+        let output = sample + prediction;
+
+        let output =
+            if output > i16::max_value() as i32 {
+                i16::max_value() as i32
+            } else if output < i16::min_value() as i32 {
+                i16::min_value() as i32
+            } else {
+                output
+            };
+
+        self.older = self.old;
+        self.old = output;
+
+        output as i16
+    }
+}
+
+/// Audio decoded from a single sector
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XaAudio {
+    /// Coding information of the sector: sample rate, number of
+    /// channels...
+    pub coding: XaAudioCoding,
+    /// 16 bit PCM samples. Stereo samples are interleaved, left
+    /// channel first.
+    pub samples: Vec<i16>,
+}
+
+/// Number of sound groups in a sector
+const SOUND_GROUPS: usize = 18;
+
+/// Weight of the previous sample for each prediction filter (in
+/// 1/64th)
+const FILTER_POS: [i32; 4] = [0, 60, 115, 98];
+/// Weight of the sample before that
+const FILTER_NEG: [i32; 4] = [0, 0, -52, -55];
+
+#[test]
+fn decode_4bit_mono() {
+    // 37.8kHz mono 4 bit, file 1 channel 0
+    let subheader = XaSubHeader::new(&[1, 0, 0x64, 0x00,
+                                       1, 0, 0x64, 0x00]);
+
+    let mut payload = vec![0; 2324];
+
+    // First sound unit: shift 12, no filter. Second sound unit: shift
+    // 12, filter 1.
+    payload[4] = 0x0c;
+    payload[5] = 0x1c;
+
+    // First sample of unit 0: 7, of unit 1: -8
+    payload[16] = 0x87;
+    // Second sample of unit 0: -1, of unit 1: 0
+    payload[20] = 0x0f;
+
+    let mut decoder = XaDecoder::for_stream(1, 0);
+
+    let audio = decoder.decode(&subheader, &payload).unwrap().unwrap();
+
+    assert!(audio.coding.sample_rate == 37800);
+    assert!(!audio.coding.stereo);
+    assert!(audio.samples.len() == 18 * 8 * 28);
+
+    assert!(audio.samples[0] == 7);
+    assert!(audio.samples[1] == -1);
+    assert!(audio.samples[2] == 0);
+
+    // Unit 1 continues from the last sample of unit 0 (0):
+    // -8 + (0 * 60 + 32) >> 6 = -8, then 0 + (-8 * 60 + 32) >> 6 = -7
+    assert!(audio.samples[28] == -8);
+    assert!(audio.samples[29] == -7);
+
+    // Other streams are ignored
+    let mut decoder = XaDecoder::for_stream(1, 1);
+
+    assert!(decoder.decode(&subheader, &payload).unwrap().is_none());
+}
+
+#[test]
+fn decode_8bit_stereo() {
+    // 18.9kHz stereo 8 bit
+    let subheader = XaSubHeader::new(&[1, 0, 0x64, 0x15,
+                                       1, 0, 0x64, 0x15]);
+
+    let mut payload = vec![0; 2324];
+
+    // Left: shift 8, right: shift 8 with filter 1 in every group
+    for group in 0..18 {
+        payload[group * 128 + 4] = 0x08;
+        payload[group * 128 + 5] = 0x18;
+        payload[group * 128 + 7] = 0x18;
+        // First sample of each unit
+        payload[group * 128 + 16] = 0x10;
+        payload[group * 128 + 17] = 0x40;
+    }
+
+    let mut decoder = XaDecoder::new();
+
+    let audio = decoder.decode(&subheader, &payload).unwrap().unwrap();
+
+    assert!(audio.coding.sample_rate == 18900);
+    assert!(audio.coding.stereo);
+    assert!(audio.coding.bits_per_sample == 8);
+    assert!(audio.samples.len() == 18 * 4 * 28);
+
+    // Interleaved left and right samples
+    assert!(audio.samples[0] == 0x10);
+    assert!(audio.samples[1] == 0x40);
+    assert!(audio.samples[2] == 0);
+    // (0x40 * 60 + 32) >> 6 = 60
+    assert!(audio.samples[3] == 60);
+
+    // The right channel's state carries over to the next sector
+    let audio = decoder.decode(&subheader, &payload).unwrap().unwrap();
+    let previous = audio.samples[1];
+
+    decoder.reset();
+
+    let audio = decoder.decode(&subheader, &payload).unwrap().unwrap();
+
+    assert!(audio.samples[1] == 0x40);
+    assert!(previous != 0x40);
+}
+
+/// Load the raw XA sectors in the test file `name`
+#[cfg(test)]
+fn read_test_sectors(name: &str) -> Vec<Sector> {
+    use std::fs::File;
+    use std::io::Read;
+    use sector::{SectorBuilder, Metadata};
+    use ecc::{self, DataMode, Status};
+    use bcd::Bcd;
+    use msf::Msf;
+    use TrackFormat;
+    use test_util::testdata;
+
+    let mut data = Vec::new();
+
+    File::open(testdata(name)).unwrap().read_to_end(&mut data).unwrap();
+
+    data.chunks(2352).map(|raw| {
+        let raw = array_ref![raw, 0, 2352];
+
+        assert!(ecc::check(raw, DataMode::Mode2Form2) == Status::Valid);
+
+        let msf = Msf::from_bcd(raw[12], raw[13], raw[14]).unwrap();
+        let mut sector = Sector::empty();
+
+        {
+            let mut builder = SectorBuilder::new(&mut sector);
+
+            builder.set_data_2352(|d| -> Result<(), ()> {
+                d.copy_from_slice(raw);
+
+                Ok(())
+            }).unwrap();
+
+            builder.set_metadata(Metadata {
+                msf: msf,
+                track_msf: msf,
+                index: Bcd::one(),
+                track: Bcd::one(),
+                format: TrackFormat::Mode2Xa,
+                session: 1,
+            });
+        }
+
+        sector
+    }).collect()
+}
+
+/// Load the 16 bit little-endian reference PCM in the test file
+/// `name`
+#[cfg(test)]
+fn read_test_pcm(name: &str) -> Vec<i16> {
+    use std::fs::File;
+    use std::io::Read;
+    use test_util::testdata;
+
+    let mut data = Vec::new();
+
+    File::open(testdata(name)).unwrap().read_to_end(&mut data).unwrap();
+
+    data.chunks(2).map(|s| (s[0] as u16 | (s[1] as u16) << 8) as i16)
+        .collect()
+}
+
+#[test]
+fn decode_reference_mono() {
+    let sectors = read_test_sectors("xa/mono.bin");
+    let expected = read_test_pcm("xa/mono.pcm");
+
+    let mut decoder = XaDecoder::for_stream(1, 0);
+    let mut samples = Vec::new();
+
+    for sector in &sectors {
+        let audio = decoder.decode_sector(sector).unwrap().unwrap();
+
+        assert!(audio.coding.sample_rate == 37800);
+        assert!(!audio.coding.stereo);
+        assert!(audio.coding.bits_per_sample == 4);
+
+        samples.extend_from_slice(&audio.samples);
+    }
+
+    assert!(samples == expected);
+}
+
+#[test]
+fn decode_reference_stereo() {
+    let sectors = read_test_sectors("xa/stereo.bin");
+    let expected = read_test_pcm("xa/stereo.pcm");
+
+    let mut decoder = XaDecoder::for_stream(1, 1);
+    let mut samples = Vec::new();
+
+    for sector in &sectors {
+        // The sectors of channel 2 are skipped
+        if let Some(audio) = decoder.decode_sector(sector).unwrap() {
+            assert!(audio.coding.sample_rate == 18900);
+            assert!(audio.coding.stereo);
+            assert!(audio.coding.bits_per_sample == 4);
+
+            samples.extend_from_slice(&audio.samples);
+        }
+    }
+
+    assert!(samples == expected);
+
+    // Without a stream filter the 8 bit sectors of channel 2 are
+    // decoded as well and mess up the filter state
+    let mut decoder = XaDecoder::new();
+    let mut samples = Vec::new();
+
+    for sector in &sectors {
+        let audio = decoder.decode_sector(sector).unwrap().unwrap();
+
+        if sector.mode2_xa_subheader().unwrap().channel_number() == 1 {
+            samples.extend_from_slice(&audio.samples);
+        } else {
+            assert!(audio.coding.bits_per_sample == 8);
+        }
+    }
+
+    assert!(samples.len() == expected.len());
+    assert!(samples != expected);
+}
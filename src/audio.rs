@@ -0,0 +1,466 @@
+//! CD-DA audio track reader.
+//!
+//! Red book audio sectors contain 2352 bytes of 16 bit signed little
+//! endian PCM at 44.1kHz: 588 stereo frames, left channel first.
+//! There's no header or error correction code so the samples can be
+//! read directly from `Sector::data_2352`.
+
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::cmp;
+
+use CdError;
+use Image;
+use TrackFormat;
+
+use bcd::Bcd;
+use msf::Msf;
+use sector::Sector;
+
+/// A single stereo sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AudioFrame {
+    /// Left channel sample
+    pub left: i16,
+    /// Right channel sample
+    pub right: i16,
+}
+
+impl AudioFrame {
+    /// Decode a frame from its 4 byte little endian representation
+    pub fn from_bytes(b: &[u8; 4]) -> AudioFrame {
+        AudioFrame {
+            left: (b[0] as u16 | (b[1] as u16) << 8) as i16,
+            right: (b[2] as u16 | (b[3] as u16) << 8) as i16,
+        }
+    }
+
+    /// Return the 4 byte little endian representation of the frame
+    pub fn to_bytes(self) -> [u8; 4] {
+        let l = self.left as u16;
+        let r = self.right as u16;
+
+        [l as u8, (l >> 8) as u8, r as u8, (r >> 8) as u8]
+    }
+}
+
+/// Decode the audio samples in `sector` into `frames`. Returns
+/// `CdError::BadFormat` if it's not an audio sector.
+pub fn sector_frames(sector: &mut Sector,
+                     frames: &mut [AudioFrame; FRAMES_PER_SECTOR])
+                     -> Result<(), CdError> {
+    match sector.metadata().format {
+        TrackFormat::Audio | TrackFormat::CdG => (),
+        _ => return Err(CdError::BadFormat),
+    }
+
+    let data = try!(sector.data_2352());
+
+    for (f, b) in frames.iter_mut().zip(data.chunks(4)) {
+        *f = AudioFrame::from_bytes(array_ref![b, 0, 4]);
+    }
+
+    Ok(())
+}
+
+/// Reader for the samples of an audio track. It can be used through
+/// the typed `read_frames` interface or as a raw stream of 16 bit
+/// little endian stereo samples through `io::Read` (the format of a
+/// WAV file's data chunk).
+pub struct AudioTrack<'a, I: Image + ?Sized + 'a> {
+    /// Image containing the track
+    image: &'a mut I,
+    /// Sector index of the first sector returned
+    start: u32,
+    /// Number of sectors in the track
+    sectors: u32,
+    /// Current position in the track, in bytes
+    pos: u64,
+    /// Last sector read from the disc
+    sector: Sector,
+    /// Position of `sector` in the track, if it's valid
+    cached: Option<u32>,
+}
+
+impl<'a, I: Image + ?Sized> AudioTrack<'a, I> {
+    /// Open `track` in `image` for reading. If `pregap` is true the
+    /// pregap (INDEX 00) is included at the beginning of the stream,
+    /// otherwise it starts at INDEX 01. Returns `CdError::BadTrack`
+    /// if there's no such track and `CdError::BadFormat` if it's not
+    /// an audio track.
+    pub fn new(image: &'a mut I,
+               track: Bcd,
+               pregap: bool) -> Result<AudioTrack<'a, I>, CdError> {
+        let (start, sectors) =
+            match image.toc().track(track) {
+                Some(t) => {
+                    match t.format {
+                        TrackFormat::Audio | TrackFormat::CdG => (),
+                        _ => return Err(CdError::BadFormat),
+                    }
+
+                    let start =
+                        if pregap {
+                            t.pregap_start()
+                        } else {
+                            t.start
+                        };
+
+                    let start = start.sector_index();
+                    let end = t.start.sector_index() +
+                        t.length.sector_index();
+
+                    (start, end - start)
+                }
+                None => return Err(CdError::BadTrack),
+            };
+
+        Ok(AudioTrack {
+            image: image,
+            start: start,
+            sectors: sectors,
+            pos: 0,
+            sector: Sector::empty(),
+            cached: None,
+        })
+    }
+
+    /// Return the number of sectors in the stream
+    pub fn sectors(&self) -> u32 {
+        self.sectors
+    }
+
+    /// Return the number of stereo frames in the stream
+    pub fn frames(&self) -> u64 {
+        self.sectors as u64 * FRAMES_PER_SECTOR as u64
+    }
+
+    /// Return the size of the stream in bytes
+    pub fn size(&self) -> u64 {
+        self.sectors as u64 * 2352
+    }
+
+    /// Return the current position in the stream, in frames
+    pub fn position(&self) -> u64 {
+        self.pos / 4
+    }
+
+    /// Move to the stereo frame number `frame`. Returns
+    /// `CdError::EndOfTrack` if it's past the end of the stream.
+    pub fn seek_frame(&mut self, frame: u64) -> Result<(), CdError> {
+        if frame > self.frames() {
+            return Err(CdError::EndOfTrack);
+        }
+
+        self.pos = frame * 4;
+
+        Ok(())
+    }
+
+    /// Read as many frames as possible into `frames`, returns the
+    /// number of frames read. It's only smaller than `frames.len()`
+    /// at the end of the stream. If the position isn't on a frame
+    /// boundary (after a partial `io::Read`) the reading starts at
+    /// the beginning of the current frame.
+    pub fn read_frames(&mut self,
+                       frames: &mut [AudioFrame]) -> Result<usize, CdError> {
+        let mut read = 0;
+
+        self.pos &= !3;
+
+        while read < frames.len() && self.pos < self.size() {
+            let sector = (self.pos / 2352) as u32;
+            let offset = (self.pos % 2352) as usize;
+
+            let len = cmp::min(frames.len() - read,
+                               (2352 - offset) / 4);
+
+            {
+                let data = try!(self.load(sector));
+                let bytes = &data[offset..offset + len * 4];
+
+                for (f, b) in frames[read..read + len].iter_mut()
+                    .zip(bytes.chunks(4)) {
+                        *f = AudioFrame::from_bytes(array_ref![b, 0, 4]);
+                    }
+            }
+
+            read += len;
+            self.pos += len as u64 * 4;
+        }
+
+        Ok(read)
+    }
+
+    /// Load the track's sector number `sector` and return its data
+    fn load(&mut self, sector: u32) -> Result<&[u8; 2352], CdError> {
+        if self.cached != Some(sector) {
+            self.cached = None;
+
+            let msf =
+                match Msf::from_sector_index(self.start + sector) {
+                    Some(m) => m,
+                    None => return Err(CdError::LeadOut),
+                };
+
+            try!(self.image.read_sector(&mut self.sector, msf));
+
+            self.cached = Some(sector);
+        }
+
+        self.sector.data_2352()
+    }
+}
+
+impl<'a, I: Image + ?Sized> Read for AudioTrack<'a, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size() || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let sector = (self.pos / 2352) as u32;
+        let offset = (self.pos % 2352) as usize;
+
+        let len = cmp::min(buf.len(), 2352 - offset);
+
+        match self.load(sector) {
+            Ok(data) =>
+                buf[..len].copy_from_slice(&data[offset..offset + len]),
+            Err(CdError::IoError(e)) => return Err(e),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::Other,
+                                                e.to_string())),
+        }
+
+        self.pos += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<'a, I: Image + ?Sized> Seek for AudioTrack<'a, I> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos =
+            match pos {
+                SeekFrom::Start(p) => p as i64,
+                SeekFrom::End(p) => self.size() as i64 + p,
+                SeekFrom::Current(p) => self.pos as i64 + p,
+            };
+
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Attempted to seek before the \
+                                       beginning of the track"));
+        }
+
+        self.pos = pos as u64;
+
+        Ok(self.pos)
+    }
+}
+
+/// Number of stereo frames in an audio sector
+pub const FRAMES_PER_SECTOR: usize = 588;
+
+/// Sample rate of CD-DA audio, in Hz
+pub const SAMPLE_RATE: u32 = 44100;
+
+#[test]
+fn frame_bytes() {
+    let frame = AudioFrame::from_bytes(&[0x34, 0x12, 0xfe, 0xff]);
+
+    assert!(frame.left == 0x1234);
+    assert!(frame.right == -2);
+    assert!(frame.to_bytes() == [0x34, 0x12, 0xfe, 0xff]);
+}
+
+/// Write a BIN/CUE image with a data track and two audio tracks in
+/// the test directory `name`. Returns the path of the cue sheet and
+/// the contents of the BIN file.
+#[cfg(test)]
+fn test_image(name: &str) -> (::std::path::PathBuf, Vec<u8>) {
+    use test_util::{temp_dir, write_file};
+
+    let dir = temp_dir(name);
+
+    // Track 01: 2 Mode 1 sectors, track 02: 2 sectors of stored
+    // pregap and 3 sectors, track 03: 1 second of pregap (not
+    // stored) and 2 sectors
+    let mut bin = vec![0; 2 * 2048];
+
+    for i in 0..7 * 2352 {
+        bin.push((i * 7 + i / 2352) as u8);
+    }
+
+    write_file(&dir, "audio.bin", &bin);
+
+    let cue = write_file(&dir, "audio.cue",
+                         b"FILE \"audio.bin\" BINARY\n\
+                           \x20 TRACK 01 MODE1/2048\n\
+                           \x20   INDEX 01 00:00:00\n\
+                           \x20 TRACK 02 AUDIO\n\
+                           \x20   INDEX 00 00:00:02\n\
+                           \x20   INDEX 01 00:00:04\n\
+                           \x20 TRACK 03 AUDIO\n\
+                           \x20   PREGAP 00:01:00\n\
+                           \x20   INDEX 01 00:00:07\n");
+
+    (cue, bin)
+}
+
+/// Return the stereo frame number `frame` in `bytes`
+#[cfg(test)]
+fn test_frame(bytes: &[u8], frame: usize) -> AudioFrame {
+    AudioFrame::from_bytes(array_ref![bytes, frame * 4, 4])
+}
+
+#[test]
+fn open_track() {
+    use cue::Cue;
+
+    let (cue, bin) = test_image("audio-open");
+
+    let mut cue = Cue::new(&cue).unwrap();
+
+    let track2 = Bcd::from_binary(2).unwrap();
+    let track3 = Bcd::from_binary(3).unwrap();
+
+    let mut frames = [AudioFrame::default(); 2];
+
+    {
+        let mut audio = AudioTrack::new(&mut cue, track2, false).unwrap();
+
+        assert!(audio.sectors() == 3);
+        assert!(audio.frames() == 3 * 588);
+        assert!(audio.size() == 3 * 2352);
+
+        assert!(audio.read_frames(&mut frames).unwrap() == 2);
+        assert!(frames[0] == test_frame(&bin[4096 + 2 * 2352..], 0));
+        assert!(frames[1] == test_frame(&bin[4096 + 2 * 2352..], 1));
+    }
+
+    {
+        // The pregap is stored in the BIN file
+        let mut audio = AudioTrack::new(&mut cue, track2, true).unwrap();
+
+        assert!(audio.sectors() == 5);
+
+        assert!(audio.read_frames(&mut frames).unwrap() == 2);
+        assert!(frames[0] == test_frame(&bin[4096..], 0));
+        assert!(frames[1] == test_frame(&bin[4096..], 1));
+    }
+
+    {
+        // The pregap isn't stored, it's generated as silence
+        let mut audio = AudioTrack::new(&mut cue, track3, true).unwrap();
+
+        assert!(audio.sectors() == 75 + 2);
+
+        assert!(audio.read_frames(&mut frames).unwrap() == 2);
+        assert!(frames == [AudioFrame::default(); 2]);
+
+        audio.seek_frame(75 * 588).unwrap();
+
+        assert!(audio.read_frames(&mut frames).unwrap() == 2);
+        assert!(frames[0] == test_frame(&bin[4096 + 5 * 2352..], 0));
+    }
+
+    match AudioTrack::new(&mut cue, Bcd::one(), false) {
+        Err(CdError::BadFormat) => (),
+        _ => panic!("Data track opened as an audio track"),
+    }
+
+    match AudioTrack::new(&mut cue, Bcd::from_binary(4).unwrap(), false) {
+        Err(CdError::BadTrack) => (),
+        _ => panic!("Missing track opened"),
+    }
+}
+
+#[test]
+fn read_frames_boundaries() {
+    use cue::Cue;
+
+    let (cue, bin) = test_image("audio-frames");
+
+    let track2 = &bin[4096 + 2 * 2352..4096 + 5 * 2352];
+
+    let mut cue = Cue::new(&cue).unwrap();
+    let mut audio = AudioTrack::new(&mut cue,
+                                    Bcd::from_binary(2).unwrap(),
+                                    false).unwrap();
+
+    let mut frames = [AudioFrame::default(); 700];
+
+    // Across the boundary between the first and second sector
+    audio.seek_frame(580).unwrap();
+
+    assert!(audio.read_frames(&mut frames).unwrap() == 700);
+    assert!(audio.position() == 1280);
+
+    for (i, f) in frames.iter().enumerate() {
+        assert!(*f == test_frame(track2, 580 + i));
+    }
+
+    // At the end of the track
+    audio.seek_frame(3 * 588 - 10).unwrap();
+
+    assert!(audio.read_frames(&mut frames).unwrap() == 10);
+    assert!(frames[9] == test_frame(track2, 3 * 588 - 1));
+    assert!(audio.read_frames(&mut frames).unwrap() == 0);
+
+    audio.seek_frame(3 * 588).unwrap();
+
+    assert!(audio.read_frames(&mut frames).unwrap() == 0);
+
+    match audio.seek_frame(3 * 588 + 1) {
+        Err(CdError::EndOfTrack) => (),
+        _ => panic!("Seek past the end of the track"),
+    }
+}
+
+#[test]
+fn read_seek_adapters() {
+    use cue::Cue;
+
+    let (cue, bin) = test_image("audio-io");
+
+    let track2 = &bin[4096..4096 + 5 * 2352];
+
+    let mut cue = Cue::new(&cue).unwrap();
+    let mut audio = AudioTrack::new(&mut cue,
+                                    Bcd::from_binary(2).unwrap(),
+                                    true).unwrap();
+
+    let mut data = Vec::new();
+
+    assert!(audio.read_to_end(&mut data).unwrap() == 5 * 2352);
+    assert!(&data[..] == track2);
+
+    // Reads stop at the end of the current sector
+    let mut buf = [0; 1000];
+
+    assert!(audio.seek(SeekFrom::Start(2000)).unwrap() == 2000);
+    assert!(audio.read(&mut buf).unwrap() == 352);
+    assert!(&buf[..352] == &track2[2000..2352]);
+
+    assert!(audio.seek(SeekFrom::Current(-2)).unwrap() == 2350);
+    assert!(audio.read(&mut buf[..1]).unwrap() == 1);
+    assert!(buf[0] == track2[2350]);
+
+    // `read_frames` goes back to the start of the current frame
+    let mut frames = [AudioFrame::default(); 2];
+
+    assert!(audio.read_frames(&mut frames).unwrap() == 2);
+    assert!(frames[0] == test_frame(track2, 587));
+    assert!(frames[1] == test_frame(track2, 588));
+
+    assert!(audio.seek(SeekFrom::End(-3)).unwrap() == 5 * 2352 - 3);
+    assert!(audio.read(&mut buf).unwrap() == 3);
+    assert!(&buf[..3] == &track2[5 * 2352 - 3..]);
+    assert!(audio.read(&mut buf).unwrap() == 0);
+
+    // Past the end
+    assert!(audio.seek(SeekFrom::End(10)).unwrap() == 5 * 2352 + 10);
+    assert!(audio.read(&mut buf).unwrap() == 0);
+
+    assert!(audio.seek(SeekFrom::Current(-(5 * 2352 + 11))).is_err());
+}
@@ -0,0 +1,641 @@
+//! Automatic detection of the image format.
+//!
+//! `open` looks at the extension and contents of a file to figure out
+//! which backend should be used to open it. Backends for formats not
+//! supported by this crate can be added with `register_backend`, they
+//! take priority over the built-in ones.
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
+
+use CdError;
+use Image;
+
+/// Description of an image format backend
+#[derive(Clone, Copy)]
+pub struct Backend {
+    /// Human-readable name of the format
+    pub name: &'static str,
+    /// Return true if the file described by the `Probe` looks like an
+    /// image in this format
+    pub probe: fn(&mut Probe) -> bool,
+    /// Open the image at `path`
+    pub open: fn(&Path) -> Result<Box<Image>, CdError>,
+}
+
+/// Information about a file being identified, given to the `probe`
+/// function of the backends
+pub struct Probe {
+    /// Path of the file
+    path: PathBuf,
+    /// Lowercase extension of the file, empty if it doesn't have one
+    extension: String,
+    /// The file itself
+    file: File,
+    /// Size of the file in bytes
+    size: u64,
+}
+
+impl Probe {
+    /// Create a new `Probe` for the file at `path`. `open` creates
+    /// one for each file, backends can also use it to test their
+    /// `probe` function.
+    pub fn new(path: &Path) -> Result<Probe, CdError> {
+        let extension =
+            match path.extension() {
+                Some(e) => e.to_string_lossy().to_lowercase(),
+                None => String::new(),
+            };
+
+        let file =
+            match File::open(path) {
+                Ok(f) => f,
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        let size =
+            match file.metadata() {
+                Ok(m) => m.len(),
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        Ok(Probe {
+            path: PathBuf::from(path),
+            extension: extension,
+            file: file,
+            size: size,
+        })
+    }
+
+    /// Return the path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the extension of the file in lowercase, or an empty
+    /// string if it doesn't have one
+    pub fn extension(&self) -> &str {
+        &self.extension
+    }
+
+    /// Return the size of the file in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Fill `buf` with the contents of the file at `offset`. Returns
+    /// false if the file is too small or can't be read.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        if offset + buf.len() as u64 > self.size {
+            return false;
+        }
+
+        self.file.seek(SeekFrom::Start(offset)).is_ok() &&
+            self.file.read_exact(buf).is_ok()
+    }
+
+    /// Fill `buf` with the last `buf.len()` bytes of the file.
+    /// Returns false if the file is too small or can't be read.
+    pub fn read_end(&mut self, buf: &mut [u8]) -> bool {
+        let len = buf.len() as u64;
+
+        if len > self.size {
+            return false;
+        }
+
+        let offset = self.size - len;
+
+        self.read_at(offset, buf)
+    }
+
+    /// Return up to `max` bytes from the beginning of the file
+    pub fn header(&mut self, max: usize) -> Vec<u8> {
+        let len =
+            if (max as u64) < self.size {
+                max
+            } else {
+                self.size as usize
+            };
+
+        let mut header = vec![0; len];
+
+        if !self.read_at(0, &mut header) {
+            header.clear();
+        }
+
+        header
+    }
+}
+
+/// Register a new image format backend. Backends are probed in the
+/// reverse order of registration, before the built-in ones.
+pub fn register_backend(backend: Backend) {
+    let mut backends =
+        match BACKENDS.lock() {
+            Ok(b) => b,
+            // A panic while probing doesn't invalidate the list
+            Err(e) => e.into_inner(),
+        };
+
+    backends.push(backend);
+}
+
+/// Open the image at `path`, using the first backend which recognizes
+/// it. Returns `CdError::BadImage` if the format is unknown.
+pub fn open(path: &Path) -> Result<Box<Image>, CdError> {
+    let registered: Vec<Backend> =
+        match BACKENDS.lock() {
+            Ok(b) => b.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+
+    let mut probe = try!(Probe::new(path));
+
+    for backend in registered.iter().rev().chain(BUILTIN.iter()) {
+        if (backend.probe)(&mut probe) {
+            return (backend.open)(path);
+        }
+    }
+
+    Err(CdError::BadImage(PathBuf::from(path),
+                          "Unknown image format".to_string()))
+}
+
+/// Backends registered with `register_backend`
+static BACKENDS: Mutex<Vec<Backend>> = Mutex::new(Vec::new());
+
+/// Built-in backends. The formats identified by their magic number
+/// come first, then the text-based ones and finally the raw ISO
+/// images which have no header at all.
+static BUILTIN: [Backend; 6] = [
+    Backend {
+        name: "CHD",
+        probe: probe_chd,
+        open: open_chd,
+    },
+    Backend {
+        name: "Nero",
+        probe: probe_nrg,
+        open: open_nrg,
+    },
+    Backend {
+        name: "Alcohol 120%",
+        probe: probe_mds,
+        open: open_mds,
+    },
+    Backend {
+        name: "CloneCD",
+        probe: probe_ccd,
+        open: open_ccd,
+    },
+    Backend {
+        name: "CUE",
+        probe: probe_cue,
+        open: open_cue,
+    },
+    Backend {
+        name: "ISO",
+        probe: probe_iso,
+        open: open_iso,
+    },
+];
+
+fn probe_chd(probe: &mut Probe) -> bool {
+    let mut magic = [0; 8];
+
+    probe.read_at(0, &mut magic) && &magic == b"MComprHD"
+}
+
+fn open_chd(path: &Path) -> Result<Box<Image>, CdError> {
+    ::chd::Chd::new(path).map(|i| Box::new(i) as Box<Image>)
+}
+
+fn probe_nrg(probe: &mut Probe) -> bool {
+    let mut footer = [0; 12];
+
+    // "NER5" followed by a 64 bit offset or "NERO" followed by a 32
+    // bit offset
+    probe.read_end(&mut footer) &&
+        (&footer[0..4] == b"NER5" || &footer[4..8] == b"NERO")
+}
+
+fn open_nrg(path: &Path) -> Result<Box<Image>, CdError> {
+    ::nrg::Nrg::new(path).map(|i| Box::new(i) as Box<Image>)
+}
+
+fn probe_mds(probe: &mut Probe) -> bool {
+    let mut magic = [0; 16];
+
+    probe.read_at(0, &mut magic) && &magic == b"MEDIA DESCRIPTOR"
+}
+
+fn open_mds(path: &Path) -> Result<Box<Image>, CdError> {
+    ::mds::Mds::new(path).map(|i| Box::new(i) as Box<Image>)
+}
+
+fn probe_ccd(probe: &mut Probe) -> bool {
+    if probe.extension() == "ccd" {
+        return true;
+    }
+
+    let header = probe.header(64);
+
+    text(&header).trim().starts_with("[CloneCD]")
+}
+
+fn open_ccd(path: &Path) -> Result<Box<Image>, CdError> {
+    ::ccd::Ccd::new(path).map(|i| Box::new(i) as Box<Image>)
+}
+
+fn probe_cue(probe: &mut Probe) -> bool {
+    if probe.extension() == "cue" {
+        return true;
+    }
+
+    // Cue sheets are small text files, look for the commands every
+    // one of them must contain
+    if probe.size() > ::cue::CUE_SHEET_MAX_LENGTH {
+        return false;
+    }
+
+    let header = probe.header(4096);
+
+    if header.contains(&0) {
+        return false;
+    }
+
+    let header = text(&header).to_uppercase();
+
+    header.contains("FILE ") && header.contains("TRACK ")
+}
+
+fn open_cue(path: &Path) -> Result<Box<Image>, CdError> {
+    ::cue::Cue::new(path).map(|i| Box::new(i) as Box<Image>)
+}
+
+fn probe_iso(probe: &mut Probe) -> bool {
+    // ISO images have no header, the only way to tell them apart from
+    // the data files of the other formats is the ISO 9660 Primary
+    // Volume Descriptor in sector 16. Look for it at the position
+    // matching the layout of the sectors.
+    let size = probe.size();
+    let mut header = [0; 16];
+
+    let pvd_offsets =
+        if size % 2352 == 0 &&
+            probe.read_at(0, &mut header) &&
+            header[0..12] == ::sector::SYNC_PATTERN {
+                // Raw sectors, the user data follows the subheader in
+                // Mode 2
+                if !probe.read_at(16 * 2352, &mut header) {
+                    return false;
+                }
+
+                match header[15] {
+                    1 => vec![16 * 2352 + 16],
+                    2 => vec![16 * 2352 + 24],
+                    _ => return false,
+                }
+            } else {
+                let mut offsets = Vec::new();
+
+                // User data only
+                if size % 2048 == 0 {
+                    offsets.push(16 * 2048);
+                }
+
+                // Headerless Mode 2, after the subheader
+                if size % 2336 == 0 {
+                    offsets.push(16 * 2336 + 8);
+                }
+
+                offsets
+            };
+
+    let mut magic = [0; 7];
+
+    pvd_offsets.iter().any(|&offset| {
+        // Type, identifier and version 1
+        probe.read_at(offset, &mut magic) &&
+            &magic[0..6] == ::iso::PVD_MAGIC &&
+            magic[6] == 1
+    })
+}
+
+fn open_iso(path: &Path) -> Result<Box<Image>, CdError> {
+    ::iso::Iso::new(path).map(|i| Box::new(i) as Box<Image>)
+}
+
+/// Interpret `b` as text for the text-based formats
+fn text(b: &[u8]) -> String {
+    String::from_utf8_lossy(b).into_owned()
+}
+
+/// Build an ISO image of 17 sectors of `sector_size` bytes with a
+/// Primary Volume Descriptor in sector 16 if `pvd` is true. Raw
+/// sectors use the given `mode`.
+#[cfg(test)]
+fn test_iso(sector_size: usize, mode: u8, pvd: bool) -> Vec<u8> {
+    use sector::SYNC_PATTERN;
+
+    let mut image = vec![0; 17 * sector_size];
+
+    // Offset of the user data in each sector
+    let data =
+        match (sector_size, mode) {
+            (2048, _) => 0,
+            (2336, _) => 8,
+            (_, 1) => 16,
+            _ => 24,
+        };
+
+    if sector_size == 2352 {
+        for sector in image.chunks_mut(2352) {
+            sector[0..12].copy_from_slice(&SYNC_PATTERN);
+            sector[15] = mode;
+        }
+    }
+
+    if pvd {
+        let offset = 16 * sector_size + data;
+
+        image[offset..offset + 7].copy_from_slice(b"\x01CD001\x01");
+    }
+
+    image
+}
+
+/// Image registered by the backend tests, it wraps an ISO image but
+/// reports its own format
+#[cfg(test)]
+struct TestImage(::iso::Iso, &'static str);
+
+#[cfg(test)]
+impl Image for TestImage {
+    fn image_format(&self) -> String {
+        self.1.to_string()
+    }
+
+    fn read_sector(&mut self,
+                   sector: &mut ::sector::Sector,
+                   msf: ::msf::Msf) -> Result<(), CdError> {
+        self.0.read_sector(sector, msf)
+    }
+
+    fn track_msf(&self,
+                 track: ::bcd::Bcd,
+                 track_msf: ::msf::Msf) -> Result<::msf::Msf, CdError> {
+        self.0.track_msf(track, track_msf)
+    }
+
+    fn toc(&self) -> ::toc::Toc {
+        self.0.toc()
+    }
+}
+
+/// Return the format of the image at `path` as detected by `open`
+#[cfg(test)]
+fn test_format(path: &Path) -> String {
+    match open(path) {
+        Ok(image) => image.image_format(),
+        Err(e) => panic!("Can't open {}: {}", path.display(), e),
+    }
+}
+
+#[test]
+fn open_by_extension() {
+    use test_util::{temp_dir, write_file, testdata};
+
+    let dir = temp_dir("backend-extension");
+
+    write_file(&dir, "disc.bin", &[0; 4 * 2048]);
+
+    let cue = write_file(&dir, "disc.cue",
+                         b"FILE \"disc.bin\" BINARY\n\
+                           \x20 TRACK 01 MODE1/2048\n\
+                           \x20   INDEX 01 00:00:00\n");
+
+    assert!(test_format(&cue) == "CUE");
+
+    write_file(&dir, "clone.img", &[0; 4 * 2352]);
+
+    // The `.ccd` extension is enough, the contents don't have to
+    // start with the [CloneCD] section
+    let ccd = write_file(&dir, "clone.ccd",
+                         b"\n[Disc]\nTocEntries=3\nSessions=1\n\
+                           [CloneCD]\nVersion=3\n\
+                           [Entry 0]\nSession=1\nPoint=0xa0\nADR=0x01\n\
+                           Control=0x04\nPMin=1\nPSec=0\nPFrame=0\n\
+                           [Entry 1]\nSession=1\nPoint=0xa2\nADR=0x01\n\
+                           Control=0x04\nPMin=0\nPSec=2\nPFrame=4\n\
+                           [Entry 2]\nSession=1\nPoint=0x01\nADR=0x01\n\
+                           Control=0x04\nPMin=0\nPSec=2\nPFrame=0\n\
+                           [TRACK 1]\nMODE=1\nINDEX 1=0\n");
+
+    assert!(test_format(&ccd) == "CloneCD");
+
+    assert!(test_format(&testdata("chd/tracks.chd")) == "CHD");
+    assert!(test_format(&testdata("backend/disc.nrg")) == "Nero");
+    assert!(test_format(&testdata("backend/disc.mds")) == "Alcohol 120%");
+
+    let iso = write_file(&dir, "disc.iso", &test_iso(2048, 1, true));
+
+    assert!(test_format(&iso) == "ISO");
+}
+
+#[test]
+fn open_by_contents() {
+    use std::fs;
+    use test_util::{temp_dir, write_file, testdata};
+
+    let dir = temp_dir("backend-contents");
+
+    let copy = |name: &str, to: &str| {
+        let path = dir.join(to);
+
+        fs::copy(testdata(name), &path).unwrap();
+
+        path
+    };
+
+    // Formats with a magic number
+    assert!(test_format(&copy("chd/tracks.chd", "chd.bin")) == "CHD");
+    assert!(test_format(&copy("backend/disc.nrg", "nrg.bin")) == "Nero");
+
+    // The data file of an MDS image must be next to it, with the same
+    // name
+    copy("backend/disc.mdf", "alcohol.mdf");
+
+    assert!(test_format(&copy("backend/disc.mds", "alcohol.dat")) ==
+            "Alcohol 120%");
+
+    // Text formats
+    write_file(&dir, "clone.img", &[0; 4 * 2352]);
+
+    let ccd = write_file(&dir, "clone.txt",
+                         b"[CloneCD]\nVersion=3\n\
+                           [Disc]\nTocEntries=3\nSessions=1\n\
+                           [Entry 0]\nSession=1\nPoint=0xa0\nADR=0x01\n\
+                           Control=0x04\nPMin=1\nPSec=0\nPFrame=0\n\
+                           [Entry 1]\nSession=1\nPoint=0xa2\nADR=0x01\n\
+                           Control=0x04\nPMin=0\nPSec=2\nPFrame=4\n\
+                           [Entry 2]\nSession=1\nPoint=0x01\nADR=0x01\n\
+                           Control=0x04\nPMin=0\nPSec=2\nPFrame=0\n\
+                           [TRACK 1]\nMODE=1\nINDEX 1=0\n");
+
+    assert!(test_format(&ccd) == "CloneCD");
+
+    write_file(&dir, "disc.bin", &[0; 4 * 2048]);
+
+    let cue = write_file(&dir, "disc.txt",
+                         b"REM A cue sheet without the .cue extension\n\
+                           FILE \"disc.bin\" BINARY\n\
+                           \x20 TRACK 01 MODE1/2048\n\
+                           \x20   INDEX 01 00:00:00\n");
+
+    assert!(test_format(&cue) == "CUE");
+
+    // ISO images in every layout, identified by their PVD
+    for &(size, mode) in &[(2048, 1), (2336, 2), (2352, 1), (2352, 2)] {
+        let name = format!("iso-{}-{}.bin", size, mode);
+        let iso = write_file(&dir, &name, &test_iso(size, mode, true));
+
+        assert!(test_format(&iso) == "ISO");
+    }
+}
+
+#[test]
+fn open_priority() {
+    use std::fs;
+    use test_util::{temp_dir, write_file, testdata};
+
+    let dir = temp_dir("backend-priority");
+
+    // The formats identified by their magic number come before the
+    // ones identified by their extension
+    let chd = dir.join("disc.cue");
+
+    fs::copy(testdata("chd/tracks.chd"), &chd).unwrap();
+
+    assert!(test_format(&chd) == "CHD");
+
+    // Cue sheets come before ISO images
+    write_file(&dir, "data.bin", &[0; 4 * 2048]);
+
+    let cue = write_file(&dir, "cue.iso",
+                         b"FILE \"data.bin\" BINARY\n\
+                           \x20 TRACK 01 MODE1/2048\n\
+                           \x20   INDEX 01 00:00:00\n");
+
+    assert!(test_format(&cue) == "CUE");
+}
+
+#[test]
+fn open_registered_backend() {
+    use test_util::{temp_dir, write_file};
+
+    fn probe_test(probe: &mut Probe) -> bool {
+        probe.path().file_name().and_then(|n| n.to_str()) ==
+            Some("registered.iso")
+    }
+
+    fn open_first(path: &Path) -> Result<Box<Image>, CdError> {
+        ::iso::Iso::new(path).map(|i| Box::new(TestImage(i, "FIRST"))
+                                  as Box<Image>)
+    }
+
+    fn open_second(path: &Path) -> Result<Box<Image>, CdError> {
+        ::iso::Iso::new(path).map(|i| Box::new(TestImage(i, "SECOND"))
+                                  as Box<Image>)
+    }
+
+    let dir = temp_dir("backend-registered");
+
+    let registered = write_file(&dir, "registered.iso",
+                                &test_iso(2048, 1, true));
+    let other = write_file(&dir, "other.iso", &test_iso(2048, 1, true));
+
+    register_backend(Backend {
+        name: "First",
+        probe: probe_test,
+        open: open_first,
+    });
+
+    // Registered backends come before the built-in ones
+    assert!(test_format(&registered) == "FIRST");
+    assert!(test_format(&other) == "ISO");
+
+    register_backend(Backend {
+        name: "Second",
+        probe: probe_test,
+        open: open_second,
+    });
+
+    // The last backend registered comes first
+    assert!(test_format(&registered) == "SECOND");
+}
+
+#[test]
+fn open_unknown_format() {
+    use test_util::{temp_dir, write_file};
+
+    let dir = temp_dir("backend-unknown");
+
+    let is_unknown = |path: &Path| {
+        match open(path) {
+            Err(CdError::BadImage(p, _)) => p == path,
+            _ => false,
+        }
+    };
+
+    // ISO images without a filesystem can't be told apart from the
+    // data files of the other formats
+    let blank = write_file(&dir, "blank.iso", &test_iso(2048, 1, false));
+    let raw = write_file(&dir, "raw.bin", &test_iso(2352, 2, false));
+
+    assert!(is_unknown(&blank));
+    assert!(is_unknown(&raw));
+    assert!(is_unknown(&write_file(&dir, "small.dat", b"CD001")));
+
+    // Missing file
+    match open(&dir.join("missing.cue")) {
+        Err(CdError::IoError(_)) => (),
+        _ => panic!("Expected an I/O error"),
+    }
+}
+
+#[test]
+fn probe_iso_layout() {
+    use test_util::{temp_dir, write_file};
+
+    let dir = temp_dir("backend-probe");
+
+    let probe = |name: &str, data: &[u8]| {
+        let path = write_file(&dir, name, data);
+
+        probe_iso(&mut Probe::new(&path).unwrap())
+    };
+
+    assert!(probe("cooked.bin", &test_iso(2048, 1, true)));
+    assert!(probe("mode1.bin", &test_iso(2352, 1, true)));
+    assert!(probe("mode2.bin", &test_iso(2352, 2, true)));
+
+    // The PVD must be where the mode of sector 16 puts it
+    let mut image = test_iso(2352, 2, true);
+
+    image[16 * 2352 + 15] = 1;
+
+    assert!(!probe("mismatch.bin", &image));
+
+    // Raw audio tracks don't have a sync pattern
+    let mut image = test_iso(2352, 1, true);
+
+    image[0] = 0xff;
+
+    assert!(!probe("audio.bin", &image));
+
+    // The extension doesn't matter
+    assert!(!probe("empty.iso", &test_iso(2048, 1, false)));
+}
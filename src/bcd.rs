@@ -90,6 +90,12 @@ impl fmt::Display for Bcd {
     }
 }
 
+impl fmt::Debug for Bcd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
 #[test]
 fn conversions() {
     assert!(Bcd::from_bcd(0) == Some(Bcd(0)));
@@ -0,0 +1,772 @@
+extern crate cdimage;
+
+use std::path::Path;
+use std::str::FromStr;
+use std::fs::File;
+use std::io::{Read, Write, BufWriter};
+
+use cdimage::{Image, CdError, TrackFormat};
+use cdimage::msf::Msf;
+use cdimage::bcd::Bcd;
+use cdimage::toc::Toc;
+use cdimage::sector::{Sector, XaSubHeader, XaForm};
+use cdimage::ecc::Status;
+use cdimage::audio::{AudioTrack, SAMPLE_RATE};
+use cdimage::cue::CueWriter;
+
+fn main() {
+    let argv: Vec<_> = std::env::args().collect();
+
+    if argv.len() < 3 {
+        usage();
+    }
+
+    let command = &argv[1];
+
+    match &**command {
+        "sector" => {
+            if argv.len() < 4 {
+                usage();
+            }
+
+            dump_sector(&mut *open_image(&argv[2]), &argv[3]);
+        }
+        "rip" => {
+            let prefix = argv.get(3).map(|p| &**p).unwrap_or("track");
+
+            rip(&mut *open_image(&argv[2]), prefix);
+        }
+        "convert" => convert(&argv[2..]),
+        // Original form without a command, equivalent to "sector"
+        _ if argv.len() == 3 =>
+            dump_sector(&mut *open_image(&argv[1]), &argv[2]),
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    panic!("Usage: cdtool [sector] <cd-image> <msf>\n       \
+            cdtool rip <cd-image> [<prefix>]\n       \
+            cdtool convert [--strip-ecc|--regenerate-ecc] \
+            <cd-image> <out.cue|out.iso>");
+}
+
+/// Open the image at `path`, the format is detected automatically
+fn open_image(path: &str) -> Box<Image> {
+    match cdimage::open(Path::new(path)) {
+        Ok(i) => i,
+        Err(e) => panic!("Can't open {}: {}", path, e),
+    }
+}
+
+/// Dump a single sector
+fn dump_sector(image: &mut Image, msf: &str) {
+    let msf =
+        match Msf::from_str(msf) {
+            Ok(m) => m,
+            Err(()) => panic!("Invalid MSF"),
+        };
+
+    println!("{}", image.image_format());
+    println!("{:?}", image.toc());
+
+    let mut sector = Sector::empty();
+
+    image.read_sector(&mut sector, msf).unwrap();
+
+    if let Ok(subheader) = sector.mode2_xa_subheader() {
+        println!("form: {:?}", subheader.form());
+    }
+
+    println!("format: {:?}", sector.metadata().format);
+
+    let bytes = sector.data_2352().unwrap();
+
+    hexdump(bytes);
+}
+
+/// Rip every audio track to a WAV file named `<prefix>NN.wav`
+fn rip(image: &mut Image, prefix: &str) {
+    let toc = image.toc();
+
+    for track in toc.tracks() {
+        match track.format {
+            TrackFormat::Audio | TrackFormat::CdG => (),
+            _ => continue,
+        }
+
+        let path = format!("{}{}.wav", prefix, track.number);
+
+        println!("Track {} -> {}", track.number, path);
+
+        let mut audio = AudioTrack::new(image, track.number, false).unwrap();
+
+        let mut wav = BufWriter::new(File::create(&path).unwrap());
+
+        write_wav_header(&mut wav, audio.size() as u32).unwrap();
+
+        let mut buf = [0; 2352];
+
+        loop {
+            let n = audio.read(&mut buf).unwrap();
+
+            if n == 0 {
+                break;
+            }
+
+            wav.write_all(&buf[..n]).unwrap();
+        }
+    }
+}
+
+/// Write the header of a 16 bit stereo 44.1kHz WAV file containing
+/// `size` bytes of samples
+fn write_wav_header<W: Write>(w: &mut W,
+                              size: u32) -> std::io::Result<()> {
+    fn le32(v: u32) -> [u8; 4] {
+        [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+    }
+
+    let channels = 2;
+    let bytes_per_frame = channels * 2;
+
+    try!(w.write_all(b"RIFF"));
+    try!(w.write_all(&le32(36 + size)));
+    try!(w.write_all(b"WAVE"));
+
+    try!(w.write_all(b"fmt "));
+    try!(w.write_all(&le32(16)));
+    // PCM, 2 channels
+    try!(w.write_all(&[1, 0, channels as u8, 0]));
+    try!(w.write_all(&le32(SAMPLE_RATE)));
+    try!(w.write_all(&le32(SAMPLE_RATE * bytes_per_frame)));
+    // Block alignment, bits per sample
+    try!(w.write_all(&[bytes_per_frame as u8, 0, 16, 0]));
+
+    try!(w.write_all(b"data"));
+    w.write_all(&le32(size))
+}
+
+/// Convert an image into another format, chosen from the extension of
+/// the output file
+fn convert(args: &[String]) {
+    let mut options = ConvertOptions {
+        strip_ecc: false,
+        regenerate_ecc: false,
+    };
+
+    let mut paths = Vec::new();
+
+    for arg in args {
+        match &**arg {
+            "--strip-ecc" => options.strip_ecc = true,
+            "--regenerate-ecc" => options.regenerate_ecc = true,
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.len() != 2 {
+        usage();
+    }
+
+    let image = open_image(paths[0]);
+    let out = Path::new(paths[1]);
+
+    println!("{} -> {}", image.image_format(), out.display());
+
+    let errors =
+        match convert_image(image, out, &options) {
+            Ok(e) => e,
+            Err(e) => panic!("\nConversion failed: {}", e),
+        };
+
+    println!("\nDone, {} sector(s) with bad EDC/ECC{}",
+             errors,
+             if options.regenerate_ecc { " (regenerated)" } else { "" });
+}
+
+/// Options of the `convert` command
+struct ConvertOptions {
+    /// Store Mode 1 tracks without their error correction codes
+    strip_ecc: bool,
+    /// Regenerate the error correction codes of every data sector
+    regenerate_ecc: bool,
+}
+
+/// Convert `image` into the format chosen from the extension of
+/// `out`. Returns the number of sectors with a bad EDC or ECC.
+fn convert_image(image: Box<Image>,
+                 out: &Path,
+                 options: &ConvertOptions) -> Result<u32, String> {
+    let extension =
+        out.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or(String::new());
+
+    let mut converter = Converter::new(image, options.regenerate_ecc);
+
+    let res =
+        match &*extension {
+            "cue" => {
+                let mut writer = CueWriter::new(out);
+
+                writer.set_strip_ecc(options.strip_ecc);
+
+                writer.write(&mut converter)
+            }
+            "iso" => {
+                // An ISO image never contains the error correction
+                // codes
+                if options.strip_ecc {
+                    return Err("--strip-ecc can't be used with ISO \
+                                images".to_string());
+                }
+
+                write_iso(&mut converter, out)
+            }
+            _ => return Err(format!("Unknown output format \"{}\"",
+                                    out.display())),
+        };
+
+    match res {
+        Ok(()) => Ok(converter.errors),
+        Err(CdError::BadFormat) =>
+            Err("The image can't be stored in this format".to_string()),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+/// Write the user data of the data track as a 2048 byte per sector
+/// ISO image. Audio tracks are skipped. Returns `CdError::BadFormat`
+/// if the image contains several data tracks or Form 2 sectors, they
+/// can't be stored in an ISO image.
+fn write_iso(image: &mut Image, path: &Path) -> Result<(), CdError> {
+    let toc = image.toc();
+
+    let mut data_tracks =
+        toc.tracks().filter(|t| t.format != TrackFormat::Audio &&
+                            t.format != TrackFormat::CdG);
+
+    let track =
+        match data_tracks.next() {
+            Some(t) => t,
+            None => return Err(CdError::BadFormat),
+        };
+
+    if data_tracks.next().is_some() {
+        return Err(CdError::BadFormat);
+    }
+
+    let file =
+        match File::create(path) {
+            Ok(f) => f,
+            Err(e) => return Err(CdError::IoError(e)),
+        };
+
+    let mut iso = BufWriter::new(file);
+
+    let mut sector = Sector::empty();
+
+    let start = track.start.sector_index();
+    let end = start + track.length.sector_index();
+
+    for pos in start..end {
+        let msf = Msf::from_sector_index(pos).unwrap();
+
+        try!(image.read_sector(&mut sector, msf));
+
+        let res =
+            match track.format {
+                TrackFormat::Mode1 =>
+                    iso.write_all(try!(sector.mode1_payload())),
+                _ => {
+                    let data = try!(sector.data_2352());
+
+                    let mut subheader = [0; 8];
+
+                    subheader.copy_from_slice(&data[16..24]);
+
+                    // Form 2 sectors have 2324 bytes of user data,
+                    // only the blank ones (the gaps at the end of
+                    // the track for instance) can be stored without
+                    // losing anything
+                    let form2 =
+                        XaSubHeader::new(&subheader).form() == XaForm::Form2;
+
+                    if form2 && data[24..2348].iter().any(|&b| b != 0) {
+                        return Err(CdError::BadFormat);
+                    }
+
+                    // 2048 bytes after the subheader
+                    iso.write_all(&data[24..2072])
+                }
+            };
+
+        if let Err(e) = res {
+            return Err(CdError::IoError(e));
+        }
+    }
+
+    match iso.flush() {
+        Ok(()) => Ok(()),
+        Err(e) => Err(CdError::IoError(e)),
+    }
+}
+
+/// Image adapter used during conversions: it displays the progress,
+/// checks the integrity of the sectors and optionally regenerates
+/// their error detection and correction codes.
+struct Converter {
+    image: Box<Image>,
+    /// Regenerate the error correction codes of every data sector
+    regenerate_ecc: bool,
+    /// First sector of the lead-out, for the progress report
+    lead_out: u32,
+    /// Sectors before this one have already been checked
+    next: u32,
+    /// Last percentage displayed
+    percent: u32,
+    /// Number of sectors with a bad EDC or ECC
+    errors: u32,
+}
+
+impl Converter {
+    fn new(image: Box<Image>, regenerate_ecc: bool) -> Converter {
+        let lead_out = image.toc().lead_out().sector_index();
+
+        Converter {
+            image: image,
+            regenerate_ecc: regenerate_ecc,
+            lead_out: lead_out,
+            next: 0,
+            percent: 101,
+            errors: 0,
+        }
+    }
+}
+
+impl Image for Converter {
+    fn image_format(&self) -> String {
+        self.image.image_format()
+    }
+
+    fn read_sector(&mut self,
+                   sector: &mut Sector,
+                   msf: Msf) -> Result<(), CdError> {
+        try!(self.image.read_sector(sector, msf));
+
+        let pos = msf.sector_index();
+
+        // The writers may read some sectors twice (to look for
+        // blank pregaps), only check them once
+        if pos >= self.next {
+            self.next = pos + 1;
+
+            match try!(sector.check_integrity()) {
+                Status::BadEdc | Status::BadEcc => {
+                    println!("\r{}: bad EDC/ECC", msf);
+
+                    self.errors += 1;
+                }
+                _ => (),
+            }
+
+            let percent = pos * 100 / self.lead_out;
+
+            if percent != self.percent {
+                print!("\r{}%", percent);
+
+                let _ = std::io::stdout().flush();
+
+                self.percent = percent;
+            }
+        }
+
+        if self.regenerate_ecc {
+            try!(sector.regenerate_ecc());
+        }
+
+        Ok(())
+    }
+
+    fn track_msf(&self, track: Bcd, track_msf: Msf) -> Result<Msf, CdError> {
+        self.image.track_msf(track, track_msf)
+    }
+
+    fn toc(&self) -> Toc {
+        self.image.toc()
+    }
+}
+
+fn hexdump(bytes: &[u8]) {
+    fn is_print(b: u8) -> bool {
+        b >= b' ' && b <= b'~'
+    }
+
+    let mut pos = 0;
+
+    while pos + 16 <= bytes.len() {
+        let bytes = &bytes[pos..pos+16];
+
+        print!("{:08x}  ", pos);
+
+        for &b in &bytes[0..8] {
+            print!("{:02x} ", b)
+        }
+
+        print!(" ");
+
+        for &b in &bytes[8..16] {
+            print!("{:02x} ", b)
+        }
+
+        print!(" |");
+
+        for &b in &bytes[0..16] {
+            if is_print(b) {
+                print!("{}", b as char);
+            } else {
+                print!(".");
+            }
+        }
+
+        println!("|");
+
+        pos += 16;
+    }
+
+    let rem = bytes.len() & !15;
+
+    if rem != bytes.len() {
+        print!("{:08x} ", rem);
+
+        for p in rem..bytes.len() {
+            let b = bytes[p];
+
+            if p % 8 == 0 {
+                print!(" ");
+            }
+
+            print!("{:02x} ", b)
+        }
+
+        let pad = 16 - bytes.len() % 16;
+
+        if pad >= 8 {
+            print!(" ");
+        }
+
+        for _ in 0..pad {
+            print!("   ");
+        }
+
+        print!(" |");
+
+        for &b in &bytes[rem..] {
+            if is_print(b) {
+                print!("{}", b as char);
+            } else {
+                print!(".");
+            }
+        }
+
+        println!("|");
+    }
+}
+
+/// Create an empty directory for the test `name`
+#[cfg(test)]
+fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir()
+        .join(format!("cdtool-{}-{}", name, std::process::id()));
+
+    let _ = std::fs::remove_dir_all(&dir);
+
+    std::fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+/// Create the file `name` in `dir` with the given contents and return
+/// its path
+#[cfg(test)]
+fn test_file(dir: &Path, name: &str, data: &[u8]) -> std::path::PathBuf {
+    let path = dir.join(name);
+
+    File::create(&path).unwrap().write_all(data).unwrap();
+
+    path
+}
+
+/// Build the raw data sector at `lba` in the given `mode` (1, or 2
+/// for Mode 2 Form 1, 3 for Mode 2 Form 2) with its user data filled
+/// with `fill`
+#[cfg(test)]
+fn test_sector(lba: u32, mode: u8, fill: u8) -> Vec<u8> {
+    use cdimage::ecc;
+    use cdimage::sector::SYNC_PATTERN;
+
+    let mut sector = [0; 2352];
+    let msf = Msf::from_sector_index(lba + 150).unwrap();
+    let (m, s, f) = msf.into_bcd();
+
+    sector[0..12].copy_from_slice(&SYNC_PATTERN);
+    sector[12..16].copy_from_slice(&[m.bcd(), s.bcd(), f.bcd(),
+                                     if mode == 1 { 1 } else { 2 }]);
+
+    match mode {
+        1 => {
+            for b in &mut sector[16..2064] {
+                *b = fill;
+            }
+
+            ecc::generate_mode1(&mut sector);
+        }
+        2 => {
+            sector[16..24].copy_from_slice(&[0, 0, 0x08, 0, 0, 0, 0x08, 0]);
+
+            for b in &mut sector[24..2072] {
+                *b = fill;
+            }
+
+            ecc::generate_mode2_form1(&mut sector);
+        }
+        _ => {
+            sector[16..24].copy_from_slice(&[1, 1, 0x20, 0, 1, 1, 0x20, 0]);
+
+            for b in &mut sector[24..2348] {
+                *b = fill;
+            }
+
+            ecc::generate_mode2_form2(&mut sector);
+        }
+    }
+
+    sector.to_vec()
+}
+
+#[cfg(test)]
+fn test_convert(source: &Path,
+                out: &Path,
+                strip_ecc: bool,
+                regenerate_ecc: bool) -> Result<u32, String> {
+    let options = ConvertOptions {
+        strip_ecc: strip_ecc,
+        regenerate_ecc: regenerate_ecc,
+    };
+
+    convert_image(cdimage::open(source).unwrap(), out, &options)
+}
+
+#[test]
+fn convert_to_cue() {
+    use cdimage::cue::Cue;
+
+    let dir = test_dir("convert-cue");
+
+    // A Mode 1 track with a corrupted sector and an audio track
+    let mut data = Vec::new();
+
+    for s in 0..4 {
+        data.extend(test_sector(s, 1, s as u8));
+    }
+
+    data[2352 + 100] ^= 0x40;
+
+    let audio: Vec<u8> = (0..3 * 2352).map(|i| (i % 253) as u8).collect();
+
+    test_file(&dir, "data.bin", &data);
+    test_file(&dir, "audio.bin", &audio);
+
+    let source = test_file(&dir, "source.cue",
+                           b"FILE \"data.bin\" BINARY\n\
+                             \x20 TRACK 01 MODE1/2352\n\
+                             \x20   INDEX 01 00:00:00\n\
+                             FILE \"audio.bin\" BINARY\n\
+                             \x20 TRACK 02 AUDIO\n\
+                             \x20   INDEX 01 00:00:00\n");
+
+    let copy = dir.join("copy.cue");
+    let regenerated = dir.join("regenerated.cue");
+
+    assert!(test_convert(&source, &copy, false, false) == Ok(1));
+    assert!(test_convert(&source, &regenerated, false, true) == Ok(1));
+
+    let mut source = Cue::new(&source).unwrap();
+    let mut copy = Cue::new(&copy).unwrap();
+    let mut regenerated = Cue::new(&regenerated).unwrap();
+
+    assert!(copy.toc() == source.toc());
+    assert!(regenerated.toc() == source.toc());
+
+    let mut a = Sector::empty();
+    let mut b = Sector::empty();
+    let mut c = Sector::empty();
+
+    for pos in 0..source.toc().lead_out().sector_index() {
+        let msf = Msf::from_sector_index(pos).unwrap();
+
+        source.read_sector(&mut a, msf).unwrap();
+        copy.read_sector(&mut b, msf).unwrap();
+        regenerated.read_sector(&mut c, msf).unwrap();
+
+        // The corrupted sector is copied as-is
+        assert!(&a.data_2352().unwrap()[..] == &b.data_2352().unwrap()[..]);
+
+        if pos == 151 {
+            assert!(c.check_integrity().unwrap() == Status::Valid);
+            assert!(c.mode1_payload().unwrap()[..] ==
+                    a.mode1_payload().unwrap()[..]);
+        } else {
+            assert!(&a.data_2352().unwrap()[..] ==
+                    &c.data_2352().unwrap()[..]);
+        }
+    }
+}
+
+#[test]
+fn convert_to_iso() {
+    use std::fs;
+
+    let dir = test_dir("convert-iso");
+
+    // Mode 2 track: Form 1 sectors followed by a blank Form 2 gap
+    let mut data = Vec::new();
+
+    for s in 0..3 {
+        data.extend(test_sector(s, 2, 0x10 + s as u8));
+    }
+
+    data.extend(test_sector(3, 3, 0));
+
+    test_file(&dir, "mode2.bin", &data);
+
+    let source = test_file(&dir, "mode2.cue",
+                           b"FILE \"mode2.bin\" BINARY\n\
+                             \x20 TRACK 01 MODE2/2352\n\
+                             \x20   INDEX 01 00:00:00\n");
+
+    let iso = dir.join("mode2.iso");
+
+    assert!(test_convert(&source, &iso, false, false) == Ok(0));
+
+    let mut expected = Vec::new();
+
+    for s in 0..3 {
+        expected.extend_from_slice(&[0x10 + s; 2048]);
+    }
+
+    expected.extend_from_slice(&[0; 2048]);
+
+    let mut written = Vec::new();
+
+    fs::File::open(&iso).unwrap().read_to_end(&mut written).unwrap();
+
+    assert!(written == expected);
+
+    // The error correction codes are never stored in an ISO image
+    assert!(test_convert(&source, &iso, true, false).is_err());
+
+    // Unknown output format
+    assert!(test_convert(&source, &dir.join("out.bin"), false, false)
+            .is_err());
+}
+
+#[test]
+fn convert_to_iso_unsupported() {
+    let dir = test_dir("convert-iso-unsupported");
+
+    // Form 2 sectors with data (XA audio, video...)
+    let mut data = Vec::new();
+
+    data.extend(test_sector(0, 2, 0x10));
+    data.extend(test_sector(1, 3, 0x20));
+
+    test_file(&dir, "form2.bin", &data);
+
+    let source = test_file(&dir, "form2.cue",
+                           b"FILE \"form2.bin\" BINARY\n\
+                             \x20 TRACK 01 MODE2/2352\n\
+                             \x20   INDEX 01 00:00:00\n");
+
+    assert!(test_convert(&source, &dir.join("form2.iso"), false, false)
+            .is_err());
+
+    // Several data tracks
+    let mut data = Vec::new();
+
+    for s in 0..4 {
+        data.extend(test_sector(s, 1, s as u8));
+    }
+
+    test_file(&dir, "tracks.bin", &data);
+
+    let source = test_file(&dir, "tracks.cue",
+                           b"FILE \"tracks.bin\" BINARY\n\
+                             \x20 TRACK 01 MODE1/2352\n\
+                             \x20   INDEX 01 00:00:00\n\
+                             \x20 TRACK 02 MODE1/2352\n\
+                             \x20   INDEX 01 00:00:02\n");
+
+    assert!(test_convert(&source, &dir.join("tracks.iso"), false, false)
+            .is_err());
+}
+
+#[test]
+fn rip_wav() {
+    use cdimage::cue::Cue;
+
+    let dir = test_dir("rip");
+
+    // Track 01: 2 Mode 1 sectors, track 02: 3 audio sectors
+    let audio: Vec<u8> = (0..3 * 2352).map(|i| (i * 3 + i / 7) as u8)
+        .collect();
+
+    test_file(&dir, "data.bin", &[0; 2 * 2048]);
+    test_file(&dir, "audio.bin", &audio);
+
+    let cue = test_file(&dir, "disc.cue",
+                        b"FILE \"data.bin\" BINARY\n\
+                          \x20 TRACK 01 MODE1/2048\n\
+                          \x20   INDEX 01 00:00:00\n\
+                          FILE \"audio.bin\" BINARY\n\
+                          \x20 TRACK 02 AUDIO\n\
+                          \x20   INDEX 01 00:00:00\n");
+
+    let prefix = dir.join("track");
+
+    rip(&mut Cue::new(&cue).unwrap(), prefix.to_str().unwrap());
+
+    // Only the audio track is ripped
+    assert!(!dir.join("track01.wav").exists());
+
+    let mut wav = Vec::new();
+
+    File::open(dir.join("track02.wav")).unwrap()
+        .read_to_end(&mut wav).unwrap();
+
+    let size = 3 * 2352;
+
+    let mut header = Vec::new();
+
+    write_wav_header(&mut header, size).unwrap();
+
+    assert!(header.len() == 44);
+    assert!(&wav[..44] == &header[..]);
+    assert!(&wav[44..] == &audio[..]);
+
+    // RIFF size, sample rate, byte rate, block alignment and bits per
+    // sample
+    let le32 = |b: &[u8]| {
+        b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 |
+        (b[3] as u32) << 24
+    };
+
+    assert!(&header[0..4] == b"RIFF" && &header[8..16] == b"WAVEfmt ");
+    assert!(le32(&header[4..8]) == 36 + size);
+    assert!(&header[20..24] == &[1, 0, 2, 0]);
+    assert!(le32(&header[24..28]) == 44100);
+    assert!(le32(&header[28..32]) == 44100 * 4);
+    assert!(&header[32..36] == &[4, 0, 16, 0]);
+    assert!(&header[36..40] == b"data" && le32(&header[40..44]) == size);
+}
@@ -0,0 +1,187 @@
+//! CloneCD (CCD/IMG/SUB) image format implementation
+//!
+//! A CloneCD image is made of three files:
+//!
+//! * The `.ccd` file is an INI-like text file containing a raw copy
+//!   of the disc's table of contents (the Q subchannel entries found
+//!   in the lead-in) and a description of each track.
+//! * The `.img` file contains the raw 2352 byte sectors of every
+//!   track, starting at the track 01's INDEX 01.
+//! * The optional `.sub` file contains the 96 bytes of subchannel
+//!   data of every sector in the `.img`, each channel stored
+//!   separately (12 bytes of P followed by 12 bytes of Q etc...).
+//!
+//! The lead-in, lead-out and track 01's pregap are not stored in the
+//! image. For multi-session discs the data of each session follows
+//! the previous one directly, the area between the sessions is not
+//! stored either.
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+use CdError;
+use Image;
+use internal::IndexCache;
+use sector::{Sector, SectorBuilder};
+use msf::Msf;
+use bcd::Bcd;
+use toc::Toc;
+
+use self::parser::CcdParser;
+
+mod parser;
+
+/// CloneCD image
+#[derive(Debug)]
+pub struct Ccd {
+    /// Cache of all the indices in the CD image
+    indices: IndexCache<Storage>,
+    /// Sector data
+    img: File,
+    /// Subchannel data, if available
+    sub: Option<File>,
+}
+
+impl Ccd {
+    /// Parse a `.ccd` file, open the corresponding `.img` and `.sub`
+    /// files and build a `Ccd` instance.
+    pub fn new(ccd_path: &Path) -> Result<Ccd, CdError> {
+        CcdParser::build_ccd(ccd_path)
+    }
+
+    /// Return `true` if the image comes with subchannel data (the
+    /// `.sub` file)
+    pub fn has_subchannel(&self) -> bool {
+        self.sub.is_some()
+    }
+
+    /// Read the 96 bytes of subchannel data for the sector at
+    /// `msf`. The data is returned exactly as it's stored in the
+    /// `.sub` file: 12 bytes for each channel, from P to W. Returns
+    /// `None` if the image doesn't contain any subchannel data for
+    /// this sector (missing `.sub` file or sector not stored in the
+    /// image).
+    pub fn read_subchannel(&mut self,
+                           msf: Msf) -> Result<Option<[u8; 96]>, CdError> {
+        let (index, _) = try!(self.indices.locate(msf));
+
+        let sub =
+            match self.sub {
+                Some(ref mut s) => s,
+                None => return Ok(None),
+            };
+
+        let img_sector =
+            match index.private() {
+                &Storage::Img(s) =>
+                    s as u64 + (msf.sector_index() - index.sector_index())
+                    as u64,
+                &Storage::PreGap => return Ok(None),
+            };
+
+        let mut subchannel = [0; 96];
+
+        if let Err(e) = read_at(sub, img_sector * 96, &mut subchannel) {
+            return Err(CdError::IoError(e));
+        }
+
+        Ok(Some(subchannel))
+    }
+}
+
+impl Image for Ccd {
+    fn image_format(&self) -> String {
+        "CloneCD".to_string()
+    }
+
+    fn read_sector(&mut self,
+                   sector: &mut Sector,
+                   msf: Msf) -> Result<(), CdError> {
+        let (index, metadata) = try!(self.indices.locate(msf));
+
+        let mut builder = SectorBuilder::new(sector);
+
+        match index.private() {
+            &Storage::Img(s) => {
+                let img_sector =
+                    s as u64 + (msf.sector_index() - index.sector_index())
+                    as u64;
+
+                let img = &mut self.img;
+
+                let res =
+                    builder.set_data_2352(
+                        |data| read_at(img, img_sector * 2352, data));
+
+                if let Err(e) = res {
+                    return Err(CdError::IoError(e));
+                }
+
+                if let Some(ref mut sub) = self.sub {
+                    let res =
+                        builder.set_subchannel_deinterleaved(
+                            |s| read_at(sub, img_sector * 96, s));
+
+                    if let Err(e) = res {
+                        return Err(CdError::IoError(e));
+                    }
+                }
+            }
+            &Storage::PreGap => builder.set_blank(index.format()),
+        }
+
+        builder.set_metadata(metadata);
+
+        Ok(())
+    }
+
+    fn track_msf(&self, track: Bcd, track_msf: Msf) -> Result<Msf, CdError> {
+        self.indices.track_msf(track, track_msf)
+    }
+
+    fn toc(&self) -> Toc {
+        self.indices.toc()
+    }
+}
+
+/// Storage for an index
+#[derive(Debug, Clone, Copy)]
+enum Storage {
+    /// The index is stored in the `.img` file. Contains the position
+    /// of its first sector in the file (in sectors, not bytes).
+    Img(u32),
+    /// The index is a pre-gap, it's not stored in the `.img` file and
+    /// must be regenerated.
+    PreGap,
+}
+
+/// Fill `buf` with the data located at `offset` in `file`
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    try!(file.seek(SeekFrom::Start(offset)));
+
+    file.read_exact(buf)
+}
+
+/// Open the file with the same name as `path` but with extension
+/// `ext`. Both the lower and upper case versions of the extension are
+/// tried. Returns `None` if the file doesn't exist.
+fn open_companion(path: &Path,
+                  ext: &str) -> Result<Option<(PathBuf, File)>, CdError> {
+    for ext in &[ext.to_lowercase(), ext.to_uppercase()] {
+        let p = path.with_extension(ext);
+
+        match File::open(&p) {
+            Ok(f) => return Ok(Some((p, f))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(CdError::IoError(e)),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Max size for a `.ccd` file, used to detect bogus input early
+/// without attempting to load a huge file to RAM.
+pub const CCD_MAX_LENGTH: u64 = 1024 * 1024;
@@ -0,0 +1,756 @@
+use std::path::{Path, PathBuf};
+use std::fs::{File, metadata};
+use std::io;
+use std::io::Read;
+
+use internal::{Index, IndexCache};
+use toc::Control;
+use CdError;
+use TrackFormat;
+use SessionFormat;
+
+use bcd::Bcd;
+use msf::Msf;
+
+use super::{Ccd, Storage, CCD_MAX_LENGTH, open_companion};
+
+pub struct CcdParser {
+    /// Path to the `.ccd` file
+    ccd_path: PathBuf,
+    /// Sections in the order they appear in the file
+    sections: Vec<Section>,
+}
+
+/// A single `[Section]` of the `.ccd` file
+struct Section {
+    /// Name of the section, without the brackets
+    name: String,
+    /// Line where the section starts
+    line: u32,
+    /// `key=value` entries in the section along with their line
+    /// number
+    entries: Vec<(String, String, u32)>,
+}
+
+impl Section {
+    /// Look for `key` in the section. The lookup is case-insensitive.
+    fn get(&self, key: &str) -> Option<(&str, u32)> {
+        self.entries.iter()
+            .find(|&&(ref k, _, _)| k.eq_ignore_ascii_case(key))
+            .map(|&(_, ref v, line)| (&**v, line))
+    }
+}
+
+/// Raw Q subchannel TOC entry (`[Entry N]` section)
+struct TocEntry {
+    session: u8,
+    point: u8,
+    adr: u8,
+    control: u8,
+    /// P-MSF field in binary (*not* BCD)
+    pmsf: (u8, u8, u8),
+    /// Line number of the section
+    line: u32,
+}
+
+/// Track description built from the TOC entries and the `[TRACK N]`
+/// sections
+struct CcdTrack {
+    number: Bcd,
+    format: TrackFormat,
+    control: Control,
+    /// Index numbers and absolute sector indices
+    indices: Vec<(Bcd, u32)>,
+}
+
+impl CcdParser {
+    /// Parse a `.ccd` file, open the `.img` and `.sub` files and
+    /// generate the CD structure
+    pub fn build_ccd(ccd_path: &Path) -> Result<Ccd, CdError> {
+        let ccd =
+            match read_file(ccd_path, CCD_MAX_LENGTH) {
+                Ok(c) => c,
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        let mut parser = CcdParser {
+            ccd_path: PathBuf::from(ccd_path),
+            sections: Vec::new(),
+        };
+
+        try!(parser.parse(&String::from_utf8_lossy(&ccd)));
+
+        let (img_path, img) =
+            match try!(open_companion(ccd_path, "img")) {
+                Some(i) => i,
+                None => {
+                    let error = "Can't find the .img file".to_string();
+
+                    return Err(CdError::BadImage(parser.ccd_path, error));
+                }
+            };
+
+        let sub =
+            match try!(open_companion(ccd_path, "sub")) {
+                Some((_, s)) => Some(s),
+                None => None,
+            };
+
+        let img_len =
+            match metadata(&img_path) {
+                Ok(m) => m.len(),
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        let indices = try!(parser.build_indices(img_len));
+
+        Ok(Ccd {
+            indices: indices,
+            img: img,
+            sub: sub,
+        })
+    }
+
+    fn error(&self, line: u32, msg: String) -> CdError {
+        CdError::ParseError(self.ccd_path.clone(), line, msg)
+    }
+
+    fn bad_image(&self, msg: String) -> CdError {
+        CdError::BadImage(self.ccd_path.clone(), msg)
+    }
+
+    /// Split the file into sections and `key=value` entries
+    fn parse(&mut self, ccd: &str) -> Result<(), CdError> {
+        for (line_no, line) in ccd.lines().enumerate() {
+            let line_no = line_no as u32 + 1;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(self.error(line_no,
+                                          "Unterminated section".to_string()));
+                }
+
+                let name = line[1..line.len() - 1].trim().to_string();
+
+                self.sections.push(Section {
+                    name: name,
+                    line: line_no,
+                    entries: Vec::new(),
+                });
+
+                continue;
+            }
+
+            let (key, value) =
+                match line.find('=') {
+                    Some(p) => (line[..p].trim(), line[p + 1..].trim()),
+                    None => {
+                        let error = format!("Unexpected line \"{}\"", line);
+
+                        return Err(self.error(line_no, error));
+                    }
+                };
+
+            match self.sections.last_mut() {
+                Some(s) => s.entries.push((key.to_string(),
+                                           value.to_string(),
+                                           line_no)),
+                None =>
+                    return Err(self.error(line_no,
+                                          "Entry outside of any section"
+                                          .to_string())),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return the first section named `name`, the lookup is
+    /// case-insensitive.
+    fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Retrieve the integer value of `key` in `section`. Returns an
+    /// error if the key is missing or invalid.
+    fn int(&self, section: &Section, key: &str) -> Result<i64, CdError> {
+        match try!(self.int_opt(section, key)) {
+            Some(v) => Ok(v),
+            None => {
+                let error = format!("Missing {} in section [{}]",
+                                    key, section.name);
+
+                Err(self.error(section.line, error))
+            }
+        }
+    }
+
+    /// Retrieve the integer value of `key` in `section` if it
+    /// exists. Returns an error if the value is invalid.
+    fn int_opt(&self,
+               section: &Section,
+               key: &str) -> Result<Option<i64>, CdError> {
+        match section.get(key) {
+            Some((v, line)) =>
+                match parse_int(v) {
+                    Ok(v) => Ok(Some(v)),
+                    Err(()) => {
+                        let error = format!("Invalid value for {}: \"{}\"",
+                                            key, v);
+
+                        Err(self.error(line, error))
+                    }
+                },
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieve the value of `key` in `section` as an `u8`
+    fn u8(&self, section: &Section, key: &str) -> Result<u8, CdError> {
+        let v = try!(self.int(section, key));
+
+        if v < 0 || v > 0xff {
+            let error = format!("{} is out of range: {}", key, v);
+
+            return Err(self.error(section.line, error));
+        }
+
+        Ok(v as u8)
+    }
+
+    /// Parse all the `[Entry N]` sections
+    fn toc_entries(&self) -> Result<Vec<TocEntry>, CdError> {
+        let mut entries = Vec::new();
+
+        for section in &self.sections {
+            // The name can contain any character, don't slice the
+            // string in the middle of one
+            let name = section.name.as_bytes();
+
+            let is_entry =
+                name.len() > 6 && name[..6].eq_ignore_ascii_case(b"entry ");
+
+            if !is_entry {
+                continue;
+            }
+
+            entries.push(TocEntry {
+                session: try!(self.u8(section, "Session")),
+                point: try!(self.u8(section, "Point")),
+                adr: try!(self.u8(section, "ADR")),
+                control: try!(self.u8(section, "Control")),
+                pmsf: (try!(self.u8(section, "PMin")),
+                       try!(self.u8(section, "PSec")),
+                       try!(self.u8(section, "PFrame"))),
+                line: section.line,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Convert the P-MSF of a TOC entry into an MSF
+    fn entry_msf(&self, entry: &TocEntry) -> Result<Msf, CdError> {
+        let (m, s, f) = entry.pmsf;
+
+        let msf =
+            match (Bcd::from_binary(m),
+                   Bcd::from_binary(s),
+                   Bcd::from_binary(f)) {
+                (Some(m), Some(s), Some(f)) => Msf::new(m, s, f),
+                _ => None,
+            };
+
+        match msf {
+            Some(msf) => Ok(msf),
+            None => {
+                let error = format!("Invalid MSF {}:{}:{}", m, s, f);
+
+                Err(self.error(entry.line, error))
+            }
+        }
+    }
+
+    /// Build the track list for `session`
+    fn session_tracks(&self,
+                      entries: &[TocEntry],
+                      session: u8,
+                      format: SessionFormat)
+                      -> Result<Vec<CcdTrack>, CdError> {
+        let mut tracks = Vec::new();
+
+        let track_entries =
+            entries.iter().filter(|e| {
+                e.session == session && e.adr == 1 &&
+                    e.point >= 1 && e.point <= 99
+            });
+
+        for entry in track_entries {
+            let number = Bcd::from_binary(entry.point).unwrap();
+            let control = Control::from_bits_truncate(entry.control & 0xf);
+            let start = try!(self.entry_msf(entry)).sector_index();
+
+            let section_name = format!("TRACK {}", entry.point);
+
+            let (mode, mut indices) =
+                match self.section(&section_name) {
+                    Some(section) => {
+                        let mode = try!(self.int_opt(section, "MODE"));
+
+                        let mut indices = Vec::new();
+
+                        for i in 0..100 {
+                            let key = format!("INDEX {}", i);
+
+                            let lba =
+                                match try!(self.int_opt(section, &key)) {
+                                    Some(l) => l,
+                                    None => continue,
+                                };
+
+                            if lba < -150 || lba + 150 > 0xffffffff {
+                                let error = format!("Invalid {}: {}",
+                                                    key, lba);
+
+                                return Err(self.error(section.line, error));
+                            }
+
+                            indices.push((Bcd::from_binary(i).unwrap(),
+                                          (lba + 150) as u32));
+                        }
+
+                        (mode, indices)
+                    }
+                    None => (None, Vec::new()),
+                };
+
+            if !indices.iter().any(|&(i, _)| i == Bcd::one()) {
+                indices.push((Bcd::one(), start));
+                indices.sort_by_key(|&(i, _)| i);
+            }
+
+            let mode2 =
+                match format {
+                    SessionFormat::Cdi => TrackFormat::Mode2CdI,
+                    _ => TrackFormat::Mode2Xa,
+                };
+
+            let format =
+                match mode {
+                    Some(0) => TrackFormat::Audio,
+                    Some(1) => TrackFormat::Mode1,
+                    Some(2) => mode2,
+                    Some(m) => {
+                        let error = format!("Track {}: unsupported mode {}",
+                                            number, m);
+
+                        return Err(self.bad_image(error));
+                    }
+                    // No [TRACK] section, use the TOC to figure out
+                    // the track type
+                    None =>
+                        if !control.contains(::toc::DATA) {
+                            TrackFormat::Audio
+                        } else if format == SessionFormat::CddaCdRom {
+                            TrackFormat::Mode1
+                        } else {
+                            mode2
+                        },
+                };
+
+            tracks.push(CcdTrack {
+                number: number,
+                format: format,
+                control: control,
+                indices: indices,
+            });
+        }
+
+        tracks.sort_by_key(|t| t.number);
+
+        Ok(tracks)
+    }
+
+    /// Build the index cache from the TOC entries
+    fn build_indices(&self,
+                     img_len: u64) -> Result<IndexCache<Storage>, CdError> {
+        let disc =
+            match self.section("Disc") {
+                Some(d) => d,
+                None => return Err(self.bad_image("Missing [Disc] section"
+                                                  .to_string())),
+            };
+
+        if try!(self.int_opt(disc, "DataTracksScrambled")).unwrap_or(0) != 0 {
+            return Err(self.bad_image("Scrambled data tracks are not \
+                                       supported".to_string()));
+        }
+
+        let entries = try!(self.toc_entries());
+
+        let mut sessions: Vec<u8> =
+            entries.iter().map(|e| e.session).collect();
+
+        sessions.sort();
+        sessions.dedup();
+
+        let mut indices = Vec::new();
+        let mut lead_outs = Vec::new();
+
+        // Position of the current session's data in the `.img` file
+        let mut img_sector = 0;
+
+        for &session in &sessions {
+            let session_entry = |point| {
+                entries.iter()
+                    .find(|e| e.session == session && e.adr == 1 &&
+                          e.point == point)
+            };
+
+            // The session format is stored in the PSec field of the
+            // 0xA0 pointer
+            let format =
+                match session_entry(0xa0).map(|e| e.pmsf.1) {
+                    Some(0x10) => SessionFormat::Cdi,
+                    Some(0x20) => SessionFormat::Cdxa,
+                    _ => SessionFormat::CddaCdRom,
+                };
+
+            let lead_out =
+                match session_entry(0xa2) {
+                    Some(e) => try!(self.entry_msf(e)).sector_index(),
+                    None => {
+                        let error = format!("Missing lead-out for session {}",
+                                            session);
+
+                        return Err(self.bad_image(error));
+                    }
+                };
+
+            let tracks = try!(self.session_tracks(&entries, session, format));
+
+            if tracks.is_empty() {
+                let error = format!("Session {} has no tracks", session);
+
+                return Err(self.bad_image(error));
+            }
+
+            // The session's data starts at its first index
+            let start = tracks[0].indices[0].1;
+
+            if start >= lead_out {
+                let error = format!("Session {}'s lead-out is before its \
+                                     first track", session);
+
+                return Err(self.bad_image(error));
+            }
+
+            // Add the first track's pregap if it's not stored in the
+            // image
+            let first = &tracks[0];
+
+            if first.indices[0].0 != Bcd::zero() {
+                let pregap_start =
+                    if lead_outs.is_empty() {
+                        Some(0)
+                    } else if start >= 150 {
+                        Some(start - 150)
+                    } else {
+                        None
+                    };
+
+                let prev_lead_out = lead_outs.last()
+                    .map_or(0, |l: &Msf| l.sector_index());
+
+                if let Some(pregap_start) = pregap_start {
+                    if pregap_start >= prev_lead_out && pregap_start < start {
+                        let mut pregap =
+                            Index::new(Bcd::zero(),
+                                       Msf::from_sector_index(pregap_start)
+                                       .unwrap(),
+                                       first.number,
+                                       first.format,
+                                       session,
+                                       Storage::PreGap);
+
+                        pregap.set_control(first.control);
+
+                        indices.push(pregap);
+                    }
+                }
+            }
+
+            for track in &tracks {
+                for &(index, sector) in &track.indices {
+                    if sector < start || sector >= lead_out {
+                        let error = format!("Track {} index {} is out of \
+                                             its session", track.number,
+                                            index);
+
+                        return Err(self.bad_image(error));
+                    }
+
+                    let msf =
+                        match Msf::from_sector_index(sector) {
+                            Some(m) => m,
+                            None => {
+                                let error = format!("Track {} index {} is \
+                                                     out of range",
+                                                    track.number, index);
+
+                                return Err(self.bad_image(error));
+                            }
+                        };
+
+                    let storage = Storage::Img(img_sector + sector - start);
+
+                    let mut index = Index::new(index,
+                                               msf,
+                                               track.number,
+                                               track.format,
+                                               session,
+                                               storage);
+
+                    index.set_control(track.control);
+
+                    indices.push(index);
+                }
+            }
+
+            img_sector += lead_out - start;
+
+            lead_outs.push(Msf::from_sector_index(lead_out).unwrap());
+        }
+
+        if (img_sector as u64) * 2352 > img_len {
+            let error = format!("The .img file is too small: expected {} \
+                                 sectors, got {}",
+                                img_sector, img_len / 2352);
+
+            return Err(self.bad_image(error));
+        }
+
+        IndexCache::new_multisession(self.ccd_path.clone(),
+                                     indices,
+                                     lead_outs)
+    }
+}
+
+/// Parse an integer in decimal or hexadecimal (with a `0x` prefix)
+/// notation
+fn parse_int(s: &str) -> Result<i64, ()> {
+    let r =
+        if s.starts_with("0x") || s.starts_with("0X") {
+            i64::from_str_radix(&s[2..], 16)
+        } else {
+            s.parse()
+        };
+
+    r.map_err(|_| ())
+}
+
+fn read_file(path: &Path, max_len: u64) -> Result<Vec<u8>, io::Error> {
+    let md = try!(metadata(path));
+
+    let len = md.len();
+
+    if len > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "CCD file is too big"));
+    }
+
+    let mut file = try!(File::open(path));
+
+    let mut ccd = Vec::with_capacity(len as usize);
+
+    try!(file.read_to_end(&mut ccd));
+
+    Ok(ccd)
+}
+
+#[test]
+fn test_parse_int() {
+    assert!(parse_int("0") == Ok(0));
+    assert!(parse_int("42") == Ok(42));
+    assert!(parse_int("-150") == Ok(-150));
+    assert!(parse_int("0xa0") == Ok(0xa0));
+    assert!(parse_int("0XA2") == Ok(0xa2));
+    assert!(parse_int("") == Err(()));
+    assert!(parse_int("0x") == Err(()));
+    assert!(parse_int("a0") == Err(()));
+}
+
+/// Parse the contents of a `.ccd` file for the tests
+#[cfg(test)]
+fn test_parser(ccd: &str) -> CcdParser {
+    let mut parser = CcdParser {
+        ccd_path: PathBuf::from("test.ccd"),
+        sections: Vec::new(),
+    };
+
+    parser.parse(ccd).unwrap();
+
+    parser
+}
+
+/// Two sessions: a Mode 1 and an audio track with an INDEX 00 in the
+/// first one, a CD-XA track without `[TRACK]` section in the second
+/// one
+#[cfg(test)]
+const MULTISESSION_CCD: &'static str = "\
+[CloneCD]
+Version=3
+[Disc]
+TocEntries=7
+Sessions=2
+DataTracksScrambled=0
+[Entry 0]
+Session=1
+Point=0xa0
+ADR=0x01
+Control=0x04
+PMin=1
+PSec=0
+PFrame=0
+[Entry 1]
+Session=1
+Point=0xa2
+ADR=0x01
+Control=0x04
+PMin=0
+PSec=6
+PFrame=0
+[Entry 2]
+Session=1
+Point=0x01
+ADR=0x01
+Control=0x04
+PMin=0
+PSec=2
+PFrame=0
+[Entry 3]
+Session=1
+Point=0x02
+ADR=0x01
+Control=0x00
+PMin=0
+PSec=4
+PFrame=0
+[Entry 4]
+Session=2
+Point=0xa0
+ADR=0x01
+Control=0x04
+PMin=3
+PSec=32
+PFrame=0
+[Entry 5]
+Session=2
+Point=0xa2
+ADR=0x01
+Control=0x04
+PMin=2
+PSec=39
+PFrame=25
+[Entry 6]
+Session=2
+Point=0x03
+ADR=0x01
+Control=0x04
+PMin=2
+PSec=38
+PFrame=0
+[TRACK 1]
+MODE=1
+INDEX 1=0
+[TRACK 2]
+MODE=0
+INDEX 0=140
+INDEX 1=150
+";
+
+#[test]
+fn multisession_indices() {
+    use bcd::Bcd;
+
+    let parser = test_parser(MULTISESSION_CCD);
+
+    let msf = |s| Msf::from_sector_index(s).unwrap();
+    let track = |t| Bcd::from_binary(t).unwrap();
+
+    // 300 sectors in the first session, 100 in the second one
+    let indices = parser.build_indices(400 * 2352).unwrap();
+
+    let toc = indices.toc();
+
+    assert!(toc.sessions.len() == 2);
+    assert!(toc.sessions[0].lead_out == msf(450));
+    assert!(toc.sessions[1].lead_out == msf(11950));
+
+    let t1 = toc.track(track(1)).unwrap();
+
+    assert!(t1.format == TrackFormat::Mode1);
+    assert!(t1.pregap_start() == msf(0));
+    assert!(t1.start == msf(150));
+
+    let t2 = toc.track(track(2)).unwrap();
+
+    assert!(t2.format == TrackFormat::Audio);
+    assert!(t2.pregap_start() == msf(290));
+    assert!(t2.start == msf(300));
+
+    // The second session's pregap isn't stored
+    let t3 = toc.track(track(3)).unwrap();
+
+    assert!(t3.format == TrackFormat::Mode2Xa);
+    assert!(t3.pregap_start() == msf(11700));
+    assert!(t3.start == msf(11850));
+
+    let img_sector = |s| {
+        match indices.locate(msf(s)) {
+            Ok((index, _)) =>
+                match *index.private() {
+                    Storage::Img(i) =>
+                        Some(i + s - index.sector_index()),
+                    Storage::PreGap => None,
+                },
+            Err(_) => panic!("Can't locate sector {}", s),
+        }
+    };
+
+    assert!(img_sector(100) == None);
+    assert!(img_sector(150) == Some(0));
+    assert!(img_sector(291) == Some(141));
+    assert!(img_sector(449) == Some(299));
+    assert!(img_sector(11800) == None);
+    assert!(img_sector(11850) == Some(300));
+    assert!(img_sector(11949) == Some(399));
+
+    let (_, metadata) = indices.locate(msf(11851)).unwrap();
+
+    assert!(metadata.session == 2);
+    assert!(metadata.track == track(3));
+    assert!(metadata.track_msf == msf(1));
+
+    // Between the sessions
+    assert!(indices.locate(msf(1000)).is_err());
+
+    // The .img file is too small
+    assert!(parser.build_indices(399 * 2352).is_err());
+}
+
+#[test]
+fn entry_section_names() {
+    // Non-ASCII section names must not cause a panic
+    let ccd = MULTISESSION_CCD.replace("[TRACK 1]", "[Entryé]\nPoint=1");
+
+    let parser = test_parser(&ccd);
+
+    assert!(parser.toc_entries().unwrap().len() == 7);
+    assert!(parser.build_indices(400 * 2352).is_ok());
+}
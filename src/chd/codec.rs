@@ -0,0 +1,224 @@
+//! Decompression of the hunks of CD CHD files.
+//!
+//! The CD codecs store the 2352 bytes of sector data and the 96
+//! bytes of subchannel data of every frame in the hunk separately:
+//! first the sector data of all the frames compressed with the
+//! codec's base algorithm, then all the subchannel data compressed
+//! with deflate.
+//!
+//! The LZMA and deflate codecs can also strip the sync pattern and
+//! ECC of Mode 1 sectors if they can be regenerated: those sectors
+//! are flagged in a bitmap at the beginning of the compressed hunk.
+
+use std::io::Cursor;
+
+use lzma_rs::decompress::raw::{LzmaDecoder, LzmaParams, LzmaProperties};
+use claxon::frame::FrameReader;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+
+use sector::SYNC_PATTERN;
+use ecc;
+
+/// CD codecs supported by this implementation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// LZMA sector data ("cdlz")
+    CdLzma,
+    /// Deflate sector data ("cdzl")
+    CdZlib,
+    /// FLAC sector data ("cdfl"), used for audio tracks
+    CdFlac,
+}
+
+impl Codec {
+    /// Return the codec corresponding to a four-character `tag` or
+    /// `None` if it's not supported.
+    pub fn from_tag(tag: &[u8; 4]) -> Option<Codec> {
+        match tag {
+            b"cdlz" => Some(Codec::CdLzma),
+            b"cdzl" => Some(Codec::CdZlib),
+            b"cdfl" => Some(Codec::CdFlac),
+            _ => None,
+        }
+    }
+
+    /// Decompress the hunk in `src` into `dst`. `dst` must be a
+    /// multiple of the frame size.
+    pub fn decompress(self, src: &[u8], dst: &mut [u8]) -> Result<(), String> {
+        let frames = dst.len() / FRAME_SIZE;
+
+        let mut sectors = vec![0; frames * SECTOR_SIZE];
+        let mut subchannel = vec![0; frames * SUBCHANNEL_SIZE];
+
+        // Bitmap of the frames whose ECC must be regenerated
+        let ecc_bytes =
+            match self {
+                Codec::CdFlac => 0,
+                _ => (frames + 7) / 8,
+            };
+
+        match self {
+            Codec::CdFlac => {
+                let base_len = try!(flac(src, &mut sectors));
+
+                try!(inflate(&src[base_len..], &mut subchannel));
+            }
+            _ => {
+                // Length of the compressed sector data, on 2 bytes
+                // unless the hunks are 64KiB or more.
+                let len_bytes = if dst.len() < 0x10000 { 2 } else { 3 };
+
+                let header_len = ecc_bytes + len_bytes;
+
+                if src.len() < header_len {
+                    return Err("Truncated hunk".to_string());
+                }
+
+                let base_len =
+                    src[ecc_bytes..header_len].iter()
+                    .fold(0, |l, &b| (l << 8) | b as usize);
+
+                if header_len + base_len > src.len() {
+                    return Err("Truncated hunk".to_string());
+                }
+
+                let base = &src[header_len..header_len + base_len];
+
+                if self == Codec::CdLzma {
+                    try!(lzma(base, &mut sectors));
+                } else {
+                    try!(inflate(base, &mut sectors));
+                }
+
+                try!(inflate(&src[header_len + base_len..], &mut subchannel));
+            }
+        }
+
+        for f in 0..frames {
+            let frame = &mut dst[f * FRAME_SIZE..(f + 1) * FRAME_SIZE];
+
+            frame[..SECTOR_SIZE].copy_from_slice(
+                &sectors[f * SECTOR_SIZE..(f + 1) * SECTOR_SIZE]);
+            frame[SECTOR_SIZE..].copy_from_slice(
+                &subchannel[f * SUBCHANNEL_SIZE..(f + 1) * SUBCHANNEL_SIZE]);
+
+            if ecc_bytes > 0 && src[f / 8] & (1 << (f % 8)) != 0 {
+                let sector = array_mut_ref![frame, 0, 2352];
+
+                sector[0..12].copy_from_slice(&SYNC_PATTERN);
+                ecc::generate_ecc(sector);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Decompress the raw deflate stream `src` into `dst`
+fn inflate(src: &[u8], dst: &mut [u8]) -> Result<(), String> {
+    match decompress_to_vec_with_limit(src, dst.len()) {
+        Ok(ref v) if v.len() == dst.len() => {
+            dst.copy_from_slice(v);
+            Ok(())
+        }
+        _ => Err("Invalid deflate data".to_string()),
+    }
+}
+
+/// Decompress the raw LZMA stream `src` into `dst`. The stream has
+/// no header, the parameters are the ones used by MAME's encoder.
+fn lzma(src: &[u8], dst: &mut [u8]) -> Result<(), String> {
+    let properties = LzmaProperties { lc: 3, lp: 0, pb: 2 };
+
+    let params = LzmaParams::new(properties,
+                                 lzma_dict_size(dst.len() as u32),
+                                 Some(dst.len() as u64));
+
+    let mut decoder =
+        match LzmaDecoder::new(params, None) {
+            Ok(d) => d,
+            Err(e) => return Err(format!("LZMA error: {}", e)),
+        };
+
+    let mut input = src;
+    let mut output = Cursor::new(dst);
+
+    if let Err(e) = decoder.decompress(&mut input, &mut output) {
+        return Err(format!("LZMA error: {}", e));
+    }
+
+    Ok(())
+}
+
+/// Dictionary size used by MAME's encoder for `len` bytes of data
+/// (compression level 9 with the size hint set to `len`).
+fn lzma_dict_size(len: u32) -> u32 {
+    for i in 11..25 {
+        if len <= 2 << i {
+            return 2 << i;
+        }
+
+        if len <= 3 << i {
+            return 3 << i;
+        }
+    }
+
+    1 << 26
+}
+
+/// Decode the stereo 16bit FLAC frames in `src` into `dst`. MAME
+/// stores CD audio big-endian, so are the decoded samples. Returns
+/// the length of the FLAC data.
+fn flac(src: &[u8], dst: &mut [u8]) -> Result<usize, String> {
+    let mut reader = FrameReader::new(Cursor::new(src));
+    let mut buffer = Vec::new();
+    let mut pos = 0;
+
+    while pos < dst.len() {
+        let block =
+            match reader.read_next_or_eof(buffer) {
+                Ok(Some(b)) => b,
+                Ok(None) => return Err("Truncated FLAC data".to_string()),
+                Err(e) => return Err(format!("FLAC error: {}", e)),
+            };
+
+        if block.channels() != 2 {
+            return Err("Unexpected FLAC channel count".to_string());
+        }
+
+        for (left, right) in block.stereo_samples() {
+            if pos >= dst.len() {
+                break;
+            }
+
+            dst[pos] = (left >> 8) as u8;
+            dst[pos + 1] = left as u8;
+            dst[pos + 2] = (right >> 8) as u8;
+            dst[pos + 3] = right as u8;
+
+            pos += 4;
+        }
+
+        buffer = block.into_buffer();
+    }
+
+    Ok(reader.into_inner().position() as usize)
+}
+
+/// Size of a CD frame in a CHD hunk: sector data followed by the
+/// subchannel data
+pub const FRAME_SIZE: usize = SECTOR_SIZE + SUBCHANNEL_SIZE;
+/// Size of the sector data in a frame
+const SECTOR_SIZE: usize = 2352;
+/// Size of the subchannel data in a frame
+const SUBCHANNEL_SIZE: usize = 96;
+
+#[test]
+fn dict_size() {
+    // Standard CD hunk: 8 frames
+    assert!(lzma_dict_size(8 * 2352) == 24 * 1024);
+    assert!(lzma_dict_size(4096) == 4096);
+    assert!(lzma_dict_size(4097) == 6144);
+    assert!(lzma_dict_size(1) == 4096);
+    assert!(lzma_dict_size(0x7fff_ffff) == 1 << 26);
+}
@@ -0,0 +1,568 @@
+//! Low level access to CHD v5 files: header, hunk map, hunks and
+//! metadata.
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io;
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+
+use CdError;
+use crc::crc16;
+
+use super::codec::{Codec, FRAME_SIZE};
+use super::huffman::{BitReader, Huffman};
+
+/// CHD v5 file
+pub struct ChdFile {
+    /// Path to the file, used for error reporting
+    path: PathBuf,
+    file: File,
+    /// Size of a hunk in bytes
+    hunk_bytes: u32,
+    /// Total size of the uncompressed data in bytes
+    logical_bytes: u64,
+    /// Codecs used by the compressed hunks
+    codecs: [Option<Codec>; 4],
+    /// Location of every hunk in the file
+    map: Vec<MapEntry>,
+    /// Offset of the first metadata entry in the file, 0 if there
+    /// isn't any
+    meta_offset: u64,
+    /// Number of the hunk currently in `hunk`, if any. Since the
+    /// sectors are usually read sequentially we only cache the last
+    /// one.
+    cached_hunk: Option<u32>,
+    /// Decompressed hunk
+    hunk: Vec<u8>,
+}
+
+impl ChdFile {
+    /// Open the CHD file at `path`, parse its header and hunk map
+    pub fn open(path: &Path) -> Result<ChdFile, CdError> {
+        let file =
+            match File::open(path) {
+                Ok(f) => f,
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        let mut chd = ChdFile {
+            path: path.to_path_buf(),
+            file: file,
+            hunk_bytes: 0,
+            logical_bytes: 0,
+            codecs: [None; 4],
+            map: Vec::new(),
+            meta_offset: 0,
+            cached_hunk: None,
+            hunk: Vec::new(),
+        };
+
+        let map_offset = try!(chd.parse_header());
+
+        try!(chd.parse_map(map_offset));
+
+        Ok(chd)
+    }
+
+    /// Parse the file header and return the offset of the hunk map
+    fn parse_header(&mut self) -> Result<u64, CdError> {
+        let mut header = [0; HEADER_LEN];
+
+        try!(self.read_at(0, &mut header));
+
+        if &header[0..8] != b"MComprHD" {
+            return Err(self.error("Not a CHD file"));
+        }
+
+        let version = be_u32(&header[12..]);
+
+        if version != 5 {
+            let error = format!("Unsupported CHD version {}", version);
+            return Err(self.error(&error));
+        }
+
+        if be_u32(&header[8..]) as usize != HEADER_LEN {
+            return Err(self.error("Invalid CHD header length"));
+        }
+
+        for (i, codec) in self.codecs.iter_mut().enumerate() {
+            let tag = array_ref![header, 16 + i * 4, 4];
+
+            if tag == &[0; 4] {
+                continue;
+            }
+
+            *codec =
+                match Codec::from_tag(tag) {
+                    Some(c) => Some(c),
+                    None => {
+                        let error =
+                            format!("Unsupported CHD codec {}",
+                                    String::from_utf8_lossy(tag));
+
+                        return Err(CdError::BadImage(self.path.clone(),
+                                                     error));
+                    }
+                };
+        }
+
+        self.logical_bytes = be_u64(&header[32..]);
+        self.meta_offset = be_u64(&header[48..]);
+        self.hunk_bytes = be_u32(&header[56..]);
+
+        let map_offset = be_u64(&header[40..]);
+        let unit_bytes = be_u32(&header[60..]);
+
+        if unit_bytes as usize != FRAME_SIZE {
+            return Err(self.error("Not a CD CHD file"));
+        }
+
+        if self.hunk_bytes == 0 || self.hunk_bytes as usize % FRAME_SIZE != 0 {
+            return Err(self.error("Invalid CHD hunk size"));
+        }
+
+        // The SHA-1 of the parent is set if this CHD only contains
+        // the differences with another file
+        if header[104..124].iter().any(|&b| b != 0) {
+            return Err(self.error("CHD files with a parent are not \
+                                   supported"));
+        }
+
+        let hunks =
+            (self.logical_bytes + self.hunk_bytes as u64 - 1) /
+            self.hunk_bytes as u64;
+
+        if hunks > MAX_HUNKS {
+            return Err(self.error("Absurd CHD size"));
+        }
+
+        self.hunk = vec![0; self.hunk_bytes as usize];
+
+        Ok(map_offset)
+    }
+
+    /// Load the hunk map located at `offset`
+    fn parse_map(&mut self, offset: u64) -> Result<(), CdError> {
+        let hunks = self.hunk_count();
+
+        if self.codecs[0].is_none() {
+            // Uncompressed map: the offset of each uncompressed hunk
+            // (in hunks), 0 if the hunk is empty.
+            let mut raw = vec![0; hunks as usize * 4];
+
+            try!(self.read_at(offset, &mut raw));
+
+            let hunk_bytes = self.hunk_bytes as u64;
+
+            self.map =
+                raw.chunks(4).map(|e| {
+                    match be_u32(e) as u64 * hunk_bytes {
+                        0 => MapEntry::Zero,
+                        o => MapEntry::Uncompressed(o, None),
+                    }
+                }).collect();
+
+            return Ok(());
+        }
+
+        let mut header = [0; 16];
+
+        try!(self.read_at(offset, &mut header));
+
+        let map_len = be_u32(&header[0..]) as usize;
+        let first_offset = be_u48(&header[4..]);
+        let map_crc = be_u16(&header[10..]);
+        let length_bits = header[12] as u32;
+        let self_bits = header[13] as u32;
+        let parent_bits = header[14] as u32;
+
+        if length_bits > 32 || self_bits > 32 || parent_bits > 32 {
+            return Err(self.error("Invalid CHD map header"));
+        }
+
+        let mut compressed = vec![0; map_len];
+
+        try!(self.read_at(offset + 16, &mut compressed));
+
+        match decode_map(&compressed,
+                         hunks,
+                         self.hunk_bytes,
+                         first_offset,
+                         (length_bits, self_bits, parent_bits)) {
+            Ok((map, raw)) => {
+                // The CRC covers the map in its uncompressed format
+                if crc16(0xffff, &raw) != map_crc {
+                    return Err(self.error("CHD map CRC mismatch"));
+                }
+
+                self.map = map;
+            }
+            Err(e) => return Err(self.error(&e)),
+        }
+
+        Ok(())
+    }
+
+    /// Return the number of hunks in the file
+    fn hunk_count(&self) -> u32 {
+        ((self.logical_bytes + self.hunk_bytes as u64 - 1) /
+         self.hunk_bytes as u64) as u32
+    }
+
+    /// Return the number of frames in the file
+    pub fn frame_count(&self) -> u32 {
+        (self.logical_bytes / FRAME_SIZE as u64) as u32
+    }
+
+    /// Return the sector and subchannel data for `frame`
+    pub fn read_frame(&mut self,
+                      frame: u32) -> Result<&[u8; FRAME_SIZE], CdError> {
+        let offset = frame as u64 * FRAME_SIZE as u64;
+
+        if offset + FRAME_SIZE as u64 > self.logical_bytes {
+            return Err(self.error("Attempted to read past the end of the \
+                                   CHD"));
+        }
+
+        let hunk = (offset / self.hunk_bytes as u64) as u32;
+        let offset = (offset % self.hunk_bytes as u64) as usize;
+
+        try!(self.load_hunk(hunk));
+
+        Ok(array_ref![self.hunk, offset, FRAME_SIZE])
+    }
+
+    /// Load and decompress `hunk` into `self.hunk`
+    fn load_hunk(&mut self, hunk: u32) -> Result<(), CdError> {
+        // Follow the references to identical hunks
+        let mut hunk = hunk;
+
+        while let MapEntry::Copy(h) = self.map[hunk as usize] {
+            if h >= hunk {
+                return Err(self.error("Invalid hunk reference in CHD map"));
+            }
+
+            hunk = h;
+        }
+
+        if self.cached_hunk == Some(hunk) {
+            return Ok(());
+        }
+
+        self.cached_hunk = None;
+
+        let crc =
+            match self.map[hunk as usize] {
+                MapEntry::Compressed(codec, offset, len, crc) => {
+                    let codec =
+                        match self.codecs[codec as usize] {
+                            Some(c) => c,
+                            None =>
+                                return Err(self.error("Hunk compressed \
+                                                       with missing codec")),
+                        };
+
+                    let mut compressed = vec![0; len as usize];
+
+                    try!(self.read_at(offset, &mut compressed));
+
+                    if let Err(e) = codec.decompress(&compressed,
+                                                     &mut self.hunk) {
+                        let error = format!("Hunk {}: {}", hunk, e);
+                        return Err(self.error(&error));
+                    }
+
+                    Some(crc)
+                }
+                MapEntry::Uncompressed(offset, crc) => {
+                    let mut data = vec![0; self.hunk_bytes as usize];
+
+                    try!(self.read_at(offset, &mut data));
+
+                    self.hunk = data;
+
+                    crc
+                }
+                MapEntry::Zero => {
+                    for b in &mut self.hunk {
+                        *b = 0;
+                    }
+
+                    None
+                }
+                MapEntry::Parent =>
+                    return Err(self.error("CHD hunk stored in parent")),
+                MapEntry::Copy(_) => unreachable!(),
+            };
+
+        if let Some(crc) = crc {
+            if crc16(0xffff, &self.hunk) != crc {
+                let error = format!("Hunk {}: CRC mismatch", hunk);
+                return Err(self.error(&error));
+            }
+        }
+
+        self.cached_hunk = Some(hunk);
+
+        Ok(())
+    }
+
+    /// Return the contents of every metadata entry with the given
+    /// `tag`, in the order they appear in the file
+    pub fn metadata(&mut self,
+                    tag: &[u8; 4]) -> Result<Vec<Vec<u8>>, CdError> {
+        let mut entries = Vec::new();
+        let mut offset = self.meta_offset;
+        let mut count = 0;
+
+        while offset != 0 {
+            // Protect ourselves against loops in the list
+            count += 1;
+
+            if count > MAX_METADATA {
+                return Err(self.error("Too many CHD metadata entries"));
+            }
+
+            let mut header = [0; 16];
+
+            try!(self.read_at(offset, &mut header));
+
+            // The length is stored on 24 bits, the MSB contains flags
+            let len = be_u32(&header[4..]) & 0xffffff;
+
+            if &header[0..4] == tag {
+                let mut data = vec![0; len as usize];
+
+                try!(self.read_at(offset + 16, &mut data));
+
+                entries.push(data);
+            }
+
+            offset = be_u64(&header[8..]);
+        }
+
+        Ok(entries)
+    }
+
+    /// Fill `buf` with the data located at `offset` in the file
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), CdError> {
+        let res =
+            self.file.seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.read_exact(buf));
+
+        match res {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof =>
+                Err(self.error("Truncated CHD file")),
+            Err(e) => Err(CdError::IoError(e)),
+        }
+    }
+
+    /// Build a `CdError::BadImage` for this file
+    pub fn error(&self, error: &str) -> CdError {
+        CdError::BadImage(self.path.clone(), error.to_string())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl fmt::Debug for ChdFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ChdFile({})", self.path.display())
+    }
+}
+
+/// Location of a hunk in the file
+#[derive(Debug, Clone, Copy)]
+enum MapEntry {
+    /// Compressed hunk. Contains the index of the codec in the
+    /// header, the offset and length of the compressed data and the
+    /// CRC of the uncompressed data.
+    Compressed(u8, u64, u32, u16),
+    /// Uncompressed hunk. Contains the offset of the data and its
+    /// CRC if available.
+    Uncompressed(u64, Option<u16>),
+    /// Identical to a previous hunk
+    Copy(u32),
+    /// Hunk filled with zeroes
+    Zero,
+    /// Hunk stored in the parent CHD
+    Parent,
+}
+
+/// Decode a compressed hunk map. `bits` contains the number of bits
+/// used to encode the compressed lengths, the self references and
+/// the parent references. Returns the map and its uncompressed
+/// representation (12 bytes per hunk) used to validate the CRC.
+fn decode_map(compressed: &[u8],
+              hunks: u32,
+              hunk_bytes: u32,
+              first_offset: u64,
+              bits: (u32, u32, u32))
+              -> Result<(Vec<MapEntry>, Vec<u8>), String> {
+    let (length_bits, self_bits, parent_bits) = bits;
+
+    let mut reader = BitReader::new(compressed);
+
+    let huffman = try!(Huffman::from_rle_tree(&mut reader));
+
+    // First the compression type of every hunk, Huffman-encoded with
+    // run-length compression
+    let mut types = Vec::with_capacity(hunks as usize);
+    let mut last = 0;
+    let mut repeat = 0;
+
+    for _ in 0..hunks {
+        if repeat > 0 {
+            types.push(last);
+            repeat -= 1;
+            continue;
+        }
+
+        match huffman.decode(&mut reader) {
+            TYPE_RLE_SMALL => {
+                repeat = 2 + huffman.decode(&mut reader) as u32;
+            }
+            TYPE_RLE_LARGE => {
+                repeat = 2 + 16 + ((huffman.decode(&mut reader) as u32) << 4);
+                repeat += huffman.decode(&mut reader) as u32;
+            }
+            t => last = t,
+        }
+
+        types.push(last);
+    }
+
+    // Then the parameters of each hunk
+    let mut map = Vec::with_capacity(hunks as usize);
+    let mut raw = Vec::with_capacity(hunks as usize * 12);
+
+    let mut offset = first_offset;
+    let mut last_self = 0;
+    let mut last_parent = 0;
+
+    for (hunk, &t) in types.iter().enumerate() {
+        let (entry, raw_type, len, entry_offset, crc) =
+            match t {
+                0 | 1 | 2 | 3 => {
+                    let len = reader.read(length_bits);
+                    let crc = reader.read(16) as u16;
+
+                    let entry = MapEntry::Compressed(t, offset, len, crc);
+                    let o = offset;
+
+                    offset += len as u64;
+
+                    (entry, t, len, o, crc)
+                }
+                TYPE_NONE => {
+                    let crc = reader.read(16) as u16;
+
+                    let entry = MapEntry::Uncompressed(offset, Some(crc));
+                    let o = offset;
+
+                    offset += hunk_bytes as u64;
+
+                    (entry, t, hunk_bytes, o, crc)
+                }
+                TYPE_SELF | TYPE_SELF_0 | TYPE_SELF_1 => {
+                    match t {
+                        TYPE_SELF => last_self = reader.read(self_bits),
+                        TYPE_SELF_1 => last_self += 1,
+                        _ => (),
+                    }
+
+                    (MapEntry::Copy(last_self), TYPE_SELF,
+                     0, last_self as u64, 0)
+                }
+                TYPE_PARENT | TYPE_PARENT_SELF |
+                TYPE_PARENT_0 | TYPE_PARENT_1 => {
+                    match t {
+                        TYPE_PARENT =>
+                            last_parent = reader.read(parent_bits) as u64,
+                        TYPE_PARENT_SELF =>
+                            last_parent =
+                            hunk as u64 * hunk_bytes as u64 /
+                            FRAME_SIZE as u64,
+                        TYPE_PARENT_1 =>
+                            last_parent +=
+                            hunk_bytes as u64 / FRAME_SIZE as u64,
+                        _ => (),
+                    }
+
+                    (MapEntry::Parent, TYPE_PARENT, 0, last_parent, 0)
+                }
+                t => return Err(format!("Invalid hunk type {}", t)),
+            };
+
+        map.push(entry);
+
+        raw.push(raw_type);
+        raw.push((len >> 16) as u8);
+        raw.push((len >> 8) as u8);
+        raw.push(len as u8);
+        for i in (0..6).rev() {
+            raw.push((entry_offset >> (i * 8)) as u8);
+        }
+        raw.push((crc >> 8) as u8);
+        raw.push(crc as u8);
+    }
+
+    if reader.overflow() {
+        return Err("Truncated CHD map".to_string());
+    }
+
+    Ok((map, raw))
+}
+
+fn be_u16(b: &[u8]) -> u16 {
+    ((b[0] as u16) << 8) | b[1] as u16
+}
+
+fn be_u32(b: &[u8]) -> u32 {
+    ((b[0] as u32) << 24) |
+    ((b[1] as u32) << 16) |
+    ((b[2] as u32) << 8) |
+    (b[3] as u32)
+}
+
+fn be_u48(b: &[u8]) -> u64 {
+    ((be_u16(b) as u64) << 32) | be_u32(&b[2..]) as u64
+}
+
+fn be_u64(b: &[u8]) -> u64 {
+    ((be_u32(b) as u64) << 32) | be_u32(&b[4..]) as u64
+}
+
+/// Length of a v5 header
+const HEADER_LEN: usize = 124;
+/// Sanity limit for the number of hunks
+const MAX_HUNKS: u64 = 1 << 24;
+/// Sanity limit for the number of metadata entries
+const MAX_METADATA: u32 = 1024;
+
+// Hunk types in the compressed map. Types 0 to 3 are hunks
+// compressed with the corresponding codec in the header.
+/// Uncompressed hunk
+const TYPE_NONE: u8 = 4;
+/// Copy of another hunk
+const TYPE_SELF: u8 = 5;
+/// Copy of a hunk in the parent
+const TYPE_PARENT: u8 = 6;
+/// Repeat the last type 2 to 17 times
+const TYPE_RLE_SMALL: u8 = 7;
+/// Repeat the last type 18 to 273 times
+const TYPE_RLE_LARGE: u8 = 8;
+/// Copy of the same hunk as the last `TYPE_SELF`
+const TYPE_SELF_0: u8 = 9;
+/// Copy of the hunk following the last `TYPE_SELF`
+const TYPE_SELF_1: u8 = 10;
+/// Copy of the same hunk in the parent
+const TYPE_PARENT_SELF: u8 = 11;
+/// Copy of the same parent hunk as the last `TYPE_PARENT`
+const TYPE_PARENT_0: u8 = 12;
+/// Copy of the parent hunk following the last `TYPE_PARENT`
+const TYPE_PARENT_1: u8 = 13;
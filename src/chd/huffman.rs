@@ -0,0 +1,208 @@
+//! Bitstream and Huffman decoder used by the compressed hunk maps of
+//! CHD v5 files.
+
+/// MSB-first bit reader. Reading past the end of the buffer returns
+/// zeroes and sets the `overflow` flag.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position of the next bit to be read
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data: data,
+            pos: 0,
+        }
+    }
+
+    /// Return the next `count` bits (at most 32) without consuming
+    /// them
+    pub fn peek(&self, count: u32) -> u32 {
+        let mut v = 0;
+
+        for i in 0..count as usize {
+            let pos = self.pos + i;
+
+            let bit =
+                match self.data.get(pos / 8) {
+                    Some(b) => (b >> (7 - pos % 8)) & 1,
+                    None => 0,
+                };
+
+            v = (v << 1) | bit as u32;
+        }
+
+        v
+    }
+
+    /// Consume `count` bits
+    pub fn remove(&mut self, count: u32) {
+        self.pos += count as usize;
+    }
+
+    /// Read `count` bits (at most 32)
+    pub fn read(&mut self, count: u32) -> u32 {
+        let v = self.peek(count);
+
+        self.remove(count);
+
+        v
+    }
+
+    /// Return true if we attempted to read past the end of the data
+    pub fn overflow(&self) -> bool {
+        self.pos > self.data.len() * 8
+    }
+}
+
+/// Canonical Huffman decoder for the 16 symbol alphabet used to
+/// encode the hunk compression types. Codes are at most 8 bits long.
+pub struct Huffman {
+    /// Lookup table indexed by the next 8 bits of the stream. Each
+    /// entry contains the decoded symbol and the length of its code.
+    lookup: [(u8, u8); 1 << MAX_BITS],
+}
+
+impl Huffman {
+    /// Read the RLE-encoded code lengths from `bits` and build the
+    /// corresponding decoder
+    pub fn from_rle_tree(bits: &mut BitReader) -> Result<Huffman, String> {
+        let mut lengths = [0u8; NUM_CODES];
+        let mut code = 0;
+
+        while code < NUM_CODES {
+            let len = bits.read(4) as u8;
+
+            if len != 1 {
+                lengths[code] = len;
+                code += 1;
+                continue;
+            }
+
+            // 1 is an escape code: if it's followed by another 1 it's
+            // a literal 1, otherwise it's followed by a repeat count
+            // for the next value.
+            let len = bits.read(4) as u8;
+
+            if len == 1 {
+                lengths[code] = len;
+                code += 1;
+                continue;
+            }
+
+            let repeat = bits.read(4) as usize + 3;
+
+            if code + repeat > NUM_CODES {
+                return Err("Invalid Huffman tree".to_string());
+            }
+
+            for l in &mut lengths[code..code + repeat] {
+                *l = len;
+            }
+
+            code += repeat;
+        }
+
+        if bits.overflow() {
+            return Err("Truncated Huffman tree".to_string());
+        }
+
+        Huffman::from_lengths(&lengths)
+    }
+
+    /// Assign the canonical codes for the given code `lengths` and
+    /// build the lookup table
+    fn from_lengths(lengths: &[u8; NUM_CODES]) -> Result<Huffman, String> {
+        // Number of codes of each length
+        let mut histogram = [0u32; MAX_BITS + 1];
+
+        for &len in lengths.iter() {
+            if len as usize > MAX_BITS {
+                return Err(format!("Invalid Huffman code length {}", len));
+            }
+
+            histogram[len as usize] += 1;
+        }
+
+        // Compute the first code for each length, starting with the
+        // longest codes
+        let mut start = 0;
+
+        for len in (1..MAX_BITS + 1).rev() {
+            let next = (start + histogram[len]) >> 1;
+
+            if len != 1 && next * 2 != start + histogram[len] {
+                return Err("Inconsistent Huffman tree".to_string());
+            }
+
+            histogram[len] = start;
+            start = next;
+        }
+
+        let mut lookup = [(0, 0); 1 << MAX_BITS];
+
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len == 0 {
+                continue;
+            }
+
+            let code = histogram[len as usize];
+            histogram[len as usize] += 1;
+
+            // Fill all the entries starting with this code
+            let shift = MAX_BITS - len as usize;
+            let first = (code as usize) << shift;
+            let last = ((code as usize + 1) << shift) - 1;
+
+            if last >= lookup.len() {
+                return Err("Inconsistent Huffman tree".to_string());
+            }
+
+            for e in &mut lookup[first..last + 1] {
+                *e = (symbol as u8, len);
+            }
+        }
+
+        Ok(Huffman {
+            lookup: lookup,
+        })
+    }
+
+    /// Decode one symbol from `bits`
+    pub fn decode(&self, bits: &mut BitReader) -> u8 {
+        let (symbol, len) = self.lookup[bits.peek(MAX_BITS as u32) as usize];
+
+        bits.remove(len as u32);
+
+        symbol
+    }
+}
+
+/// Number of symbols in the alphabet
+const NUM_CODES: usize = 16;
+/// Maximum length of a code, in bits
+const MAX_BITS: usize = 8;
+
+#[test]
+fn decode_huffman() {
+    // Tree: symbol 0 has a 1 bit code, symbols 1 and 2 have 2 bit
+    // codes, all the others are unused. Encoded as "1 1 (literal 1),
+    // 2, 2, 1 0 10 (13 times 0)".
+    //
+    // The longest codes come first: 1 => 0b00, 2 => 0b01, 0 => 0b1
+    //
+    // Followed by the sequence 2, 0, 1, 0
+    let data = [0x11, 0x22, 0x10, 0xa6, 0x40];
+
+    let mut bits = BitReader::new(&data);
+
+    let huffman = Huffman::from_rle_tree(&mut bits).unwrap();
+
+    assert!(huffman.decode(&mut bits) == 2);
+    assert!(huffman.decode(&mut bits) == 0);
+    assert!(huffman.decode(&mut bits) == 1);
+    assert!(huffman.decode(&mut bits) == 0);
+    assert!(!bits.overflow());
+}
@@ -0,0 +1,590 @@
+//! MAME's Compressed Hunks of Data (CHD) image format implementation
+//!
+//! Only version 5 of the format is supported, using the CD codecs
+//! (`cdlz`, `cdzl` and `cdfl`).
+//!
+//! The CHD is split into "hunks" of several frames compressed
+//! independently. Each frame contains the 2352 bytes of sector data
+//! followed by 96 bytes of subchannel data. The tracks are stored
+//! one after the other starting with track 01's INDEX 01, each one
+//! padded to a multiple of 4 frames. The layout of the disc is
+//! described by a metadata entry for each track.
+//!
+//! Data tracks can be stored "cooked" (without the sync pattern,
+//! header and error detection/correction codes) and audio samples
+//! are stored big-endian.
+
+use std::path::Path;
+use std::cmp;
+
+use CdError;
+use Image;
+use TrackFormat;
+use internal::{Index, IndexCache};
+use sector::{Sector, SectorBuilder};
+use msf::Msf;
+use bcd::Bcd;
+use toc::Toc;
+
+use self::file::ChdFile;
+
+mod file;
+mod codec;
+mod huffman;
+
+/// CHD image
+#[derive(Debug)]
+pub struct Chd {
+    /// Cache of all the indices in the CD image
+    indices: IndexCache<Storage>,
+    /// The CHD file itself
+    chd: ChdFile,
+}
+
+impl Chd {
+    /// Open a CHD file, parse its header and metadata and build a
+    /// `Chd` instance.
+    pub fn new(path: &Path) -> Result<Chd, CdError> {
+        let mut chd = try!(ChdFile::open(path));
+
+        let tracks = try!(parse_tracks(&mut chd));
+
+        let indices = try!(build_indices(&chd, &tracks));
+
+        Ok(Chd {
+            indices: indices,
+            chd: chd,
+        })
+    }
+}
+
+impl Image for Chd {
+    fn image_format(&self) -> String {
+        "CHD".to_string()
+    }
+
+    fn read_sector(&mut self,
+                   sector: &mut Sector,
+                   msf: Msf) -> Result<(), CdError> {
+        let (index, metadata) = try!(self.indices.locate(msf));
+
+        let mut builder = SectorBuilder::new(sector);
+
+        match index.private() {
+            &Storage::Frames(first, data_type, subchannel) => {
+                let frame =
+                    first + (msf.sector_index() - index.sector_index());
+
+                let chd = &mut self.chd;
+
+                let res =
+                    match data_type {
+                        DataType::Audio => builder.set_data_2352(|data| {
+                            chd.read_frame(frame).map(|f| {
+                                // Convert the samples to little-endian
+                                for (d, s) in data.chunks_mut(2)
+                                    .zip(f.chunks(2)) {
+                                        d[0] = s[1];
+                                        d[1] = s[0];
+                                    }
+                            })
+                        }),
+                        DataType::Raw => builder.set_data_2352(|data| {
+                            chd.read_frame(frame).map(|f| {
+                                data.copy_from_slice(&f[..2352])
+                            })
+                        }),
+                        DataType::Mode1 => builder.set_mode1_payload(|p| {
+                            chd.read_frame(frame).map(|f| {
+                                p.copy_from_slice(&f[..2048])
+                            })
+                        }),
+                        DataType::Mode2 => builder.set_mode2_payload(|p| {
+                            chd.read_frame(frame).map(|f| {
+                                p.copy_from_slice(&f[..2336])
+                            })
+                        }),
+                    };
+
+                try!(res);
+
+                if subchannel {
+                    try!(builder.set_subchannel_interleaved(|s| {
+                        chd.read_frame(frame).map(|f| {
+                            s.copy_from_slice(&f[2352..])
+                        })
+                    }));
+                }
+            }
+            &Storage::PreGap => builder.set_blank(index.format()),
+        }
+
+        builder.set_metadata(metadata);
+
+        Ok(())
+    }
+
+    fn track_msf(&self, track: Bcd, track_msf: Msf) -> Result<Msf, CdError> {
+        self.indices.track_msf(track, track_msf)
+    }
+
+    fn toc(&self) -> Toc {
+        self.indices.toc()
+    }
+}
+
+/// Storage for an index
+#[derive(Debug, Clone, Copy)]
+enum Storage {
+    /// The index is stored in the CHD. Contains the position of its
+    /// first sector in the CHD (in frames), the format of the data and
+    /// whether the frames contain raw subchannel data.
+    Frames(u32, DataType, bool),
+    /// The index is a pre-gap or post-gap, it's not stored in the CHD
+    /// and must be regenerated.
+    PreGap,
+}
+
+/// Format of the sector data stored in a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DataType {
+    /// 2352 bytes of big-endian audio samples
+    Audio,
+    /// Full 2352 bytes of sector data
+    Raw,
+    /// 2048 bytes of Mode 1 user data
+    Mode1,
+    /// 2336 bytes of Mode 2 data (everything but the sync pattern
+    /// and header)
+    Mode2,
+}
+
+/// Track description found in the CHD metadata
+struct ChdTrack {
+    number: u8,
+    format: TrackFormat,
+    data_type: DataType,
+    /// Number of frames stored in the CHD for this track, including
+    /// the pregap if `pregap_stored` is true
+    frames: u32,
+    /// Length of the pregap in sectors
+    pregap: u32,
+    /// True if the pregap's data is stored in the CHD
+    pregap_stored: bool,
+    /// True if the frames contain raw interleaved subchannel data
+    subchannel: bool,
+    /// Same as `subchannel` for the stored pregap
+    pregap_subchannel: bool,
+    /// Length of the postgap in sectors, never stored in the CHD
+    postgap: u32,
+}
+
+/// Parse the track metadata of `chd`
+fn parse_tracks(chd: &mut ChdFile) -> Result<Vec<ChdTrack>, CdError> {
+    let mut entries = try!(chd.metadata(b"CHT2"));
+
+    if entries.is_empty() {
+        // Older format without the pregap and postgap
+        entries = try!(chd.metadata(b"CHTR"));
+    }
+
+    if entries.is_empty() {
+        if !try!(chd.metadata(b"CHGD")).is_empty() {
+            return Err(chd.error("GD-ROM CHD files are not supported"));
+        }
+
+        return Err(chd.error("Missing CD track metadata"));
+    }
+
+    let mut tracks = Vec::with_capacity(entries.len());
+
+    for (i, entry) in entries.iter().enumerate() {
+        let track = try!(parse_track(chd, entry));
+
+        if track.number as usize != i + 1 {
+            let error = format!("Unexpected track number {}", track.number);
+            return Err(chd.error(&error));
+        }
+
+        tracks.push(track);
+    }
+
+    Ok(tracks)
+}
+
+/// Parse a single track metadata entry. It's a string in the format
+/// "TRACK:1 TYPE:MODE1_RAW SUBTYPE:NONE FRAMES:1234 PREGAP:0
+/// PGTYPE:MODE1 PGSUB:NONE POSTGAP:0"
+fn parse_track(chd: &ChdFile, entry: &[u8]) -> Result<ChdTrack, CdError> {
+    let entry = String::from_utf8_lossy(entry);
+    let entry = entry.trim_matches('\0');
+
+    let mut number = None;
+    let mut track_type = None;
+    let mut frames = None;
+    let mut pregap = 0;
+    let mut pregap_stored = false;
+    let mut subchannel = false;
+    let mut pregap_subchannel = false;
+    let mut postgap = 0;
+
+    for field in entry.split_whitespace() {
+        let mut split = field.splitn(2, ':');
+
+        let key = split.next().unwrap();
+
+        let value =
+            match split.next() {
+                Some(v) => v,
+                None => {
+                    let error = format!("Invalid track metadata \"{}\"",
+                                        entry);
+                    return Err(chd.error(&error));
+                }
+            };
+
+        let parse_int = || {
+            value.parse::<u32>().map_err(|_| {
+                let error = format!("Invalid value for {} in track \
+                                     metadata \"{}\"", key, entry);
+                chd.error(&error)
+            })
+        };
+
+        match key {
+            "TRACK" => number = Some(try!(parse_int())),
+            "TYPE" => track_type = Some(value),
+            "FRAMES" => frames = Some(try!(parse_int())),
+            "PREGAP" => pregap = try!(parse_int()),
+            // A 'V' prefix means that the pregap's data is stored in
+            // the CHD
+            "PGTYPE" => pregap_stored = value.starts_with('V'),
+            "POSTGAP" => postgap = try!(parse_int()),
+            // Only the raw P-W subchannel is supported, "RW" is the
+            // R-W channels after error correction which we can't do
+            // much with.
+            "SUBTYPE" => subchannel = value == "RW_RAW",
+            "PGSUB" => pregap_subchannel = value == "RW_RAW",
+            _ => (),
+        }
+    }
+
+    let (number, track_type, frames) =
+        match (number, track_type, frames) {
+            (Some(n), Some(t), Some(f)) if n >= 1 && n <= 99 => (n, t, f),
+            _ => {
+                let error = format!("Incomplete track metadata \"{}\"",
+                                    entry);
+                return Err(chd.error(&error));
+            }
+        };
+
+    let (format, data_type) =
+        match track_type {
+            "AUDIO" => (TrackFormat::Audio, DataType::Audio),
+            "MODE1" => (TrackFormat::Mode1, DataType::Mode1),
+            "MODE1_RAW" => (TrackFormat::Mode1, DataType::Raw),
+            "MODE2" | "MODE2_FORM_MIX" => (TrackFormat::Mode2Xa,
+                                           DataType::Mode2),
+            "MODE2_RAW" => (TrackFormat::Mode2Xa, DataType::Raw),
+            t => {
+                let error = format!("Unsupported track type {}", t);
+                return Err(chd.error(&error));
+            }
+        };
+
+    if pregap_stored && pregap > frames {
+        let error = format!("Track {}'s pregap is longer than the track",
+                            number);
+        return Err(chd.error(&error));
+    }
+
+    Ok(ChdTrack {
+        number: number as u8,
+        format: format,
+        data_type: data_type,
+        frames: frames,
+        pregap: pregap,
+        pregap_stored: pregap_stored,
+        subchannel: subchannel,
+        pregap_subchannel: pregap_subchannel,
+        postgap: postgap,
+    })
+}
+
+/// Build the index cache from the track list
+fn build_indices(chd: &ChdFile,
+                 tracks: &[ChdTrack]) -> Result<IndexCache<Storage>, CdError> {
+    let mut indices = Vec::new();
+
+    // Current absolute sector index
+    let mut sector = 0;
+    // Current frame in the CHD
+    let mut frame = 0;
+
+    for track in tracks {
+        let number = Bcd::from_binary(track.number).unwrap();
+
+        let mut pregap = track.pregap;
+
+        if track.number == 1 {
+            // Track 01's pregap is always at least 2 seconds long and
+            // it's normally not stored. When it is, the stored part
+            // is the end of the pregap, right before INDEX 01.
+            let len = cmp::max(track.pregap, 150);
+
+            let blank =
+                if track.pregap_stored {
+                    len - track.pregap
+                } else {
+                    pregap = 0;
+                    len
+                };
+
+            if blank > 0 {
+                indices.push(Index::new(Bcd::zero(),
+                                        Msf::zero(),
+                                        number,
+                                        track.format,
+                                        1,
+                                        Storage::PreGap));
+            }
+
+            sector += blank;
+        }
+
+        let mut track_frame = frame;
+
+        if pregap > 0 {
+            let storage =
+                if track.pregap_stored {
+                    track_frame += pregap;
+                    Storage::Frames(frame,
+                                    track.data_type,
+                                    track.pregap_subchannel)
+                } else {
+                    Storage::PreGap
+                };
+
+            indices.push(Index::new(Bcd::zero(),
+                                    try!(sector_msf(chd, sector)),
+                                    number,
+                                    track.format,
+                                    1,
+                                    storage));
+
+            sector += pregap;
+        }
+
+        let storage = Storage::Frames(track_frame,
+                                      track.data_type,
+                                      track.subchannel);
+
+        indices.push(Index::new(Bcd::one(),
+                                try!(sector_msf(chd, sector)),
+                                number,
+                                track.format,
+                                1,
+                                storage));
+
+        sector += frames_after_pregap(track);
+
+        if track.postgap > 0 {
+            indices.push(Index::new(Bcd::one(),
+                                    try!(sector_msf(chd, sector)),
+                                    number,
+                                    track.format,
+                                    1,
+                                    Storage::PreGap));
+
+            sector += track.postgap;
+        }
+
+        if frame + track.frames > chd.frame_count() {
+            let error = format!("Track {} extends past the end of the CHD",
+                                track.number);
+            return Err(chd.error(&error));
+        }
+
+        // Each track is padded to a multiple of 4 frames
+        frame += (track.frames + 3) / 4 * 4;
+    }
+
+    let lead_out = try!(sector_msf(chd, sector));
+
+    IndexCache::new(chd.path().to_path_buf(), indices, lead_out)
+}
+
+/// Return the number of frames of the track stored after its pregap
+fn frames_after_pregap(track: &ChdTrack) -> u32 {
+    if track.pregap_stored {
+        track.frames - track.pregap
+    } else {
+        track.frames
+    }
+}
+
+/// Convert `sector` into an MSF, returns an error if it's out of
+/// range
+fn sector_msf(chd: &ChdFile, sector: u32) -> Result<Msf, CdError> {
+    match Msf::from_sector_index(sector) {
+        Some(m) => Ok(m),
+        None => Err(chd.error("The disc is too long")),
+    }
+}
+
+/// Read sector `s` of a test image and check it against the pattern
+/// it was generated with. Returns the sector's metadata index and
+/// track numbers.
+#[cfg(test)]
+fn read_test_sector(chd: &mut Chd,
+                    s: u32,
+                    subchannel: bool) -> (u8, u8) {
+    let msf = Msf::from_sector_index(s).unwrap();
+    let mut sector = Sector::empty();
+
+    chd.read_sector(&mut sector, msf).unwrap();
+
+    let format = sector.metadata().format;
+
+    match format {
+        TrackFormat::Mode1 => {
+            let payload = sector.mode1_payload().unwrap();
+
+            for (i, &b) in payload.iter().enumerate() {
+                assert!(b == (s as usize * 3 + i) as u8);
+            }
+        }
+        TrackFormat::Mode2Xa => {
+            let data = sector.data_2352().unwrap();
+
+            for (i, &b) in data[16..].iter().enumerate() {
+                assert!(b == (s as usize * 5 + i) as u8);
+            }
+        }
+        _ => {
+            // Constant samples, converted to little-endian
+            let left = 0x100 + s as u16;
+            let right = 0x4000 + s as u16;
+
+            let sample = [left as u8, (left >> 8) as u8,
+                          right as u8, (right >> 8) as u8];
+
+            for c in sector.data_2352().unwrap().chunks(4) {
+                assert!(c == &sample);
+            }
+        }
+    }
+
+    match sector.subchannel_interleaved() {
+        Some(sub) => {
+            assert!(subchannel);
+
+            for (i, &b) in sub.iter().enumerate() {
+                assert!(b == (s as usize + i * 3) as u8);
+            }
+        }
+        None => assert!(!subchannel),
+    }
+
+    let metadata = sector.metadata();
+
+    assert!(metadata.msf == msf);
+
+    (metadata.index.binary(), metadata.track.binary())
+}
+
+#[test]
+fn chd_tracks() {
+    use test_util::testdata;
+
+    let msf = |s| Msf::from_sector_index(s).unwrap();
+
+    let mut chd = Chd::new(&testdata("chd/tracks.chd")).unwrap();
+
+    let toc = chd.toc();
+    let tracks: Vec<_> = toc.tracks().collect();
+
+    assert!(tracks.len() == 3);
+    assert!(tracks[0].format == TrackFormat::Mode1);
+    assert!(tracks[0].pregap_start() == msf(0));
+    assert!(tracks[0].start == msf(150));
+    assert!(tracks[0].length == msf(10));
+    assert!(tracks[1].format == TrackFormat::Audio);
+    assert!(tracks[1].pregap_start() == msf(160));
+    assert!(tracks[1].start == msf(164));
+    assert!(tracks[1].length == msf(8));
+    assert!(tracks[2].format == TrackFormat::Mode2Xa);
+    assert!(tracks[2].pregap_start() == msf(172));
+    assert!(tracks[2].start == msf(177));
+    // Including the postgap
+    assert!(tracks[2].length == msf(8));
+    assert!(toc.lead_out() == msf(185));
+
+    // Track 01 (cdlz and cdzl hunks, cooked Mode 1 sectors)
+    for s in 150..160 {
+        assert!(read_test_sector(&mut chd, s, false) == (1, 1));
+    }
+
+    // Track 02's stored pregap and the cdfl hunk
+    for s in 160..164 {
+        assert!(read_test_sector(&mut chd, s, true) == (0, 2));
+    }
+
+    for s in 164..172 {
+        assert!(read_test_sector(&mut chd, s, true) == (1, 2));
+    }
+
+    for s in 177..183 {
+        assert!(read_test_sector(&mut chd, s, true) == (1, 3));
+    }
+
+    // Track 03's pregap and postgap aren't stored
+    let mut sector = Sector::empty();
+
+    for &(s, index) in &[(172, 0), (176, 0), (183, 1), (184, 1)] {
+        chd.read_sector(&mut sector, msf(s)).unwrap();
+
+        assert!(!sector.has_subchannel());
+        assert!(sector.metadata().track.binary() == 3);
+        assert!(sector.metadata().index.binary() == index);
+    }
+
+    chd.read_sector(&mut sector, msf(176)).unwrap();
+
+    assert!(sector.metadata().track_msf == msf(1));
+}
+
+#[test]
+fn chd_stored_pregap() {
+    use test_util::testdata;
+
+    let msf = |s| Msf::from_sector_index(s).unwrap();
+
+    let mut chd = Chd::new(&testdata("chd/pregap.chd")).unwrap();
+
+    let toc = chd.toc();
+    let tracks: Vec<_> = toc.tracks().collect();
+
+    // The stored pregap is the standard 2 second pregap, INDEX 01 is
+    // still at 00:02:00
+    assert!(tracks.len() == 2);
+    assert!(tracks[0].pregap_start() == msf(0));
+    assert!(tracks[0].start == msf(150));
+    assert!(tracks[0].length == msf(8));
+    assert!(tracks[1].start == msf(158));
+    assert!(tracks[1].length == msf(8));
+    assert!(toc.lead_out() == msf(166));
+
+    for s in 0..150 {
+        assert!(read_test_sector(&mut chd, s, true) == (0, 1));
+    }
+
+    for s in 150..158 {
+        assert!(read_test_sector(&mut chd, s, true) == (1, 1));
+    }
+
+    for s in 158..166 {
+        assert!(read_test_sector(&mut chd, s, true) == (1, 2));
+    }
+}
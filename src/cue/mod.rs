@@ -5,22 +5,37 @@
 //! The original format was described in the CDRWIN user guide but
 //! many extensions and variations exist.
 //!
-//! The CUE file format does not support multi-session discs
+//! The original CUE file format does not support multi-session discs
+//! but the `REM SESSION nn` extension is commonly used to mark the
+//! beginning of each session. The lead-out of a session and the
+//! lead-in of the next one are not stored in the BIN files, their
+//! length can be given with `REM LEAD-OUT mm:ss:ff` and `REM LEAD-IN
+//! mm:ss:ff`, otherwise the standard lengths are used.
+//!
+//! BIN files compressed with ECM (with a `.ecm` extension) can be
+//! referenced directly by the `FILE` command.
 
 use std::path::Path;
 use std::fs::File;
+use std::io;
 use std::io::{Read, Seek, SeekFrom};
 
 use CdError;
 use Image;
 use internal::IndexCache;
-use sector::{Sector, SectorBuilder, Metadata};
+use sector::{Sector, SectorBuilder};
+use subchannel::SubChannelQ;
 use msf::Msf;
 use bcd::Bcd;
+use toc::Toc;
+use ecm::Ecm;
 
 use self::parser::CueParser;
 
+pub use self::writer::CueWriter;
+
 mod parser;
+mod writer;
 
 /// CUE parser state.
 #[derive(Debug)]
@@ -29,6 +44,10 @@ pub struct Cue {
     indices: IndexCache<Storage>,
     /// List of all the BIN files referenced in the cue sheet
     bin_files: Vec<BinaryBlob>,
+    /// Media Catalog Number set by the `CATALOG` command
+    catalog: Option<String>,
+    /// ISRC of the tracks, set by the `ISRC` commands
+    isrc: Vec<(Bcd, String)>,
 }
 
 impl Cue {
@@ -38,6 +57,14 @@ impl Cue {
 
         CueParser::build_cue(cue_path)
     }
+
+    /// Synthesize the Q subchannel data for the sector at `msf`. The
+    /// BIN/CUE format doesn't store any subchannel data, it's rebuilt
+    /// from the disc structure. `read_sector` attaches it to the
+    /// sectors which don't have any other subchannel data.
+    pub fn q_subchannel(&self, msf: Msf) -> SubChannelQ {
+        self.indices.q_subchannel(msf)
+    }
 }
 
 impl Image for Cue {
@@ -48,43 +75,7 @@ impl Image for Cue {
     fn read_sector(&mut self,
                    sector: &mut Sector,
                    msf: Msf) -> Result<(), CdError> {
-        let (pos, index) =
-            match self.indices.find_index_for_msf(msf) {
-                Some(i) => i,
-                None => return Err(CdError::LeadOut),
-            };
-
-        // First we compute the relative track MSF
-        let track_msf =
-            if index.is_pregap() {
-                // In the pregap the track MSF decreases until index1
-                // is reached
-                let index1 =
-                    match self.indices.get(pos + 1) {
-                        Some(i) => i,
-                        None => panic!("Pregap without index 1!"),
-                    };
-
-                index1.msf() - msf
-            } else {
-                // The track MSF is relative to index1
-                let index1 =
-                    if index.index().bcd() == 0x01 {
-                        index
-                    } else {
-                        match self.indices
-                            .find_index01_for_track(index.track()) {
-                                Ok((_, i)) => i,
-                                // Shouldn't be reached, should be
-                                // caught by IndexCache's constructor
-                                Err(_) =>
-                                    panic!("Missing index 1 for track {}",
-                                           index.track()),
-                            }
-                    };
-
-                msf - index1.msf()
-            };
+        let (index, metadata) = try!(self.indices.locate(msf));
 
         let mut builder = SectorBuilder::new(sector);
 
@@ -93,41 +84,57 @@ impl Image for Cue {
             &Storage::Bin(bin, offset, ty) => {
                 let bin = &mut self.bin_files[bin as usize];
 
-                // For now we only support "simple sector" format
-                if ty.sector_size() != 2352 {
-                    panic!("Unimplemented CUE track type: {:?}", ty);
-                }
-
                 let index_offset = ty.sector_size() as u64 *
                     (msf.sector_index() - index.sector_index()) as u64;
 
                 let offset = offset + index_offset;
 
                 let res =
-                    builder.set_data_2352(|data| {
-                        try!(bin.file.seek(SeekFrom::Start(offset)));
-
-                        bin.file.read_exact(data)
-                    });
+                    match ty {
+                        CueTrackType::Audio |
+                        CueTrackType::Mode1Raw |
+                        CueTrackType::Mode2Raw |
+                        CueTrackType::CdIRaw |
+                        CueTrackType::CdG =>
+                            builder.set_data_2352(
+                                |data| bin.read_at(offset, data)),
+                        CueTrackType::Mode1Data =>
+                            builder.set_mode1_payload(
+                                |data| bin.read_at(offset, data)),
+                        CueTrackType::Mode2Headerless |
+                        CueTrackType::CdIHeaderless =>
+                            builder.set_mode2_payload(
+                                |data| bin.read_at(offset, data)),
+                    };
 
                 if let Err(e) = res {
                     return Err(CdError::IoError(e));
                 }
+
+                if ty == CueTrackType::CdG {
+                    // For CD+G the 96 bytes of subchannel data follow
+                    // the 2352 bytes of sector data
+                    let res =
+                        builder.set_subchannel_interleaved(
+                            |s| bin.read_at(offset + 2352, s));
+
+                    if let Err(e) = res {
+                        return Err(CdError::IoError(e));
+                    }
+                }
             }
-            &Storage::PreGap =>
-                panic!("Unhandled CUE pregap read"),
+            &Storage::PreGap => builder.set_blank(index.format()),
         }
 
         // Now let's fill up the metadata
-        builder.set_metadata(
-            Metadata {
-                msf: msf,
-                track_msf: track_msf,
-                index: index.index(),
-                track: index.track(),
-                format: index.format(),
-                session: index.session(),
-            });
+        builder.set_metadata(metadata);
+
+        // Only CD+G tracks store their subchannel data, synthesize
+        // the Q subchannel for everything else. The other channels
+        // are left zeroed.
+        if !sector.has_subchannel() {
+            sector.set_subchannel_q(&self.indices.q_subchannel(msf));
+        }
 
         Ok(())
     }
@@ -135,6 +142,24 @@ impl Image for Cue {
     fn track_msf(&self, track: Bcd, track_msf: Msf) -> Result<Msf, CdError> {
         self.indices.track_msf(track, track_msf)
     }
+
+    fn toc(&self) -> Toc {
+        let mut toc = self.indices.toc();
+
+        toc.catalog = self.catalog.clone();
+
+        for &(track, ref isrc) in &self.isrc {
+            let t = toc.sessions.iter_mut()
+                .flat_map(|s| s.tracks.iter_mut())
+                .find(|t| t.number == track);
+
+            if let Some(t) = t {
+                t.isrc = Some(isrc.clone());
+            }
+        }
+
+        toc
+    }
 }
 
 /// Possible types for a CUE track.
@@ -164,7 +189,7 @@ impl CueTrackType {
             CueTrackType::Audio => 2352,
             CueTrackType::CdG => 2448,
             CueTrackType::Mode1Data => 2048,
-            CueTrackType::Mode1Raw => 2336,
+            CueTrackType::Mode1Raw => 2352,
             CueTrackType::Mode2Headerless => 2336,
             CueTrackType::Mode2Raw => 2352,
             CueTrackType::CdIHeaderless => 2336,
@@ -178,21 +203,34 @@ enum Storage {
     /// The slice is stored in a portion of a BIN file. Contains the
     /// index of the BIN file and the offset in the file.
     Bin(u32, u64, CueTrackType),
-    /// The slice is a pre-gap, it's not stored in the BIN file and
-    /// must be regererated.
+    /// The slice is a pre-gap or a post-gap, it's not stored in the
+    /// BIN file and must be regererated.
     PreGap,
 }
 
 /// `BinaryBlob` can contain one or several slices interrupted by pre-
 /// and post-gaps.
 #[derive(Debug)]
-struct BinaryBlob {
-    /// BIN file
-    file: File,
+enum BinaryBlob {
+    /// Plain BIN file
+    File(File),
+    /// ECM-compressed BIN file, decoded on the fly
+    Ecm(Ecm),
 }
 
 impl BinaryBlob {
+    /// Open the BIN file at `path`. Files with the `.ecm` extension
+    /// are decoded with `Ecm`.
     fn new(path: &Path) -> Result<BinaryBlob, CdError> {
+        let is_ecm =
+            match path.extension() {
+                Some(e) => e.to_string_lossy().to_lowercase() == "ecm",
+                None => false,
+            };
+
+        if is_ecm {
+            return Ecm::new(path).map(BinaryBlob::Ecm);
+        }
 
         let file =
             match File::open(path) {
@@ -200,9 +238,28 @@ impl BinaryBlob {
                 Err(e) => return Err(CdError::IoError(e)),
             };
 
-        Ok(BinaryBlob {
-            file: file,
-        })
+        Ok(BinaryBlob::File(file))
+    }
+
+    /// Return the size of the BIN file in bytes (after decoding for
+    /// ECM files)
+    fn size(&self) -> io::Result<u64> {
+        match *self {
+            BinaryBlob::File(ref f) => f.metadata().map(|m| m.len()),
+            BinaryBlob::Ecm(ref ecm) => Ok(ecm.size()),
+        }
+    }
+
+    /// Fill `buf` with the data located at `offset` in the BIN file
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match *self {
+            BinaryBlob::File(ref mut f) => {
+                try!(f.seek(SeekFrom::Start(offset)));
+
+                f.read_exact(buf)
+            }
+            BinaryBlob::Ecm(ref mut ecm) => ecm.read_at(offset, buf),
+        }
     }
 }
 
@@ -210,3 +267,342 @@ impl BinaryBlob {
 /// attempting to load a huge file to RAM. Cue sheets bigger than this
 /// will be rejected.
 pub const CUE_SHEET_MAX_LENGTH: u64 = 1024 * 1024;
+
+#[test]
+fn read_cdg() {
+    use subchannel::SubChannel;
+    use test_util::{temp_dir, write_file};
+
+    let dir = temp_dir("cue-cdg");
+
+    let mut bin = Vec::new();
+
+    for s in 0..3 {
+        for i in 0..2448 {
+            bin.push((s * 7 + i) as u8);
+        }
+    }
+
+    write_file(&dir, "cdg.bin", &bin);
+
+    let cue_path = write_file(&dir, "cdg.cue",
+                              b"FILE \"cdg.bin\" BINARY\n\
+                                \x20 TRACK 01 CDG\n\
+                                \x20   INDEX 01 00:00:00\n");
+
+    let mut cue = Cue::new(&cue_path).unwrap();
+    let mut sector = Sector::empty();
+
+    cue.read_sector(&mut sector, Msf::from_bcd(0, 0x02, 0x01).unwrap())
+        .unwrap();
+
+    let raw = &bin[2448..2 * 2448];
+
+    assert!(sector.has_subchannel());
+    assert!(&sector.data_2352().unwrap()[..] == &raw[..2352]);
+    assert!(&sector.subchannel_interleaved().unwrap()[..] == &raw[2352..]);
+
+    // The pregap isn't stored, only its Q subchannel is synthesized
+    cue.read_sector(&mut sector, Msf::zero()).unwrap();
+
+    let q = cue.q_subchannel(Msf::zero());
+    let subchannel = sector.subchannel_deinterleaved().unwrap();
+
+    assert!(sector.subchannel_q().unwrap().raw() == q.raw());
+    assert!(subchannel[..12].iter().all(|&b| b == 0));
+    assert!(subchannel[24..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn read_ecm() {
+    use ecc;
+    use ecm::push_record_header;
+    use sector::SYNC_PATTERN;
+    use test_util::{temp_dir, write_file};
+
+    let mut ecm = b"ECM\0".to_vec();
+    let mut bin = Vec::new();
+
+    // Track 01: 2 Mode 1 sectors
+    push_record_header(&mut ecm, 1, 2);
+
+    for s in 0..2 {
+        let mut sector = [0; 2352];
+
+        sector[0..12].copy_from_slice(&SYNC_PATTERN);
+        sector[12..16].copy_from_slice(&[0x00, 0x02, s, 0x01]);
+
+        for (i, b) in sector[16..2064].iter_mut().enumerate() {
+            *b = (i + s as usize) as u8;
+        }
+
+        ecc::generate_mode1(&mut sector);
+
+        ecm.extend_from_slice(&sector[12..15]);
+        ecm.extend_from_slice(&sector[16..2064]);
+        bin.extend_from_slice(&sector);
+    }
+
+    // Track 02: 2 audio sectors, stored as-is
+    push_record_header(&mut ecm, 0, 2 * 2352);
+
+    for i in 0..2 * 2352 {
+        ecm.push((i * 7) as u8);
+        bin.push((i * 7) as u8);
+    }
+
+    ecm.extend_from_slice(&[0xfc, 0xff, 0xff, 0xff, 0x3f]);
+    ecm.extend_from_slice(&[0; 4]);
+
+    let dir = temp_dir("cue-ecm");
+
+    write_file(&dir, "game.bin.ecm", &ecm);
+
+    let cue_path = write_file(&dir, "game.cue",
+                              b"FILE \"game.bin.ecm\" BINARY\n\
+                                \x20 TRACK 01 MODE1/2352\n\
+                                \x20   INDEX 01 00:00:00\n\
+                                \x20 TRACK 02 AUDIO\n\
+                                \x20   INDEX 01 00:00:02\n");
+
+    let mut cue = Cue::new(&cue_path).unwrap();
+    let mut sector = Sector::empty();
+
+    assert!(cue.toc().tracks().count() == 2);
+
+    for s in 0..4 {
+        let msf = Msf::from_sector_index(150 + s).unwrap();
+
+        cue.read_sector(&mut sector, msf).unwrap();
+
+        let raw = &bin[s as usize * 2352..(s as usize + 1) * 2352];
+
+        assert!(&sector.data_2352().unwrap()[..] == raw);
+        assert!(sector.metadata().track.binary() ==
+                if s < 2 { 1 } else { 2 });
+    }
+
+    // The lead-out starts right after the decoded data
+    let msf = Msf::from_sector_index(154).unwrap();
+
+    assert!(cue.read_sector(&mut sector, msf).is_err());
+}
+
+#[test]
+fn read_track_types() {
+    use ecc;
+    use ecc::Status;
+    use sector::SYNC_PATTERN;
+    use test_util::{temp_dir, write_file};
+    use TrackFormat;
+
+    assert!(CueTrackType::Mode1Raw.sector_size() == 2352);
+
+    let dir = temp_dir("cue-track-types");
+
+    for &(ty, name, format) in
+        &[(CueTrackType::Audio, "AUDIO", TrackFormat::Audio),
+          (CueTrackType::CdG, "CDG", TrackFormat::CdG),
+          (CueTrackType::Mode1Data, "MODE1/2048", TrackFormat::Mode1),
+          (CueTrackType::Mode1Raw, "MODE1/2352", TrackFormat::Mode1),
+          (CueTrackType::Mode2Headerless, "MODE2/2336", TrackFormat::Mode2Xa),
+          (CueTrackType::Mode2Raw, "MODE2/2352", TrackFormat::Mode2Xa),
+          (CueTrackType::CdIHeaderless, "CDI/2336", TrackFormat::Mode2CdI),
+          (CueTrackType::CdIRaw, "CDI/2352", TrackFormat::Mode2CdI)] {
+        let mut bin = Vec::new();
+        let mut sectors = Vec::new();
+
+        // Mode 2 tracks get a Form 1 and a Form 2 sector
+        for s in 0..2 {
+            let mut sector = [0; 2352];
+
+            for (i, b) in sector.iter_mut().enumerate() {
+                *b = (i * 3 + s * 11) as u8;
+            }
+
+            match ty {
+                CueTrackType::Audio | CueTrackType::CdG => (),
+                _ => {
+                    sector[0..12].copy_from_slice(&SYNC_PATTERN);
+                    sector[12..15].copy_from_slice(&[0x00, 0x02, s as u8]);
+                }
+            }
+
+            match format {
+                TrackFormat::Mode1 => {
+                    sector[15] = 1;
+                    ecc::generate_mode1(&mut sector);
+                }
+                TrackFormat::Mode2Xa | TrackFormat::Mode2CdI => {
+                    let submode = if s == 0 { 0x08 } else { 0x20 };
+                    let subheader = [1, 0, submode, 0];
+
+                    sector[15] = 2;
+                    sector[16..20].copy_from_slice(&subheader);
+                    sector[20..24].copy_from_slice(&subheader);
+
+                    if s == 0 {
+                        ecc::generate_mode2_form1(&mut sector);
+                    } else {
+                        ecc::generate_mode2_form2(&mut sector);
+                    }
+                }
+                TrackFormat::Audio | TrackFormat::CdG => (),
+            }
+
+            match ty.sector_size() {
+                2048 => bin.extend_from_slice(&sector[16..2064]),
+                2336 => bin.extend_from_slice(&sector[16..]),
+                _ => bin.extend_from_slice(&sector),
+            }
+
+            if ty == CueTrackType::CdG {
+                bin.extend_from_slice(&[s as u8; 96]);
+            }
+
+            sectors.push(sector);
+        }
+
+        assert!(bin.len() == 2 * ty.sector_size() as usize);
+
+        write_file(&dir, "track.bin", &bin);
+
+        let sheet = format!("FILE \"track.bin\" BINARY\n\
+                             \x20 TRACK 01 {}\n\
+                             \x20   INDEX 01 00:00:00\n", name);
+
+        let cue_path = write_file(&dir, "track.cue", sheet.as_bytes());
+
+        let mut cue = Cue::new(&cue_path).unwrap();
+        let mut sector = Sector::empty();
+
+        for s in 0..2 {
+            let msf = Msf::from_sector_index(150 + s).unwrap();
+
+            cue.read_sector(&mut sector, msf).unwrap();
+
+            assert!(sector.metadata().format == format);
+            assert!(&sector.data_2352().unwrap()[..] ==
+                    &sectors[s as usize][..]);
+
+            let status =
+                match format {
+                    TrackFormat::Audio | TrackFormat::CdG =>
+                        Status::Unprotected,
+                    _ => Status::Valid,
+                };
+
+            assert!(sector.check_integrity().unwrap() == status);
+        }
+    }
+}
+
+#[test]
+fn read_gaps() {
+    use ecc;
+    use ecc::Status;
+    use sector::{SYNC_PATTERN, XaForm};
+    use test_util::{temp_dir, write_file};
+    use TrackFormat;
+
+    let dir = temp_dir("cue-gaps");
+
+    write_file(&dir, "data.bin", &[0x11; 2 * 2048]);
+    write_file(&dir, "audio.bin", &[0x22; 3 * 2352]);
+
+    let mut xa = Vec::new();
+
+    for s in 0..2 {
+        let mut sector = [0; 2352];
+
+        sector[0..12].copy_from_slice(&SYNC_PATTERN);
+        sector[12..16].copy_from_slice(&[0x00, 0x02, 0x23 + s, 0x02]);
+        sector[16..24].copy_from_slice(&[0, 0, 0x08, 0, 0, 0, 0x08, 0]);
+        ecc::generate_mode2_form1(&mut sector);
+
+        xa.extend_from_slice(&sector);
+    }
+
+    write_file(&dir, "xa.bin", &xa);
+
+    let cue_path = write_file(&dir, "gaps.cue",
+                              b"FILE \"data.bin\" BINARY\n\
+                                \x20 TRACK 01 MODE1/2048\n\
+                                \x20   INDEX 01 00:00:00\n\
+                                FILE \"audio.bin\" BINARY\n\
+                                \x20 TRACK 02 AUDIO\n\
+                                \x20   PREGAP 00:00:10\n\
+                                \x20   INDEX 01 00:00:00\n\
+                                \x20   POSTGAP 00:00:05\n\
+                                FILE \"xa.bin\" BINARY\n\
+                                \x20 TRACK 03 MODE2/2352\n\
+                                \x20   PREGAP 00:00:03\n\
+                                \x20   INDEX 01 00:00:00\n");
+
+    let mut cue = Cue::new(&cue_path).unwrap();
+    let mut sector = Sector::empty();
+
+    let msf = |s| Msf::from_sector_index(s).unwrap();
+
+    // PREGAP and POSTGAP aren't stored in the BIN files
+    let toc = cue.toc();
+    let track = |t| toc.track(Bcd::from_binary(t).unwrap()).unwrap();
+
+    assert!(track(1).pregap_start() == msf(0));
+    assert!(track(1).start == msf(150));
+    assert!(track(2).pregap_start() == msf(152));
+    assert!(track(2).start == msf(162));
+    assert!(track(3).pregap_start() == msf(170));
+    assert!(track(3).start == msf(173));
+    assert!(toc.lead_out() == msf(175));
+
+    // Track 01's implicit pregap: blank Mode 1 sectors
+    for &s in &[0, 75, 149] {
+        cue.read_sector(&mut sector, msf(s)).unwrap();
+
+        let (m, sec, f) = msf(s).into_bcd();
+
+        assert!(sector.metadata().index == Bcd::zero());
+        assert!(sector.metadata().format == TrackFormat::Mode1);
+        assert!(sector.check_integrity().unwrap() == Status::Valid);
+
+        let data = sector.data_2352().unwrap();
+
+        assert!(&data[..12] == &SYNC_PATTERN);
+        assert!(&data[12..16] == &[m.bcd(), sec.bcd(), f.bcd(), 1]);
+        assert!(data[16..2064].iter().all(|&b| b == 0));
+    }
+
+    // Track 02's pregap and postgap: digital silence
+    for &s in &[152, 161, 165, 169] {
+        cue.read_sector(&mut sector, msf(s)).unwrap();
+
+        assert!(sector.metadata().track == Bcd::from_binary(2).unwrap());
+        assert!(sector.metadata().format == TrackFormat::Audio);
+        assert!(sector.data_2352().unwrap().iter().all(|&b| b == 0));
+    }
+
+    cue.read_sector(&mut sector, msf(164)).unwrap();
+
+    assert!(sector.data_2352().unwrap().iter().all(|&b| b == 0x22));
+
+    // Track 03's pregap: blank Form 2 sectors
+    for &s in &[170, 172] {
+        cue.read_sector(&mut sector, msf(s)).unwrap();
+
+        assert!(sector.metadata().index == Bcd::zero());
+        assert!(sector.metadata().format == TrackFormat::Mode2Xa);
+        assert!(sector.mode2_xa_subheader().unwrap().form() ==
+                XaForm::Form2);
+        assert!(sector.check_integrity().unwrap() == Status::Valid);
+
+        let data = sector.data_2352().unwrap();
+
+        assert!(data[15] == 2);
+        assert!(data[24..2348].iter().all(|&b| b == 0));
+    }
+
+    cue.read_sector(&mut sector, msf(173)).unwrap();
+
+    assert!(&sector.data_2352().unwrap()[..] == &xa[..2352]);
+}
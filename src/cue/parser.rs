@@ -5,6 +5,7 @@ use std::io::Read;
 use std::str::FromStr;
 
 use internal::{Index, IndexCache};
+use toc::{self, Control};
 use CdError;
 use TrackFormat;
 
@@ -20,7 +21,8 @@ pub struct CueParser {
     pos: usize,
     /// Current line in the buffer
     line: u32,
-    /// Current absolute MSF
+    /// Absolute MSF of the last generated index into the current
+    /// BIN file
     msf: Msf,
     /// List of BIN files
     bin_files: Vec<BinaryBlob>,
@@ -35,8 +37,39 @@ pub struct CueParser {
     index_type: Option<CueTrackType>,
     /// Current Track: track no, type and list of indices
     track: Option<(Bcd, CueTrackType, TrackFormat)>,
+    /// Number of the last index in the current track
+    track_index: Option<Bcd>,
+    /// Control flags for the current track
+    control: Control,
+    /// Length of the pre-gap of the current track, inserted before
+    /// the next index
+    pregap: Option<Msf>,
+    /// Length of the post-gap of the previous track, inserted when the
+    /// track's data ends. Also contains the track number, track format
+    /// and number of the last index of the track.
+    postgap: Option<(Msf, Bcd, TrackFormat, Bcd)>,
     /// Indices
     indices: Vec<Index<Storage>>,
+    /// Current session number
+    session: u8,
+    /// True if a new session has been started with `REM SESSION` and
+    /// its first index hasn't been created yet
+    session_pending: bool,
+    /// Length of the lead-out of the current session, set by `REM
+    /// LEAD-OUT`
+    lead_out_len: Option<Msf>,
+    /// Length of the lead-in of the next session, set by `REM
+    /// LEAD-IN`
+    lead_in_len: Option<Msf>,
+    /// MSF of the first sector in the lead-out of each finished
+    /// session
+    lead_outs: Vec<Msf>,
+    /// Length of the lead-in of each session after the first one
+    lead_ins: Vec<(u8, Msf)>,
+    /// Media Catalog Number
+    catalog: Option<String>,
+    /// ISRC of the tracks
+    isrc: Vec<(Bcd, String)>,
 }
 
 impl CueParser {
@@ -63,16 +96,40 @@ impl CueParser {
             index_type: None,
             index_msf: Msf::zero(),
             track: None,
+            track_index: None,
+            control: Control::empty(),
+            pregap: None,
+            postgap: None,
             indices: Vec::new(),
+            session: 1,
+            session_pending: false,
+            lead_out_len: None,
+            lead_in_len: None,
+            lead_outs: Vec::new(),
+            lead_ins: Vec::new(),
+            catalog: None,
+            isrc: Vec::new(),
         };
 
         try!(parser.parse(&cue_sheet));
 
+        // The end of the data is the last session's lead-out
+        parser.lead_outs.push(parser.msf);
+
+        let mut indices =
+            try!(IndexCache::new_multisession(parser.cue_path,
+                                              parser.indices,
+                                              parser.lead_outs));
+
+        for &(session, len) in &parser.lead_ins {
+            try!(indices.set_session_lead_in(session, len));
+        }
+
         Ok(Cue {
-            indices: try!(IndexCache::new(parser.cue_path,
-                                          parser.indices,
-                                          parser.msf)),
+            indices: indices,
             bin_files: parser.bin_files,
+            catalog: parser.catalog,
+            isrc: parser.isrc,
         })
     }
 
@@ -102,11 +159,16 @@ impl CueParser {
             type Callback = fn (&mut CueParser,
                                 &[&[u8]]) -> Result<(), CdError>;
 
-            let handlers: [(&'static [u8], Callback, Option<u32>); 4] =
+            let handlers: [(&'static [u8], Callback, Option<u32>); 9] =
                 [(b"REM", CueParser::command_rem, None),
                  (b"FILE", CueParser::command_file, Some(3)),
                  (b"TRACK", CueParser::command_track, Some(3)),
-                 (b"INDEX", CueParser::command_index, Some(3))];
+                 (b"INDEX", CueParser::command_index, Some(3)),
+                 (b"PREGAP", CueParser::command_pregap, Some(2)),
+                 (b"POSTGAP", CueParser::command_postgap, Some(2)),
+                 (b"FLAGS", CueParser::command_flags, None),
+                 (b"CATALOG", CueParser::command_catalog, Some(2)),
+                 (b"ISRC", CueParser::command_isrc, Some(2))];
 
             let callback = handlers.iter()
                 .find(|&&(name, _, _)| name == command);
@@ -141,12 +203,94 @@ impl CueParser {
 
         try!(self.finalize_bin());
 
+        if self.track.is_some() && self.track_index.is_none() {
+            return Err(self.error_str("Last track has no index"));
+        }
+
+        if self.session_pending {
+            let error = format!("Session {} has no track", self.session);
+            return Err(self.error(error));
+        }
+
         Ok(())
     }
 
-    /// REM comment
-    fn command_rem(&mut self, _: &[&[u8]]) -> Result<(), CdError> {
-        // REM is used for comments, we can ignore this line
+    /// REM comment. A few extensions are also stored as REM
+    /// commands to remain compatible with older software, we handle
+    /// the multi-session ones: `REM SESSION nn`, `REM LEAD-OUT
+    /// mm:ss:ff` and `REM LEAD-IN mm:ss:ff`.
+    fn command_rem(&mut self, params: &[&[u8]]) -> Result<(), CdError> {
+        if params.len() != 3 {
+            // Comment
+            return Ok(());
+        }
+
+        match params[1] {
+            b"SESSION" => self.rem_session(params[2]),
+            b"LEAD-OUT" => {
+                let len =
+                    match from_buf(params[2]) {
+                        Ok(b) => b,
+                        Err(_) =>
+                            return Err(self.error_str("Invalid LEAD-OUT MSF")),
+                    };
+
+                self.lead_out_len = Some(len);
+
+                Ok(())
+            }
+            b"LEAD-IN" => {
+                let len =
+                    match from_buf(params[2]) {
+                        Ok(b) => b,
+                        Err(_) =>
+                            return Err(self.error_str("Invalid LEAD-IN MSF")),
+                    };
+
+                self.lead_in_len = Some(len);
+
+                Ok(())
+            }
+            // Regular comment
+            _ => Ok(()),
+        }
+    }
+
+    /// REM SESSION number
+    fn rem_session(&mut self, number: &[u8]) -> Result<(), CdError> {
+        let n: u8 =
+            match from_buf(number) {
+                Ok(n) => n,
+                Err(_) => return Err(self.error_str("Invalid session number")),
+            };
+
+        if self.track.is_some() && self.track_index.is_none() {
+            return Err(self.error_str("SESSION must be placed between \
+                                       tracks"));
+        }
+
+        if self.track.is_none() && !self.session_pending {
+            // The first session is implicit but it's often declared
+            // explicitly
+            if n != self.session {
+                let error = format!("Unexpected session number {}", n);
+                return Err(self.error(error));
+            }
+
+            return Ok(());
+        }
+
+        if self.session_pending || n != self.session.wrapping_add(1) {
+            let error = format!("Unexpected session number {}", n);
+            return Err(self.error(error));
+        }
+
+        // The lead-out of the previous session will be inserted
+        // before the next index, once we know where the session's
+        // data ends.
+        self.session = n;
+        self.session_pending = true;
+
         Ok(())
     }
 
@@ -188,15 +332,15 @@ impl CueParser {
             return Err(self.error(error));
         }
 
+        // Open the new BIN blob
+        let bin = try!(BinaryBlob::new(&bin_path));
+
         let size =
-            match metadata(&bin_path) {
-                Ok(m) => m.len(),
+            match bin.size() {
+                Ok(s) => s,
                 Err(e) => return Err(CdError::IoError(e)),
             };
 
-        // Open the new BIN blob
-        let bin = try!(BinaryBlob::new(&bin_path));
-
         self.bin_files.push(bin);
         self.bin_len = size;
         self.consumed_bytes = 0;
@@ -242,7 +386,17 @@ impl CueParser {
             CueTrackType::CdIRaw => TrackFormat::Mode2CdI,
         };
 
+        if self.track_index.is_none() {
+            if let Some((prev, _, _)) = self.track {
+                let error = format!("Track {} has no index", prev);
+
+                return Err(self.error(error));
+            }
+        }
+
         self.track = Some((n, t, f));
+        self.track_index = None;
+        self.control = Control::from_format(f);
 
         if n.binary() == 1 {
             // CUE always ignores track 1's pregap, let's add it in
@@ -251,7 +405,7 @@ impl CueParser {
                                     Msf::zero(),
                                     n,
                                     f,
-                                    0,
+                                    self.session,
                                     Storage::PreGap);
 
             self.indices.push(pregap);
@@ -279,30 +433,308 @@ impl CueParser {
             Err(_) => return Err(self.error_str("Invalid index MSF")),
         };
 
-        try!(self.consume_bin_sectors(msf));
+        if msf < self.index_msf {
+            return Err(self.error_str("Index MSF goes backwards"));
+        }
+
+        let delta = try!(self.consume_bin_sectors(msf));
+
+        self.msf = try!(self.advance(self.msf, delta));
+        self.index_msf = msf;
+
+        // If we had a post-gap pending it means that we're starting a
+        // new track and the previous one is over.
+        if let Some((_, t, _, _)) = self.postgap {
+            if t == track_number {
+                return Err(self.error_str("INDEX after POSTGAP"));
+            }
+        }
+
+        try!(self.insert_postgap());
+
+        if self.session_pending {
+            try!(self.insert_session_gap());
+
+            // The first track of a session always has a pregap of at
+            // least 2 seconds. If the cue sheet doesn't contain it we
+            // assume it's not stored in the BIN file.
+            if n != Bcd::zero() && self.pregap.is_none() {
+                self.pregap = Some(Msf::from_sector_index(150).unwrap());
+            }
+        }
 
-        self.msf = self.msf + msf;
+        // A zero-length PREGAP is only used to disable the implicit
+        // pregap of the first track of a session
+        let pregap =
+            match self.pregap.take() {
+                Some(len) if len.sector_index() != 0 => Some(len),
+                _ => None,
+            };
+
+        if let Some(len) = pregap {
+            let mut pregap = Index::new(Bcd::zero(),
+                                        self.msf,
+                                        track_number,
+                                        track_format,
+                                        self.session,
+                                        Storage::PreGap);
+
+            pregap.set_control(self.control);
+
+            self.indices.push(pregap);
+
+            self.msf = try!(self.advance(self.msf, len));
+        }
 
         // Should be validated in `command_track`
         assert!(!self.bin_files.is_empty());
 
         let bin_index = (self.bin_files.len() - 1) as u32;
 
-        let index = Index::new(n,
-                               self.msf,
-                               track_number,
-                               track_format,
-                               0,
-                               Storage::Bin(bin_index,
-                                            self.consumed_bytes,
-                                            track_type));
+        let mut index = Index::new(n,
+                                   self.msf,
+                                   track_number,
+                                   track_format,
+                                   self.session,
+                                   Storage::Bin(bin_index,
+                                                self.consumed_bytes,
+                                                track_type));
+
+        index.set_control(self.control);
 
         self.indices.push(index);
         self.index_type = Some(track_type);
+        self.track_index = Some(n);
 
         Ok(())
     }
 
+    /// PREGAP mm:ss:ff
+    fn command_pregap(&mut self, params: &[&[u8]]) -> Result<(), CdError> {
+        let track_number =
+            match self.track {
+                Some((n, _, _)) => n,
+                None => return Err(self.error_str("Track-less PREGAP")),
+            };
+
+        if self.track_index.is_some() || self.pregap.is_some() {
+            return Err(self.error_str("PREGAP must be placed before the \
+                                       track's indices"));
+        }
+
+        let len: Msf =
+            match from_buf(params[1]) {
+                Ok(b) => b,
+                Err(_) => return Err(self.error_str("Invalid PREGAP MSF")),
+            };
+
+        if track_number.binary() == 1 {
+            // Track 01 already has an implicit 2 second pregap, we
+            // only need to extend it if the PREGAP is bigger than
+            // that.
+            let implicit = Msf::from_sector_index(150).unwrap();
+
+            if len > implicit {
+                let extra = len - implicit;
+
+                self.msf = try!(self.advance(self.msf, extra));
+            }
+        } else {
+            self.pregap = Some(len);
+        }
+
+        Ok(())
+    }
+
+    /// POSTGAP mm:ss:ff
+    fn command_postgap(&mut self, params: &[&[u8]]) -> Result<(), CdError> {
+        let (track_number, track_format) =
+            match self.track {
+                Some((n, _, f)) => (n, f),
+                None => return Err(self.error_str("Track-less POSTGAP")),
+            };
+
+        let index =
+            match self.track_index {
+                Some(i) => i,
+                None =>
+                    return Err(self.error_str("POSTGAP must be placed after \
+                                               the track's indices")),
+            };
+
+        if self.postgap.is_some() {
+            return Err(self.error_str("Duplicate POSTGAP"));
+        }
+
+        let len =
+            match from_buf(params[1]) {
+                Ok(b) => b,
+                Err(_) => return Err(self.error_str("Invalid POSTGAP MSF")),
+            };
+
+        self.postgap = Some((len, track_number, track_format, index));
+
+        Ok(())
+    }
+
+    /// FLAGS flag [flag...]
+    fn command_flags(&mut self, params: &[&[u8]]) -> Result<(), CdError> {
+        let track_number =
+            match self.track {
+                Some((n, _, _)) => n,
+                None => return Err(self.error_str("Track-less FLAGS")),
+            };
+
+        // The data flag is not part of the FLAGS command, it depends
+        // on the track type.
+        let mut control = self.control & toc::DATA;
+
+        for &flag in &params[1..] {
+            control.insert(
+                match flag {
+                    b"DCP" => toc::DIGITAL_COPY_PERMITTED,
+                    b"4CH" => toc::FOUR_CHANNEL,
+                    b"PRE" => toc::PRE_EMPHASIS,
+                    // Serial copy management system, not stored in the
+                    // Q subchannel control field
+                    b"SCMS" => Control::empty(),
+                    _ => {
+                        let flag = String::from_utf8_lossy(flag);
+
+                        let error = format!("Unsupported flag \"{}\"", flag);
+
+                        return Err(self.error(error));
+                    }
+                });
+        }
+
+        self.control = control;
+
+        // FLAGS should be placed before the track's indices but
+        // let's update the ones we already created just in case (and
+        // track 01's implicit pregap).
+        for index in self.indices.iter_mut().rev()
+            .take_while(|i| i.track() == track_number) {
+            index.set_control(control);
+        }
+
+        Ok(())
+    }
+
+    /// CATALOG number
+    fn command_catalog(&mut self,
+                       params: &[&[u8]]) -> Result<(), CdError> {
+        let catalog = params[1];
+
+        if self.catalog.is_some() {
+            return Err(self.error_str("Duplicate CATALOG"));
+        }
+
+        // The Media Catalog Number is a 13 digit UPC/EAN code
+        if catalog.len() != 13 || !catalog.iter().all(|b| b.is_ascii_digit()) {
+            return Err(self.error_str("Invalid CATALOG number"));
+        }
+
+        self.catalog = Some(String::from_utf8_lossy(catalog).into_owned());
+
+        Ok(())
+    }
+
+    /// ISRC code
+    fn command_isrc(&mut self, params: &[&[u8]]) -> Result<(), CdError> {
+        let isrc = params[1];
+
+        let track_number =
+            match self.track {
+                Some((n, _, _)) => n,
+                None => return Err(self.error_str("Track-less ISRC")),
+            };
+
+        if self.isrc.iter().any(|&(t, _)| t == track_number) {
+            return Err(self.error_str("Duplicate ISRC"));
+        }
+
+        // Country code, owner code, year of reference and serial
+        // number: 12 alphanumeric characters
+        if isrc.len() != 12 ||
+            !isrc.iter().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(self.error_str("Invalid ISRC"));
+        }
+
+        let isrc = String::from_utf8_lossy(isrc).into_owned();
+
+        self.isrc.push((track_number, isrc));
+
+        Ok(())
+    }
+
+    /// Insert the pending post-gap (if any) at the current position
+    fn insert_postgap(&mut self) -> Result<(), CdError> {
+        if let Some((len, track, format, index)) = self.postgap.take() {
+            // The post-gap is part of the last index of the track,
+            // which must be the last one we've created.
+            let (control, session) =
+                match self.indices.last() {
+                    Some(i) => (i.control(), i.session()),
+                    None => (Control::from_format(format), self.session),
+                };
+
+            let mut postgap = Index::new(index,
+                                         self.msf,
+                                         track,
+                                         format,
+                                         session,
+                                         Storage::PreGap);
+
+            postgap.set_control(control);
+
+            self.indices.push(postgap);
+
+            self.msf = try!(self.advance(self.msf, len));
+        }
+
+        Ok(())
+    }
+
+    /// Insert the lead-out of the previous session and the lead-in
+    /// of the new one at the current position
+    fn insert_session_gap(&mut self) -> Result<(), CdError> {
+        self.lead_outs.push(self.msf);
+
+        // The first lead-out is 1m30s long, the following ones 30s.
+        let default_lead_out =
+            if self.lead_outs.len() == 1 {
+                6750
+            } else {
+                2250
+            };
+
+        let lead_out =
+            self.lead_out_len.take()
+            .unwrap_or(Msf::from_sector_index(default_lead_out).unwrap());
+
+        let lead_in =
+            self.lead_in_len.take()
+            .unwrap_or(Msf::from_sector_index(4500).unwrap());
+
+        self.msf = try!(self.advance(self.msf, lead_out));
+        self.msf = try!(self.advance(self.msf, lead_in));
+
+        self.lead_ins.push((self.session, lead_in));
+
+        self.session_pending = false;
+
+        Ok(())
+    }
+
+    /// Return `msf + offset` or an error if it overflows
+    fn advance(&self, msf: Msf, offset: Msf) -> Result<Msf, CdError> {
+        match msf.checked_add(offset) {
+            Some(m) => Ok(m),
+            None => Err(self.error_str("MSF overflow")),
+        }
+    }
+
     /// Split the buffer into individual words. Handles quoted strings
     /// and treats them as a single word but returns them with the
     /// first quote included (to detect elements that shouldn't be
@@ -356,14 +788,15 @@ impl CueParser {
     }
 
     /// Advance in the current BIN file, updating how many bytes are
-    /// left to consume.
-    fn consume_bin_sectors(&mut self, offset: Msf) -> Result<(), CdError> {
-        let delta = offset - self.index_msf;
+    /// left to consume. Returns the distance between `offset` and the
+    /// previous index in the file.
+    fn consume_bin_sectors(&mut self, offset: Msf) -> Result<Msf, CdError> {
+        let delta_msf = offset - self.index_msf;
 
-        let delta = delta.sector_index() as u64;
+        let delta = delta_msf.sector_index() as u64;
 
         if delta == 0 {
-            return Ok(());
+            return Ok(delta_msf);
         }
 
         let ty = match self.index_type {
@@ -388,7 +821,7 @@ impl CueParser {
 
         self.consumed_bytes += index_size;
 
-        Ok(())
+        Ok(delta_msf)
     }
 
     /// We're done with this bin file which means that whatever's left
@@ -427,7 +860,9 @@ impl CueParser {
                         "Previous BIN file is too big, MSF overflow")),
             };
 
-        Ok(())
+        // The previous BIN file is over and so is the data of its
+        // last track
+        self.insert_postgap()
     }
 }
 
@@ -509,3 +944,185 @@ pub fn build_path(bytes: &[u8]) -> Option<PathBuf> {
 
     Some(PathBuf::from(s))
 }
+
+#[test]
+fn multisession_layout() {
+    use test_util::{temp_dir, write_file};
+    use sector::Sector;
+    use subchannel::SubChannel;
+    use Image;
+    use SessionFormat;
+
+    let dir = temp_dir("cue-multisession");
+
+    let pattern = |len: usize, seed: usize| -> Vec<u8> {
+        (0..len).map(|i| (i * seed + i / 2048) as u8).collect()
+    };
+
+    let data = pattern(10 * 2048, 3);
+    let audio = pattern(20 * 2352, 5);
+    let xa = pattern(8 * 2336, 7);
+    let cdi = pattern(10 * 2336, 11);
+
+    write_file(&dir, "data.bin", &data);
+    write_file(&dir, "audio.bin", &audio);
+    write_file(&dir, "xa.bin", &xa);
+    write_file(&dir, "cdi.bin", &cdi);
+
+    // Session 1: track 01 at 00:02:00, track 02's pregap at 160,
+    // INDEX 01 at 165 and postgap at 185, lead-out at 188.
+    //
+    // Session 2: 10s lead-out and 20s lead-in, track 03's implicit
+    // pregap at 2438 and INDEX 01 at 2588, lead-out at 2596.
+    //
+    // Session 3: default 30s lead-out and 1m lead-in, track 04's
+    // stored INDEX 00 at 9346 and INDEX 01 at 9350, lead-out at 9356.
+    let cue_path =
+        write_file(&dir, "disc.cue",
+                   b"REM SESSION 01\n\
+                     FILE \"data.bin\" BINARY\n\
+                     \x20 TRACK 01 MODE1/2048\n\
+                     \x20   INDEX 01 00:00:00\n\
+                     FILE \"audio.bin\" BINARY\n\
+                     \x20 TRACK 02 AUDIO\n\
+                     \x20   PREGAP 00:00:05\n\
+                     \x20   INDEX 01 00:00:00\n\
+                     \x20   POSTGAP 00:00:03\n\
+                     REM LEAD-OUT 00:10:00\n\
+                     REM SESSION 02\n\
+                     REM LEAD-IN 00:20:00\n\
+                     FILE \"xa.bin\" BINARY\n\
+                     \x20 TRACK 03 MODE2/2336\n\
+                     \x20   INDEX 01 00:00:00\n\
+                     REM SESSION 03\n\
+                     FILE \"cdi.bin\" BINARY\n\
+                     \x20 TRACK 04 CDI/2336\n\
+                     \x20   INDEX 00 00:00:00\n\
+                     \x20   INDEX 01 00:00:04\n");
+
+    let msf = |s| Msf::from_sector_index(s).unwrap();
+    let track = |t| Bcd::from_binary(t).unwrap();
+
+    let mut cue = Cue::new(&cue_path).unwrap();
+
+    assert!(cue.indices.session_count() == 3);
+    assert!(cue.indices.session_first_track(2).unwrap() == track(3));
+    assert!(cue.indices.session_last_track(1).unwrap() == track(2));
+    assert!(cue.indices.session_lead_out(2).unwrap() == msf(2596));
+    assert!(cue.indices.session_lead_in(2).unwrap() == msf(1500));
+    assert!(cue.indices.session_lead_in(3).unwrap() == msf(4500));
+
+    // The Q subchannel switches from the lead-out to the lead-in
+    // according to REM LEAD-IN
+    assert!(cue.q_subchannel(msf(937)).raw()[1] == 0xaa);
+    assert!(cue.q_subchannel(msf(938)).raw()[1] == 0x00);
+    assert!(cue.q_subchannel(msf(938)).raw()[2] == 0x03);
+
+    let toc = cue.toc();
+
+    assert!(toc.sessions.len() == 3);
+
+    let s1 = &toc.sessions[0];
+
+    assert!(s1.format == SessionFormat::CddaCdRom);
+    assert!(s1.lead_out == msf(188));
+    assert!(s1.tracks[0].format == TrackFormat::Mode1);
+    assert!(s1.tracks[0].pregap_start() == msf(0));
+    assert!(s1.tracks[0].start == msf(150));
+    assert!(s1.tracks[0].length == msf(10));
+    assert!(s1.tracks[1].format == TrackFormat::Audio);
+    assert!(s1.tracks[1].pregap_start() == msf(160));
+    assert!(s1.tracks[1].start == msf(165));
+    // Including the postgap
+    assert!(s1.tracks[1].length == msf(23));
+
+    let s2 = &toc.sessions[1];
+
+    assert!(s2.number == 2);
+    assert!(s2.format == SessionFormat::Cdxa);
+    assert!(s2.lead_out == msf(2596));
+    assert!(s2.tracks.len() == 1);
+    assert!(s2.tracks[0].pregap_start() == msf(2438));
+    assert!(s2.tracks[0].start == msf(2588));
+    assert!(s2.tracks[0].length == msf(8));
+
+    let s3 = &toc.sessions[2];
+
+    assert!(s3.number == 3);
+    assert!(s3.format == SessionFormat::Cdi);
+    assert!(s3.lead_out == msf(9356));
+    assert!(s3.tracks[0].format == TrackFormat::Mode2CdI);
+    assert!(s3.tracks[0].pregap_start() == msf(9346));
+    assert!(s3.tracks[0].start == msf(9350));
+    assert!(s3.tracks[0].length == msf(6));
+
+    let mut sector = Sector::empty();
+
+    // Read `s` and check its metadata: track, index, track MSF and
+    // session
+    let mut read = |s, t, i, track_msf, session| {
+        cue.read_sector(&mut sector, msf(s)).unwrap();
+
+        {
+            let m = sector.metadata();
+
+            assert!(m.msf == msf(s));
+            assert!(m.track == track(t));
+            assert!(m.index.binary() == i);
+            assert!(m.track_msf == msf(track_msf));
+            assert!(m.session == session);
+        }
+
+        let data = sector.data_2352().unwrap().to_vec();
+
+        // Every data sector has a full header
+        if sector.metadata().format != TrackFormat::Audio {
+            let (m, s, f) = msf(s).into_bcd();
+
+            assert!(&data[..12] == &::sector::SYNC_PATTERN);
+            assert!(&data[12..15] == &[m.bcd(), s.bcd(), f.bcd()]);
+        }
+
+        data
+    };
+
+    // Track 01, MODE1/2048 with the header and ECC rebuilt
+    let d = read(155, 1, 1, 5, 1);
+
+    assert!(d[15] == 1);
+    assert!(&d[16..2064] == &data[5 * 2048..6 * 2048]);
+
+    // Track 02's pregap, audio and postgap
+    assert!(read(162, 2, 0, 3, 1).iter().all(|&b| b == 0));
+    assert!(&read(170, 2, 1, 5, 1)[..] == &audio[5 * 2352..6 * 2352]);
+    assert!(read(186, 2, 1, 21, 1).iter().all(|&b| b == 0));
+
+    // Track 03's implicit pregap and MODE2/2336 data
+    let d = read(2500, 3, 0, 88, 2);
+
+    assert!(d[15] == 2);
+    // Form 2 subheader
+    assert!(d[18] == 0x20);
+
+    let d = read(2590, 3, 1, 2, 2);
+
+    assert!(d[15] == 2);
+    assert!(&d[16..] == &xa[2 * 2336..3 * 2336]);
+
+    // Track 04's stored pregap and CDI/2336 data
+    let d = read(9347, 4, 0, 3, 3);
+
+    assert!(&d[16..] == &cdi[2336..2 * 2336]);
+
+    let d = read(9355, 4, 1, 5, 3);
+
+    assert!(&d[16..] == &cdi[9 * 2336..]);
+
+    // Lead-out and lead-in areas
+    for &s in &[188, 2437, 2596, 9345, 9356] {
+        match cue.read_sector(&mut sector, msf(s)) {
+            Err(CdError::LeadOut) => (),
+            _ => panic!("Sector {} should be in the lead-out", s),
+        }
+    }
+}
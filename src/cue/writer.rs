@@ -0,0 +1,702 @@
+//! CUE sheet writer
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{Write, BufWriter};
+use std::cmp;
+
+use CdError;
+use Image;
+use TrackFormat;
+
+use msf::Msf;
+use bcd::Bcd;
+use sector::{Sector, SectorBuilder, Metadata};
+use toc::{self, Track};
+
+/// Writer converting any `Image` into a BIN/CUE image with one BIN
+/// file per track. By default all the tracks are stored as raw 2352
+/// byte sectors (2448 bytes for CD+G) so that reading the result back
+/// with `Cue` returns the exact same sectors.
+///
+/// The files follow the redump.org conventions: the BIN files are
+/// named after the cue sheet, `<name>.bin` for single track discs and
+/// `<name> (Track N).bin` otherwise (with two digit track numbers if
+/// there are more than 9 tracks), the lines end with CR LF and the
+/// pregaps are stored at the beginning of their track's BIN file.
+/// Pregaps which only contain blank sectors (most likely not stored
+/// in the original image) are written as `PREGAP` instead. The
+/// first track of a session without any pregap gets a zero-length
+/// `PREGAP`, otherwise `Cue` would assume a 2 second pregap.
+///
+/// The first 2 seconds of track 01's pregap can't be stored in a
+/// BIN/CUE image, they're always regenerated as blank sectors.
+///
+/// CD+G tracks can only be converted if `image` provides the
+/// subchannel data of their sectors, `write` returns
+/// `CdError::BadFormat` otherwise.
+pub struct CueWriter {
+    /// Path of the cue sheet
+    cue_path: PathBuf,
+    /// True if Mode 1 tracks are stored without their error
+    /// detection and correction codes
+    strip_ecc: bool,
+}
+
+impl CueWriter {
+    /// Create a writer for the cue sheet at `cue_path`. The BIN files
+    /// will be created in the same directory.
+    pub fn new(cue_path: &Path) -> CueWriter {
+        CueWriter {
+            cue_path: PathBuf::from(cue_path),
+            strip_ecc: false,
+        }
+    }
+
+    /// If `strip` is true Mode 1 tracks are stored as `MODE1/2048`
+    /// (user data only) instead of raw sectors. The error detection
+    /// and correction codes are regenerated when the image is read
+    /// back so the sectors only match the original if their codes
+    /// were valid. Mode 2 tracks are always stored raw.
+    pub fn set_strip_ecc(&mut self, strip: bool) {
+        self.strip_ecc = strip;
+    }
+
+    /// Convert `image`, overwriting any existing file
+    pub fn write<I: Image + ?Sized>(&self,
+                                    image: &mut I) -> Result<(), CdError> {
+        let toc = image.toc();
+
+        let name =
+            match self.cue_path.file_stem() {
+                Some(s) => s.to_string_lossy().into_owned(),
+                None => return Err(CdError::BadFormat),
+            };
+
+        let ntracks = toc.tracks().count();
+
+        let mut sheet = String::new();
+
+        if let Some(ref catalog) = toc.catalog {
+            sheet.push_str(&format!("CATALOG {}\r\n", catalog));
+        }
+
+        let multisession = toc.sessions.len() > 1;
+
+        let mut sector = Sector::empty();
+
+        for (s, session) in toc.sessions.iter().enumerate() {
+            if s > 0 {
+                // The gap between the sessions is made of the
+                // lead-out of the previous one and the lead-in of this
+                // one, the parser uses the standard lead-out length by
+                // default.
+                let lead_out = toc.sessions[s - 1].lead_out.sector_index();
+                let next = session.tracks[0].pregap_start().sector_index();
+
+                let gap = next - lead_out;
+
+                let default = if s == 1 { 6750 } else { 2250 };
+
+                let lead_out_len = cmp::min(gap, default);
+
+                sheet.push_str(&format!("REM LEAD-OUT {}\r\n",
+                                        msf(lead_out_len)));
+                sheet.push_str(&format!("REM SESSION {:02}\r\n",
+                                        session.number));
+                sheet.push_str(&format!("REM LEAD-IN {}\r\n",
+                                        msf(gap - lead_out_len)));
+            } else if multisession {
+                sheet.push_str(&format!("REM SESSION {:02}\r\n",
+                                        session.number));
+            }
+
+            for (t, track) in session.tracks.iter().enumerate() {
+                let end =
+                    match session.tracks.get(t + 1) {
+                        Some(next) => next.pregap_start(),
+                        None => session.lead_out,
+                    };
+
+                let bin_name = bin_name(&name, track.number, ntracks);
+
+                let start = track.start.sector_index();
+                let mut pregap_start = track.pregap_start().sector_index();
+
+                if track.number == Bcd::one() {
+                    // The first 2 seconds of track 01's pregap are
+                    // never stored
+                    pregap_start = cmp::min(pregap_start + 150, start);
+                }
+
+                let stored_pregap =
+                    if pregap_start == start {
+                        false
+                    } else {
+                        !try!(is_blank(image,
+                                       &mut sector,
+                                       pregap_start,
+                                       start))
+                    };
+
+                let bin_start =
+                    if stored_pregap {
+                        pregap_start
+                    } else {
+                        start
+                    };
+
+                sheet.push_str(&format!("FILE \"{}\" BINARY\r\n", bin_name));
+                sheet.push_str(&format!("  TRACK {} {}\r\n",
+                                        track.number,
+                                        self.track_type(track.format)));
+
+                let flags = flags(track);
+
+                if !flags.is_empty() {
+                    sheet.push_str(&format!("    FLAGS {}\r\n", flags));
+                }
+
+                if let Some(ref isrc) = track.isrc {
+                    sheet.push_str(&format!("    ISRC {}\r\n", isrc));
+                }
+
+                if !stored_pregap {
+                    let len = start - track.pregap_start().sector_index();
+
+                    // The parser adds a 2 second pregap to the first
+                    // track of a session if it doesn't have one, a
+                    // zero-length PREGAP prevents that
+                    let session_start = s > 0 && t == 0;
+
+                    // Track 01's implicit pregap doesn't need to be
+                    // specified
+                    if (len > 0 || session_start) &&
+                        !(track.number == Bcd::one() && len == 150) {
+                            sheet.push_str(&format!("    PREGAP {}\r\n",
+                                                    msf(len)));
+                        }
+                }
+
+                for index in &track.indices {
+                    let pos = index.msf.sector_index();
+
+                    if pos < bin_start {
+                        if !stored_pregap {
+                            continue;
+                        }
+
+                        // Part of track 01's pregap is stored, the
+                        // INDEX 00 starts with the BIN file
+                    }
+
+                    let pos = cmp::max(pos, bin_start);
+
+                    sheet.push_str(&format!("    INDEX {} {}\r\n",
+                                            index.number,
+                                            msf(pos - bin_start)));
+                }
+
+                let mut path = self.cue_path.clone();
+                path.set_file_name(&bin_name);
+
+                try!(self.write_bin(image,
+                                    &mut sector,
+                                    &path,
+                                    track.format,
+                                    bin_start,
+                                    end.sector_index()));
+            }
+        }
+
+        let res = File::create(&self.cue_path)
+            .and_then(|mut f| f.write_all(sheet.as_bytes()));
+
+        match res {
+            Ok(()) => Ok(()),
+            Err(e) => Err(CdError::IoError(e)),
+        }
+    }
+
+    /// Return the CUE track type for a track in `format`
+    fn track_type(&self, format: TrackFormat) -> &'static str {
+        match format {
+            TrackFormat::Audio => "AUDIO",
+            TrackFormat::CdG => "CDG",
+            TrackFormat::Mode1 if self.strip_ecc => "MODE1/2048",
+            TrackFormat::Mode1 => "MODE1/2352",
+            TrackFormat::Mode2Xa => "MODE2/2352",
+            TrackFormat::Mode2CdI => "CDI/2352",
+        }
+    }
+
+    /// Write the sectors in `start..end` to a new BIN file at `path`
+    fn write_bin<I: Image + ?Sized>(&self,
+                                    image: &mut I,
+                                    sector: &mut Sector,
+                                    path: &Path,
+                                    format: TrackFormat,
+                                    start: u32,
+                                    end: u32) -> Result<(), CdError> {
+        let file =
+            match File::create(path) {
+                Ok(f) => f,
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        let mut bin = BufWriter::new(file);
+
+        for pos in start..end {
+            try!(image.read_sector(sector, msf(pos)));
+
+            let res =
+                if format == TrackFormat::Mode1 && self.strip_ecc {
+                    bin.write_all(try!(sector.mode1_payload()))
+                } else {
+                    bin.write_all(try!(sector.data_2352()))
+                };
+
+            if let Err(e) = res {
+                return Err(CdError::IoError(e));
+            }
+
+            if format == TrackFormat::CdG {
+                // The subchannel data follows the sector data in the
+                // "raw" interleaved layout. Padding it with zeroes
+                // would silently drop the graphics.
+                let subchannel =
+                    match sector.subchannel_interleaved() {
+                        Some(s) => s,
+                        None => return Err(CdError::BadFormat),
+                    };
+
+                if let Err(e) = bin.write_all(&subchannel) {
+                    return Err(CdError::IoError(e));
+                }
+            }
+        }
+
+        match bin.flush() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(CdError::IoError(e)),
+        }
+    }
+}
+
+/// Return the name of the BIN file for `track` following the redump
+/// conventions
+fn bin_name(name: &str, track: Bcd, ntracks: usize) -> String {
+    if ntracks == 1 {
+        format!("{}.bin", name)
+    } else if ntracks > 9 {
+        format!("{} (Track {:02}).bin", name, track.binary())
+    } else {
+        format!("{} (Track {}).bin", name, track.binary())
+    }
+}
+
+/// Return the parameters of the FLAGS command for `track`, empty if
+/// no flag is set
+fn flags(track: &Track) -> String {
+    let mut flags = Vec::new();
+
+    // The DATA flag is implied by the track type
+    if track.control.contains(toc::DIGITAL_COPY_PERMITTED) {
+        flags.push("DCP");
+    }
+
+    if track.control.contains(toc::FOUR_CHANNEL) {
+        flags.push("4CH");
+    }
+
+    if track.control.contains(toc::PRE_EMPHASIS) {
+        flags.push("PRE");
+    }
+
+    flags.join(" ")
+}
+
+/// Convert a sector count into an MSF for the cue sheet
+fn msf(sectors: u32) -> Msf {
+    // The image's sectors all have a valid MSF so that can't overflow
+    Msf::from_sector_index(sectors).unwrap()
+}
+
+/// Return true if all the sectors in `start..end` are identical to
+/// the blank sectors `Cue` generates for the gaps which are not
+/// stored in the BIN files
+fn is_blank<I: Image + ?Sized>(image: &mut I,
+                               sector: &mut Sector,
+                               start: u32,
+                               end: u32) -> Result<bool, CdError> {
+    let mut blank = Sector::empty();
+
+    for pos in start..end {
+        try!(image.read_sector(sector, msf(pos)));
+
+        {
+            let m = sector.metadata();
+
+            let mut builder = SectorBuilder::new(&mut blank);
+
+            builder.set_blank(m.format);
+            builder.set_metadata(Metadata {
+                msf: m.msf,
+                track_msf: m.track_msf,
+                index: m.index,
+                track: m.track,
+                format: m.format,
+                session: m.session,
+            });
+        }
+
+        let data = try!(sector.data_2352());
+
+        if &data[..] != &try!(blank.data_2352())[..] {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+#[test]
+fn redump_names() {
+    let track = Bcd::from_binary(3).unwrap();
+
+    assert!(bin_name("Disc", Bcd::one(), 1) == "Disc.bin");
+    assert!(bin_name("Disc", track, 3) == "Disc (Track 3).bin");
+    assert!(bin_name("Disc", track, 12) == "Disc (Track 03).bin");
+}
+
+#[test]
+fn round_trip() {
+    use std::fs;
+    use std::io::Read;
+    use test_util::{temp_dir, write_file};
+    use cue::Cue;
+
+    let dir = temp_dir("cue-writer");
+
+    // Track 01: 20 Mode 1 sectors of user data
+    let data: Vec<u8> = (0..20 * 2048).map(|i| (i * 13 + i / 2048) as u8)
+        .collect();
+
+    // Track 02: 30 sectors, track 03: 8 sectors of stored pregap and
+    // 25 sectors
+    let audio: Vec<u8> = (0..63 * 2352).map(|i| (i % 251) as u8)
+        .collect();
+
+    // Track 04: 10 CD+G sectors with their subchannel data
+    let cdg: Vec<u8> = (0..10 * 2448).map(|i| (i % 241) as u8).collect();
+
+    write_file(&dir, "data.bin", &data);
+    write_file(&dir, "audio.bin", &audio);
+    write_file(&dir, "cdg.bin", &cdg);
+
+    let source_path =
+        write_file(&dir, "source.cue",
+                   b"FILE \"data.bin\" BINARY\n\
+                     \x20 TRACK 01 MODE1/2048\n\
+                     \x20   INDEX 01 00:00:00\n\
+                     FILE \"audio.bin\" BINARY\n\
+                     \x20 TRACK 02 AUDIO\n\
+                     \x20   PREGAP 00:00:10\n\
+                     \x20   INDEX 01 00:00:00\n\
+                     \x20   POSTGAP 00:00:05\n\
+                     \x20 TRACK 03 AUDIO\n\
+                     \x20   INDEX 00 00:00:30\n\
+                     \x20   INDEX 01 00:00:38\n\
+                     FILE \"cdg.bin\" BINARY\n\
+                     \x20 TRACK 04 CDG\n\
+                     \x20   PREGAP 00:00:02\n\
+                     \x20   INDEX 01 00:00:00\n");
+
+    let mut source = Cue::new(&source_path).unwrap();
+
+    let cue_path = dir.join("copy.cue");
+
+    let mut writer = CueWriter::new(&cue_path);
+
+    writer.set_strip_ecc(true);
+    writer.write(&mut source).unwrap();
+
+    let mut sheet = String::new();
+
+    fs::File::open(&cue_path).unwrap().read_to_string(&mut sheet).unwrap();
+
+    assert!(sheet ==
+            "FILE \"copy (Track 1).bin\" BINARY\r\n\
+             \x20 TRACK 01 MODE1/2048\r\n\
+             \x20   INDEX 01 00:00:00\r\n\
+             FILE \"copy (Track 2).bin\" BINARY\r\n\
+             \x20 TRACK 02 AUDIO\r\n\
+             \x20   PREGAP 00:00:10\r\n\
+             \x20   INDEX 01 00:00:00\r\n\
+             FILE \"copy (Track 3).bin\" BINARY\r\n\
+             \x20 TRACK 03 AUDIO\r\n\
+             \x20   INDEX 00 00:00:00\r\n\
+             \x20   INDEX 01 00:00:08\r\n\
+             FILE \"copy (Track 4).bin\" BINARY\r\n\
+             \x20 TRACK 04 CDG\r\n\
+             \x20   PREGAP 00:00:02\r\n\
+             \x20   INDEX 01 00:00:00\r\n");
+
+    check_copy(&mut source, &mut Cue::new(&cue_path).unwrap());
+}
+
+#[test]
+fn round_trip_multisession() {
+    use std::fs;
+    use std::io::Read;
+    use test_util::{temp_dir, write_file};
+    use cue::Cue;
+
+    let dir = temp_dir("cue-writer-multisession");
+
+    let data: Vec<u8> = (0..40 * 2352).map(|i| (i * 7 + i / 2352) as u8)
+        .collect();
+
+    write_file(&dir, "data.bin", &data);
+
+    // Session 2 uses the default gap and implicit pregap, session 3
+    // starts right after a custom lead-in without any pregap
+    let source_path =
+        write_file(&dir, "source.cue",
+                   b"REM SESSION 01\n\
+                     FILE \"data.bin\" BINARY\n\
+                     \x20 TRACK 01 MODE1/2352\n\
+                     \x20   INDEX 01 00:00:00\n\
+                     \x20 TRACK 02 AUDIO\n\
+                     \x20   INDEX 00 00:00:10\n\
+                     \x20   INDEX 01 00:00:12\n\
+                     REM SESSION 02\n\
+                     \x20 TRACK 03 MODE2/2352\n\
+                     \x20   INDEX 01 00:00:20\n\
+                     REM LEAD-OUT 00:30:00\n\
+                     REM SESSION 03\n\
+                     REM LEAD-IN 00:20:00\n\
+                     \x20 TRACK 04 MODE1/2352\n\
+                     \x20   PREGAP 00:00:00\n\
+                     \x20   INDEX 01 00:00:30\n");
+
+    let mut source = Cue::new(&source_path).unwrap();
+
+    let t4 = source.toc().track(Bcd::from_binary(4).unwrap()).unwrap()
+        .clone();
+
+    assert!(t4.pregap_start() == t4.start);
+
+    let cue_path = dir.join("copy.cue");
+
+    CueWriter::new(&cue_path).write(&mut source).unwrap();
+
+    let mut sheet = String::new();
+
+    fs::File::open(&cue_path).unwrap().read_to_string(&mut sheet).unwrap();
+
+    assert!(sheet ==
+            "REM SESSION 01\r\n\
+             FILE \"copy (Track 1).bin\" BINARY\r\n\
+             \x20 TRACK 01 MODE1/2352\r\n\
+             \x20   INDEX 01 00:00:00\r\n\
+             FILE \"copy (Track 2).bin\" BINARY\r\n\
+             \x20 TRACK 02 AUDIO\r\n\
+             \x20   INDEX 00 00:00:00\r\n\
+             \x20   INDEX 01 00:00:02\r\n\
+             REM LEAD-OUT 01:30:00\r\n\
+             REM SESSION 02\r\n\
+             REM LEAD-IN 01:00:00\r\n\
+             FILE \"copy (Track 3).bin\" BINARY\r\n\
+             \x20 TRACK 03 MODE2/2352\r\n\
+             \x20   PREGAP 00:02:00\r\n\
+             \x20   INDEX 01 00:00:00\r\n\
+             REM LEAD-OUT 00:30:00\r\n\
+             REM SESSION 03\r\n\
+             REM LEAD-IN 00:20:00\r\n\
+             FILE \"copy (Track 4).bin\" BINARY\r\n\
+             \x20 TRACK 04 MODE1/2352\r\n\
+             \x20   PREGAP 00:00:00\r\n\
+             \x20   INDEX 01 00:00:00\r\n");
+
+    check_copy(&mut source, &mut Cue::new(&cue_path).unwrap());
+}
+
+#[test]
+fn strip_ecc() {
+    use std::fs;
+    use ecc::{self, Status};
+    use sector::SYNC_PATTERN;
+    use test_util::{temp_dir, write_file};
+    use cue::Cue;
+
+    let dir = temp_dir("cue-writer-strip-ecc");
+
+    // Track 01: 6 raw Mode 1 sectors with valid error correction
+    // codes, track 02: 3 audio sectors
+    let mut bin = Vec::new();
+
+    for s in 0..6 {
+        let mut sector = [0; 2352];
+        let (m, sec, f) = msf(150 + s).into_bcd();
+
+        sector[0..12].copy_from_slice(&SYNC_PATTERN);
+        sector[12..16].copy_from_slice(&[m.bcd(), sec.bcd(), f.bcd(), 1]);
+
+        for (i, b) in sector[16..2064].iter_mut().enumerate() {
+            *b = (i * 3 + s as usize * 5) as u8;
+        }
+
+        ecc::generate_mode1(&mut sector);
+
+        bin.extend_from_slice(&sector);
+    }
+
+    for i in 0..3 * 2352 {
+        bin.push((i % 249) as u8);
+    }
+
+    write_file(&dir, "source.bin", &bin);
+
+    let source_path = write_file(&dir, "source.cue",
+                                 b"FILE \"source.bin\" BINARY\n\
+                                   \x20 TRACK 01 MODE1/2352\n\
+                                   \x20   INDEX 01 00:00:00\n\
+                                   \x20 TRACK 02 AUDIO\n\
+                                   \x20   INDEX 01 00:00:06\n");
+
+    let mut source = Cue::new(&source_path).unwrap();
+
+    let cue_path = dir.join("stripped.cue");
+
+    let mut writer = CueWriter::new(&cue_path);
+
+    writer.set_strip_ecc(true);
+    writer.write(&mut source).unwrap();
+
+    // Only the user data of the Mode 1 sectors is stored
+    let track1 = fs::metadata(dir.join("stripped (Track 1).bin")).unwrap();
+
+    assert!(track1.len() == 6 * 2048);
+
+    let mut copy = Cue::new(&cue_path).unwrap();
+
+    assert!(copy.toc() == source.toc());
+
+    let mut a = Sector::empty();
+    let mut b = Sector::empty();
+
+    for pos in 150..159 {
+        source.read_sector(&mut a, msf(pos)).unwrap();
+        copy.read_sector(&mut b, msf(pos)).unwrap();
+
+        let raw = &bin[(pos - 150) as usize * 2352..][..2352];
+
+        // The sync pattern, header, EDC and ECC are regenerated
+        // identically
+        assert!(&b.data_2352().unwrap()[..] == raw);
+
+        if pos < 156 {
+            assert!(b.mode1_payload().unwrap()[..] ==
+                    a.mode1_payload().unwrap()[..]);
+            assert!(b.check_integrity().unwrap() == Status::Valid);
+        } else {
+            assert!(b.check_integrity().unwrap() == Status::Unprotected);
+        }
+    }
+}
+
+/// Make sure that `copy` has the same TOC as `source` and that every
+/// sector up to the lead-out of the last session is identical
+#[cfg(test)]
+fn check_copy<A: Image, B: Image>(source: &mut A, copy: &mut B) {
+    let toc = source.toc();
+
+    assert!(copy.toc() == toc);
+
+    let mut a = Sector::empty();
+    let mut b = Sector::empty();
+
+    for pos in 0..toc.lead_out().sector_index() {
+        let ra = source.read_sector(&mut a, msf(pos));
+        let rb = copy.read_sector(&mut b, msf(pos));
+
+        // The lead-out and lead-in between the sessions aren't
+        // stored
+        if ra.is_err() {
+            assert!(rb.is_err());
+            continue;
+        }
+
+        rb.unwrap();
+
+        let (ma, mb) = (a.metadata(), b.metadata());
+
+        assert!(ma.msf == mb.msf);
+        assert!(ma.track_msf == mb.track_msf);
+        assert!(ma.index == mb.index);
+        assert!(ma.track == mb.track);
+        assert!(ma.format == mb.format);
+        assert!(ma.session == mb.session);
+
+        assert!(&a.data_2352().unwrap()[..] == &b.data_2352().unwrap()[..]);
+        assert!(a.subchannel_interleaved().map(|s| s.to_vec()) ==
+                b.subchannel_interleaved().map(|s| s.to_vec()));
+    }
+}
+
+#[test]
+fn cdg_without_subchannel() {
+    use std::path::PathBuf;
+    use internal::{IndexCache, Index};
+    use toc::Toc;
+
+    /// CD+G image which doesn't provide any subchannel data
+    struct NoSubchannel(IndexCache<()>);
+
+    impl Image for NoSubchannel {
+        fn image_format(&self) -> String {
+            "Test".to_string()
+        }
+
+        fn read_sector(&mut self,
+                       sector: &mut Sector,
+                       msf: Msf) -> Result<(), CdError> {
+            let (_, metadata) = try!(self.0.locate(msf));
+
+            let mut builder = SectorBuilder::new(sector);
+
+            builder.set_blank(TrackFormat::CdG);
+            builder.set_metadata(metadata);
+
+            Ok(())
+        }
+
+        fn track_msf(&self,
+                     track: Bcd,
+                     track_msf: Msf) -> Result<Msf, CdError> {
+            self.0.track_msf(track, track_msf)
+        }
+
+        fn toc(&self) -> Toc {
+            self.0.toc()
+        }
+    }
+
+    let indices = vec![
+        Index::new(Bcd::zero(), msf(0), Bcd::one(), TrackFormat::CdG, 1, ()),
+        Index::new(Bcd::one(), msf(150), Bcd::one(), TrackFormat::CdG, 1, ()),
+    ];
+
+    let cache = IndexCache::new(PathBuf::new(), indices, msf(160)).unwrap();
+
+    let dir = ::test_util::temp_dir("cue-writer-cdg");
+
+    let res = CueWriter::new(&dir.join("cdg.cue"))
+        .write(&mut NoSubchannel(cache));
+
+    match res {
+        Err(CdError::BadFormat) => (),
+        _ => panic!("CD+G track written without subchannel data"),
+    }
+}
@@ -0,0 +1,652 @@
+//! CD-ROM error detection and correction codes.
+//!
+//! CD-ROM data sectors are protected by a 32bit error detection code
+//! (EDC) followed by two layers of Reed-Solomon product code called
+//! the P and Q parity (together they form the ECC). The ECC is
+//! computed over the GF(2^8) field generated by the polynomial x^8 +
+//! x^4 + x^3 + x^2 + 1.
+//!
+//! See sections 14.3 to 14.5 and annex A of [ECMA-130]
+//! (http://www.ecma-international.org/publications/files/ECMA-ST/Ecma-130.pdf)
+//! for more details.
+
+use crc::crc32;
+
+/// The various CD-ROM data sector layouts protected by error
+/// detection and/or correction codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataMode {
+    /// CD-ROM Mode 1: 2048 bytes of user data protected by EDC and
+    /// ECC
+    Mode1,
+    /// CD-ROM XA Mode 2 Form 1: 2048 bytes of user data protected by
+    /// EDC and ECC. The header is not covered by the ECC.
+    Mode2Form1,
+    /// CD-ROM XA Mode 2 Form 2: 2324 bytes of user data, the EDC is
+    /// optional and there's no ECC.
+    Mode2Form2,
+}
+
+impl DataMode {
+    /// Guess the layout of a raw sector by looking at the mode byte
+    /// in the header and, for Mode 2 sectors, at the form bit in the
+    /// subheader. Returns `None` if the sector isn't a Mode 1 or Mode
+    /// 2 sector.
+    pub fn from_sector(sector: &[u8; 2352]) -> Option<DataMode> {
+        match sector[15] {
+            1 => Some(DataMode::Mode1),
+            2 =>
+                if sector[18] & 0x20 != 0 {
+                    Some(DataMode::Mode2Form2)
+                } else {
+                    Some(DataMode::Mode2Form1)
+                },
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of a sector integrity check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The EDC and ECC (if present) match the sector data
+    Valid,
+    /// The sector doesn't have any error detection code to validate
+    /// its contents against (audio sectors or Mode 2 Form 2 sectors
+    /// with an empty EDC field)
+    Unprotected,
+    /// The EDC doesn't match the sector data, the sector is corrupted
+    BadEdc,
+    /// The EDC is valid but the ECC doesn't match, either the ECC
+    /// itself is corrupted or the EDC failed to catch an error.
+    BadEcc,
+}
+
+/// Generate the EDC and ECC (if any) of a sector in place
+pub fn generate(sector: &mut [u8; 2352], mode: DataMode) {
+    match mode {
+        DataMode::Mode1 => generate_mode1(sector),
+        DataMode::Mode2Form1 => generate_mode2_form1(sector),
+        DataMode::Mode2Form2 => generate_mode2_form2(sector),
+    }
+}
+
+/// Generate the EDC and ECC of a CD-ROM Mode 1 sector in
+/// place. `sector` must already contain the sync pattern, the
+/// header and the 2048 bytes of user data.
+pub fn generate_mode1(sector: &mut [u8; 2352]) {
+    let edc = crc32(&sector[0..2064]);
+
+    store_edc(&mut sector[2064..2068], edc);
+
+    // 8 bytes of "intermediate" field, always 0
+    for b in &mut sector[2068..2076] {
+        *b = 0;
+    }
+
+    generate_ecc(sector);
+}
+
+/// Generate the EDC and ECC of a CD-ROM XA Mode 2 Form 1 sector in
+/// place. `sector` must already contain the subheader and the 2048
+/// bytes of user data.
+pub fn generate_mode2_form1(sector: &mut [u8; 2352]) {
+    let edc = crc32(&sector[16..2072]);
+
+    store_edc(&mut sector[2072..2076], edc);
+
+    // The header is not protected by the ECC for Mode 2 sectors, it
+    // has to be set to zero during the computation
+    let header = *array_ref![sector, 12, 4];
+
+    for b in &mut sector[12..16] {
+        *b = 0;
+    }
+
+    generate_ecc(sector);
+
+    sector[12..16].copy_from_slice(&header);
+}
+
+/// Generate the optional EDC of a CD-ROM XA Mode 2 Form 2 sector in
+/// place. `sector` must already contain the subheader and the 2324
+/// bytes of user data.
+pub fn generate_mode2_form2(sector: &mut [u8; 2352]) {
+    let edc = crc32(&sector[16..2348]);
+
+    store_edc(&mut sector[2348..2352], edc);
+}
+
+/// Check the integrity of `sector` using its EDC and ECC
+pub fn check(sector: &[u8; 2352], mode: DataMode) -> Status {
+    if mode == DataMode::Mode2Form2 && load_edc(&sector[2348..2352]) == 0 {
+        // The EDC is optional for Form 2 sectors
+        return Status::Unprotected;
+    }
+
+    if !edc_valid(sector, mode) {
+        return Status::BadEdc;
+    }
+
+    if !ecc_valid(sector, mode) {
+        return Status::BadEcc;
+    }
+
+    Status::Valid
+}
+
+/// Return `true` if the EDC stored in `sector` matches its contents
+pub fn edc_valid(sector: &[u8; 2352], mode: DataMode) -> bool {
+    let (start, end) =
+        match mode {
+            DataMode::Mode1 => (0, 2064),
+            DataMode::Mode2Form1 => (16, 2072),
+            DataMode::Mode2Form2 => (16, 2348),
+        };
+
+    crc32(&sector[start..end]) == load_edc(&sector[end..end + 4])
+}
+
+/// Return `true` if the P and Q parity stored in `sector` match its
+/// contents. Always returns `true` for Mode 2 Form 2 sectors since
+/// they don't have any ECC.
+pub fn ecc_valid(sector: &[u8; 2352], mode: DataMode) -> bool {
+    let mut data = *sector;
+
+    match mode {
+        DataMode::Mode1 => (),
+        DataMode::Mode2Form1 => clear_header(&mut data),
+        DataMode::Mode2Form2 => return true,
+    }
+
+    ecc_clean(&data[12..])
+}
+
+/// Attempt to repair `sector` using its ECC. Each P and Q vector can
+/// correct a single erroneous byte, the vectors are processed
+/// alternately until all the errors are fixed which means that
+/// bursts of errors can sometimes be repaired.
+///
+/// On success returns the number of bytes which were corrected. If
+/// the sector can't be repaired (too many errors or the EDC still
+/// doesn't match after correction) `sector` is left untouched and
+/// `Err(())` is returned.
+pub fn correct(sector: &mut [u8; 2352], mode: DataMode) -> Result<u32, ()> {
+    if mode == DataMode::Mode2Form2 {
+        // Nothing we can do without ECC
+        return match check(sector, mode) {
+            Status::BadEdc => Err(()),
+            _ => Ok(0),
+        };
+    }
+
+    let mut data = *sector;
+
+    if mode == DataMode::Mode2Form1 {
+        clear_header(&mut data);
+    }
+
+    let corrected = try!(correct_ecc(&mut data[12..]));
+
+    if mode == DataMode::Mode2Form1 {
+        data[12..16].copy_from_slice(&sector[12..16]);
+    }
+
+    if !edc_valid(&data, mode) {
+        // We either failed to fix the sector or we "miscorrected" it
+        return Err(());
+    }
+
+    *sector = data;
+
+    Ok(corrected)
+}
+
+/// Number of P/Q correction passes attempted before giving up
+const ECC_PASSES: u32 = 4;
+
+/// Iterative P/Q single error correction on the 2340 bytes of sector
+/// data following the sync pattern.
+fn correct_ecc(data: &mut [u8]) -> Result<u32, ()> {
+    let mut corrected = 0;
+
+    for _ in 0..ECC_PASSES {
+        let mut clean = true;
+        let mut fixed = 0;
+
+        for parity in &[P_PARITY, Q_PARITY] {
+            for major in parity.vectors() {
+                match parity.correct_vector(data, major) {
+                    Vector::Clean => (),
+                    Vector::Fixed => {
+                        clean = false;
+                        fixed += 1;
+                    }
+                    Vector::Uncorrectable => clean = false,
+                }
+            }
+        }
+
+        if clean {
+            return Ok(corrected);
+        }
+
+        if fixed == 0 {
+            // We're not making any progress
+            return Err(());
+        }
+
+        corrected += fixed;
+    }
+
+    // Check if the last pass managed to fix everything
+    if ecc_clean(data) {
+        Ok(corrected)
+    } else {
+        Err(())
+    }
+}
+
+/// Return `true` if all the syndromes of all the P and Q vectors are
+/// zero
+fn ecc_clean(data: &[u8]) -> bool {
+    [P_PARITY, Q_PARITY].iter().all(|parity| {
+        parity.vectors().all(|major| parity.syndromes(data, major) == (0, 0))
+    })
+}
+
+/// Mode 2 sectors compute the ECC as if the header was zeroed
+fn clear_header(sector: &mut [u8; 2352]) {
+    for b in &mut sector[12..16] {
+        *b = 0;
+    }
+}
+
+/// The EDC is stored little-endian
+fn store_edc(dst: &mut [u8], edc: u32) {
+    dst[0] = edc as u8;
+    dst[1] = (edc >> 8) as u8;
+    dst[2] = (edc >> 16) as u8;
+    dst[3] = (edc >> 24) as u8;
+}
+
+fn load_edc(src: &[u8]) -> u32 {
+    (src[0] as u32) |
+    ((src[1] as u32) << 8) |
+    ((src[2] as u32) << 16) |
+    ((src[3] as u32) << 24)
+}
+
+/// Compute the P and Q parity bytes of `sector`. The P parity covers
+/// the header, the user data and the EDC. The Q parity covers the
+/// same data plus the P parity. The header is used as-is, it must be
+/// zeroed beforehand for Mode 2 sectors.
+pub fn generate_ecc(sector: &mut [u8; 2352]) {
+    let p = P_PARITY.generate(&sector[12..]);
+
+    sector[2076..2248].copy_from_slice(&p);
+
+    let q = Q_PARITY.generate(&sector[12..]);
+
+    sector[2248..2352].copy_from_slice(&q);
+}
+
+/// Geometry of the Reed-Solomon P or Q vectors. Offsets are relative
+/// to the end of the sync pattern.
+///
+/// The data is seen as 1170 16bit words, the MSB and LSB of each word
+/// are protected by different vectors.
+struct Parity {
+    /// Number of vectors
+    major_count: usize,
+    /// Number of data bytes in each vector
+    minor_count: usize,
+    /// Offset between the start of each pair of vectors
+    major_mult: usize,
+    /// Offset between two consecutive bytes in a vector
+    minor_inc: usize,
+}
+
+/// P parity: 86 vectors of 24 data bytes. The parity bytes are stored
+/// right after the data.
+const P_PARITY: Parity = Parity {
+    major_count: 86,
+    minor_count: 24,
+    major_mult: 2,
+    minor_inc: 86,
+};
+
+/// Q parity: 52 vectors of 43 data bytes, covering the P parity
+/// bytes.
+const Q_PARITY: Parity = Parity {
+    major_count: 52,
+    minor_count: 43,
+    major_mult: 86,
+    minor_inc: 88,
+};
+
+/// State of a P or Q vector
+enum Vector {
+    /// The vector's syndromes are zero
+    Clean,
+    /// A single byte error was fixed
+    Fixed,
+    /// The vector contains more than one error
+    Uncorrectable,
+}
+
+impl Parity {
+    /// Size of the data covered by the parity
+    fn size(&self) -> usize {
+        self.major_count * self.minor_count
+    }
+
+    /// Iterate over all the vector numbers
+    fn vectors(&self) -> ::std::ops::Range<usize> {
+        0..self.major_count
+    }
+
+    /// Return the offset of the `minor`th byte of vector
+    /// `major`. The two parity bytes are at `minor_count` and
+    /// `minor_count + 1`.
+    fn position(&self, major: usize, minor: usize) -> usize {
+        if minor < self.minor_count {
+            let start = (major >> 1) * self.major_mult + (major & 1);
+
+            (start + minor * self.minor_inc) % self.size()
+        } else {
+            let parity = minor - self.minor_count;
+
+            self.size() + parity * self.major_count + major
+        }
+    }
+
+    /// Compute the parity bytes for all the vectors. Returns `2 *
+    /// major_count` parity bytes.
+    fn generate(&self, data: &[u8]) -> Vec<u8> {
+        let mut parity = vec![0; self.major_count * 2];
+
+        for major in self.vectors() {
+            let mut ecc_a = 0;
+            let mut ecc_b = 0;
+
+            for minor in 0..self.minor_count {
+                let b = data[self.position(major, minor)];
+
+                ecc_a ^= b;
+                ecc_b ^= b;
+                ecc_a = gf_mul2(ecc_a);
+            }
+
+            ecc_a = ECC_B_TABLE[(gf_mul2(ecc_a) ^ ecc_b) as usize];
+
+            parity[major] = ecc_a;
+            parity[major + self.major_count] = ecc_a ^ ecc_b;
+        }
+
+        parity
+    }
+
+    /// Compute the two syndromes of vector `major`:
+    ///
+    /// * S0 is the sum of all the bytes in the vector
+    /// * S1 is the sum of all the bytes weighted by `alpha^(n - 1 -
+    ///   i)` where `i` is the position of the byte in the vector and
+    ///   `n` the length of the vector (including the parity)
+    ///
+    /// Both are zero if the vector is error-free.
+    fn syndromes(&self, data: &[u8], major: usize) -> (u8, u8) {
+        let mut s0 = 0;
+        let mut s1 = 0;
+
+        for minor in 0..self.minor_count + 2 {
+            let b = data[self.position(major, minor)];
+
+            s0 ^= b;
+            s1 = gf_mul2(s1) ^ b;
+        }
+
+        (s0, s1)
+    }
+
+    /// Attempt to fix a single byte error in vector `major`
+    fn correct_vector(&self, data: &mut [u8], major: usize) -> Vector {
+        let (s0, s1) = self.syndromes(data, major);
+
+        if s0 == 0 && s1 == 0 {
+            return Vector::Clean;
+        }
+
+        if s0 == 0 || s1 == 0 {
+            // Can't be a single byte error
+            return Vector::Uncorrectable;
+        }
+
+        let len = self.minor_count + 2;
+
+        // For an error `e` at position `i` we have S0 = e and S1 = e *
+        // alpha^(len - 1 - i).
+        let log_s0 = GF_LOG[s0 as usize] as usize;
+        let log_s1 = GF_LOG[s1 as usize] as usize;
+
+        let weight = (log_s1 + 255 - log_s0) % 255;
+
+        if weight >= len {
+            return Vector::Uncorrectable;
+        }
+
+        let pos = self.position(major, len - 1 - weight);
+
+        data[pos] ^= s0;
+
+        Vector::Fixed
+    }
+}
+
+/// Multiply `v` by alpha (i.e. 2) in GF(2^8)
+fn gf_mul2(v: u8) -> u8 {
+    let carry = v & 0x80 != 0;
+
+    let v = v << 1;
+
+    if carry {
+        v ^ 0x1d
+    } else {
+        v
+    }
+}
+
+/// Inverse of the multiplication by (alpha + 1) in GF(2^8): if `b =
+/// a ^ gf_mul2(a)` then `ECC_B_TABLE[b] = a`.
+const ECC_B_TABLE: [u8; 0x100] = [
+    0x00, 0xf4, 0xf5, 0x01, 0xf7, 0x03, 0x02, 0xf6, 0xf3, 0x07, 0x06, 0xf2,
+    0x04, 0xf0, 0xf1, 0x05, 0xfb, 0x0f, 0x0e, 0xfa, 0x0c, 0xf8, 0xf9, 0x0d,
+    0x08, 0xfc, 0xfd, 0x09, 0xff, 0x0b, 0x0a, 0xfe, 0xeb, 0x1f, 0x1e, 0xea,
+    0x1c, 0xe8, 0xe9, 0x1d, 0x18, 0xec, 0xed, 0x19, 0xef, 0x1b, 0x1a, 0xee,
+    0x10, 0xe4, 0xe5, 0x11, 0xe7, 0x13, 0x12, 0xe6, 0xe3, 0x17, 0x16, 0xe2,
+    0x14, 0xe0, 0xe1, 0x15, 0xcb, 0x3f, 0x3e, 0xca, 0x3c, 0xc8, 0xc9, 0x3d,
+    0x38, 0xcc, 0xcd, 0x39, 0xcf, 0x3b, 0x3a, 0xce, 0x30, 0xc4, 0xc5, 0x31,
+    0xc7, 0x33, 0x32, 0xc6, 0xc3, 0x37, 0x36, 0xc2, 0x34, 0xc0, 0xc1, 0x35,
+    0x20, 0xd4, 0xd5, 0x21, 0xd7, 0x23, 0x22, 0xd6, 0xd3, 0x27, 0x26, 0xd2,
+    0x24, 0xd0, 0xd1, 0x25, 0xdb, 0x2f, 0x2e, 0xda, 0x2c, 0xd8, 0xd9, 0x2d,
+    0x28, 0xdc, 0xdd, 0x29, 0xdf, 0x2b, 0x2a, 0xde, 0x8b, 0x7f, 0x7e, 0x8a,
+    0x7c, 0x88, 0x89, 0x7d, 0x78, 0x8c, 0x8d, 0x79, 0x8f, 0x7b, 0x7a, 0x8e,
+    0x70, 0x84, 0x85, 0x71, 0x87, 0x73, 0x72, 0x86, 0x83, 0x77, 0x76, 0x82,
+    0x74, 0x80, 0x81, 0x75, 0x60, 0x94, 0x95, 0x61, 0x97, 0x63, 0x62, 0x96,
+    0x93, 0x67, 0x66, 0x92, 0x64, 0x90, 0x91, 0x65, 0x9b, 0x6f, 0x6e, 0x9a,
+    0x6c, 0x98, 0x99, 0x6d, 0x68, 0x9c, 0x9d, 0x69, 0x9f, 0x6b, 0x6a, 0x9e,
+    0x40, 0xb4, 0xb5, 0x41, 0xb7, 0x43, 0x42, 0xb6, 0xb3, 0x47, 0x46, 0xb2,
+    0x44, 0xb0, 0xb1, 0x45, 0xbb, 0x4f, 0x4e, 0xba, 0x4c, 0xb8, 0xb9, 0x4d,
+    0x48, 0xbc, 0xbd, 0x49, 0xbf, 0x4b, 0x4a, 0xbe, 0xab, 0x5f, 0x5e, 0xaa,
+    0x5c, 0xa8, 0xa9, 0x5d, 0x58, 0xac, 0xad, 0x59, 0xaf, 0x5b, 0x5a, 0xae,
+    0x50, 0xa4, 0xa5, 0x51, 0xa7, 0x53, 0x52, 0xa6, 0xa3, 0x57, 0x56, 0xa2,
+    0x54, 0xa0, 0xa1, 0x55
+    ];
+
+/// Discrete logarithm in GF(2^8): `GF_LOG[alpha^i] = i`. `GF_LOG[0]` is
+/// undefined and set to 0.
+const GF_LOG: [u8; 0x100] = [
+    0x00, 0x00, 0x01, 0x19, 0x02, 0x32, 0x1a, 0xc6, 0x03, 0xdf, 0x33, 0xee,
+    0x1b, 0x68, 0xc7, 0x4b, 0x04, 0x64, 0xe0, 0x0e, 0x34, 0x8d, 0xef, 0x81,
+    0x1c, 0xc1, 0x69, 0xf8, 0xc8, 0x08, 0x4c, 0x71, 0x05, 0x8a, 0x65, 0x2f,
+    0xe1, 0x24, 0x0f, 0x21, 0x35, 0x93, 0x8e, 0xda, 0xf0, 0x12, 0x82, 0x45,
+    0x1d, 0xb5, 0xc2, 0x7d, 0x6a, 0x27, 0xf9, 0xb9, 0xc9, 0x9a, 0x09, 0x78,
+    0x4d, 0xe4, 0x72, 0xa6, 0x06, 0xbf, 0x8b, 0x62, 0x66, 0xdd, 0x30, 0xfd,
+    0xe2, 0x98, 0x25, 0xb3, 0x10, 0x91, 0x22, 0x88, 0x36, 0xd0, 0x94, 0xce,
+    0x8f, 0x96, 0xdb, 0xbd, 0xf1, 0xd2, 0x13, 0x5c, 0x83, 0x38, 0x46, 0x40,
+    0x1e, 0x42, 0xb6, 0xa3, 0xc3, 0x48, 0x7e, 0x6e, 0x6b, 0x3a, 0x28, 0x54,
+    0xfa, 0x85, 0xba, 0x3d, 0xca, 0x5e, 0x9b, 0x9f, 0x0a, 0x15, 0x79, 0x2b,
+    0x4e, 0xd4, 0xe5, 0xac, 0x73, 0xf3, 0xa7, 0x57, 0x07, 0x70, 0xc0, 0xf7,
+    0x8c, 0x80, 0x63, 0x0d, 0x67, 0x4a, 0xde, 0xed, 0x31, 0xc5, 0xfe, 0x18,
+    0xe3, 0xa5, 0x99, 0x77, 0x26, 0xb8, 0xb4, 0x7c, 0x11, 0x44, 0x92, 0xd9,
+    0x23, 0x20, 0x89, 0x2e, 0x37, 0x3f, 0xd1, 0x5b, 0x95, 0xbc, 0xcf, 0xcd,
+    0x90, 0x87, 0x97, 0xb2, 0xdc, 0xfc, 0xbe, 0x61, 0xf2, 0x56, 0xd3, 0xab,
+    0x14, 0x2a, 0x5d, 0x9e, 0x84, 0x3c, 0x39, 0x53, 0x47, 0x6d, 0x41, 0xa2,
+    0x1f, 0x2d, 0x43, 0xd8, 0xb7, 0x7b, 0xa4, 0x76, 0xc4, 0x17, 0x49, 0xec,
+    0x7f, 0x0c, 0x6f, 0xf6, 0x6c, 0xa1, 0x3b, 0x52, 0x29, 0x9d, 0x55, 0xaa,
+    0xfb, 0x60, 0x86, 0xb1, 0xbb, 0xcc, 0x3e, 0x5a, 0xcb, 0x59, 0x5f, 0xb0,
+    0x9c, 0xa9, 0xa0, 0x51, 0x0b, 0xf5, 0x16, 0xeb, 0x7a, 0x75, 0x2c, 0xd7,
+    0x4f, 0xae, 0xd5, 0xe9, 0xe6, 0xe7, 0xad, 0xe8, 0x74, 0xd6, 0xf4, 0xea,
+    0xa8, 0x50, 0x58, 0xaf
+    ];
+
+/// Test that the ECC_B table has been correctly generated
+#[test]
+fn validate_ecc_b_table() {
+    for i in 0..0x100u32 {
+        let a = i as u8;
+
+        assert!(ECC_B_TABLE[(a ^ gf_mul2(a)) as usize] == a);
+    }
+}
+
+/// Test that the GF_LOG table has been correctly generated
+#[test]
+fn validate_gf_log_table() {
+    let mut v = 1;
+
+    for i in 0..255 {
+        assert!(GF_LOG[v as usize] as usize == i);
+
+        v = gf_mul2(v);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DataMode, Status, generate, check, correct};
+
+    /// Build a sector with some non-trivial payload
+    fn build_sector(mode: DataMode) -> [u8; 2352] {
+        let mut sector = [0; 2352];
+
+        sector[1..11].copy_from_slice(&[0xff; 10]);
+        // 00:02:17
+        sector[12] = 0x00;
+        sector[13] = 0x02;
+        sector[14] = 0x17;
+
+        match mode {
+            DataMode::Mode1 => sector[15] = 1,
+            DataMode::Mode2Form1 => sector[15] = 2,
+            DataMode::Mode2Form2 => {
+                sector[15] = 2;
+                sector[18] = 0x20;
+                sector[22] = 0x20;
+            }
+        }
+
+        for i in 24..2072 {
+            sector[i] = (i * 7 + (i >> 8)) as u8;
+        }
+
+        generate(&mut sector, mode);
+
+        sector
+    }
+
+    #[test]
+    fn check_valid() {
+        for &mode in &[DataMode::Mode1,
+                       DataMode::Mode2Form1,
+                       DataMode::Mode2Form2] {
+            let sector = build_sector(mode);
+
+            assert!(DataMode::from_sector(&sector) == Some(mode));
+            assert!(check(&sector, mode) == Status::Valid);
+        }
+    }
+
+    #[test]
+    fn check_corrupted() {
+        let mut sector = build_sector(DataMode::Mode1);
+
+        sector[100] ^= 0x01;
+
+        assert!(check(&sector, DataMode::Mode1) == Status::BadEdc);
+
+        let mut sector = build_sector(DataMode::Mode1);
+
+        // Corrupt the Q parity
+        sector[2300] ^= 0x80;
+
+        assert!(check(&sector, DataMode::Mode1) == Status::BadEcc);
+
+        let mut sector = build_sector(DataMode::Mode2Form2);
+
+        for b in &mut sector[2348..2352] {
+            *b = 0;
+        }
+
+        assert!(check(&sector, DataMode::Mode2Form2) == Status::Unprotected);
+    }
+
+    #[test]
+    fn correct_errors() {
+        for &mode in &[DataMode::Mode1, DataMode::Mode2Form1] {
+            let reference = build_sector(mode);
+
+            let mut sector = reference;
+
+            // Single error
+            sector[500] ^= 0x42;
+
+            assert!(correct(&mut sector, mode) == Ok(1));
+            assert!(&sector[..] == &reference[..]);
+
+            // Burst of errors, too many for a single P vector
+            for b in &mut sector[1000..1010] {
+                *b = !*b;
+            }
+            // Error in the P parity
+            sector[2100] ^= 0xff;
+
+            assert!(correct(&mut sector, mode) == Ok(11));
+            assert!(&sector[..] == &reference[..]);
+
+            // Mode 2 headers are not covered by the ECC
+            if mode == DataMode::Mode2Form1 {
+                sector[13] = 0x99;
+
+                assert!(correct(&mut sector, mode) == Ok(0));
+                assert!(sector[13] == 0x99);
+            }
+        }
+    }
+
+    #[test]
+    fn correct_too_many_errors() {
+        let reference = build_sector(DataMode::Mode1);
+
+        let mut sector = reference;
+
+        for b in &mut sector[16..1016] {
+            *b = !*b;
+        }
+
+        let corrupted = sector;
+
+        assert!(correct(&mut sector, DataMode::Mode1) == Err(()));
+        assert!(&sector[..] == &corrupted[..]);
+    }
+}
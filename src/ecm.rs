@@ -0,0 +1,588 @@
+//! ECM (Error Code Modeler) decoder
+//!
+//! ECM is a simple compression scheme for CD images created by Neill
+//! Corlett: the sync pattern, EDC and ECC of the data sectors are
+//! stripped since they can be regenerated from the rest of the
+//! sector. It's usually applied to a BIN file, `game.bin` becoming
+//! `game.bin.ecm`.
+//!
+//! The file starts with the "ECM\0" magic, followed by a list of
+//! records. Each record starts with a variable-length header giving
+//! its type and a count, followed by the encoded data:
+//!
+//! * Type 0: `count` bytes stored as-is.
+//! * Type 1: `count` Mode 1 sectors (2352 bytes), only the 3 address
+//!   bytes and the 2048 bytes of user data are stored.
+//! * Type 2: `count` Mode 2 Form 1 sectors without their sync
+//!   pattern and header (2336 bytes), only the subheader (4 bytes
+//!   since it's stored twice) and the 2048 bytes of user data are
+//!   stored.
+//! * Type 3: `count` Mode 2 Form 2 sectors without their sync
+//!   pattern and header (2336 bytes), only the subheader and the 2324
+//!   bytes of user data are stored.
+//!
+//! The list ends with a record whose count is 0xffffffff followed by
+//! the EDC of the whole decoded file.
+//!
+//! Since each record has a fixed decoded size, `Ecm` only needs to go
+//! through the record headers once to be able to locate any byte of
+//! the decoded file.
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, BufRead, BufReader};
+use std::cmp;
+
+use CdError;
+
+use ecc;
+use sector::SYNC_PATTERN;
+
+/// Random access reader for an ECM file
+#[derive(Debug)]
+pub struct Ecm {
+    /// ECM file
+    file: File,
+    /// List of all the records in the file, in order
+    records: Vec<Record>,
+    /// Index in `records` of the record containing the first byte of
+    /// each block of `BLOCK_SIZE` bytes of the decoded file
+    blocks: Vec<u32>,
+    /// Size of the decoded file
+    len: u64,
+    /// Last decoded sector, 2352 bytes
+    sector: Vec<u8>,
+    /// Record and sector number within the record of `sector`, if
+    /// it's valid
+    cached: Option<(usize, u32)>,
+}
+
+impl Ecm {
+    /// Open the ECM file at `path` and index its records
+    pub fn new(path: &Path) -> Result<Ecm, CdError> {
+        let file =
+            match File::open(path) {
+                Ok(f) => f,
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        let records = try!(parse_records(&mut BufReader::new(&file),
+                                         path));
+
+        let len =
+            match records.last() {
+                Some(r) => r.end(),
+                None => 0,
+            };
+
+        let nblocks = (len + BLOCK_SIZE - 1) / BLOCK_SIZE;
+
+        let mut blocks = Vec::with_capacity(nblocks as usize);
+
+        for (i, record) in records.iter().enumerate() {
+            // Add all the blocks starting in this record
+            while (blocks.len() as u64) * BLOCK_SIZE < record.end() {
+                blocks.push(i as u32);
+            }
+        }
+
+        Ok(Ecm {
+            file: file,
+            records: records,
+            blocks: blocks,
+            len: len,
+            sector: vec![0; 2352],
+            cached: None,
+        })
+    }
+
+    /// Return the size of the decoded file in bytes
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// Fill `buf` with the decoded data located at `offset`. Only the
+    /// sectors overlapping `buf` are decoded.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset + buf.len() as u64 > self.len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "Attempted to read past the end \
+                                       of the ECM file"));
+        }
+
+        let mut offset = offset;
+        let mut pos = 0;
+
+        while pos < buf.len() {
+            let mut r = self.blocks[(offset / BLOCK_SIZE) as usize] as usize;
+
+            // There can be several records in a single block
+            while self.records[r].end() <= offset {
+                r += 1;
+            }
+
+            let record = self.records[r];
+
+            let len = cmp::min((buf.len() - pos) as u64,
+                               record.end() - offset) as usize;
+
+            let record_offset = offset - record.offset;
+
+            let len =
+                if record.kind == RecordType::Raw {
+                    let input = record.input + record_offset;
+
+                    try!(self.file.seek(SeekFrom::Start(input)));
+                    try!(self.file.read_exact(&mut buf[pos..pos + len]));
+
+                    len
+                } else {
+                    let decoded = record.kind.sizes().1;
+
+                    let sector = (record_offset / decoded) as u32;
+                    let within = (record_offset % decoded) as usize;
+
+                    try!(self.load(r, sector));
+
+                    // Mode 2 sectors are stored without their sync
+                    // pattern and header
+                    let start = 2352 - decoded as usize + within;
+
+                    let len = cmp::min(len, decoded as usize - within);
+
+                    buf[pos..pos + len]
+                        .copy_from_slice(&self.sector[start..start + len]);
+
+                    len
+                };
+
+            offset += len as u64;
+            pos += len;
+        }
+
+        Ok(())
+    }
+
+    /// Decode the sector number `sector` of the record number `record`
+    /// into `self.sector`
+    fn load(&mut self, record: usize, sector: u32) -> io::Result<()> {
+        if self.cached == Some((record, sector)) {
+            return Ok(());
+        }
+
+        self.cached = None;
+
+        let kind = self.records[record].kind;
+
+        let input = self.records[record].input +
+            sector as u64 * kind.sizes().0;
+
+        try!(self.file.seek(SeekFrom::Start(input)));
+        try!(decode_sector(&mut self.file,
+                           kind,
+                           array_mut_ref![self.sector, 0, 2352]));
+
+        self.cached = Some((record, sector));
+
+        Ok(())
+    }
+}
+
+/// A single ECM record
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    /// Type of the encoded data
+    kind: RecordType,
+    /// Number of bytes or sectors in the record
+    count: u32,
+    /// Offset of the record's first byte in the decoded file
+    offset: u64,
+    /// Offset of the record's data in the ECM file
+    input: u64,
+}
+
+impl Record {
+    /// Offset of the end of the record in the decoded file
+    fn end(&self) -> u64 {
+        self.offset + self.count as u64 * self.kind.sizes().1
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordType {
+    /// Bytes stored as-is
+    Raw,
+    /// Mode 1 sectors
+    Mode1,
+    /// Headerless Mode 2 Form 1 sectors
+    Mode2Form1,
+    /// Headerless Mode 2 Form 2 sectors
+    Mode2Form2,
+}
+
+impl RecordType {
+    /// Return the encoded and decoded size of a single element of
+    /// the record
+    fn sizes(self) -> (u64, u64) {
+        match self {
+            RecordType::Raw => (1, 1),
+            RecordType::Mode1 => (3 + 2048, 2352),
+            RecordType::Mode2Form1 => (4 + 2048, 2336),
+            RecordType::Mode2Form2 => (4 + 2324, 2336),
+        }
+    }
+}
+
+/// Parse all the record headers of the ECM file read by `reader`,
+/// skipping over their data. `path` is only used for error reporting.
+fn parse_records<R: BufRead + Seek>(reader: &mut R,
+                                    path: &Path)
+                                    -> Result<Vec<Record>, CdError> {
+    let corrupted = |error: &str| {
+        CdError::BadImage(PathBuf::from(path), error.to_string())
+    };
+
+    let io_error = |e: io::Error| {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => corrupted("Truncated ECM file"),
+            _ => CdError::IoError(e),
+        }
+    };
+
+    let mut magic = [0; 4];
+
+    if let Err(e) = reader.read_exact(&mut magic) {
+        return Err(io_error(e));
+    }
+
+    if &magic != b"ECM\0" {
+        return Err(corrupted("Missing ECM magic"));
+    }
+
+    let mut records = Vec::new();
+
+    // Position in the ECM and decoded files
+    let mut input = 4;
+    let mut offset = 0;
+
+    loop {
+        let mut b = [0; 1];
+
+        if let Err(e) = reader.read_exact(&mut b) {
+            return Err(io_error(e));
+        }
+
+        input += 1;
+
+        let kind =
+            match b[0] & 3 {
+                0 => RecordType::Raw,
+                1 => RecordType::Mode1,
+                2 => RecordType::Mode2Form1,
+                _ => RecordType::Mode2Form2,
+            };
+
+        // The count is stored 5 bits in the first byte then 7 bits
+        // per byte, the MSB is set if more bytes follow
+        let mut count = ((b[0] >> 2) & 0x1f) as u64;
+        let mut shift = 5;
+
+        while b[0] & 0x80 != 0 {
+            if shift > 32 {
+                return Err(corrupted("Invalid ECM record count"));
+            }
+
+            if let Err(e) = reader.read_exact(&mut b) {
+                return Err(io_error(e));
+            }
+
+            input += 1;
+
+            count |= ((b[0] & 0x7f) as u64) << shift;
+            shift += 7;
+        }
+
+        if count == 0xffffffff {
+            // End of the records, only the EDC remains
+            break;
+        }
+
+        let count = count + 1;
+
+        if count >= 0x80000000 {
+            return Err(corrupted("Invalid ECM record count"));
+        }
+
+        let record = Record {
+            kind: kind,
+            count: count as u32,
+            offset: offset,
+            input: input,
+        };
+
+        let len = count * kind.sizes().0;
+
+        // Skip the record's data
+        let buffered =
+            match reader.fill_buf() {
+                Ok(b) => b.len() as u64,
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        if len <= buffered {
+            reader.consume(len as usize);
+        } else if let Err(e) = reader.seek(SeekFrom::Current(len as i64)) {
+            return Err(CdError::IoError(e));
+        }
+
+        input += len;
+        offset = record.end();
+
+        records.push(record);
+    }
+
+    // Make sure the data of the last record is actually there
+    let mut edc = [0; 4];
+
+    if let Err(e) = reader.read_exact(&mut edc) {
+        return Err(io_error(e));
+    }
+
+    Ok(records)
+}
+
+/// Read a single encoded sector of type `kind` from `reader` and
+/// rebuild the full 2352 byte sector in `sector`. For Mode 2 the
+/// sync pattern and header are left blank.
+fn decode_sector<R: Read>(reader: &mut R,
+                          kind: RecordType,
+                          sector: &mut [u8; 2352]) -> io::Result<()> {
+    for b in sector.iter_mut() {
+        *b = 0;
+    }
+
+    match kind {
+        RecordType::Raw => unreachable!(),
+        RecordType::Mode1 => {
+            sector[0..12].copy_from_slice(&SYNC_PATTERN);
+            sector[15] = 1;
+
+            try!(reader.read_exact(&mut sector[12..15]));
+            try!(reader.read_exact(&mut sector[16..2064]));
+
+            ecc::generate_mode1(sector);
+        }
+        RecordType::Mode2Form1 => {
+            try!(reader.read_exact(&mut sector[20..2072]));
+
+            let subheader = *array_ref![sector, 20, 4];
+            sector[16..20].copy_from_slice(&subheader);
+
+            ecc::generate_mode2_form1(sector);
+        }
+        RecordType::Mode2Form2 => {
+            try!(reader.read_exact(&mut sector[20..2348]));
+
+            let subheader = *array_ref![sector, 20, 4];
+            sector[16..20].copy_from_slice(&subheader);
+
+            ecc::generate_mode2_form2(sector);
+        }
+    }
+
+    Ok(())
+}
+
+/// Granularity of the index used to locate the records, one sector
+const BLOCK_SIZE: u64 = 2352;
+
+/// Append the header of a record of type `kind` (0 to 3) containing
+/// `count` elements to `ecm`
+#[cfg(test)]
+pub fn push_record_header(ecm: &mut Vec<u8>, kind: u8, count: u32) {
+    let count = count - 1;
+
+    let mut b = kind | ((count & 0x1f) << 2) as u8;
+    let mut count = count >> 5;
+
+    while count != 0 {
+        ecm.push(b | 0x80);
+
+        b = (count & 0x7f) as u8;
+        count >>= 7;
+    }
+
+    ecm.push(b);
+}
+
+#[test]
+fn record_index() {
+    use std::io::Cursor;
+
+    let mut ecm = b"ECM\0".to_vec();
+
+    // 3 raw bytes
+    ecm.push(0x08);
+    ecm.extend_from_slice(&[1, 2, 3]);
+    // 2 Mode 1 sectors
+    ecm.push(0x05);
+    ecm.extend_from_slice(&[0; 2 * 2051]);
+    // 200 raw bytes, the count needs two bytes: 199 = 7 + (6 << 5)
+    ecm.push(0x80 | (7 << 2));
+    ecm.push(6);
+    ecm.extend_from_slice(&[0; 200]);
+    // End marker and EDC
+    ecm.extend_from_slice(&[0xfc, 0xff, 0xff, 0xff, 0x3f]);
+    ecm.extend_from_slice(&[0; 4]);
+
+    let records = parse_records(&mut Cursor::new(&ecm),
+                                Path::new("test.ecm")).unwrap();
+
+    assert!(records.len() == 3);
+
+    assert!(records[0].kind == RecordType::Raw);
+    assert!(records[0].input == 5);
+    assert!(records[0].end() == 3);
+
+    assert!(records[1].kind == RecordType::Mode1);
+    assert!(records[1].count == 2);
+    assert!(records[1].input == 9);
+    assert!(records[1].end() == 3 + 2 * 2352);
+
+    assert!(records[2].count == 200);
+    assert!(records[2].input == 9 + 2 * 2051 + 2);
+
+    // Truncated file
+    let len = ecm.len();
+
+    assert!(parse_records(&mut Cursor::new(&ecm[..len - 10]),
+                          Path::new("test.ecm")).is_err());
+}
+
+#[test]
+fn decode_mode2() {
+    use std::io::Cursor;
+
+    let mut encoded = vec![0; 4 + 2048];
+
+    // Form 1 data subheader
+    encoded[2] = 0x08;
+    encoded[4] = 0x42;
+
+    let mut sector = [0; 2352];
+
+    decode_sector(&mut Cursor::new(&encoded),
+                  RecordType::Mode2Form1,
+                  &mut sector).unwrap();
+
+    assert!(sector[16..24] == [0, 0, 8, 0, 0, 0, 8, 0]);
+    assert!(sector[24] == 0x42);
+    assert!(ecc::edc_valid(&sector, ecc::DataMode::Mode2Form1));
+    assert!(ecc::ecc_valid(&sector, ecc::DataMode::Mode2Form1));
+}
+
+#[test]
+fn read_across_records() {
+    use test_util::{temp_dir, write_file};
+
+    let mut ecm = b"ECM\0".to_vec();
+    let mut decoded = Vec::new();
+
+    // 100 raw bytes
+    push_record_header(&mut ecm, 0, 100);
+
+    for i in 0..100 {
+        ecm.push(i as u8);
+        decoded.push(i as u8);
+    }
+
+    // 2 Mode 1 sectors at LBA 0 and 1
+    push_record_header(&mut ecm, 1, 2);
+
+    for s in 0..2 {
+        let mut sector = [0; 2352];
+
+        sector[0..12].copy_from_slice(&SYNC_PATTERN);
+        sector[12..16].copy_from_slice(&[0x00, 0x02, s, 0x01]);
+
+        for (i, b) in sector[16..2064].iter_mut().enumerate() {
+            *b = (i * 3 + s as usize) as u8;
+        }
+
+        ecc::generate_mode1(&mut sector);
+
+        ecm.extend_from_slice(&sector[12..15]);
+        ecm.extend_from_slice(&sector[16..2064]);
+        decoded.extend_from_slice(&sector);
+    }
+
+    // 2 headerless Mode 2 Form 2 sectors
+    push_record_header(&mut ecm, 3, 2);
+
+    for s in 0..2 {
+        let mut sector = [0; 2352];
+
+        sector[16..24].copy_from_slice(&[1, s, 0x64, 0, 1, s, 0x64, 0]);
+
+        for (i, b) in sector[24..2348].iter_mut().enumerate() {
+            *b = (i * 5 + s as usize) as u8;
+        }
+
+        ecc::generate_mode2_form2(&mut sector);
+
+        ecm.extend_from_slice(&sector[20..2348]);
+        decoded.extend_from_slice(&sector[16..]);
+    }
+
+    // End marker and EDC (not checked)
+    ecm.extend_from_slice(&[0xfc, 0xff, 0xff, 0xff, 0x3f]);
+    ecm.extend_from_slice(&[0; 4]);
+
+    let dir = temp_dir("ecm-records");
+    let path = write_file(&dir, "test.bin.ecm", &ecm);
+
+    let mut ecm = Ecm::new(&path).unwrap();
+
+    assert!(ecm.size() == decoded.len() as u64);
+
+    let mode1 = 100;
+    let mode2 = mode1 + 2 * 2352;
+
+    // (offset, length)
+    let reads = [
+        // Whole file
+        (0, decoded.len()),
+        // Raw bytes into the first Mode 1 sector
+        (90, 30),
+        // Within the first Mode 1 sector, its EDC and ECC
+        (mode1 + 2064, 288),
+        // From the first Mode 1 sector to the second
+        (mode1 + 2300, 100),
+        // Mode 1 into Mode 2 Form 2
+        (mode2 - 10, 20),
+        // From the raw bytes to the last Mode 2 sector
+        (50, mode2 + 2336 + 10 - 50),
+        // Last byte of the file
+        (decoded.len() - 1, 1)];
+
+    for &(offset, len) in reads.iter() {
+        let mut buf = vec![0; len];
+
+        ecm.read_at(offset as u64, &mut buf).unwrap();
+
+        assert!(&buf[..] == &decoded[offset..offset + len]);
+    }
+
+    // Going backwards with the sector cache
+    let mut buf = [0; 4];
+
+    ecm.read_at(mode1 as u64 + 12, &mut buf).unwrap();
+
+    assert!(buf == [0x00, 0x02, 0x00, 0x01]);
+
+    // Reading past the end
+    let mut buf = [0; 2];
+
+    assert!(ecm.read_at(decoded.len() as u64 - 1, &mut buf).is_err());
+}
@@ -9,8 +9,12 @@ use std::path::PathBuf;
 
 use bcd::Bcd;
 use msf::Msf;
+use toc::{Toc, Session, Track, TrackIndex, Control};
+use sector::Metadata;
+use subchannel::{SubChannelQ, QData};
 
 use TrackFormat;
+use SessionFormat;
 use CdError;
 
 /// A generic CD index implementation. Each image format can
@@ -27,12 +31,16 @@ pub struct Index<T> {
     format: TrackFormat,
     /// Session number this index belongs to
     session: u8,
+    /// Control flags of the track this index belongs to
+    control: Control,
     /// Generic private data associated with this index
     private: T,
 }
 
 impl<T> Index<T> {
-    /// Create a new index
+    /// Create a new index. The control flags are set to the
+    /// default value for `format`, use `set_control` to override
+    /// them.
     pub fn new(index: Bcd,
                start: Msf,
                track: Bcd,
@@ -45,6 +53,7 @@ impl<T> Index<T> {
             track: track,
             format: format,
             session: session,
+            control: Control::from_format(format),
             private: private,
         }
     }
@@ -90,6 +99,16 @@ impl<T> Index<T> {
         self.session
     }
 
+    /// Retrieve the control flags of the track containing this index
+    pub fn control(&self) -> Control {
+        self.control
+    }
+
+    /// Set the control flags of the track containing this index
+    pub fn set_control(&mut self, control: Control) {
+        self.control = control;
+    }
+
     /// Return `true` if the index number is 0
     pub fn is_pregap(&self) -> bool {
         self.index.bcd() == 0
@@ -125,6 +144,14 @@ pub struct IndexCache<T> {
     /// First sector in the lead-out, given as a sector index instead
     /// of an MSF to avoid converting back and forth all the time.
     lead_out: u32,
+    /// Session number and first sector in the lead-out for each
+    /// session on the disc, ordered by position. The last entry's
+    /// lead-out is always equal to `lead_out`.
+    sessions: Vec<(u8, u32)>,
+    /// Length of the lead-in of each session in sectors, in the same
+    /// order as `sessions`. The first session's lead-in is before
+    /// 00:00:00, its length is never used.
+    lead_ins: Vec<u32>,
 }
 
 impl<T> IndexCache<T> {
@@ -134,8 +161,20 @@ impl<T> IndexCache<T> {
     /// an error if the disc structure makes no sense (duplicate
     /// tracks, indices in the wrong order etc...).
     pub fn new(file: PathBuf,
-               mut indices: Vec<Index<T>>,
+               indices: Vec<Index<T>>,
                lead_out: Msf) -> Result<IndexCache<T>, CdError> {
+        IndexCache::new_multisession(file, indices, vec![lead_out])
+    }
+
+    /// Create a new `IndexCache` for a multi-session disc. `lead_outs`
+    /// contains the MSF of the first sector in the lead-out of each
+    /// session, in order. Sectors between the lead-out of a session
+    /// and the first index of the next one (the lead-out and lead-in
+    /// areas) are not part of any index.
+    pub fn new_multisession(file: PathBuf,
+                            mut indices: Vec<Index<T>>,
+                            lead_outs: Vec<Msf>)
+                            -> Result<IndexCache<T>, CdError> {
         if indices.is_empty() {
             return Err(CdError::BadImage(file, "Empty disc".to_string()));
         }
@@ -154,11 +193,128 @@ impl<T> IndexCache<T> {
             }
         }
 
-        // TODO: Add more validation here.
+        // Match the sessions with their lead-out
+        let mut sessions: Vec<(u8, u32)> = Vec::new();
+
+        for (i, index) in indices.iter().enumerate() {
+            let new_session =
+                match sessions.last() {
+                    Some(&(s, _)) => s != index.session,
+                    None => true,
+                };
+
+            if !new_session {
+                continue;
+            }
+
+            if let Some(&(s, _)) = sessions.last() {
+                if index.session < s {
+                    let error = format!("Session {} starts after session {}",
+                                        s, index.session);
+
+                    return Err(CdError::BadImage(file, error));
+                }
+            }
+
+            let lead_out =
+                match lead_outs.get(sessions.len()) {
+                    Some(l) => l.sector_index(),
+                    None => {
+                        let error = format!("Missing lead-out for session {}",
+                                            index.session);
+
+                        return Err(CdError::BadImage(file, error));
+                    }
+                };
+
+            // The lead-out must be between the last index of this
+            // session and the first index of the next one.
+            let next =
+                indices[i..].iter().find(|i| i.session != index.session);
+
+            let last = indices[i..].iter()
+                .take_while(|i| i.session == index.session)
+                .last()
+                .unwrap();
+
+            let bad_lead_out =
+                lead_out <= last.sector_index ||
+                next.map_or(false, |n| lead_out > n.sector_index);
+
+            if bad_lead_out {
+                let error = format!("Session {}'s lead-out is out of place \
+                                     ({})",
+                                    index.session,
+                                    Msf::from_sector_index(lead_out).unwrap());
+
+                return Err(CdError::BadImage(file, error));
+            }
+
+            sessions.push((index.session, lead_out));
+        }
+
+        if sessions.len() != lead_outs.len() {
+            return Err(CdError::BadImage(file,
+                                         "Too many lead-outs".to_string()));
+        }
+
+        // The tracks and their indices must be in order for the
+        // lookups by track number to work
+        for pair in indices.windows(2) {
+            let (prev, index) = (&pair[0], &pair[1]);
+
+            let out_of_order =
+                index.track < prev.track ||
+                (index.track == prev.track && index.index < prev.index);
+
+            if out_of_order {
+                let error = format!("Track {} index {} is out of order",
+                                    index.track, index.index);
+
+                return Err(CdError::BadImage(file, error));
+            }
+        }
+
+        // Every track needs an INDEX 01, the track MSF is relative to
+        // it
+        for (i, index) in indices.iter().enumerate() {
+            let first = i == 0 || indices[i - 1].track != index.track;
+
+            let has_index01 =
+                indices[i..].iter()
+                .take_while(|i| i.track == index.track)
+                .any(|i| i.index == Bcd::one());
+
+            if first && !has_index01 {
+                let error = format!("Track {} has no INDEX 01", index.track);
+
+                return Err(CdError::BadImage(file, error));
+            }
+        }
+
+        // Use the standard lead-in length unless the gap between the
+        // sessions is too short for it
+        let lead_ins =
+            sessions.iter().enumerate().map(|(i, &(session, _))| {
+                match i.checked_sub(1).map(|p| sessions[p].1) {
+                    Some(lead_out) => {
+                        let start =
+                            indices.iter()
+                            .find(|i| i.session == session)
+                            .unwrap()
+                            .sector_index;
+
+                        cmp::min(start - lead_out, LEAD_IN_LENGTH)
+                    }
+                    None => LEAD_IN_LENGTH,
+                }
+            }).collect();
 
         Ok(IndexCache {
             indices: indices,
-            lead_out: lead_out.sector_index(),
+            lead_out: sessions[sessions.len() - 1].1,
+            sessions: sessions,
+            lead_ins: lead_ins,
         })
     }
 
@@ -174,7 +330,8 @@ impl<T> IndexCache<T> {
     }
 
     /// Locate the index directly before `msf` and return its
-    /// position along with a reference to the `Index` struct.
+    /// position along with a reference to the `Index` struct. Returns
+    /// `None` if `msf` is in the lead-out or between two sessions.
     pub fn find_index_for_msf(&self, msf: Msf) -> Option<(usize, &Index<T>)> {
         let sector = msf.sector_index();
 
@@ -193,23 +350,274 @@ impl<T> IndexCache<T> {
                 Err(i) => i - 1,
             };
 
-        Some((pos, &self.indices[pos]))
+        let index = &self.indices[pos];
+
+        if sector >= self.session_lead_out_sector(index.session) {
+            // We're in the lead-out/lead-in area between two sessions
+            return None;
+        }
+
+        Some((pos, index))
+    }
+
+    /// Locate the index containing `msf` and build the corresponding
+    /// sector `Metadata`. Returns `CdError::LeadOut` if `msf` is not
+    /// part of any index.
+    pub fn locate(&self, msf: Msf) -> Result<(&Index<T>, Metadata),
+                                              CdError> {
+        let index =
+            match self.find_index_for_msf(msf) {
+                Some((_, i)) => i,
+                None => return Err(CdError::LeadOut),
+            };
+
+        // The track MSF is relative to index1. We can't just use
+        // `index` if it's an index1 since it could be a post-gap
+        // split from the actual one.
+        let index1 =
+            match self.find_index01_for_track(index.track()) {
+                Ok((_, i)) => i,
+                // Shouldn't be reached, should be caught by the
+                // constructor
+                Err(_) => panic!("Missing index 1 for track {}",
+                                 index.track()),
+            };
+
+        let track_msf =
+            if msf < index1.msf() {
+                // In the pregap the track MSF decreases until index1
+                // is reached
+                index1.msf() - msf
+            } else {
+                msf - index1.msf()
+            };
+
+        let metadata = Metadata {
+            msf: msf,
+            track_msf: track_msf,
+            index: index.index(),
+            track: index.track(),
+            format: index.format(),
+            session: index.session(),
+        };
+
+        Ok((index, metadata))
+    }
+
+    /// Return the number of sessions on the disc
+    pub fn session_count(&self) -> u8 {
+        self.sessions.len() as u8
+    }
+
+    /// Return the number of the first track in `session`
+    pub fn session_first_track(&self, session: u8) -> Result<Bcd, CdError> {
+        match self.indices.iter().find(|i| i.session == session) {
+            Some(i) => Ok(i.track),
+            None => Err(CdError::BadSession),
+        }
+    }
+
+    /// Return the number of the last track in `session`
+    pub fn session_last_track(&self, session: u8) -> Result<Bcd, CdError> {
+        match self.indices.iter().rev().find(|i| i.session == session) {
+            Some(i) => Ok(i.track),
+            None => Err(CdError::BadSession),
+        }
+    }
+
+    /// Return the MSF of the first sector in the lead-out of
+    /// `session`
+    pub fn session_lead_out(&self, session: u8) -> Result<Msf, CdError> {
+        match self.sessions.iter().find(|&&(s, _)| s == session) {
+            Some(&(_, lead_out)) =>
+                Ok(Msf::from_sector_index(lead_out).unwrap()),
+            None => Err(CdError::BadSession),
+        }
+    }
+
+    /// Return the length of the lead-in of `session`
+    pub fn session_lead_in(&self, session: u8) -> Result<Msf, CdError> {
+        match self.sessions.iter().position(|&(s, _)| s == session) {
+            Some(pos) =>
+                Ok(Msf::from_sector_index(self.lead_ins[pos]).unwrap()),
+            None => Err(CdError::BadSession),
+        }
+    }
+
+    /// Set the length of the lead-in of `session`, `LEAD_IN_LENGTH`
+    /// by default. The lead-in can't be longer than the gap between
+    /// the previous session's lead-out and the first index of
+    /// `session`, `len` is truncated if needed.
+    pub fn set_session_lead_in(&mut self,
+                               session: u8,
+                               len: Msf) -> Result<(), CdError> {
+        let pos =
+            match self.sessions.iter().position(|&(s, _)| s == session) {
+                Some(p) => p,
+                None => return Err(CdError::BadSession),
+            };
+
+        let mut len = len.sector_index();
+
+        if pos > 0 {
+            let lead_out = self.sessions[pos - 1].1;
+
+            let start =
+                self.indices.iter()
+                .find(|i| i.session == session)
+                .unwrap()
+                .sector_index;
+
+            len = cmp::min(len, start - lead_out);
+        }
+
+        self.lead_ins[pos] = len;
+
+        Ok(())
+    }
+
+    /// Synthesize the Q subchannel data for the sector at `msf` from
+    /// the disc structure. Sectors past the lead-out of a session
+    /// contain lead-out position data, except for the sectors before
+    /// the next session which are part of its lead-in (see
+    /// `set_session_lead_in`).
+    pub fn q_subchannel(&self, msf: Msf) -> SubChannelQ {
+        if let Ok((index, metadata)) = self.locate(msf) {
+            let data = QData::Mode1(metadata.track,
+                                    metadata.index,
+                                    metadata.track_msf,
+                                    msf);
+
+            return SubChannelQ::from_data(index.control(), &data).unwrap();
+        }
+
+        let sector = msf.sector_index();
+
+        // We're not in the program area of a session so we must be
+        // past the lead-out of one of them (track 01's pregap always
+        // starts at 00:00:00).
+        let pos =
+            self.sessions.iter()
+            .rposition(|&(_, lead_out)| lead_out <= sector)
+            .unwrap();
+
+        let (session, lead_out) = self.sessions[pos];
+
+        if let Some(&(next, _)) = self.sessions.get(pos + 1) {
+            let start =
+                self.indices.iter()
+                .find(|i| i.session == next)
+                .unwrap()
+                .sector_index;
+
+            let lead_in = self.lead_ins[pos + 1];
+
+            if start - sector <= lead_in {
+                let frame = lead_in - (start - sector);
+
+                return self.lead_in_q_subchannel(next, frame).unwrap();
+            }
+        }
+
+        // The lead-out uses the control flags of the session's last
+        // track
+        let control =
+            self.indices.iter()
+            .rev()
+            .find(|i| i.session == session)
+            .unwrap()
+            .control;
+
+        let lead_out = Msf::from_sector_index(lead_out).unwrap();
+
+        let data = QData::Mode1LeadOut(msf - lead_out, msf);
+
+        SubChannelQ::from_data(control, &data).unwrap()
+    }
+
+    /// Synthesize the Q subchannel data for sector `frame` of the
+    /// lead-in of `session`. The lead-in contains the table of
+    /// contents of the session: one entry per track followed by the
+    /// 0xa0 (first track), 0xa1 (last track) and 0xa2 (lead-out)
+    /// pointers, each entry repeated in 3 consecutive sectors.
+    pub fn lead_in_q_subchannel(&self,
+                                session: u8,
+                                frame: u32) -> Result<SubChannelQ, CdError> {
+        let toc = self.toc();
+
+        let session =
+            match toc.sessions.iter().find(|s| s.number == session) {
+                Some(s) => s,
+                None => return Err(CdError::BadSession),
+            };
+
+        // The running time wraps around after 99:59:74
+        let msf = Msf::from_sector_index(frame % (100 * 60 * 75)).unwrap();
+
+        let tracks = &session.tracks;
+        let first = &tracks[0];
+        let last = &tracks[tracks.len() - 1];
+
+        let entry = (frame / 3) as usize % (tracks.len() + 3);
+
+        let (control, data) =
+            match entry.checked_sub(tracks.len()) {
+                None => {
+                    let track = &tracks[entry];
+
+                    (track.control,
+                     QData::Mode1Toc(track.number, track.start, msf))
+                }
+                Some(0) =>
+                    (first.control,
+                     QData::Mode1TocFirstTrack(first.number,
+                                               session.format,
+                                               msf)),
+                Some(1) =>
+                    (last.control,
+                     QData::Mode1TocLastTrack(last.number, msf)),
+                _ =>
+                    (last.control,
+                     QData::Mode1TocLeadOut(session.lead_out, msf)),
+            };
+
+        Ok(SubChannelQ::from_data(control, &data).unwrap())
+    }
+
+    /// Return the first sector in the lead-out of `session`
+    fn session_lead_out_sector(&self, session: u8) -> u32 {
+        match self.sessions.iter().find(|&&(s, _)| s == session) {
+            Some(&(_, lead_out)) => lead_out,
+            None => self.lead_out,
+        }
     }
 
     /// Locate `index` for `track` and return its position along with
-    /// a reference to the `Index` struct.
+    /// a reference to the `Index` struct. If the index is split
+    /// across several entries (when it's followed by a post-gap for
+    /// instance) the first one is returned.
     pub fn find_index_for_track(&self,
                                 track: Bcd,
                                 index: Bcd) -> Result<(usize, &Index<T>),
                                                       CdError> {
-        match self.indices.binary_search_by(
-            |idx| match idx.track().cmp(&track) {
-                cmp::Ordering::Equal => idx.index().cmp(&index),
-                o => o,
-            }) {
-            Ok(i) => Ok((i, &self.indices[i])),
-            Err(_) => Err(CdError::BadTrack),
-        }
+        let pos =
+            match self.indices.binary_search_by(
+                |idx| match idx.track().cmp(&track) {
+                    cmp::Ordering::Equal => idx.index().cmp(&index),
+                    o => o,
+                }) {
+                Ok(i) => i,
+                Err(_) => return Err(CdError::BadTrack),
+            };
+
+        // The binary search returns an arbitrary entry if there are
+        // several matches, rewind to the first one
+        let pos =
+            self.indices[..pos].iter()
+            .rposition(|i| i.track() != track || i.index() != index)
+            .map_or(0, |p| p + 1);
+
+        Ok((pos, &self.indices[pos]))
     }
 
     /// Locate index1 for `track` and return its position along with a
@@ -236,11 +644,12 @@ impl<T> IndexCache<T> {
 
         let end =
             match next_track {
-                // We found the next track, the previous sector is the
-                // last one in our track.
-                Some(next) => next.sector_index(),
-                // Seems like we got the last track
-                None => self.lead_out,
+                // We found the next track in the same session, the
+                // previous sector is the last one in our track.
+                Some(next) if next.session() == index01.session() =>
+                    next.sector_index(),
+                // Seems like we got the last track of the session
+                _ => self.session_lead_out_sector(index01.session()),
             };
 
         let len =
@@ -267,6 +676,94 @@ impl<T> IndexCache<T> {
             Err(CdError::EndOfTrack)
         }
     }
+
+    /// Build the table of contents of the disc
+    pub fn toc(&self) -> Toc {
+        let mut sessions: Vec<Session> = Vec::new();
+
+        for &(session, lead_out) in &self.sessions {
+            let mut tracks: Vec<Track> = Vec::new();
+
+            for index in self.indices.iter().filter(|i| i.session == session) {
+                let toc_index = TrackIndex {
+                    number: index.index,
+                    msf: index.msf(),
+                };
+
+                let new_track =
+                    match tracks.last() {
+                        Some(t) => t.number != index.track,
+                        None => true,
+                    };
+
+                if new_track {
+                    let (start, length) =
+                        match self.track_length(index.track) {
+                            Ok((len, _, index01)) => (index01.msf(), len),
+                            // Track without INDEX 01, shouldn't happen
+                            Err(_) => (index.msf(), Msf::zero()),
+                        };
+
+                    tracks.push(Track {
+                        number: index.track,
+                        format: index.format,
+                        control: index.control,
+                        start: start,
+                        length: length,
+                        indices: vec![toc_index],
+                        isrc: None,
+                    });
+                } else {
+                    let track = tracks.last_mut().unwrap();
+
+                    // Post-gaps are stored as a separate `Index` with
+                    // the same number, we don't want to list them
+                    // twice.
+                    if track.indices.last().unwrap().number != index.index {
+                        track.indices.push(toc_index);
+                    }
+
+                    // The INDEX 01 is authoritative for the control
+                    // flags
+                    if index.index == Bcd::one() {
+                        track.control = index.control;
+                    }
+                }
+            }
+
+            let format = session_format(&tracks);
+
+            sessions.push(Session {
+                number: session,
+                format: format,
+                tracks: tracks,
+                lead_out: Msf::from_sector_index(lead_out).unwrap(),
+            });
+        }
+
+        Toc {
+            sessions: sessions,
+            catalog: None,
+        }
+    }
+}
+
+/// Default length of the lead-in of a session in sectors. The actual
+/// length depends on the disc, this is the value used by most
+/// writers.
+pub const LEAD_IN_LENGTH: u32 = 4500;
+
+/// Guess the format of a session from the format of its tracks
+fn session_format(tracks: &[Track]) -> SessionFormat {
+    let has = |f| tracks.iter().any(|t| t.format == f);
+
+    if has(TrackFormat::Mode2CdI) {
+        SessionFormat::Cdi
+    } else if has(TrackFormat::Mode2Xa) {
+        SessionFormat::Cdxa
+    } else {
+        SessionFormat::CddaCdRom
+    }
 }
 
 impl<T> fmt::Debug for IndexCache<T> {
@@ -277,6 +774,13 @@ impl<T> fmt::Debug for IndexCache<T> {
 
         for i in &self.indices {
             if i.session != session || force_display {
+                if !force_display {
+                    try!(writeln!(f, "  Lead-out: {}",
+                                  Msf::from_sector_index(
+                                      self.session_lead_out_sector(session))
+                                  .unwrap()));
+                }
+
                 try!(writeln!(f, "Session {}:", i.session));
                 session = i.session;
                 force_display = true;
@@ -294,3 +798,306 @@ impl<T> fmt::Debug for IndexCache<T> {
         writeln!(f, "Lead-out: {}", self.lead_out())
     }
 }
+
+#[test]
+fn session_queries() {
+    let msf = |s| Msf::from_sector_index(s).unwrap();
+    let track = |t| Bcd::from_binary(t).unwrap();
+
+    let index = |i, s, t, session| {
+        Index::new(Bcd::from_binary(i).unwrap(),
+                   msf(s),
+                   track(t),
+                   TrackFormat::Audio,
+                   session,
+                   ())
+    };
+
+    let indices = vec![index(0, 0, 1, 1),
+                       index(1, 150, 1, 1),
+                       index(1, 1000, 2, 1),
+                       index(0, 13000, 3, 2),
+                       index(1, 13150, 3, 2)];
+
+    let cache =
+        IndexCache::new_multisession(PathBuf::new(),
+                                     indices,
+                                     vec![msf(2000), msf(14000)]).unwrap();
+
+    assert!(cache.session_count() == 2);
+    assert!(cache.session_first_track(1).unwrap() == track(1));
+    assert!(cache.session_last_track(1).unwrap() == track(2));
+    assert!(cache.session_lead_out(1).unwrap() == msf(2000));
+    assert!(cache.session_first_track(2).unwrap() == track(3));
+    assert!(cache.session_last_track(2).unwrap() == track(3));
+    assert!(cache.session_lead_out(2).unwrap() == msf(14000));
+    assert!(cache.session_first_track(3).is_err());
+    assert!(cache.session_lead_out(0).is_err());
+
+    // Between the two sessions
+    assert!(cache.find_index_for_msf(msf(5000)).is_none());
+    assert!(cache.track_length(track(2)).unwrap().0 == msf(1000));
+}
+
+#[test]
+fn q_subchannel_synthesis() {
+    use subchannel::SubChannel;
+
+    let msf = |s| Msf::from_sector_index(s).unwrap();
+    let track = |t| Bcd::from_binary(t).unwrap();
+
+    let index = |i, s, t, session, format| {
+        Index::new(Bcd::from_binary(i).unwrap(),
+                   msf(s),
+                   track(t),
+                   format,
+                   session,
+                   ())
+    };
+
+    let indices = vec![index(0, 0, 1, 1, TrackFormat::Mode1),
+                       index(1, 150, 1, 1, TrackFormat::Mode1),
+                       index(0, 1000, 2, 1, TrackFormat::Audio),
+                       index(1, 1010, 2, 1, TrackFormat::Audio),
+                       index(1, 13000, 3, 2, TrackFormat::Mode2Xa)];
+
+    let cache =
+        IndexCache::new_multisession(PathBuf::new(),
+                                     indices,
+                                     vec![msf(2000), msf(14000)]).unwrap();
+
+    // Track 02's pregap, the relative MSF counts down
+    assert!(&cache.q_subchannel(msf(1003)).raw()[..10] ==
+            &[0x01, 0x02, 0x00, 0x00, 0x00, 0x07,
+              0x00, 0x00, 0x13, 0x28]);
+
+    // Track 01
+    assert!(&cache.q_subchannel(msf(151)).raw()[..10] ==
+            &[0x41, 0x01, 0x01, 0x00, 0x00, 0x01,
+              0x00, 0x00, 0x02, 0x01]);
+
+    // Session 1's lead-out
+    assert!(&cache.q_subchannel(msf(2001)).raw()[..10] ==
+            &[0x01, 0xaa, 0x01, 0x00, 0x00, 0x01,
+              0x00, 0x00, 0x26, 0x51]);
+
+    // Session 2's lead-in, first entry: track 03
+    assert!(&cache.q_subchannel(msf(13000 - 4500)).raw()[..10] ==
+            &[0x41, 0x00, 0x03, 0x00, 0x00, 0x00,
+              0x00, 0x02, 0x53, 0x25]);
+
+    // Session 1's lead-in: 01, 02, A0, A1, A2
+    let toc = |frame| {
+        let q = cache.lead_in_q_subchannel(1, frame).unwrap();
+
+        let raw = q.raw();
+
+        [raw[0], raw[2], raw[7], raw[8], raw[9]]
+    };
+
+    assert!(toc(0) == [0x41, 0x01, 0x00, 0x02, 0x00]);
+    assert!(toc(5) == [0x01, 0x02, 0x00, 0x13, 0x35]);
+    assert!(toc(6) == [0x41, 0xa0, 0x01, 0x00, 0x00]);
+    assert!(toc(9) == [0x01, 0xa1, 0x02, 0x00, 0x00]);
+    assert!(toc(12) == [0x01, 0xa2, 0x00, 0x26, 0x50]);
+    assert!(toc(15) == toc(0));
+
+    assert!(cache.lead_in_q_subchannel(3, 0).is_err());
+}
+
+#[test]
+fn multisession_toc_and_locate() {
+    let msf = |s| Msf::from_sector_index(s).unwrap();
+    let track = |t| Bcd::from_binary(t).unwrap();
+
+    let index = |i, s, t, session, format| {
+        Index::new(Bcd::from_binary(i).unwrap(),
+                   msf(s),
+                   track(t),
+                   format,
+                   session,
+                   ())
+    };
+
+    // Track 02 has a post-gap split from its INDEX 01
+    let indices = vec![index(0, 0, 1, 1, TrackFormat::Audio),
+                       index(1, 150, 1, 1, TrackFormat::Audio),
+                       index(1, 1000, 2, 1, TrackFormat::Audio),
+                       index(1, 1800, 2, 1, TrackFormat::Audio),
+                       index(0, 13000, 3, 2, TrackFormat::Mode2Xa),
+                       index(1, 13150, 3, 2, TrackFormat::Mode2Xa),
+                       index(2, 13500, 3, 2, TrackFormat::Mode2Xa)];
+
+    let cache =
+        IndexCache::new_multisession(PathBuf::new(),
+                                     indices,
+                                     vec![msf(2000), msf(14000)]).unwrap();
+
+    let toc = cache.toc();
+
+    assert!(toc.sessions.len() == 2);
+
+    let session1 = &toc.sessions[0];
+
+    assert!(session1.number == 1);
+    assert!(session1.format == SessionFormat::CddaCdRom);
+    assert!(session1.lead_out == msf(2000));
+    assert!(session1.tracks.len() == 2);
+    assert!(session1.tracks[1].start == msf(1000));
+    assert!(session1.tracks[1].length == msf(1000));
+    assert!(session1.tracks[1].indices ==
+            vec![TrackIndex { number: Bcd::one(), msf: msf(1000) }]);
+
+    let session2 = &toc.sessions[1];
+
+    assert!(session2.number == 2);
+    assert!(session2.format == SessionFormat::Cdxa);
+    assert!(session2.lead_out == msf(14000));
+    assert!(session2.tracks.len() == 1);
+
+    let track3 = &session2.tracks[0];
+
+    assert!(track3.number == track(3));
+    assert!(track3.start == msf(13150));
+    assert!(track3.length == msf(850));
+    assert!(track3.pregap_start() == msf(13000));
+    assert!(track3.indices.len() == 3);
+
+    assert!(toc.lead_out() == msf(14000));
+
+    // Track 03's pregap, in session 2
+    let (i, metadata) = cache.locate(msf(13100)).unwrap();
+
+    assert!(i.sector_index() == 13000);
+    assert!(metadata.track == track(3));
+    assert!(metadata.index == Bcd::zero());
+    assert!(metadata.track_msf == msf(50));
+    assert!(metadata.session == 2);
+    assert!(metadata.format == TrackFormat::Mode2Xa);
+
+    // Track 03 INDEX 02, relative to INDEX 01
+    let (_, metadata) = cache.locate(msf(13600)).unwrap();
+
+    assert!(metadata.index == Bcd::from_binary(2).unwrap());
+    assert!(metadata.track_msf == msf(450));
+
+    // Track 02's post-gap, relative to the first INDEX 01 entry
+    let (i, metadata) = cache.locate(msf(1900)).unwrap();
+
+    assert!(i.sector_index() == 1800);
+    assert!(metadata.track_msf == msf(900));
+    assert!(metadata.session == 1);
+
+    // Lead-out and lead-in areas and the final lead-out
+    for &s in &[2000, 8500, 12999, 14000] {
+        match cache.locate(msf(s)) {
+            Err(CdError::LeadOut) => (),
+            _ => panic!("Sector {} should be in the lead-out", s),
+        }
+    }
+}
+
+#[test]
+fn missing_index01() {
+    let msf = |s| Msf::from_sector_index(s).unwrap();
+
+    let index = |i, s, t, session| {
+        Index::new(Bcd::from_binary(i).unwrap(),
+                   msf(s),
+                   Bcd::from_binary(t).unwrap(),
+                   TrackFormat::Audio,
+                   session,
+                   ())
+    };
+
+    let is_bad_image = |indices, lead_outs| {
+        match IndexCache::new_multisession(PathBuf::new(),
+                                           indices,
+                                           lead_outs) {
+            Err(CdError::BadImage(..)) => true,
+            _ => false,
+        }
+    };
+
+    // Track 02 only has a pregap
+    assert!(is_bad_image(vec![index(0, 0, 1, 1),
+                              index(1, 150, 1, 1),
+                              index(0, 1000, 2, 1)],
+                         vec![msf(2000)]));
+
+    // Track 03, the first track of session 2, only has a pregap
+    assert!(is_bad_image(vec![index(0, 0, 1, 1),
+                              index(1, 150, 1, 1),
+                              index(0, 13000, 3, 2)],
+                         vec![msf(2000), msf(14000)]));
+
+    // Tracks out of order
+    assert!(is_bad_image(vec![index(0, 0, 2, 1),
+                              index(1, 150, 2, 1),
+                              index(1, 1000, 1, 1)],
+                         vec![msf(2000)]));
+
+    assert!(!is_bad_image(vec![index(0, 0, 1, 1),
+                               index(1, 150, 1, 1),
+                               index(1, 13150, 2, 2)],
+                          vec![msf(2000), msf(14000)]));
+}
+
+#[test]
+fn session_lead_in_length() {
+    use subchannel::SubChannel;
+
+    let msf = |s| Msf::from_sector_index(s).unwrap();
+
+    let index = |i, s, t, session| {
+        Index::new(Bcd::from_binary(i).unwrap(),
+                   msf(s),
+                   Bcd::from_binary(t).unwrap(),
+                   TrackFormat::Audio,
+                   session,
+                   ())
+    };
+
+    let mut cache =
+        IndexCache::new_multisession(PathBuf::new(),
+                                     vec![index(0, 0, 1, 1),
+                                          index(1, 150, 1, 1),
+                                          index(1, 13000, 2, 2)],
+                                     vec![msf(2000), msf(14000)]).unwrap();
+
+    // Point field of the Q subchannel: track number in the lead-in,
+    // 0xaa in the lead-out
+    let point = |c: &IndexCache<()>, s| c.q_subchannel(msf(s)).raw()[2];
+    let tno = |c: &IndexCache<()>, s| c.q_subchannel(msf(s)).raw()[1];
+
+    assert!(cache.session_lead_in(2).unwrap() == msf(4500));
+    assert!(tno(&cache, 8500) == 0x00);
+    assert!(tno(&cache, 8499) == 0xaa);
+
+    cache.set_session_lead_in(2, msf(1000)).unwrap();
+
+    assert!(cache.session_lead_in(2).unwrap() == msf(1000));
+    assert!(tno(&cache, 12000) == 0x00);
+    assert!(point(&cache, 12000) == 0x02);
+    assert!(tno(&cache, 11999) == 0xaa);
+
+    // Truncated to the gap between the sessions
+    cache.set_session_lead_in(2, msf(20000)).unwrap();
+
+    assert!(cache.session_lead_in(2).unwrap() == msf(11000));
+    assert!(tno(&cache, 2000) == 0x00);
+
+    assert!(cache.set_session_lead_in(3, msf(1000)).is_err());
+    assert!(cache.session_lead_in(3).is_err());
+
+    // The default length is truncated as well
+    let cache =
+        IndexCache::new_multisession(PathBuf::new(),
+                                     vec![index(0, 0, 1, 1),
+                                          index(1, 150, 1, 1),
+                                          index(1, 3000, 2, 2)],
+                                     vec![msf(2000), msf(4000)]).unwrap();
+
+    assert!(cache.session_lead_in(2).unwrap() == msf(1000));
+    assert!(tno(&cache, 2000) == 0x00);
+}
@@ -0,0 +1,413 @@
+//! Single track ISO image implementation
+//!
+//! ISO images are raw dumps of a disc's only data track without any
+//! metadata. The most common variant only contains the 2048 bytes of
+//! user data of each Mode 1 sector (the "cooked" `.iso`) but raw
+//! dumps with full 2352 byte sectors (Mode 1 or Mode 2) and headerless
+//! 2336 byte Mode 2 sectors are also found in the wild, usually with
+//! a `.bin` or `.img` extension.
+//!
+//! The sector size is detected from the sync pattern of raw sectors
+//! or the position of the ISO 9660 Primary Volume Descriptor. The
+//! headers, track 01's pregap and the sector metadata are rebuilt so
+//! that the sectors are identical to those of a BIN/CUE image of the
+//! same disc.
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::cmp;
+
+use CdError;
+use Image;
+use TrackFormat;
+use internal::{IndexCache, Index};
+use sector::{Sector, SectorBuilder, SYNC_PATTERN};
+use msf::Msf;
+use bcd::Bcd;
+use toc::Toc;
+
+/// ISO image
+#[derive(Debug)]
+pub struct Iso {
+    /// Cache of all the indices in the CD image
+    indices: IndexCache<Storage>,
+    /// Sector data
+    file: File,
+    /// Layout of the sectors in `file`
+    layout: Layout,
+}
+
+impl Iso {
+    /// Open the ISO image at `path` and detect its sector size
+    pub fn new(path: &Path) -> Result<Iso, CdError> {
+        let mut file =
+            match File::open(path) {
+                Ok(f) => f,
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        let size =
+            match file.metadata() {
+                Ok(m) => m.len(),
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        let header_len = cmp::min(size, HEADER_LENGTH as u64) as usize;
+
+        let mut header = vec![0; header_len];
+
+        if let Err(e) = file.read_exact(&mut header) {
+            return Err(CdError::IoError(e));
+        }
+
+        let layout =
+            match detect(&header, size) {
+                Some(l) => l,
+                None => return Err(bad_image(path, "Unknown sector size")),
+            };
+
+        let sectors = size / layout.sector_size() as u64;
+
+        if sectors == 0 {
+            return Err(bad_image(path, "Empty image"));
+        }
+
+        // The data track starts after the standard 2 second pregap
+        let lead_out =
+            match Msf::from_sector_index(150 + sectors as u32) {
+                Some(m) => m,
+                None => return Err(bad_image(path, "Image is too big")),
+            };
+
+        let format = layout.format();
+
+        let indices = vec![
+            Index::new(Bcd::zero(),
+                       Msf::zero(),
+                       Bcd::one(),
+                       format,
+                       1,
+                       Storage::PreGap),
+            Index::new(Bcd::one(),
+                       Msf::from_sector_index(150).unwrap(),
+                       Bcd::one(),
+                       format,
+                       1,
+                       Storage::Iso),
+        ];
+
+        let indices = try!(IndexCache::new(PathBuf::from(path),
+                                           indices,
+                                           lead_out));
+
+        Ok(Iso {
+            indices: indices,
+            file: file,
+            layout: layout,
+        })
+    }
+
+    /// Return the size of the sectors stored in the image file: 2048,
+    /// 2336 or 2352 bytes
+    pub fn sector_size(&self) -> u16 {
+        self.layout.sector_size()
+    }
+
+    /// Fill `buf` with the data located at `offset` in the image file
+    fn read_at(file: &mut File,
+               offset: u64,
+               buf: &mut [u8]) -> io::Result<()> {
+        try!(file.seek(SeekFrom::Start(offset)));
+
+        file.read_exact(buf)
+    }
+}
+
+impl Image for Iso {
+    fn image_format(&self) -> String {
+        "ISO".to_string()
+    }
+
+    fn read_sector(&mut self,
+                   sector: &mut Sector,
+                   msf: Msf) -> Result<(), CdError> {
+        let (index, metadata) = try!(self.indices.locate(msf));
+
+        let mut builder = SectorBuilder::new(sector);
+
+        match index.private() {
+            &Storage::Iso => {
+                let file = &mut self.file;
+
+                let offset = self.layout.sector_size() as u64 *
+                    (msf.sector_index() - index.sector_index()) as u64;
+
+                let res =
+                    match self.layout {
+                        Layout::Mode1Data =>
+                            builder.set_mode1_payload(
+                                |data| Iso::read_at(file, offset, data)),
+                        Layout::Raw(_) =>
+                            builder.set_data_2352(
+                                |data| Iso::read_at(file, offset, data)),
+                        Layout::Mode2Headerless =>
+                            builder.set_mode2_payload(
+                                |data| Iso::read_at(file, offset, data)),
+                    };
+
+                if let Err(e) = res {
+                    return Err(CdError::IoError(e));
+                }
+            }
+            &Storage::PreGap => builder.set_blank(index.format()),
+        }
+
+        builder.set_metadata(metadata);
+
+        Ok(())
+    }
+
+    fn track_msf(&self, track: Bcd, track_msf: Msf) -> Result<Msf, CdError> {
+        self.indices.track_msf(track, track_msf)
+    }
+
+    fn toc(&self) -> Toc {
+        self.indices.toc()
+    }
+}
+
+/// Layout of the sectors in the image file
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Layout {
+    /// Mode 1 user data only (2048 bytes per sector)
+    Mode1Data,
+    /// Full 2352 byte sectors of a track in the given format
+    Raw(TrackFormat),
+    /// CD-ROM XA Mode 2 sectors without the 16 byte header (2336
+    /// bytes per sector)
+    Mode2Headerless,
+}
+
+impl Layout {
+    fn sector_size(self) -> u16 {
+        match self {
+            Layout::Mode1Data => 2048,
+            Layout::Raw(_) => 2352,
+            Layout::Mode2Headerless => 2336,
+        }
+    }
+
+    fn format(self) -> TrackFormat {
+        match self {
+            Layout::Mode1Data => TrackFormat::Mode1,
+            Layout::Raw(f) => f,
+            Layout::Mode2Headerless => TrackFormat::Mode2Xa,
+        }
+    }
+}
+
+/// Storage for an index
+#[derive(Debug)]
+enum Storage {
+    /// The data track stored in the image file
+    Iso,
+    /// Track 01's pregap, it's not stored in the image and must be
+    /// regenerated
+    PreGap,
+}
+
+/// Figure out the layout of an image of `size` bytes from its first
+/// bytes in `header`. Raw sectors are identified by their sync
+/// pattern, the other layouts by the position of the Primary Volume
+/// Descriptor (in sector 16) or by the size of the file if there's no
+/// ISO 9660 filesystem.
+fn detect(header: &[u8], size: u64) -> Option<Layout> {
+    let has_pvd = |offset: usize| {
+        header.len() >= offset + PVD_MAGIC.len() &&
+            &header[offset..offset + PVD_MAGIC.len()] == PVD_MAGIC
+    };
+
+    if header.len() >= 16 &&
+        header[0..12] == SYNC_PATTERN &&
+        size % 2352 == 0 {
+            // Use the mode of the first sector, the PVD's if it's
+            // not a data sector
+            let mode =
+                match header[15] {
+                    m @ 1 | m @ 2 => m,
+                    _ => header.get(16 * 2352 + 15).cloned().unwrap_or(0),
+                };
+
+            return match mode {
+                1 => Some(Layout::Raw(TrackFormat::Mode1)),
+                2 => Some(Layout::Raw(TrackFormat::Mode2Xa)),
+                _ => None,
+            };
+        }
+
+    if size % 2048 == 0 && has_pvd(16 * 2048) {
+        return Some(Layout::Mode1Data);
+    }
+
+    // Skip the 8 byte XA subheader
+    if size % 2336 == 0 && has_pvd(16 * 2336 + 8) {
+        return Some(Layout::Mode2Headerless);
+    }
+
+    if size % 2048 == 0 {
+        Some(Layout::Mode1Data)
+    } else if size % 2336 == 0 {
+        Some(Layout::Mode2Headerless)
+    } else {
+        None
+    }
+}
+
+fn bad_image(path: &Path, error: &str) -> CdError {
+    CdError::BadImage(PathBuf::from(path), error.to_string())
+}
+
+/// Type code and standard identifier at the start of the Primary
+/// Volume Descriptor
+pub const PVD_MAGIC: &'static [u8] = b"\x01CD001";
+
+/// Number of bytes needed by `detect` to find the PVD in any layout
+const HEADER_LENGTH: usize = 16 * 2352 + 24 + 6;
+
+#[test]
+fn detect_layout() {
+    let mut header = vec![0; HEADER_LENGTH];
+
+    // No filesystem, only the size is known
+    assert!(detect(&header, 2048 * 20) == Some(Layout::Mode1Data));
+    assert!(detect(&header, 2336 * 21) == Some(Layout::Mode2Headerless));
+    assert!(detect(&header, 2352 * 21) == None);
+
+    // 2048 * 73 is also a multiple of 2336, use the PVD
+    header[16 * 2336 + 8..16 * 2336 + 14].copy_from_slice(PVD_MAGIC);
+
+    assert!(detect(&header, 2048 * 73) == Some(Layout::Mode2Headerless));
+
+    header[16 * 2048..16 * 2048 + 6].copy_from_slice(PVD_MAGIC);
+
+    assert!(detect(&header, 2048 * 73) == Some(Layout::Mode1Data));
+
+    // Raw Mode 2 sectors
+    header[0..12].copy_from_slice(&SYNC_PATTERN);
+    header[15] = 2;
+
+    assert!(detect(&header, 2352 * 40) ==
+            Some(Layout::Raw(TrackFormat::Mode2Xa)));
+
+    // Blank first sector, use the PVD's mode
+    header[15] = 0;
+    header[16 * 2352 + 15] = 1;
+
+    assert!(detect(&header, 2352 * 40) ==
+            Some(Layout::Raw(TrackFormat::Mode1)));
+}
+
+#[test]
+fn same_as_cue() {
+    use cue::Cue;
+    use ecc;
+    use test_util;
+
+    let dir = test_util::temp_dir("iso-cue");
+
+    for &(size, mode) in &[(2048, "MODE1/2048"),
+                           (2336, "MODE2/2336"),
+                           (2352, "MODE1/2352"),
+                           (2352, "MODE2/2352")] {
+        let mut data = Vec::new();
+        let mut sectors = Vec::new();
+
+        for i in 0..20 {
+            let mut sector = [0; 2352];
+            let msf = Msf::from_sector_index(150 + i).unwrap();
+            let (m, s, f) = msf.into_bcd();
+
+            sector[0..12].copy_from_slice(&SYNC_PATTERN);
+            sector[12..15].copy_from_slice(&[m.bcd(), s.bcd(), f.bcd()]);
+
+            for (j, b) in sector[24..2072].iter_mut().enumerate() {
+                *b = (j as u32 * 3 + i) as u8;
+            }
+
+            if mode.starts_with("MODE1") {
+                sector[15] = 1;
+                ecc::generate_mode1(&mut sector);
+            } else {
+                // Form 1 data sector
+                let subheader = [0, 0, 0x08, 0, 0, 0, 0x08, 0];
+
+                sector[15] = 2;
+                sector[16..24].copy_from_slice(&subheader);
+                ecc::generate_mode2_form1(&mut sector);
+            }
+
+            match size {
+                2048 => data.extend_from_slice(&sector[16..2064]),
+                2336 => data.extend_from_slice(&sector[16..]),
+                _ => data.extend_from_slice(&sector),
+            }
+
+            sectors.push(sector);
+        }
+
+        let name = format!("disc{}-{}", size, &mode[..5]);
+        let sheet = format!("FILE \"{}.bin\" BINARY\n\
+                             \x20 TRACK 01 {}\n\
+                             \x20   INDEX 01 00:00:00\n",
+                            name, mode);
+
+        let iso = test_util::write_file(&dir, &format!("{}.iso", name), &data);
+
+        test_util::write_file(&dir, &format!("{}.bin", name), &data);
+
+        let cue = test_util::write_file(&dir,
+                                        &format!("{}.cue", name),
+                                        sheet.as_bytes());
+
+        let mut iso = Iso::new(&iso).unwrap();
+        let mut cue = Cue::new(&cue).unwrap();
+
+        assert!(iso.sector_size() == size);
+
+        let toc = cue.toc();
+
+        assert!(iso.toc() == toc);
+        assert!(toc.lead_out().sector_index() == 170);
+
+        let mut a = Sector::empty();
+        let mut b = Sector::empty();
+
+        // Track 01's pregap included
+        for pos in 0..toc.lead_out().sector_index() {
+            let msf = Msf::from_sector_index(pos).unwrap();
+
+            iso.read_sector(&mut a, msf).unwrap();
+            cue.read_sector(&mut b, msf).unwrap();
+
+            let (ma, mb) = (a.metadata(), b.metadata());
+
+            assert!(ma.msf == mb.msf);
+            assert!(ma.track_msf == mb.track_msf);
+            assert!(ma.index == mb.index);
+            assert!(ma.track == mb.track);
+            assert!(ma.format == mb.format);
+            assert!(ma.session == mb.session);
+
+            assert!(&a.data_2352().unwrap()[..] ==
+                    &b.data_2352().unwrap()[..]);
+
+            if pos >= 150 {
+                let sector = &sectors[pos as usize - 150];
+
+                assert!(&a.data_2352().unwrap()[..] == &sector[..]);
+            }
+        }
+    }
+}
@@ -0,0 +1,347 @@
+//! ISO 9660 directory records
+
+use CdError;
+
+use super::le32;
+use super::rock_ridge::RockRidge;
+use super::xa::XaAttributes;
+
+/// A single entry in a directory: file or subdirectory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    /// Raw file identifier, including the version number if any
+    identifier: String,
+    /// Location of the first block of the file
+    extent: u32,
+    /// Size of the file in bytes
+    size: u32,
+    /// Date and time of recording
+    recorded: Timestamp,
+    /// File flags
+    flags: FileFlags,
+    /// Size of the file units for interleaved files, 0 otherwise
+    unit_size: u8,
+    /// Size of the gap between file units for interleaved files
+    gap_size: u8,
+    /// System use area, used by the various extensions to ISO 9660
+    system_use: Vec<u8>,
+    /// Rock Ridge attributes, if any
+    rock_ridge: Option<RockRidge>,
+}
+
+impl DirectoryEntry {
+    /// Parse a directory record. Joliet file identifiers are encoded
+    /// in UCS-2 if `joliet` is true. Returns `None` if `record` is
+    /// truncated.
+    pub fn parse(record: &[u8], joliet: bool) -> Option<DirectoryEntry> {
+        if record.len() < 33 {
+            return None;
+        }
+
+        let len = record[0] as usize;
+        let name_len = record[32] as usize;
+
+        if len < 33 + name_len || len > record.len() {
+            return None;
+        }
+
+        let identifier =
+            match &record[33..33 + name_len] {
+                b"\0" => ".".to_string(),
+                b"\x01" => "..".to_string(),
+                name if joliet => ucs2(name),
+                name => String::from_utf8_lossy(name).into_owned(),
+            };
+
+        // There's a padding byte after the identifier if its length
+        // is even
+        let system_use = 33 + name_len + (1 - name_len % 2);
+        let system_use =
+            if system_use < len {
+                record[system_use..len].to_vec()
+            } else {
+                Vec::new()
+            };
+
+        Some(DirectoryEntry {
+            identifier: identifier,
+            extent: le32(&record[2..6]),
+            size: le32(&record[10..14]),
+            recorded: Timestamp::from_directory_record(
+                array_ref![record, 18, 7]),
+            flags: FileFlags::from_bits_truncate(record[25]),
+            unit_size: record[26],
+            gap_size: record[27],
+            system_use: system_use,
+            rock_ridge: None,
+        })
+    }
+
+    /// Return the raw file identifier, including the version number
+    /// (such as "SYSTEM.CNF;1"). The current and parent directory
+    /// entries are returned as "." and "..".
+    pub fn identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    /// Return the name of the file the way most operating systems
+    /// display it: the Rock Ridge name if there's one, otherwise the
+    /// identifier without the version number and without the
+    /// trailing dot of files without an extension.
+    pub fn name(&self) -> &str {
+        if let Some(name) = self.rock_ridge.as_ref().and_then(|r| r.name()) {
+            return name;
+        }
+
+        let name =
+            match self.identifier.rfind(';') {
+                Some(pos) => &self.identifier[..pos],
+                None => &self.identifier,
+            };
+
+        if name.len() > 1 && name.ends_with('.') && name != ".." {
+            &name[..name.len() - 1]
+        } else {
+            name
+        }
+    }
+
+    /// Return the logical block address of the first block of the
+    /// file
+    pub fn extent(&self) -> u32 {
+        self.extent
+    }
+
+    /// Return the size of the file in bytes
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Return the date and time of recording
+    pub fn recorded(&self) -> Timestamp {
+        self.recorded
+    }
+
+    /// Return the file flags
+    pub fn flags(&self) -> FileFlags {
+        self.flags
+    }
+
+    /// Return true if this entry is a directory
+    pub fn is_dir(&self) -> bool {
+        self.flags.contains(DIRECTORY)
+    }
+
+    /// Return the size of the file units and the size of the gap
+    /// between them in blocks for interleaved files, or `None` if the
+    /// file is not interleaved.
+    pub fn interleave(&self) -> Option<(u8, u8)> {
+        if self.unit_size != 0 {
+            Some((self.unit_size, self.gap_size))
+        } else {
+            None
+        }
+    }
+
+    /// Return the contents of the system use area
+    pub fn system_use(&self) -> &[u8] {
+        &self.system_use
+    }
+
+    /// Return the CD-ROM XA attributes of the entry, if any
+    pub fn xa(&self) -> Option<XaAttributes> {
+        XaAttributes::parse(&self.system_use)
+    }
+
+    /// Return the Rock Ridge attributes of the entry, if any
+    pub fn rock_ridge(&self) -> Option<&RockRidge> {
+        self.rock_ridge.as_ref()
+    }
+}
+
+/// Attach the Rock Ridge attributes `rr` to `entry`
+pub fn set_rock_ridge(entry: &mut DirectoryEntry, rr: RockRidge) {
+    entry.rock_ridge = Some(rr);
+}
+
+/// Make `entry` point to the relocated directory at `extent`
+pub fn relocate(entry: &mut DirectoryEntry, extent: u32, size: u32) {
+    entry.extent = extent;
+    entry.size = size;
+    entry.flags.insert(DIRECTORY);
+}
+
+bitflags! {
+    /// Directory record file flags
+    flags FileFlags: u8 {
+        /// The file should be hidden from the user
+        const HIDDEN      = 0b00000001,
+        /// The entry is a directory
+        const DIRECTORY   = 0b00000010,
+        /// The file is an associated file
+        const ASSOCIATED  = 0b00000100,
+        /// The file's format is described by the extended attribute
+        /// record
+        const RECORD      = 0b00001000,
+        /// Owner and group permissions are set in the extended
+        /// attribute record
+        const PROTECTION  = 0b00010000,
+        /// This is not the final directory record for the file
+        const MULTI_EXTENT = 0b10000000,
+    }
+}
+
+/// Date and time in the 7 byte directory record format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    /// Year (1900 to 2155)
+    pub year: u16,
+    /// Month (1 to 12)
+    pub month: u8,
+    /// Day of the month (1 to 31)
+    pub day: u8,
+    /// Hour (0 to 23)
+    pub hour: u8,
+    /// Minute (0 to 59)
+    pub minute: u8,
+    /// Second (0 to 59)
+    pub second: u8,
+    /// Offset from GMT in 15 minute intervals
+    pub gmt_offset: i8,
+}
+
+impl Timestamp {
+    /// Decode a timestamp in the directory record format
+    pub fn from_directory_record(raw: &[u8; 7]) -> Timestamp {
+        Timestamp {
+            year: 1900 + raw[0] as u16,
+            month: raw[1],
+            day: raw[2],
+            hour: raw[3],
+            minute: raw[4],
+            second: raw[5],
+            gmt_offset: raw[6] as i8,
+        }
+    }
+
+    /// Decode a timestamp in the 17 byte volume descriptor format:
+    /// the date and time as ASCII digits followed by the offset from
+    /// GMT. Invalid digits are treated as zeroes.
+    pub fn from_volume_descriptor(raw: &[u8; 17]) -> Timestamp {
+        let digits = |from: usize, to: usize| {
+            raw[from..to].iter().fold(0u16, |v, &d| {
+                let d =
+                    if d >= b'0' && d <= b'9' {
+                        (d - b'0') as u16
+                    } else {
+                        0
+                    };
+
+                v * 10 + d
+            })
+        };
+
+        Timestamp {
+            year: digits(0, 4),
+            month: digits(4, 6) as u8,
+            day: digits(6, 8) as u8,
+            hour: digits(8, 10) as u8,
+            minute: digits(10, 12) as u8,
+            second: digits(12, 14) as u8,
+            gmt_offset: raw[16] as i8,
+        }
+    }
+}
+
+/// Decode a big-endian UCS-2 Joliet identifier
+pub fn ucs2(b: &[u8]) -> String {
+    let chars: Vec<u16> =
+        b.chunks(2)
+        .filter(|c| c.len() == 2)
+        .map(|c| (c[0] as u16) << 8 | c[1] as u16)
+        .collect();
+
+    String::from_utf16_lossy(&chars)
+}
+
+/// Parse all the records in the directory data `dir`. Records never
+/// cross a block boundary, the rest of a block is zero-filled if the
+/// next record doesn't fit.
+pub fn parse_directory(dir: &[u8],
+                       joliet: bool) -> Result<Vec<DirectoryEntry>, CdError> {
+    let mut entries = Vec::new();
+
+    for block in dir.chunks(2048) {
+        let mut pos = 0;
+
+        while pos < block.len() && block[pos] != 0 {
+            let len = block[pos] as usize;
+
+            match DirectoryEntry::parse(&block[pos..], joliet) {
+                Some(e) => entries.push(e),
+                None => {
+                    let error = format!("Invalid directory record of \
+                                         length {}", len);
+                    return Err(CdError::BadFilesystem(error));
+                }
+            }
+
+            pos += len;
+        }
+    }
+
+    Ok(entries)
+}
+
+#[test]
+fn directory_record() {
+    let mut record = vec![0; 33];
+
+    record[2..6].copy_from_slice(&[0x18, 0, 0, 0]);
+    record[10..14].copy_from_slice(&[0x00, 0x08, 0, 0]);
+    record[18..25].copy_from_slice(&[99, 12, 31, 23, 59, 58, 4]);
+    record[25] = 0;
+    record[32] = 12;
+    record.extend_from_slice(b"SYSTEM.CNF;1");
+    // Padding byte and 2 bytes of system use
+    record.extend_from_slice(&[0, 0xaa, 0xbb]);
+    record[0] = record.len() as u8;
+
+    let entry = DirectoryEntry::parse(&record, false).unwrap();
+
+    assert!(entry.identifier() == "SYSTEM.CNF;1");
+    assert!(entry.name() == "SYSTEM.CNF");
+    assert!(entry.extent() == 0x18);
+    assert!(entry.size() == 2048);
+    assert!(entry.recorded().year == 1999);
+    assert!(entry.recorded().gmt_offset == 4);
+    assert!(!entry.is_dir());
+    assert!(entry.interleave() == None);
+    assert!(entry.system_use() == &[0xaa, 0xbb]);
+
+    // Identifier without extension
+    record[32] = 7;
+    record[33..40].copy_from_slice(b"README.");
+    record[0] = 40;
+
+    let entry = DirectoryEntry::parse(&record, false).unwrap();
+
+    assert!(entry.name() == "README");
+
+    // Truncated record
+    record[0] = 30;
+
+    assert!(DirectoryEntry::parse(&record, false).is_none());
+
+    // Joliet identifier
+    let name = [0x00, 0x65, 0x00, 0x6c, 0x00, 0xe9, 0x00, 0x3b, 0x00, 0x31];
+
+    record[32] = name.len() as u8;
+    record[33..43].copy_from_slice(&name);
+    record[0] = 44;
+
+    let entry = DirectoryEntry::parse(&record, true).unwrap();
+
+    assert!(entry.identifier() == "el\u{e9};1");
+    assert!(entry.name() == "el\u{e9}");
+}
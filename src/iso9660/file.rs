@@ -0,0 +1,164 @@
+//! Reader for the contents of a file
+
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::cmp;
+
+use CdError;
+use Image;
+
+use super::{Iso9660, DirectoryEntry};
+
+/// Handle to a file opened for reading. The file's contents are read
+/// one logical block at a time through `Image::read_sector`.
+pub struct File<'a, I: Image + 'a> {
+    /// Filesystem containing the file
+    fs: &'a mut Iso9660<I>,
+    /// Logical block address of the first block of the file
+    extent: u32,
+    /// Size of the file in bytes
+    size: u64,
+    /// Current position in the file
+    pos: u64,
+    /// Last block read from the disc
+    block: [u8; 2048],
+    /// Offset of `block` in the file (in blocks), if it's valid
+    cached: Option<u32>,
+}
+
+impl<'a, I: Image> File<'a, I> {
+    /// Create a new handle for the file described by `entry`
+    pub fn new(fs: &'a mut Iso9660<I>, entry: &DirectoryEntry) -> File<'a, I> {
+        File {
+            fs: fs,
+            extent: entry.extent(),
+            size: entry.size() as u64,
+            pos: 0,
+            block: [0; 2048],
+            cached: None,
+        }
+    }
+
+    /// Return the size of the file in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<'a, I: Image> Read for File<'a, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block = (self.pos / 2048) as u32;
+        let offset = (self.pos % 2048) as usize;
+
+        if self.cached != Some(block) {
+            let res = self.fs.read_block(self.extent + block,
+                                         &mut self.block);
+
+            if let Err(e) = res {
+                return Err(io_error(e));
+            }
+
+            self.cached = Some(block);
+        }
+
+        // Don't go past the end of the block or of the file
+        let len = cmp::min(buf.len(), 2048 - offset);
+        let len = cmp::min(len as u64, self.size - self.pos) as usize;
+
+        buf[..len].copy_from_slice(&self.block[offset..offset + len]);
+
+        self.pos += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<'a, I: Image> Seek for File<'a, I> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos =
+            match pos {
+                SeekFrom::Start(p) => p as i64,
+                SeekFrom::End(p) => self.size as i64 + p,
+                SeekFrom::Current(p) => self.pos as i64 + p,
+            };
+
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Attempted to seek before the \
+                                       beginning of the file"));
+        }
+
+        self.pos = pos as u64;
+
+        Ok(self.pos)
+    }
+}
+
+/// Convert a `CdError` into an `io::Error` for the `Read` interface
+pub fn io_error(e: CdError) -> io::Error {
+    match e {
+        CdError::IoError(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+#[test]
+fn read_and_seek() {
+    use iso::Iso;
+    use bcd::Bcd;
+    use test_util;
+    use super::{test_record, test_directory, test_volume};
+
+    // 5000 byte file spanning blocks 20 to 22
+    let contents: Vec<u8> = (0..5000).map(|i| (i * 13 % 251) as u8).collect();
+
+    let mut image = test_volume(23, &test_directory(19, 19, &[
+        test_record(b"DATA.BIN;1", 20, 5000, false),
+    ]));
+
+    image[20 * 2048..20 * 2048 + 5000].copy_from_slice(&contents);
+
+    let dir = test_util::temp_dir("iso9660-file");
+    let path = test_util::write_file(&dir, "file.iso", &image);
+
+    let mut fs = Iso9660::new(Iso::new(&path).unwrap(), Bcd::one()).unwrap();
+
+    let entry = fs.lookup("/DATA.BIN").unwrap().unwrap();
+    let mut file = fs.open(&entry).unwrap();
+
+    assert!(file.size() == 5000);
+
+    let mut data = Vec::new();
+
+    file.read_to_end(&mut data).unwrap();
+
+    assert!(data == contents);
+
+    // Across a block boundary
+    let mut buf = [0; 16];
+
+    assert!(file.seek(SeekFrom::Start(2040)).unwrap() == 2040);
+    file.read_exact(&mut buf).unwrap();
+    assert!(&buf[..] == &contents[2040..2056]);
+
+    assert!(file.seek(SeekFrom::Current(-6)).unwrap() == 2050);
+    file.read_exact(&mut buf).unwrap();
+    assert!(&buf[..] == &contents[2050..2066]);
+
+    assert!(file.seek(SeekFrom::End(-10)).unwrap() == 4990);
+
+    // Short read at the end of the file, then EOF
+    assert!(file.read(&mut buf).unwrap() == 10);
+    assert!(&buf[..10] == &contents[4990..]);
+    assert!(file.read(&mut buf).unwrap() == 0);
+
+    // Past the end of the file
+    assert!(file.seek(SeekFrom::End(100)).unwrap() == 5100);
+    assert!(file.read(&mut buf).unwrap() == 0);
+
+    assert!(file.seek(SeekFrom::Current(-6000)).is_err());
+}
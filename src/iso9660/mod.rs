@@ -0,0 +1,909 @@
+//! ISO 9660 filesystem reader
+//!
+//! Data tracks generally contain an ISO 9660 filesystem (sometimes
+//! called "CDFS"). The filesystem is described by a list of volume
+//! descriptors starting at the 16th sector of the track, the primary
+//! volume descriptor contains the location of the root directory and
+//! of the path table (a flat list of all the directories on the
+//! disc).
+//!
+//! The filesystem uses 2048 byte logical blocks which match the user
+//! data of Mode 1 and Mode 2 Form 1 sectors. Logical block addresses
+//! are absolute: block 0 is the sector at 00:02:00, even if the
+//! filesystem is in a later session.
+//!
+//! Two extensions work around the limitations of the ISO 9660 file
+//! names (8.3 upper case characters): Joliet stores a second
+//! directory hierarchy with UCS-2 names, described by a supplementary
+//! volume descriptor, and Rock Ridge adds POSIX attributes (long
+//! names, permissions, symbolic links...) to the system use area of
+//! the primary hierarchy's directory records. Like most operating
+//! systems we use Rock Ridge if it's available, otherwise Joliet.
+//!
+//! For more details see [ECMA-119]
+//! (http://www.ecma-international.org/publications/files/ECMA-ST/Ecma-119.pdf)
+
+use std::cmp;
+
+use CdError;
+use Image;
+use TrackFormat;
+
+use sector::Sector;
+use msf::Msf;
+use bcd::Bcd;
+
+pub use self::directory::{DirectoryEntry, FileFlags, Timestamp};
+pub use self::directory::{HIDDEN, DIRECTORY, ASSOCIATED, RECORD};
+pub use self::directory::{PROTECTION, MULTI_EXTENT};
+pub use self::file::File;
+pub use self::rock_ridge::{RockRidge, PosixAttributes};
+
+use self::rock_ridge::SuspParser;
+use self::xa::{XaAttributes, XaFile, XaLayout, XA_RECORD_LENGTH};
+
+mod directory;
+mod file;
+mod rock_ridge;
+pub mod xa;
+
+/// ISO 9660 filesystem on top of a data track of an `Image`
+pub struct Iso9660<I: Image> {
+    /// Underlying image
+    image: I,
+    /// Primary volume descriptor
+    volume: VolumeDescriptor,
+    /// Joliet supplementary volume descriptor, if any
+    joliet: Option<VolumeDescriptor>,
+    /// Extension used to decode the directory hierarchy
+    extension: Extension,
+    /// Root of the directory hierarchy in use
+    root: DirectoryEntry,
+    /// Number of bytes to skip at the beginning of the system use
+    /// area of each directory record when looking for Rock Ridge
+    /// entries
+    susp_skip: usize,
+    /// Path table of the directory hierarchy in use
+    path_table: Vec<PathTableEntry>,
+    /// Buffer used to read the sectors
+    sector: Sector,
+}
+
+impl<I: Image> Iso9660<I> {
+    /// Parse the volume descriptors and the path table of the
+    /// filesystem stored in `track`. Returns `CdError::BadFormat` if
+    /// `track` is an audio track or starts before 00:02:00.
+    pub fn new(image: I, track: Bcd) -> Result<Iso9660<I>, CdError> {
+        let start =
+            match image.toc().track(track) {
+                Some(t) => {
+                    match t.format {
+                        TrackFormat::Audio | TrackFormat::CdG =>
+                            return Err(CdError::BadFormat),
+                        _ => t.start,
+                    }
+                }
+                None => return Err(CdError::BadTrack),
+            };
+
+        let mut image = image;
+        let mut sector = Sector::empty();
+
+        let (volume, joliet) =
+            try!(read_volume_descriptors(&mut image, &mut sector, start));
+
+        let mut fs = Iso9660 {
+            image: image,
+            root: volume.root.clone(),
+            volume: volume,
+            joliet: joliet,
+            extension: Extension::None,
+            susp_skip: 0,
+            path_table: Vec::new(),
+            sector: sector,
+        };
+
+        // Rock Ridge is advertised by an `SP` entry in the system use
+        // area of the root directory's "." entry
+        let root = fs.root.clone();
+        let dot = try!(fs.read_dir(&root)).into_iter().next();
+
+        if let Some(mut dot) = dot {
+            let mut susp = SuspParser::new();
+
+            // On XA discs the SUSP entries come after the XA record
+            let offset =
+                match XaAttributes::parse(dot.system_use()) {
+                    Some(_) => XA_RECORD_LENGTH,
+                    None => 0,
+                };
+
+            susp.parse(&dot.system_use()[offset..]);
+
+            if let Some(skip) = susp.skip() {
+                let skip = offset + skip;
+
+                try!(fs.load_rock_ridge(&mut dot, skip));
+
+                if dot.rock_ridge().is_some() {
+                    fs.extension = Extension::RockRidge;
+                    fs.susp_skip = skip;
+                }
+            }
+        }
+
+        if fs.extension == Extension::None {
+            if let Some(root) = fs.joliet.as_ref().map(|j| j.root.clone()) {
+                fs.extension = Extension::Joliet;
+                fs.root = root;
+            }
+        }
+
+        fs.path_table = {
+            let volume =
+                match fs.extension {
+                    Extension::Joliet => fs.joliet.as_ref().unwrap(),
+                    _ => &fs.volume,
+                };
+
+            try!(read_path_table(&mut fs.image, &mut fs.sector, volume))
+        };
+
+        Ok(fs)
+    }
+
+    /// Return the primary volume descriptor
+    pub fn volume(&self) -> &VolumeDescriptor {
+        &self.volume
+    }
+
+    /// Return the Joliet supplementary volume descriptor, if any
+    pub fn joliet_volume(&self) -> Option<&VolumeDescriptor> {
+        self.joliet.as_ref()
+    }
+
+    /// Return the extension used to decode the directory hierarchy
+    pub fn extension(&self) -> Extension {
+        self.extension
+    }
+
+    /// Return the path table: all the directories in the filesystem.
+    /// The first entry is the root directory.
+    pub fn path_table(&self) -> &[PathTableEntry] {
+        &self.path_table
+    }
+
+    /// Return the entry of the root directory
+    pub fn root(&self) -> &DirectoryEntry {
+        &self.root
+    }
+
+    /// Return the entries of the directory `dir`, including the "."
+    /// and ".." entries. With Rock Ridge the directories relocated to
+    /// work around the depth limit are listed at their original
+    /// location. Returns `CdError::BadFormat` if `dir` is not a
+    /// directory.
+    pub fn read_dir(&mut self,
+                    dir: &DirectoryEntry) -> Result<Vec<DirectoryEntry>,
+                                                    CdError> {
+        if !dir.is_dir() {
+            return Err(CdError::BadFormat);
+        }
+
+        let data = try!(read_extent(&mut self.image,
+                                    &mut self.sector,
+                                    dir.extent(),
+                                    dir.size()));
+
+        let joliet = self.extension == Extension::Joliet;
+
+        let entries = try!(directory::parse_directory(&data, joliet));
+
+        if self.extension != Extension::RockRidge {
+            return Ok(entries);
+        }
+
+        let mut listing = Vec::with_capacity(entries.len());
+
+        for mut entry in entries {
+            let skip = self.susp_skip;
+
+            try!(self.load_rock_ridge(&mut entry, skip));
+
+            let (relocated, child_link) =
+                match entry.rock_ridge() {
+                    Some(rr) => (rr.relocated(), rr.child_link()),
+                    None => (false, None),
+                };
+
+            if relocated {
+                continue;
+            }
+
+            if let Some(extent) = child_link {
+                // The size of the relocated directory is in its "."
+                // entry
+                let mut block = [0; 2048];
+
+                try!(self.read_block(extent, &mut block));
+
+                let size =
+                    match DirectoryEntry::parse(&block, false) {
+                        Some(dot) => dot.size(),
+                        None => return Err(bad_fs("Invalid relocated \
+                                                   directory")),
+                    };
+
+                directory::relocate(&mut entry, extent, size);
+            }
+
+            listing.push(entry);
+        }
+
+        Ok(listing)
+    }
+
+    /// Look up the entry at `path`. The components of `path` are
+    /// separated by slashes and compared case-insensitively with the
+    /// entry names (without their version number). Returns `Ok(None)`
+    /// if the path doesn't exist.
+    pub fn lookup(&mut self,
+                  path: &str) -> Result<Option<DirectoryEntry>, CdError> {
+        let mut entry = self.root.clone();
+
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if !entry.is_dir() {
+                return Ok(None);
+            }
+
+            let entries = try!(self.read_dir(&entry));
+
+            match entries.into_iter()
+                .find(|e| e.name().eq_ignore_ascii_case(component)) {
+                    Some(e) => entry = e,
+                    None => return Ok(None),
+                }
+        }
+
+        Ok(Some(entry))
+    }
+
+    /// Open the file described by `entry` for reading. Returns
+    /// `CdError::BadFormat` if `entry` is a directory.
+    ///
+    /// Only the first 2048 bytes of Form 2 sectors are returned, use
+    /// `open_xa` to read Form 2 files (XA audio, video...) intact.
+    pub fn open(&mut self,
+                entry: &DirectoryEntry) -> Result<File<I>, CdError> {
+        if entry.is_dir() {
+            return Err(CdError::BadFormat);
+        }
+
+        Ok(File::new(self, entry))
+    }
+
+    /// Open the Mode 2 file described by `entry` for reading full
+    /// sectors in the given `layout`. Returns `CdError::BadFormat` if
+    /// `entry` is a directory.
+    pub fn open_xa(&mut self,
+                   entry: &DirectoryEntry,
+                   layout: XaLayout) -> Result<XaFile<I>, CdError> {
+        if entry.is_dir() {
+            return Err(CdError::BadFormat);
+        }
+
+        Ok(XaFile::new(self, entry, layout))
+    }
+
+    /// Decode the Rock Ridge entries in the system use area of
+    /// `entry` (after the first `skip` bytes) and in the continuation
+    /// areas it points to
+    fn load_rock_ridge(&mut self,
+                       entry: &mut DirectoryEntry,
+                       skip: usize) -> Result<(), CdError> {
+        let mut susp = SuspParser::new();
+
+        if skip < entry.system_use().len() {
+            susp.parse(&entry.system_use()[skip..]);
+        }
+
+        // Limit the number of continuation areas in case of loops
+        for _ in 0..MAX_CONTINUATIONS {
+            let (block, offset, len) =
+                match susp.take_continuation() {
+                    Some(c) => c,
+                    None => break,
+                };
+
+            // The area can span several sectors
+            let end = offset as u64 + len as u64;
+
+            if end > 0x10000 {
+                return Err(bad_fs("Invalid SUSP continuation area"));
+            }
+
+            let data = try!(read_extent(&mut self.image,
+                                        &mut self.sector,
+                                        block,
+                                        end as u32));
+
+            susp.parse(&data[offset as usize..]);
+        }
+
+        if let Some(rr) = susp.finish() {
+            directory::set_rock_ridge(entry, rr);
+        }
+
+        Ok(())
+    }
+
+    /// Read the 2048 bytes of user data of logical block `lba`. Form
+    /// 2 sectors are truncated to 2048 bytes.
+    pub fn read_block(&mut self,
+                      lba: u32,
+                      block: &mut [u8; 2048]) -> Result<(), CdError> {
+        read_block(&mut self.image, &mut self.sector, lba, block)
+    }
+
+    /// Return a reference to the underlying image
+    pub fn image(&self) -> &I {
+        &self.image
+    }
+
+    /// Remove the filesystem layer and return the underlying image
+    pub fn into_inner(self) -> I {
+        self.image
+    }
+}
+
+/// Read the 2048 bytes of user data of logical block `lba` using
+/// `sector` as a buffer
+fn read_block<I: Image>(image: &mut I,
+                        sector: &mut Sector,
+                        lba: u32,
+                        block: &mut [u8; 2048]) -> Result<(), CdError> {
+    let msf = try!(lba_msf(lba));
+
+    try!(image.read_sector(sector, msf));
+
+    match sector.metadata().format {
+        TrackFormat::Mode1 =>
+            block.copy_from_slice(try!(sector.mode1_payload())),
+        TrackFormat::Mode2Xa | TrackFormat::Mode2CdI => {
+            let payload = try!(sector.mode2_xa_payload());
+
+            block.copy_from_slice(&payload[..2048]);
+        }
+        TrackFormat::Audio | TrackFormat::CdG =>
+            return Err(CdError::BadFormat),
+    }
+
+    Ok(())
+}
+
+/// Convert a logical block address into the MSF of the sector
+fn lba_msf(lba: u32) -> Result<Msf, CdError> {
+    match Msf::from_sector_index(lba + 150) {
+        Some(m) => Ok(m),
+        None => Err(CdError::LeadOut),
+    }
+}
+
+/// Read the first `len` bytes of the extent starting at `lba`
+fn read_extent<I: Image>(image: &mut I,
+                         sector: &mut Sector,
+                         lba: u32,
+                         len: u32) -> Result<Vec<u8>, CdError> {
+    let len = len as usize;
+
+    // `len` comes from the disc, let the buffer grow as the blocks
+    // are actually read instead of trusting it for the allocation
+    let mut data = Vec::new();
+    let mut block = [0; 2048];
+    let mut lba = lba;
+
+    while data.len() < len {
+        try!(read_block(image, sector, lba, &mut block));
+
+        let remaining = cmp::min(len - data.len(), 2048);
+
+        data.extend_from_slice(&block[..remaining]);
+
+        lba += 1;
+    }
+
+    Ok(data)
+}
+
+/// Go through the volume descriptor set starting at the 16th sector
+/// of the track at `start` and parse the primary volume descriptor
+/// and the Joliet supplementary volume descriptor, if any
+fn read_volume_descriptors<I: Image>(image: &mut I,
+                                     sector: &mut Sector,
+                                     start: Msf)
+                                     -> Result<(VolumeDescriptor,
+                                                Option<VolumeDescriptor>),
+                                               CdError> {
+    // The filesystem can't start before 00:02:00 (logical block 0)
+    let mut lba =
+        match start.sector_index().checked_sub(150) {
+            Some(lba) => lba + 16,
+            None => return Err(CdError::BadFormat),
+        };
+    let mut block = [0; 2048];
+
+    let mut primary = None;
+    let mut joliet = None;
+
+    loop {
+        try!(read_block(image, sector, lba, &mut block));
+
+        if &block[1..6] != b"CD001" {
+            return Err(bad_fs("Missing volume descriptor"));
+        }
+
+        match block[0] {
+            1 if primary.is_none() =>
+                primary = Some(try!(VolumeDescriptor::parse(&block, false))),
+            // Supplementary volume descriptor, the escape sequence
+            // tells us if it's Joliet (UCS-2 level 1, 2 or 3)
+            2 if joliet.is_none() && is_joliet(&block[88..120]) =>
+                joliet = Some(try!(VolumeDescriptor::parse(&block, true))),
+            // Volume descriptor set terminator
+            255 => break,
+            // Boot record, partition descriptor...
+            _ => (),
+        }
+
+        lba += 1;
+    }
+
+    match primary {
+        Some(p) => Ok((p, joliet)),
+        None => Err(bad_fs("Missing primary volume descriptor")),
+    }
+}
+
+/// Return true if the escape sequences of a supplementary volume
+/// descriptor identify a Joliet volume
+fn is_joliet(escapes: &[u8]) -> bool {
+    match &escapes[0..3] {
+        b"%/@" | b"%/C" | b"%/E" => true,
+        _ => false,
+    }
+}
+
+/// Load the type L (little-endian) path table of `volume`
+fn read_path_table<I: Image>(image: &mut I,
+                             sector: &mut Sector,
+                             volume: &VolumeDescriptor)
+                             -> Result<Vec<PathTableEntry>, CdError> {
+    let table =
+        try!(read_extent(image,
+                         sector,
+                         volume.path_table,
+                         volume.path_table_size));
+
+    let mut entries = Vec::new();
+    let mut pos = 0;
+
+    while pos + 8 <= table.len() {
+        let name_len = table[pos] as usize;
+        let end = pos + 8 + name_len;
+
+        if name_len == 0 || end > table.len() {
+            return Err(bad_fs("Invalid path table entry"));
+        }
+
+        let name =
+            match &table[pos + 8..end] {
+                b"\0" => String::new(),
+                n if volume.joliet => directory::ucs2(n),
+                n => String::from_utf8_lossy(n).into_owned(),
+            };
+
+        entries.push(PathTableEntry {
+            name: name,
+            extent: le32(&table[pos + 2..pos + 6]),
+            parent: le16(&table[pos + 6..pos + 8]),
+        });
+
+        // Entries are padded to an even length
+        pos = end + name_len % 2;
+    }
+
+    Ok(entries)
+}
+
+/// Directory hierarchy extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    /// Plain ISO 9660 hierarchy
+    None,
+    /// Joliet hierarchy with UCS-2 names
+    Joliet,
+    /// ISO 9660 hierarchy with Rock Ridge attributes
+    RockRidge,
+}
+
+/// Primary or supplementary volume descriptor
+#[derive(Debug, Clone)]
+pub struct VolumeDescriptor {
+    /// Identifier of the system which can act upon the first 16
+    /// sectors of the track
+    system_id: String,
+    /// Name of the volume
+    volume_id: String,
+    /// Number of logical blocks in the volume
+    volume_space_size: u32,
+    /// Name of the volume set
+    volume_set_id: String,
+    /// Publisher of the volume
+    publisher_id: String,
+    /// Person or entity which prepared the data
+    data_preparer_id: String,
+    /// Application used to create the volume
+    application_id: String,
+    /// Size of the path table in bytes
+    path_table_size: u32,
+    /// Location of the type L path table
+    path_table: u32,
+    /// Root directory record
+    root: DirectoryEntry,
+    /// True if this is a Joliet supplementary volume descriptor
+    joliet: bool,
+}
+
+impl VolumeDescriptor {
+    /// Parse a volume descriptor. The identifiers of Joliet
+    /// descriptors are encoded in UCS-2.
+    fn parse(block: &[u8; 2048],
+             joliet: bool) -> Result<VolumeDescriptor, CdError> {
+        let block_size = le16(&block[128..130]);
+
+        if block_size != 2048 {
+            let error = format!("Unsupported logical block size {}",
+                                block_size);
+            return Err(CdError::BadFilesystem(error));
+        }
+
+        let root =
+            match DirectoryEntry::parse(&block[156..190], joliet) {
+                Some(r) => r,
+                None => return Err(bad_fs("Invalid root directory record")),
+            };
+
+        let string = |b: &[u8]| {
+            if joliet {
+                directory::ucs2(b).trim_matches(|c| c == ' ' || c == '\0')
+                    .to_string()
+            } else {
+                string(b)
+            }
+        };
+
+        Ok(VolumeDescriptor {
+            system_id: string(&block[8..40]),
+            volume_id: string(&block[40..72]),
+            volume_space_size: le32(&block[80..84]),
+            volume_set_id: string(&block[190..318]),
+            publisher_id: string(&block[318..446]),
+            data_preparer_id: string(&block[446..574]),
+            application_id: string(&block[574..702]),
+            path_table_size: le32(&block[132..136]),
+            path_table: le32(&block[140..144]),
+            root: root,
+            joliet: joliet,
+        })
+    }
+
+    /// Return true if this is a Joliet supplementary volume
+    /// descriptor
+    pub fn is_joliet(&self) -> bool {
+        self.joliet
+    }
+
+    /// Return the system identifier ("PLAYSTATION" for instance)
+    pub fn system_id(&self) -> &str {
+        &self.system_id
+    }
+
+    /// Return the name of the volume
+    pub fn volume_id(&self) -> &str {
+        &self.volume_id
+    }
+
+    /// Return the size of the volume in logical blocks
+    pub fn volume_space_size(&self) -> u32 {
+        self.volume_space_size
+    }
+
+    /// Return the name of the volume set
+    pub fn volume_set_id(&self) -> &str {
+        &self.volume_set_id
+    }
+
+    /// Return the publisher of the volume
+    pub fn publisher_id(&self) -> &str {
+        &self.publisher_id
+    }
+
+    /// Return the person or entity which prepared the data
+    pub fn data_preparer_id(&self) -> &str {
+        &self.data_preparer_id
+    }
+
+    /// Return the application used to create the volume
+    pub fn application_id(&self) -> &str {
+        &self.application_id
+    }
+}
+
+/// Path table entry describing a single directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathTableEntry {
+    /// Directory name, empty for the root directory
+    pub name: String,
+    /// Location of the directory's first block
+    pub extent: u32,
+    /// Position of the parent directory in the path table, starting
+    /// at 1. The root directory is its own parent.
+    pub parent: u16,
+}
+
+/// Max number of SUSP continuation areas for a single directory
+/// record, used to avoid infinite loops on corrupted filesystems
+const MAX_CONTINUATIONS: u32 = 32;
+
+/// Build a `CdError::BadFilesystem` error
+fn bad_fs(msg: &str) -> CdError {
+    CdError::BadFilesystem(msg.to_string())
+}
+
+/// Decode a space-padded identifier
+fn string(b: &[u8]) -> String {
+    let len =
+        match b.iter().rposition(|&c| c != b' ' && c != 0) {
+            Some(p) => p + 1,
+            None => 0,
+        };
+
+    String::from_utf8_lossy(&b[..len]).into_owned()
+}
+
+/// Decode a little-endian 16 bit value
+fn le16(b: &[u8]) -> u16 {
+    b[0] as u16 | (b[1] as u16) << 8
+}
+
+/// Decode a little-endian 32 bit value. Most values in the
+/// filesystem are stored in both byte orders, we only use the
+/// little-endian copy.
+fn le32(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 |
+    (b[3] as u32) << 24
+}
+
+/// Build a directory record for `name` (raw identifier bytes)
+#[cfg(test)]
+fn test_record(name: &[u8], extent: u32, size: u32, dir: bool) -> Vec<u8> {
+    let len = 33 + name.len() + (1 - name.len() % 2);
+    let mut record = vec![0; len];
+
+    record[0] = len as u8;
+    record[2..6].copy_from_slice(&test_le32(extent));
+    record[10..14].copy_from_slice(&test_le32(size));
+    record[25] = if dir { 2 } else { 0 };
+    record[32] = name.len() as u8;
+    record[33..33 + name.len()].copy_from_slice(name);
+
+    record
+}
+
+#[cfg(test)]
+fn test_le32(v: u32) -> [u8; 4] {
+    [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+}
+
+/// Encode `s` in big-endian UCS-2 like Joliet identifiers
+#[cfg(test)]
+fn test_ucs2(s: &str) -> Vec<u8> {
+    s.chars().flat_map(|c| vec![(c as u32 >> 8) as u8, c as u8]).collect()
+}
+
+/// Append the system use area `su` to the directory record `record`
+#[cfg(test)]
+fn test_system_use(record: Vec<u8>, su: &[u8]) -> Vec<u8> {
+    let mut record = record;
+
+    record.extend_from_slice(su);
+
+    // Directory records have an even length
+    if record.len() % 2 != 0 {
+        record.push(0);
+    }
+
+    record[0] = record.len() as u8;
+
+    record
+}
+
+/// Build the records of the directory at `dot`, starting with the
+/// "." and ".." entries
+#[cfg(test)]
+fn test_directory(dot: u32, parent: u32, entries: &[Vec<u8>]) -> Vec<u8> {
+    let mut dir = test_record(b"\0", dot, 2048, true);
+
+    dir.extend(test_record(b"\x01", parent, 2048, true));
+
+    for e in entries {
+        dir.extend_from_slice(e);
+    }
+
+    dir
+}
+
+/// Build a volume of `blocks` logical blocks with the primary volume
+/// descriptor in block 16, the terminator in block 17, the path table
+/// in block 18 and the `root` directory in block 19
+#[cfg(test)]
+fn test_volume(blocks: u32, root: &[u8]) -> Vec<u8> {
+    let mut image = vec![0; blocks as usize * 2048];
+
+    test_block(&mut image, 16, b"\x01CD001\x01");
+    test_block(&mut image, 17, b"\xffCD001\x01");
+    test_block(&mut image, 18, &[1, 0, 19, 0, 0, 0, 1, 0, 0, 0]);
+    test_block(&mut image, 19, root);
+
+    let pvd = &mut image[16 * 2048..17 * 2048];
+
+    pvd[80..84].copy_from_slice(&test_le32(blocks));
+    pvd[128..130].copy_from_slice(&[0x00, 0x08]);
+    pvd[132..136].copy_from_slice(&test_le32(10));
+    pvd[140..144].copy_from_slice(&test_le32(18));
+    pvd[156..190].copy_from_slice(&test_record(b"\0", 19, 2048, true));
+
+    image
+}
+
+/// Copy `data` at the beginning of block `n` of `image`
+#[cfg(test)]
+fn test_block(image: &mut [u8], n: usize, data: &[u8]) {
+    image[n * 2048..n * 2048 + data.len()].copy_from_slice(data);
+}
+
+#[test]
+fn joliet_lookup() {
+    use iso::Iso;
+    use test_util;
+
+    // Block 16: PVD, 17: Joliet SVD, 18: terminator, 19-20: path
+    // tables, 21-24: directories, 25: file data
+    let mut image = vec![0; 26 * 2048];
+
+    {
+        let mut block = |n: usize, data: &[u8]| {
+            image[n * 2048..n * 2048 + data.len()].copy_from_slice(data);
+        };
+
+        for &(n, ty, path_table, root) in &[(16, 1, 19, 21),
+                                              (17, 2, 20, 22)] {
+            let mut vd = vec![0; 2048];
+
+            vd[0] = ty;
+            vd[1..6].copy_from_slice(b"CD001");
+            vd[6] = 1;
+            vd[80..84].copy_from_slice(&test_le32(26));
+            vd[128..130].copy_from_slice(&[0x00, 0x08]);
+            vd[132..136].copy_from_slice(&test_le32(10));
+            vd[140..144].copy_from_slice(&test_le32(path_table));
+            vd[156..190].copy_from_slice(
+                &test_record(b"\0", root, 2048, true));
+
+            if ty == 2 {
+                vd[88..91].copy_from_slice(b"%/E");
+            }
+
+            block(n, &vd);
+            block(path_table as usize,
+                  &[1, 0, root as u8, 0, 0, 0, 1, 0, 0, 0]);
+        }
+
+        block(18, b"\xffCD001\x01");
+
+        // Primary hierarchy with 8.3 names
+        block(21, &test_directory(21, 21, &[
+            test_record(b"SUBDIR", 23, 2048, true),
+        ]));
+        block(23, &test_directory(23, 21, &[
+            test_record(b"NESTED.TXT;1", 25, 5, false),
+        ]));
+
+        // Joliet hierarchy with long names
+        block(22, &test_directory(22, 22, &[
+            test_record(&test_ucs2("Sub Directory"), 24, 2048, true),
+        ]));
+        block(24, &test_directory(24, 22, &[
+            test_record(&test_ucs2("Nested File.txt;1"), 25, 5, false),
+        ]));
+
+        block(25, b"hello");
+    }
+
+    let dir = test_util::temp_dir("joliet-lookup");
+    let path = test_util::write_file(&dir, "joliet.iso", &image);
+
+    let mut fs = Iso9660::new(Iso::new(&path).unwrap(), Bcd::one()).unwrap();
+
+    assert!(fs.extension() == Extension::Joliet);
+
+    let entry = fs.lookup("/Sub Directory/nested file.TXT").unwrap().unwrap();
+
+    assert!(entry.name() == "Nested File.txt");
+    assert!(entry.extent() == 25);
+    assert!(entry.size() == 5);
+
+    assert!(fs.lookup("/sub directory").unwrap().unwrap().is_dir());
+    assert!(fs.lookup("/").unwrap().unwrap().extent() == 22);
+
+    // The primary hierarchy's names aren't visible
+    assert!(fs.lookup("/SUBDIR/NESTED.TXT").unwrap().is_none());
+    assert!(fs.lookup("/Sub Directory/Missing.txt").unwrap().is_none());
+}
+
+/// Image whose table of contents has been tampered with
+#[cfg(test)]
+struct TestToc(::iso::Iso, ::toc::Toc);
+
+#[cfg(test)]
+impl Image for TestToc {
+    fn image_format(&self) -> String {
+        self.0.image_format()
+    }
+
+    fn read_sector(&mut self,
+                   sector: &mut Sector,
+                   msf: Msf) -> Result<(), CdError> {
+        self.0.read_sector(sector, msf)
+    }
+
+    fn track_msf(&self, track: Bcd, track_msf: Msf) -> Result<Msf, CdError> {
+        self.0.track_msf(track, track_msf)
+    }
+
+    fn toc(&self) -> ::toc::Toc {
+        self.1.clone()
+    }
+}
+
+#[test]
+fn untrusted_sizes() {
+    use iso::Iso;
+    use test_util;
+
+    // The directory claims to be almost 4GB long
+    let image = test_volume(20, &test_directory(19, 19, &[
+        test_record(b"HUGE", 19, 0xfffff800, true),
+    ]));
+
+    let dir = test_util::temp_dir("iso9660-untrusted");
+    let path = test_util::write_file(&dir, "untrusted.iso", &image);
+
+    let mut fs = Iso9660::new(Iso::new(&path).unwrap(), Bcd::one()).unwrap();
+
+    let huge = fs.lookup("/HUGE").unwrap().unwrap();
+
+    // Reading stops at the end of the image
+    assert!(fs.read_dir(&huge).is_err());
+
+    // Track starting before the first logical block
+    let iso = Iso::new(&path).unwrap();
+    let mut toc = iso.toc();
+
+    toc.sessions[0].tracks[0].start = Msf::zero();
+
+    match Iso9660::new(TestToc(iso, toc), Bcd::one()) {
+        Err(CdError::BadFormat) => (),
+        _ => panic!("Track starting at 00:00:00 accepted"),
+    }
+}
@@ -0,0 +1,515 @@
+//! Rock Ridge extensions
+//!
+//! Rock Ridge (IEEE P1282) stores POSIX file attributes in the system
+//! use area of the directory records, using the System Use Sharing
+//! Protocol (SUSP, IEEE P1281). The system use area contains a list
+//! of entries, each starting with a two character signature, its
+//! length and a version number. If the entries don't fit in the
+//! directory record they continue in a "continuation area" somewhere
+//! else on the disc, pointed to by a `CE` entry.
+
+use super::{le32, Timestamp};
+
+/// Rock Ridge attributes of a directory entry
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RockRidge {
+    /// Alternate name (`NM`)
+    name: Option<String>,
+    /// POSIX file attributes (`PX`)
+    attributes: Option<PosixAttributes>,
+    /// Symbolic link target (`SL`)
+    symlink: Option<String>,
+    /// Creation time (`TF`)
+    created: Option<Timestamp>,
+    /// Modification time (`TF`)
+    modified: Option<Timestamp>,
+    /// Last access time (`TF`)
+    accessed: Option<Timestamp>,
+    /// Last attribute change time (`TF`)
+    attributes_changed: Option<Timestamp>,
+    /// True if this is a directory which has been moved to work
+    /// around the ISO 9660 depth limit (`RE`). It's listed in its
+    /// original location as well and should be hidden here.
+    relocated: bool,
+    /// Location of the directory this entry stands for, if it's been
+    /// relocated (`CL`)
+    child_link: Option<u32>,
+}
+
+impl RockRidge {
+    /// Return the alternate name of the entry, if any
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|n| &**n)
+    }
+
+    /// Return the POSIX file attributes, if any
+    pub fn attributes(&self) -> Option<PosixAttributes> {
+        self.attributes
+    }
+
+    /// Return the target of the symbolic link if the entry is one
+    pub fn symlink(&self) -> Option<&str> {
+        self.symlink.as_ref().map(|s| &**s)
+    }
+
+    /// Return the creation time, if any
+    pub fn created(&self) -> Option<Timestamp> {
+        self.created
+    }
+
+    /// Return the modification time, if any
+    pub fn modified(&self) -> Option<Timestamp> {
+        self.modified
+    }
+
+    /// Return the time of last access, if any
+    pub fn accessed(&self) -> Option<Timestamp> {
+        self.accessed
+    }
+
+    /// Return the time of the last attribute change, if any
+    pub fn attributes_changed(&self) -> Option<Timestamp> {
+        self.attributes_changed
+    }
+
+    /// Return true if this is a relocated directory which should be
+    /// hidden from the listing
+    pub fn relocated(&self) -> bool {
+        self.relocated
+    }
+
+    /// Return the location of the relocated directory this entry
+    /// stands for, if any
+    pub fn child_link(&self) -> Option<u32> {
+        self.child_link
+    }
+}
+
+/// POSIX file attributes (`PX` entry)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PosixAttributes {
+    /// File mode, in the `st_mode` format: file type and permissions
+    pub mode: u32,
+    /// Number of links
+    pub links: u32,
+    /// User ID of the owner
+    pub uid: u32,
+    /// Group ID of the owner
+    pub gid: u32,
+}
+
+/// Parser for a chain of SUSP areas (the directory record's system
+/// use area followed by the continuation areas)
+pub struct SuspParser {
+    /// Attributes decoded so far
+    rock_ridge: RockRidge,
+    /// True if at least one Rock Ridge entry has been found
+    found: bool,
+    /// Name being built out of `NM` entries
+    name: Vec<u8>,
+    /// True if the next `NM` entry continues the name
+    name_continues: bool,
+    /// Symbolic link being built out of `SL` entries
+    symlink: Vec<u8>,
+    /// True if the next `SL` entry continues the symbolic link
+    symlink_continues: bool,
+    /// True if the next `SL` component continues the current one
+    component_continues: bool,
+    /// Length of the skipped area at the beginning of each system use
+    /// area, from the `SP` entry
+    skip: Option<usize>,
+    /// Next continuation area: block, offset and length
+    continuation: Option<(u32, u32, u32)>,
+}
+
+impl SuspParser {
+    /// Create a new parser
+    pub fn new() -> SuspParser {
+        SuspParser {
+            rock_ridge: RockRidge::default(),
+            found: false,
+            name: Vec::new(),
+            name_continues: false,
+            symlink: Vec::new(),
+            symlink_continues: false,
+            component_continues: false,
+            skip: None,
+            continuation: None,
+        }
+    }
+
+    /// Parse the entries in `area`. Parsing stops at the first
+    /// malformed entry the same way most implementations do.
+    pub fn parse(&mut self, area: &[u8]) {
+        let mut pos = 0;
+
+        while pos + 4 <= area.len() {
+            let len = area[pos + 2] as usize;
+
+            if len < 4 || pos + len > area.len() {
+                break;
+            }
+
+            let entry = &area[pos..pos + len];
+
+            match &entry[0..2] {
+                // Terminator
+                b"ST" => break,
+                b"SP" if len >= 7 && entry[4..6] == [0xbe, 0xef] =>
+                    self.skip = Some(entry[6] as usize),
+                b"CE" if len >= 28 =>
+                    self.continuation = Some((le32(&entry[4..8]),
+                                              le32(&entry[12..16]),
+                                              le32(&entry[20..24]))),
+                // Extension reference, old Rock Ridge marker and
+                // entries we don't use
+                b"ER" | b"RR" | b"PN" | b"PL" | b"SF" => self.found = true,
+                b"NM" if len >= 5 => self.parse_name(entry),
+                b"PX" if len >= 36 => self.parse_attributes(entry),
+                b"SL" if len >= 5 => self.parse_symlink(entry),
+                b"TF" if len >= 5 => self.parse_timestamps(entry),
+                b"RE" => {
+                    self.found = true;
+                    self.rock_ridge.relocated = true;
+                }
+                b"CL" if len >= 12 => {
+                    self.found = true;
+                    self.rock_ridge.child_link = Some(le32(&entry[4..8]));
+                }
+                _ => (),
+            }
+
+            pos += len;
+        }
+    }
+
+    /// Return the length of the area to skip at the beginning of each
+    /// system use area, if an `SP` entry has been found
+    pub fn skip(&self) -> Option<usize> {
+        self.skip
+    }
+
+    /// Return the next continuation area to parse, if any: block,
+    /// offset within the block and length
+    pub fn take_continuation(&mut self) -> Option<(u32, u32, u32)> {
+        self.continuation.take()
+    }
+
+    /// Return the decoded attributes or `None` if there weren't any
+    /// Rock Ridge entries
+    pub fn finish(mut self) -> Option<RockRidge> {
+        if !self.found {
+            return None;
+        }
+
+        if !self.name.is_empty() {
+            let name = String::from_utf8_lossy(&self.name).into_owned();
+
+            self.rock_ridge.name = Some(name);
+        }
+
+        if !self.symlink.is_empty() {
+            let link = String::from_utf8_lossy(&self.symlink).into_owned();
+
+            self.rock_ridge.symlink = Some(link);
+        }
+
+        Some(self.rock_ridge)
+    }
+
+    /// Alternate name (`NM`)
+    fn parse_name(&mut self, entry: &[u8]) {
+        let flags = entry[4];
+
+        self.found = true;
+
+        if !self.name_continues {
+            self.name.clear();
+        }
+
+        if flags & 0x2 != 0 {
+            self.name.extend_from_slice(b".");
+        } else if flags & 0x4 != 0 {
+            self.name.extend_from_slice(b"..");
+        } else {
+            self.name.extend_from_slice(&entry[5..]);
+        }
+
+        self.name_continues = flags & 0x1 != 0;
+    }
+
+    /// POSIX file attributes (`PX`)
+    fn parse_attributes(&mut self, entry: &[u8]) {
+        self.found = true;
+
+        self.rock_ridge.attributes = Some(PosixAttributes {
+            mode: le32(&entry[4..8]),
+            links: le32(&entry[12..16]),
+            uid: le32(&entry[20..24]),
+            gid: le32(&entry[28..32]),
+        });
+    }
+
+    /// Symbolic link (`SL`). The target is split into component
+    /// records, each one containing a single path component which
+    /// can itself be split across several records.
+    fn parse_symlink(&mut self, entry: &[u8]) {
+        self.found = true;
+
+        if !self.symlink_continues {
+            self.symlink.clear();
+            self.component_continues = false;
+        }
+
+        let mut pos = 5;
+
+        while pos + 2 <= entry.len() {
+            let flags = entry[pos];
+            let len = entry[pos + 1] as usize;
+
+            if pos + 2 + len > entry.len() {
+                break;
+            }
+
+            let content = &entry[pos + 2..pos + 2 + len];
+
+            let new_component = !self.component_continues;
+
+            if new_component && !self.symlink.is_empty() &&
+                self.symlink != b"/" {
+                self.symlink.push(b'/');
+            }
+
+            if flags & 0x2 != 0 {
+                self.symlink.extend_from_slice(b".");
+            } else if flags & 0x4 != 0 {
+                self.symlink.extend_from_slice(b"..");
+            } else if flags & 0x8 != 0 {
+                self.symlink.clear();
+                self.symlink.push(b'/');
+            } else {
+                self.symlink.extend_from_slice(content);
+            }
+
+            self.component_continues = flags & 0x1 != 0;
+
+            pos += 2 + len;
+        }
+
+        self.symlink_continues = entry[4] & 0x1 != 0;
+    }
+
+    /// Timestamps (`TF`)
+    fn parse_timestamps(&mut self, entry: &[u8]) {
+        let flags = entry[4];
+
+        self.found = true;
+
+        // Long form timestamps use the 17 byte volume descriptor
+        // format
+        let size = if flags & 0x80 != 0 { 17 } else { 7 };
+
+        let mut pos = 5;
+
+        // The timestamps are stored in the order of the flag bits:
+        // creation, modification, access, attributes, backup,
+        // expiration and effective.
+        for bit in 0..7 {
+            if flags & (1 << bit) == 0 {
+                continue;
+            }
+
+            if pos + size > entry.len() {
+                break;
+            }
+
+            let raw = &entry[pos..pos + size];
+
+            let timestamp =
+                if size == 17 {
+                    Timestamp::from_volume_descriptor(array_ref![raw, 0, 17])
+                } else {
+                    Timestamp::from_directory_record(array_ref![raw, 0, 7])
+                };
+
+            match bit {
+                0 => self.rock_ridge.created = Some(timestamp),
+                1 => self.rock_ridge.modified = Some(timestamp),
+                2 => self.rock_ridge.accessed = Some(timestamp),
+                3 => self.rock_ridge.attributes_changed = Some(timestamp),
+                _ => (),
+            }
+
+            pos += size;
+        }
+    }
+}
+
+#[test]
+fn susp_entries() {
+    let mut area = Vec::new();
+
+    // Name split in two entries
+    area.extend_from_slice(b"NM\x09\x01\x01long");
+    area.extend_from_slice(b"NM\x0a\x01\x00_name");
+    // rwxr-xr-x regular file
+    area.extend_from_slice(b"PX\x24\x01");
+    for &v in &[0o100755u32, 1, 1000, 100] {
+        let le = [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8];
+        area.extend_from_slice(&le);
+        area.extend_from_slice(&[le[3], le[2], le[1], le[0]]);
+    }
+    // Link to "/usr/../lib", "lib" split across two components
+    area.extend_from_slice(b"SL\x15\x01\x00\x08\x00\x00\x03usr\x04\x00\
+                             \x01\x02li\x00\x01b");
+    // Modification time
+    area.extend_from_slice(b"TF\x0c\x01\x02\x76\x01\x02\x03\x04\x05\x00");
+    area.extend_from_slice(b"ST\x04\x01");
+    // Ignored after the terminator
+    area.extend_from_slice(b"RE\x04\x01");
+
+    let mut parser = SuspParser::new();
+
+    parser.parse(&area);
+
+    let rr = parser.finish().unwrap();
+
+    assert!(rr.name() == Some("long_name"));
+    assert!(rr.attributes().unwrap().mode == 0o100755);
+    assert!(rr.attributes().unwrap().uid == 1000);
+    assert!(rr.attributes().unwrap().gid == 100);
+    assert!(rr.symlink() == Some("/usr/../lib"));
+    assert!(rr.modified().unwrap().year == 2018);
+    assert!(rr.created() == None);
+    assert!(!rr.relocated());
+
+    let mut parser = SuspParser::new();
+
+    parser.parse(b"SP\x07\x01\xbe\xef\x00");
+
+    assert!(parser.skip() == Some(0));
+    assert!(parser.finish() == None);
+}
+
+/// Encode `v` in both byte orders like most SUSP fields
+#[cfg(test)]
+fn test_both32(v: u32) -> Vec<u8> {
+    let le = super::test_le32(v);
+
+    vec![le[0], le[1], le[2], le[3], le[3], le[2], le[1], le[0]]
+}
+
+/// Build a `CE` entry pointing to the continuation area at `offset`
+/// in block `block`
+#[cfg(test)]
+fn test_ce(block: u32, offset: u32, len: u32) -> Vec<u8> {
+    let mut ce = b"CE\x1c\x01".to_vec();
+
+    ce.extend(test_both32(block));
+    ce.extend(test_both32(offset));
+    ce.extend(test_both32(len));
+
+    ce
+}
+
+/// Build an `NM` entry
+#[cfg(test)]
+fn test_nm(name: &str) -> Vec<u8> {
+    let mut nm = vec![b'N', b'M', 5 + name.len() as u8, 1, 0];
+
+    nm.extend_from_slice(name.as_bytes());
+
+    nm
+}
+
+#[test]
+fn rock_ridge_listing() {
+    use iso::Iso;
+    use bcd::Bcd;
+    use test_util;
+    use super::{Iso9660, Extension};
+    use super::{test_record, test_system_use, test_directory};
+    use super::{test_volume, test_block};
+
+    // Block 19: root directory, 20-21: continuation area across a
+    // block boundary, 22: continuation area pointing to itself, 23:
+    // relocated directory, 24: "rr_moved"
+    let dot = test_system_use(test_record(b"\0", 19, 2048, true),
+                              b"SP\x07\x01\xbe\xef\x00RR\x05\x01\x89");
+
+    let long_name = "a rather long name which doesn't fit in the record.txt";
+
+    let long = test_system_use(test_record(b"LONG.TXT;1", 0, 0, false),
+                               &test_ce(20, 2000, 5 + long_name.len() as u32));
+
+    let looping = test_system_use(test_record(b"LOOP.TXT;1", 0, 0, false),
+                                  &test_ce(22, 0, 28));
+
+    let mut cl = test_nm("deep");
+
+    cl.extend_from_slice(b"CL\x0c\x01");
+    cl.extend(test_both32(23));
+
+    let deep = test_system_use(test_record(b"DEEP", 0, 0, false), &cl);
+
+    let moved = test_system_use(test_record(b"RR_MOVED", 24, 2048, true),
+                                &test_nm("rr_moved"));
+
+    let mut root = dot;
+
+    root.extend(test_record(b"\x01", 19, 2048, true));
+
+    for record in &[long, looping, deep, moved] {
+        root.extend_from_slice(record);
+    }
+
+    let mut image = test_volume(25, &root);
+
+    image[20 * 2048 + 2000..20 * 2048 + 2005 + long_name.len()]
+        .copy_from_slice(&test_nm(long_name));
+
+    let mut looped = test_ce(22, 0, 28 + 9);
+
+    looped.extend(test_nm("loop"));
+
+    test_block(&mut image, 22, &looped);
+
+    let nested = test_system_use(test_record(b"NESTED.TXT;1", 0, 0, false),
+                                 &test_nm("nested file"));
+
+    test_block(&mut image, 23, &test_directory(23, 19, &[nested]));
+
+    let relocated = test_system_use(test_record(b"DEEP", 23, 2048, true),
+                                    b"RE\x04\x01");
+
+    test_block(&mut image, 24, &test_directory(24, 19, &[relocated]));
+
+    let dir = test_util::temp_dir("rock-ridge-listing");
+    let path = test_util::write_file(&dir, "rr.iso", &image);
+
+    let mut fs = Iso9660::new(Iso::new(&path).unwrap(), Bcd::one()).unwrap();
+
+    assert!(fs.extension() == Extension::RockRidge);
+
+    let root = fs.root().clone();
+    let entries = fs.read_dir(&root).unwrap();
+    let names: Vec<&str> = entries.iter().map(|e| e.name()).collect();
+
+    assert!(names == [".", "..", long_name, "loop", "deep", "rr_moved"]);
+
+    // The relocated directory is listed at its original location
+    let deep = &entries[4];
+
+    assert!(deep.is_dir());
+    assert!(deep.extent() == 23);
+    assert!(deep.size() == 2048);
+
+    let nested = fs.lookup("/deep/nested file").unwrap().unwrap();
+
+    assert!(nested.identifier() == "NESTED.TXT;1");
+
+    // And hidden from "rr_moved"
+    let moved = fs.lookup("/rr_moved").unwrap().unwrap();
+
+    assert!(fs.read_dir(&moved).unwrap().len() == 2);
+}
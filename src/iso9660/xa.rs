@@ -0,0 +1,383 @@
+//! CD-ROM XA extensions
+//!
+//! CD-ROM XA discs (PlayStation, CD-i...) store a 14 byte record at
+//! the beginning of the system use area of each directory record
+//! with the owner of the file, its permissions and whether it's made
+//! of Mode 2 Form 2 sectors (XA audio, video...) or interleaved.
+//!
+//! Form 2 sectors contain 2324 bytes of user data instead of the
+//! 2048 bytes of a logical block so such files can't be read through
+//! the regular `File` interface without losing data. `XaFile` reads
+//! them one full sector at a time instead.
+
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::cmp;
+
+use CdError;
+use Image;
+use TrackFormat;
+
+use sector::XaSubHeader;
+
+use super::{Iso9660, DirectoryEntry, lba_msf};
+use super::file::io_error;
+
+/// XA directory record extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XaAttributes {
+    /// Group ID of the owner
+    pub group_id: u16,
+    /// User ID of the owner
+    pub user_id: u16,
+    /// Permissions and file type
+    pub attributes: XaFlags,
+    /// File number, used in the subheader of the file's sectors
+    pub file_number: u8,
+}
+
+impl XaAttributes {
+    /// Parse the XA record at the beginning of `system_use`. Returns
+    /// `None` if there's no XA record.
+    pub fn parse(system_use: &[u8]) -> Option<XaAttributes> {
+        if system_use.len() < XA_RECORD_LENGTH ||
+            &system_use[6..8] != b"XA" {
+            return None;
+        }
+
+        let be16 = |b: &[u8]| (b[0] as u16) << 8 | b[1] as u16;
+
+        Some(XaAttributes {
+            group_id: be16(&system_use[0..2]),
+            user_id: be16(&system_use[2..4]),
+            attributes: XaFlags::from_bits_truncate(
+                be16(&system_use[4..6])),
+            file_number: system_use[8],
+        })
+    }
+}
+
+bitflags! {
+    /// XA file attributes
+    flags XaFlags: u16 {
+        /// The owner can read the file
+        const OWNER_READ    = 0x0001,
+        /// The owner can execute the file
+        const OWNER_EXECUTE = 0x0004,
+        /// The group can read the file
+        const GROUP_READ    = 0x0010,
+        /// The group can execute the file
+        const GROUP_EXECUTE = 0x0040,
+        /// Everybody can read the file
+        const WORLD_READ    = 0x0100,
+        /// Everybody can execute the file
+        const WORLD_EXECUTE = 0x0400,
+        /// The file contains Mode 2 Form 1 sectors
+        const MODE2         = 0x0800,
+        /// The file contains Mode 2 Form 2 sectors
+        const MODE2_FORM2   = 0x1000,
+        /// The file's sectors are interleaved with other files
+        const INTERLEAVED   = 0x2000,
+        /// The file is a CD-DA track
+        const CDDA          = 0x4000,
+        /// The entry is a directory
+        const DIRECTORY     = 0x8000,
+    }
+}
+
+/// Layout of the sectors returned by `XaFile`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XaLayout {
+    /// 2336 bytes per sector: the subheader followed by the rest of
+    /// the sector (user data and error detection/correction codes).
+    /// This is the "raw" format used by most extraction tools and
+    /// it's the only one which lets the subheaders be recovered.
+    Mode2,
+    /// 2324 bytes per sector: the user data area of a Form 2
+    /// sector. For Form 1 sectors it contains the 2048 bytes of user
+    /// data followed by the error detection/correction codes.
+    Form2,
+}
+
+impl XaLayout {
+    /// Return the number of bytes per sector for this layout
+    pub fn sector_size(self) -> usize {
+        match self {
+            XaLayout::Mode2 => 2336,
+            XaLayout::Form2 => 2324,
+        }
+    }
+
+    /// Return the offset of this layout in a 2352 byte sector
+    fn offset(self) -> usize {
+        match self {
+            XaLayout::Mode2 => 16,
+            XaLayout::Form2 => 24,
+        }
+    }
+}
+
+/// Handle to a Mode 2 file opened for reading full sectors. The
+/// directory records of XA files give their size as if every sector
+/// contained 2048 bytes, the file returned here contains as many
+/// sectors in the layout requested.
+pub struct XaFile<'a, I: Image + 'a> {
+    /// Filesystem containing the file
+    fs: &'a mut Iso9660<I>,
+    /// Logical block address of the first sector of the file
+    extent: u32,
+    /// Number of sectors in the file
+    sectors: u32,
+    /// Layout of the data returned
+    layout: XaLayout,
+    /// Current position in the file
+    pos: u64,
+    /// Last sector read from the disc, in `layout`
+    buffer: [u8; 2336],
+    /// Position of `buffer` in the file (in sectors), if it's valid
+    cached: Option<u32>,
+}
+
+impl<'a, I: Image> XaFile<'a, I> {
+    /// Create a new handle for the file described by `entry`
+    pub fn new(fs: &'a mut Iso9660<I>,
+               entry: &DirectoryEntry,
+               layout: XaLayout) -> XaFile<'a, I> {
+        XaFile {
+            fs: fs,
+            extent: entry.extent(),
+            // Computed on 64 bits so that the rounding can't
+            // overflow for sizes close to 4GB
+            sectors: ((entry.size() as u64 + 2047) / 2048) as u32,
+            layout: layout,
+            pos: 0,
+            buffer: [0; 2336],
+            cached: None,
+        }
+    }
+
+    /// Return the number of sectors in the file
+    pub fn sectors(&self) -> u32 {
+        self.sectors
+    }
+
+    /// Return the size of the file in bytes for the requested layout
+    pub fn size(&self) -> u64 {
+        self.sectors as u64 * self.layout.sector_size() as u64
+    }
+
+    /// Return the subheader of the file's sector number `sector`,
+    /// used to tell apart the various channels of interleaved files
+    pub fn subheader(&mut self,
+                     sector: u32) -> Result<XaSubHeader, CdError> {
+        if sector >= self.sectors {
+            return Err(CdError::EndOfTrack);
+        }
+
+        let fs = &mut *self.fs;
+
+        try!(fs.image.read_sector(&mut fs.sector,
+                                  try!(lba_msf(self.extent + sector))));
+
+        fs.sector.mode2_xa_subheader()
+    }
+
+    /// Load the file's sector number `sector` into `buffer`
+    fn load(&mut self, sector: u32) -> Result<(), CdError> {
+        let fs = &mut *self.fs;
+
+        try!(fs.image.read_sector(&mut fs.sector,
+                                  try!(lba_msf(self.extent + sector))));
+
+        match fs.sector.metadata().format {
+            TrackFormat::Mode2Xa | TrackFormat::Mode2CdI => (),
+            _ => return Err(CdError::BadFormat),
+        }
+
+        let data = try!(fs.sector.data_2352());
+
+        let offset = self.layout.offset();
+        let size = self.layout.sector_size();
+
+        self.buffer[..size].copy_from_slice(&data[offset..offset + size]);
+
+        Ok(())
+    }
+}
+
+impl<'a, I: Image> Read for XaFile<'a, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.layout.sector_size() as u64;
+
+        if self.pos >= self.size() || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let sector = (self.pos / size) as u32;
+        let offset = (self.pos % size) as usize;
+
+        if self.cached != Some(sector) {
+            if let Err(e) = self.load(sector) {
+                return Err(io_error(e));
+            }
+
+            self.cached = Some(sector);
+        }
+
+        let len = cmp::min(buf.len(), size as usize - offset);
+
+        buf[..len].copy_from_slice(&self.buffer[offset..offset + len]);
+
+        self.pos += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<'a, I: Image> Seek for XaFile<'a, I> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos =
+            match pos {
+                SeekFrom::Start(p) => p as i64,
+                SeekFrom::End(p) => self.size() as i64 + p,
+                SeekFrom::Current(p) => self.pos as i64 + p,
+            };
+
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Attempted to seek before the \
+                                       beginning of the file"));
+        }
+
+        self.pos = pos as u64;
+
+        Ok(self.pos)
+    }
+}
+
+/// Length of the XA record in the system use area
+pub const XA_RECORD_LENGTH: usize = 14;
+
+#[test]
+fn xa_record() {
+    let record = [0x00, 0x00, 0x00, 0x00, 0x3d, 0x55, b'X', b'A',
+                  0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+    let xa = XaAttributes::parse(&record).unwrap();
+
+    assert!(xa.attributes.contains(MODE2_FORM2));
+    assert!(xa.attributes.contains(INTERLEAVED));
+    assert!(xa.attributes.contains(MODE2));
+    assert!(!xa.attributes.contains(DIRECTORY));
+    assert!(xa.file_number == 1);
+
+    assert!(XaAttributes::parse(&record[..10]).is_none());
+    assert!(XaAttributes::parse(&[0; 14]).is_none());
+}
+
+#[test]
+fn read_form2_file() {
+    use iso::Iso;
+    use bcd::Bcd;
+    use test_util;
+    use super::{test_record, test_system_use, test_directory, test_volume};
+
+    // Three interleaved Form 2 sectors of file 1 in blocks 20 to 22
+    let xa = [0x00, 0x00, 0x00, 0x00, 0x35, 0x55, b'X', b'A',
+              0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+    let file = test_system_use(test_record(b"MOVIE.STR;1", 20, 3 * 2048,
+                                           false),
+                               &xa);
+
+    let blocks = test_volume(23, &test_directory(19, 19, &[file]));
+
+    // Headerless Mode 2 sectors: the filesystem in Form 1 sectors
+    // followed by the Form 2 sectors of the file
+    let mut image = Vec::new();
+    let mut sectors = Vec::new();
+
+    for (n, block) in blocks.chunks(2048).enumerate() {
+        let mut sector = vec![0; 2336];
+
+        if n < 20 {
+            sector[0..8].copy_from_slice(&[0, 0, 0x08, 0, 0, 0, 0x08, 0]);
+            sector[8..2056].copy_from_slice(block);
+        } else {
+            // Real time audio on channel n
+            let channel = n as u8 - 20;
+            let subheader = [1, channel, 0x64, 0x01];
+
+            sector[0..4].copy_from_slice(&subheader);
+            sector[4..8].copy_from_slice(&subheader);
+
+            for (i, b) in sector[8..2332].iter_mut().enumerate() {
+                *b = (i * 7 + n) as u8;
+            }
+
+            sectors.push(sector.clone());
+        }
+
+        image.extend_from_slice(&sector);
+    }
+
+    let dir = test_util::temp_dir("xa-form2");
+    let path = test_util::write_file(&dir, "movie.iso", &image);
+
+    let mut fs = Iso9660::new(Iso::new(&path).unwrap(), Bcd::one()).unwrap();
+
+    let entry = fs.lookup("/MOVIE.STR").unwrap().unwrap();
+
+    assert!(entry.xa().unwrap().attributes.contains(MODE2_FORM2));
+
+    {
+        let mut file = fs.open_xa(&entry, XaLayout::Mode2).unwrap();
+
+        assert!(file.sectors() == 3);
+        assert!(file.size() == 3 * 2336);
+
+        for n in 0..3 {
+            let subheader = file.subheader(n).unwrap();
+
+            assert!(subheader.form() == ::sector::XaForm::Form2);
+            assert!(subheader.file_number() == 1);
+            assert!(subheader.channel_number() == n as u8);
+        }
+
+        assert!(file.subheader(3).is_err());
+
+        let mut data = Vec::new();
+
+        file.read_to_end(&mut data).unwrap();
+
+        assert!(data == sectors.concat());
+    }
+
+    let mut file = fs.open_xa(&entry, XaLayout::Form2).unwrap();
+
+    assert!(file.size() == 3 * 2324);
+
+    let mut data = Vec::new();
+
+    file.read_to_end(&mut data).unwrap();
+
+    for (n, sector) in sectors.iter().enumerate() {
+        assert!(&data[n * 2324..(n + 1) * 2324] == &sector[8..2332]);
+    }
+
+    // The last payload again, through a seek from the end
+    let mut payload = vec![0; 2324];
+
+    file.seek(SeekFrom::End(-2324)).unwrap();
+    file.read_exact(&mut payload).unwrap();
+
+    assert!(payload == &sectors[2][8..2332]);
+
+    // Rounding the size of a file close to 4GB up mustn't overflow
+    let huge = test_record(b"HUGE.STR;1", 20, 0xffffffff, false);
+    let huge = DirectoryEntry::parse(&huge, false).unwrap();
+
+    assert!(fs.open_xa(&huge, XaLayout::Mode2).unwrap().sectors() ==
+            0x200000);
+}
+
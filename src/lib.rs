@@ -10,6 +10,9 @@ extern crate bitflags;
 extern crate arrayref;
 
 extern crate rustc_serialize;
+extern crate lzma_rs;
+extern crate miniz_oxide;
+extern crate claxon;
 
 use std::path::PathBuf;
 use std::io;
@@ -17,6 +20,7 @@ use std::fmt;
 use sector::Sector;
 use msf::Msf;
 use bcd::Bcd;
+use toc::Toc;
 
 pub mod bcd;
 pub mod msf;
@@ -24,7 +28,25 @@ pub mod subchannel;
 pub mod internal;
 pub mod sector;
 pub mod cue;
+pub mod ccd;
+pub mod chd;
+pub mod mds;
+pub mod nrg;
+pub mod libcrypt;
+pub mod iso;
+pub mod iso9660;
+pub mod adpcm;
+pub mod audio;
 pub mod crc;
+pub mod ecc;
+pub mod ecm;
+pub mod toc;
+pub mod backend;
+
+#[cfg(test)]
+mod test_util;
+
+pub use backend::{open, register_backend};
 
 /// Abstract read-only interface to an image format
 pub trait Image {
@@ -40,6 +62,29 @@ pub trait Image {
     /// `track`. Will return an error if the `track_msf` is outside of
     /// the track or if `track` doesn't exist.
     fn track_msf(&self, track: Bcd, track_msf: Msf) -> Result<Msf, CdError>;
+
+    /// Return the table of contents of the disc
+    fn toc(&self) -> Toc;
+}
+
+impl<I: Image + ?Sized> Image for Box<I> {
+    fn image_format(&self) -> String {
+        (**self).image_format()
+    }
+
+    fn read_sector(&mut self,
+                   sector: &mut Sector,
+                   msf: Msf) -> Result<(), CdError> {
+        (**self).read_sector(sector, msf)
+    }
+
+    fn track_msf(&self, track: Bcd, track_msf: Msf) -> Result<Msf, CdError> {
+        (**self).track_msf(track, track_msf)
+    }
+
+    fn toc(&self) -> Toc {
+        (**self).toc()
+    }
 }
 
 /// Possible session formats.
@@ -91,8 +136,15 @@ pub enum CdError {
     BadImage(PathBuf, String),
     /// Attempted to access an invalid track number
     BadTrack,
+    /// Attempted to access an invalid session number
+    BadSession,
     /// Attempted to access a track past its end
     EndOfTrack,
+    /// The sector data is corrupted and couldn't be repaired
+    CorruptedSector,
+    /// Invalid or unsupported filesystem structure. Contains a
+    /// `String` describing the problem.
+    BadFilesystem(String),
 }
 
 impl fmt::Display for CdError {
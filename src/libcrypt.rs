@@ -0,0 +1,425 @@
+//! PlayStation libcrypt patch support.
+//!
+//! libcrypt is a copy protection used by some PlayStation games: a
+//! few sectors of the disc have a deliberately corrupted Q subchannel
+//! (see the `subchannel` module). Images which don't store the
+//! subchannel data (BIN/CUE in particular) lose this information,
+//! so it's usually distributed alongside the image in a patch file
+//! containing the modified Q frames:
+//!
+//! * `.sbi` files start with the magic "SBI\0" followed by 14 byte
+//!   entries: the absolute MSF of the sector in BCD, an entry type
+//!   (always 1) and the first 10 bytes of the Q subchannel. The CRC
+//!   is not stored, it must be invalid for the protection to work.
+//!
+//! * `.lsd` files have no header, only 15 byte entries: the absolute
+//!   MSF of the sector in BCD followed by the full 12 bytes of the Q
+//!   subchannel, CRC included.
+//!
+//! `Libcrypt` is an overlay which can be put on top of any `Image`
+//! to replace the Q subchannel of the sectors listed in the patch.
+//! The Q subchannel of the other sectors is synthesized from the
+//! disc structure if the image doesn't provide it.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::fs::{File, metadata};
+use std::io;
+use std::io::Read;
+
+use CdError;
+use Image;
+use internal::{Index, IndexCache};
+use sector::Sector;
+use subchannel::SubChannelQ;
+use crc::crc16_q;
+use msf::Msf;
+use bcd::Bcd;
+use toc::Toc;
+
+/// Overlay applying a libcrypt patch on top of an image
+pub struct Libcrypt<I: Image> {
+    /// Underlying image
+    image: I,
+    /// Patched Q subchannel data, indexed by absolute MSF
+    patches: BTreeMap<Msf, [u8; 12]>,
+    /// Structure of the disc, used to synthesize the Q subchannel of
+    /// the sectors which aren't patched
+    indices: IndexCache<()>,
+}
+
+impl<I: Image> Libcrypt<I> {
+    /// Load the patch file at `path` (either in the SBI or LSD format,
+    /// based on its extension) and apply it on top of `image`.
+    pub fn new(image: I, path: &Path) -> Result<Libcrypt<I>, CdError> {
+        let is_lsd =
+            match path.extension() {
+                Some(ext) => ext.to_string_lossy()
+                    .eq_ignore_ascii_case("lsd"),
+                None => false,
+            };
+
+        let data =
+            match read_file(path, PATCH_MAX_LENGTH) {
+                Ok(d) => d,
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        let patches =
+            if is_lsd {
+                try!(parse_lsd(path, &data))
+            } else {
+                try!(parse_sbi(path, &data))
+            };
+
+        Libcrypt::with_patches(image, path, patches)
+    }
+
+    /// Look for a patch file with the same name as `image_path` (the
+    /// cue sheet for instance) but with a `.sbi` or `.lsd` extension
+    /// and apply it on top of `image`. If there's no such file the
+    /// overlay doesn't patch anything.
+    pub fn find(image: I, image_path: &Path) -> Result<Libcrypt<I>, CdError> {
+        for ext in &["sbi", "SBI", "lsd", "LSD"] {
+            let path = image_path.with_extension(ext);
+
+            match metadata(&path) {
+                Ok(_) => return Libcrypt::new(image, &path),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(CdError::IoError(e)),
+            }
+        }
+
+        Libcrypt::with_patches(image, image_path, BTreeMap::new())
+    }
+
+    /// Build the overlay for `image` with the given `patches`
+    fn with_patches(image: I,
+                    path: &Path,
+                    patches: BTreeMap<Msf, [u8; 12]>)
+                    -> Result<Libcrypt<I>, CdError> {
+        let indices = try!(toc_indices(path, &image.toc()));
+
+        Ok(Libcrypt {
+            image: image,
+            patches: patches,
+            indices: indices,
+        })
+    }
+
+    /// Return the number of sectors patched by the overlay
+    pub fn patch_count(&self) -> usize {
+        self.patches.len()
+    }
+
+    /// Return the patched Q subchannel data for the sector at `msf`,
+    /// or `None` if the sector is not patched.
+    pub fn q_patch(&self, msf: Msf) -> Option<SubChannelQ> {
+        self.patches.get(&msf).map(|&q| SubChannelQ::new(q))
+    }
+
+    /// Return the Q subchannel data of the sector at `msf`: the
+    /// patched data if there's one, otherwise the data synthesized
+    /// from the disc structure.
+    pub fn q_subchannel(&self, msf: Msf) -> SubChannelQ {
+        match self.q_patch(msf) {
+            Some(q) => q,
+            None => self.indices.q_subchannel(msf),
+        }
+    }
+
+    /// Return a reference to the underlying image
+    pub fn image(&self) -> &I {
+        &self.image
+    }
+
+    /// Remove the overlay and return the underlying image
+    pub fn into_inner(self) -> I {
+        self.image
+    }
+}
+
+impl<I: Image> Image for Libcrypt<I> {
+    fn image_format(&self) -> String {
+        format!("{} (libcrypt patch)", self.image.image_format())
+    }
+
+    fn read_sector(&mut self,
+                   sector: &mut Sector,
+                   msf: Msf) -> Result<(), CdError> {
+        try!(self.image.read_sector(sector, msf));
+
+        // If the image doesn't store the subchannel data only the
+        // patched sectors would have a Q subchannel, synthesize it
+        // for the others
+        if let Some(q) = self.q_patch(msf) {
+            sector.set_subchannel_q(&q);
+        } else if !sector.has_subchannel() {
+            sector.set_subchannel_q(&self.indices.q_subchannel(msf));
+        }
+
+        Ok(())
+    }
+
+    fn track_msf(&self, track: Bcd, track_msf: Msf) -> Result<Msf, CdError> {
+        self.image.track_msf(track, track_msf)
+    }
+
+    fn toc(&self) -> Toc {
+        self.image.toc()
+    }
+}
+
+/// Rebuild the index cache of a disc from its table of contents
+fn toc_indices(path: &Path, toc: &Toc) -> Result<IndexCache<()>, CdError> {
+    let mut indices = Vec::new();
+
+    for session in &toc.sessions {
+        for track in &session.tracks {
+            for toc_index in &track.indices {
+                let mut index = Index::new(toc_index.number,
+                                           toc_index.msf,
+                                           track.number,
+                                           track.format,
+                                           session.number,
+                                           ());
+
+                index.set_control(track.control);
+
+                indices.push(index);
+            }
+        }
+    }
+
+    let lead_outs = toc.sessions.iter().map(|s| s.lead_out).collect();
+
+    IndexCache::new_multisession(PathBuf::from(path), indices, lead_outs)
+}
+
+/// Parse the MSF at the beginning of a patch entry
+fn entry_msf(path: &Path, entry: &[u8]) -> Result<Msf, CdError> {
+    match Msf::from_bcd(entry[0], entry[1], entry[2]) {
+        Some(msf) => Ok(msf),
+        None => {
+            let error = format!("Invalid MSF {:02x}:{:02x}:{:02x}",
+                                entry[0], entry[1], entry[2]);
+            Err(CdError::BadImage(PathBuf::from(path), error))
+        }
+    }
+}
+
+/// Parse the contents of an `.sbi` file
+fn parse_sbi(path: &Path,
+             sbi: &[u8]) -> Result<BTreeMap<Msf, [u8; 12]>, CdError> {
+    let bad_image = |msg: &str| {
+        CdError::BadImage(PathBuf::from(path), msg.to_string())
+    };
+
+    if sbi.len() < 4 || &sbi[0..4] != b"SBI\0" {
+        return Err(bad_image("Missing SBI header"));
+    }
+
+    let entries = &sbi[4..];
+
+    if entries.len() % 14 != 0 {
+        return Err(bad_image("Truncated SBI entry"));
+    }
+
+    let mut patches = BTreeMap::new();
+
+    for entry in entries.chunks(14) {
+        let msf = try!(entry_msf(path, entry));
+
+        // Other types only store part of the Q data (one of the
+        // MSFs) but they don't seem to be used in practice.
+        if entry[3] != 1 {
+            let error = format!("Unsupported SBI entry type {}", entry[3]);
+            return Err(bad_image(&error));
+        }
+
+        let mut q = [0; 12];
+
+        q[..10].copy_from_slice(&entry[4..14]);
+
+        // The actual CRC is lost, but libcrypt only cares that it's
+        // wrong. Inverting the valid one guarantees that.
+        let crc = !crc16_q(&q[..10]);
+
+        q[10] = (crc >> 8) as u8;
+        q[11] = crc as u8;
+
+        patches.insert(msf, q);
+    }
+
+    Ok(patches)
+}
+
+/// Parse the contents of an `.lsd` file
+fn parse_lsd(path: &Path,
+             lsd: &[u8]) -> Result<BTreeMap<Msf, [u8; 12]>, CdError> {
+    if lsd.len() % 15 != 0 {
+        let error = "Truncated LSD entry".to_string();
+        return Err(CdError::BadImage(PathBuf::from(path), error));
+    }
+
+    let mut patches = BTreeMap::new();
+
+    for entry in lsd.chunks(15) {
+        let msf = try!(entry_msf(path, entry));
+
+        patches.insert(msf, *array_ref![entry, 3, 12]);
+    }
+
+    Ok(patches)
+}
+
+/// Read the whole patch file, returning an error if it's bigger than
+/// `max_len`
+fn read_file(path: &Path, max_len: u64) -> Result<Vec<u8>, io::Error> {
+    let md = try!(metadata(path));
+
+    let len = md.len();
+
+    if len > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "libcrypt patch file is too big"));
+    }
+
+    let mut file = try!(File::open(path));
+
+    let mut data = Vec::with_capacity(len as usize);
+
+    try!(file.read_to_end(&mut data));
+
+    Ok(data)
+}
+
+/// Max size for a patch file, used to detect bogus input early
+/// without attempting to load a huge file to RAM.
+pub const PATCH_MAX_LENGTH: u64 = 1024 * 1024;
+
+#[test]
+fn sbi_lsd_equivalence() {
+    use subchannel::SubChannel;
+
+    let path = Path::new("test.sbi");
+
+    // Sector 03:08:05 with both MSFs slightly altered
+    let q = [0x41, 0x01, 0x01, 0x03, 0x04, 0x05,
+             0x00, 0x03, 0x0a, 0x05];
+
+    let mut sbi = b"SBI\0".to_vec();
+
+    sbi.extend_from_slice(&[0x03, 0x08, 0x05, 0x01]);
+    sbi.extend_from_slice(&q);
+
+    let sbi = parse_sbi(path, &sbi).unwrap();
+
+    let msf = Msf::from_bcd(0x03, 0x08, 0x05).unwrap();
+    let patched = SubChannelQ::new(sbi[&msf]);
+
+    assert!(&patched.raw()[..10] == &q);
+    assert!(!patched.crc_valid());
+
+    let mut lsd = vec![0x03, 0x08, 0x05];
+
+    lsd.extend_from_slice(patched.raw());
+
+    let lsd = parse_lsd(path, &lsd).unwrap();
+
+    assert!(lsd == sbi);
+
+    // Truncated entry and bogus type
+    assert!(parse_sbi(path, b"SBI\0\x03\x08\x05\x01").is_err());
+
+    let mut bad = b"SBI\0\x03\x08\x05\x02".to_vec();
+
+    bad.extend_from_slice(&q);
+
+    assert!(parse_sbi(path, &bad).is_err());
+}
+
+#[test]
+fn read_patched_sectors() {
+    use test_util::{temp_dir, write_file};
+    use cue::Cue;
+    use subchannel::SubChannel;
+
+    let dir = temp_dir("libcrypt");
+
+    // CD+G track, the image provides the subchannel data of the
+    // stored sectors but not the pregap's
+    let bin: Vec<u8> = (0..10 * 2448).map(|i| (i % 253) as u8).collect();
+
+    write_file(&dir, "disc.bin", &bin);
+
+    let cue_path = write_file(&dir, "disc.cue",
+                              b"FILE \"disc.bin\" BINARY\n\
+                                \x20 TRACK 01 CDG\n\
+                                \x20   INDEX 01 00:00:00\n");
+
+    let stored = Msf::from_bcd(0x00, 0x02, 0x03).unwrap();
+    let pregap = Msf::from_bcd(0x00, 0x01, 0x00).unwrap();
+
+    let q = [0x41, 0x01, 0x01, 0x00, 0x00, 0x04,
+             0x00, 0x02, 0x07, 0x05];
+
+    let mut sbi = b"SBI\0".to_vec();
+
+    for msf in &[stored, pregap] {
+        let (m, s, f) = msf.into_bcd();
+
+        sbi.extend_from_slice(&[m.bcd(), s.bcd(), f.bcd(), 0x01]);
+        sbi.extend_from_slice(&q);
+    }
+
+    write_file(&dir, "disc.sbi", &sbi);
+
+    let mut cue = Cue::new(&cue_path).unwrap();
+    let mut libcrypt =
+        Libcrypt::find(Cue::new(&cue_path).unwrap(), &cue_path).unwrap();
+
+    assert!(libcrypt.patch_count() == 2);
+
+    let mut original = Sector::empty();
+    let mut sector = Sector::empty();
+
+    // Patched sector with subchannel data, only Q is replaced
+    cue.read_sector(&mut original, stored).unwrap();
+    libcrypt.read_sector(&mut sector, stored).unwrap();
+
+    let sub = sector.subchannel_deinterleaved().unwrap();
+    let orig = original.subchannel_deinterleaved().unwrap();
+
+    assert!(&sub[12..22] == &q);
+    assert!(!sector.subchannel_q().unwrap().crc_valid());
+    assert!(&sub[..12] == &orig[..12]);
+    assert!(&sub[24..] == &orig[24..]);
+
+    // Unpatched sector with subchannel data, left untouched
+    let msf = Msf::from_bcd(0x00, 0x02, 0x05).unwrap();
+
+    cue.read_sector(&mut original, msf).unwrap();
+    libcrypt.read_sector(&mut sector, msf).unwrap();
+
+    assert!(&sector.subchannel_deinterleaved().unwrap()[..] ==
+            &original.subchannel_deinterleaved().unwrap()[..]);
+
+    // Patched sector in the pregap, without subchannel data
+    libcrypt.read_sector(&mut sector, pregap).unwrap();
+
+    assert!(&sector.subchannel_q().unwrap().raw()[..10] == &q);
+
+    // Unpatched sector without subchannel data, the Q subchannel is
+    // synthesized
+    let msf = Msf::from_bcd(0x00, 0x00, 0x10).unwrap();
+
+    libcrypt.read_sector(&mut sector, msf).unwrap();
+
+    let synthesized = cue.q_subchannel(msf);
+
+    assert!(synthesized.crc_valid());
+    assert!(sector.subchannel_q().unwrap().raw() == synthesized.raw());
+    assert!(libcrypt.q_subchannel(msf).raw() == synthesized.raw());
+    assert!(&libcrypt.q_subchannel(pregap).raw()[..10] == &q);
+}
@@ -0,0 +1,250 @@
+//! Alcohol 120% (MDS/MDF) image format implementation
+//!
+//! The `.mds` file is a binary descriptor containing the layout of
+//! every session and track of the disc, the `.mdf` file contains the
+//! sector data. Each track can be stored with a different sector
+//! size: 2048 or 2336 bytes for "cooked" data tracks, 2352 bytes for
+//! raw sectors and 2448 bytes for raw sectors followed by 96 bytes of
+//! interleaved subchannel data.
+//!
+//! The `.mdf` contains the sectors of every session, starting with
+//! track 01's INDEX 01, without the pregap of each session's first
+//! track and without the area between the sessions.
+//!
+//! Images of copy protected discs can also contain Data Position
+//! Measurement (DPM) information: the angular position of the
+//! sectors on the disc measured while dumping it.
+
+use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+use CdError;
+use Image;
+use internal::IndexCache;
+use sector::{Sector, SectorBuilder};
+use msf::Msf;
+use bcd::Bcd;
+use toc::Toc;
+
+use self::parser::MdsParser;
+
+mod parser;
+
+/// Alcohol 120% image
+#[derive(Debug)]
+pub struct Mds {
+    /// Cache of all the indices in the CD image
+    indices: IndexCache<Storage>,
+    /// Data files referenced by the tracks, usually a single `.mdf`
+    files: Vec<File>,
+    /// Data Position Measurement, if available
+    dpm: Option<Dpm>,
+}
+
+impl Mds {
+    /// Parse a `.mds` file, open the data file(s) and build a `Mds`
+    /// instance.
+    pub fn new(mds_path: &Path) -> Result<Mds, CdError> {
+        MdsParser::build_mds(mds_path)
+    }
+
+    /// Return the Data Position Measurement stored in the image, if
+    /// any
+    pub fn dpm(&self) -> Option<&Dpm> {
+        self.dpm.as_ref()
+    }
+}
+
+impl Image for Mds {
+    fn image_format(&self) -> String {
+        "Alcohol 120%".to_string()
+    }
+
+    fn read_sector(&mut self,
+                   sector: &mut Sector,
+                   msf: Msf) -> Result<(), CdError> {
+        let (index, metadata) = try!(self.indices.locate(msf));
+
+        let mut builder = SectorBuilder::new(sector);
+
+        match index.private() {
+            &Storage::Mdf { file, offset, sector_size, data } => {
+                let offset =
+                    offset +
+                    (msf.sector_index() - index.sector_index()) as u64 *
+                    sector_size as u64;
+
+                let file = &mut self.files[file];
+
+                let res =
+                    match data {
+                        DataType::Raw =>
+                            builder.set_data_2352(
+                                |d| read_at(file, offset, d)),
+                        DataType::Mode1 =>
+                            builder.set_mode1_payload(
+                                |p| read_at(file, offset, p)),
+                        DataType::Mode2 =>
+                            builder.set_mode2_payload(
+                                |p| read_at(file, offset, p)),
+                    };
+
+                if let Err(e) = res {
+                    return Err(CdError::IoError(e));
+                }
+
+                // The subchannel data follows the 2352 bytes of
+                // sector data
+                if sector_size == 2448 {
+                    let res =
+                        builder.set_subchannel_interleaved(
+                            |s| read_at(file, offset + 2352, s));
+
+                    if let Err(e) = res {
+                        return Err(CdError::IoError(e));
+                    }
+                }
+            }
+            &Storage::PreGap => builder.set_blank(index.format()),
+        }
+
+        builder.set_metadata(metadata);
+
+        Ok(())
+    }
+
+    fn track_msf(&self, track: Bcd, track_msf: Msf) -> Result<Msf, CdError> {
+        self.indices.track_msf(track, track_msf)
+    }
+
+    fn toc(&self) -> Toc {
+        self.indices.toc()
+    }
+}
+
+/// Data Position Measurement. Alcohol samples the time it takes for
+/// the drive to read a fixed number of sectors, which gives the
+/// angular position of each sector on the disc. Some copy protection
+/// schemes rely on it to tell an original from a copy.
+#[derive(Debug, Clone)]
+pub struct Dpm {
+    /// First sector covered by the measurements
+    start: Msf,
+    /// Number of sectors between two measurements
+    resolution: u32,
+    /// Cumulative measurement at the end of each block of
+    /// `resolution` sectors, in 1/256th of a revolution
+    entries: Vec<u32>,
+}
+
+impl Dpm {
+    /// Return the first sector covered by the measurements
+    pub fn start(&self) -> Msf {
+        self.start
+    }
+
+    /// Return the number of sectors between two measurements
+    pub fn resolution(&self) -> u32 {
+        self.resolution
+    }
+
+    /// Return the raw measurements
+    pub fn entries(&self) -> &[u32] {
+        &self.entries
+    }
+
+    /// Return the angular position of `msf` relative to `start()`
+    /// (in revolutions) along with the angular size of a sector
+    /// (in revolutions per sector), interpolated from the
+    /// measurements. Returns `None` if `msf` is not covered.
+    pub fn position(&self, msf: Msf) -> Option<(f64, f64)> {
+        if msf < self.start {
+            return None;
+        }
+
+        let offset = msf.sector_index() - self.start.sector_index();
+        let block = (offset / self.resolution) as usize;
+
+        if block >= self.entries.len() {
+            return None;
+        }
+
+        let previous =
+            if block == 0 {
+                0.
+            } else {
+                self.entries[block - 1] as f64
+            };
+
+        let density =
+            (self.entries[block] as f64 - previous) / self.resolution as f64;
+
+        let position =
+            previous + (offset % self.resolution) as f64 * density;
+
+        Some((position / 256., density / 256.))
+    }
+}
+
+/// Storage for an index
+#[derive(Debug, Clone, Copy)]
+enum Storage {
+    /// The index is stored in a data file
+    Mdf {
+        /// Position of the file in `Mds::files`
+        file: usize,
+        /// Offset of the index's first sector in the file
+        offset: u64,
+        /// Size of a sector in the file, including the subchannel
+        /// data if any
+        sector_size: u16,
+        /// Format of the sector data
+        data: DataType,
+    },
+    /// The index is a pre-gap, it's not stored in the image and must
+    /// be regenerated.
+    PreGap,
+}
+
+/// Format of the sector data stored in a data file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DataType {
+    /// Full 2352 bytes of sector data
+    Raw,
+    /// 2048 bytes of Mode 1 user data
+    Mode1,
+    /// 2336 bytes of Mode 2 data (everything but the sync pattern
+    /// and header)
+    Mode2,
+}
+
+/// Fill `buf` with the data located at `offset` in `file`
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    try!(file.seek(SeekFrom::Start(offset)));
+
+    file.read_exact(buf)
+}
+
+/// Max size for a `.mds` file, used to detect bogus input early
+/// without attempting to load a huge file to RAM.
+pub const MDS_MAX_LENGTH: u64 = 16 * 1024 * 1024;
+
+#[test]
+fn dpm_position() {
+    let dpm = Dpm {
+        start: Msf::from_sector_index(150).unwrap(),
+        resolution: 10,
+        entries: vec![256, 768, 1024],
+    };
+
+    let sector = |s: u32| Msf::from_sector_index(150 + s).unwrap();
+
+    assert!(dpm.position(Msf::zero()) == None);
+    assert!(dpm.position(sector(0)) == Some((0., 0.1)));
+    assert!(dpm.position(sector(5)) == Some((0.5, 0.1)));
+    assert!(dpm.position(sector(10)) == Some((1., 0.2)));
+    assert!(dpm.position(sector(25)) == Some((3.5, 0.1)));
+    assert!(dpm.position(sector(30)) == None);
+}
@@ -0,0 +1,849 @@
+use std::path::{Path, PathBuf};
+use std::fs::{File, metadata};
+use std::io;
+use std::io::Read;
+
+use internal::{Index, IndexCache};
+use toc::Control;
+use CdError;
+use TrackFormat;
+
+use bcd::Bcd;
+use msf::Msf;
+
+use super::{Mds, Dpm, Storage, DataType, MDS_MAX_LENGTH};
+
+pub struct MdsParser {
+    /// Path to the `.mds` file
+    mds_path: PathBuf,
+    /// Contents of the `.mds` file
+    mds: Vec<u8>,
+    /// Paths of the data files used by the tracks
+    file_paths: Vec<PathBuf>,
+}
+
+/// Session description (session block)
+struct MdsSession {
+    number: u8,
+    /// First sector of the session's lead-out, as an LBA
+    end: i32,
+    tracks: Vec<MdsTrack>,
+}
+
+/// Track description (track block and its extra block)
+struct MdsTrack {
+    number: Bcd,
+    format: TrackFormat,
+    control: Control,
+    data: DataType,
+    sector_size: u16,
+    /// LBA of INDEX 01
+    start: i32,
+    /// Offset of INDEX 01 in the data file
+    offset: u64,
+    /// Length of the pregap in sectors
+    pregap: u32,
+    /// Length of the track in sectors, not counting the pregap
+    length: u32,
+    /// Position of the data file in `file_paths`
+    file: usize,
+}
+
+impl MdsParser {
+    /// Parse a `.mds` file, open the data files and generate the CD
+    /// structure
+    pub fn build_mds(mds_path: &Path) -> Result<Mds, CdError> {
+        let mds =
+            match read_file(mds_path, MDS_MAX_LENGTH) {
+                Ok(m) => m,
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        let mut parser = MdsParser {
+            mds_path: PathBuf::from(mds_path),
+            mds: mds,
+            file_paths: Vec::new(),
+        };
+
+        if parser.mds.len() < HEADER_LEN ||
+            &parser.mds[0..16] != b"MEDIA DESCRIPTOR" {
+            return Err(parser.bad_image("Not an MDS file".to_string()));
+        }
+
+        let medium_type = try!(parser.u16(18));
+
+        // 0: CD-ROM, 1: CD-R, 2: CD-RW. Everything else is a DVD.
+        if medium_type > 2 {
+            let error = format!("Unsupported medium type 0x{:02x}",
+                                medium_type);
+            return Err(parser.bad_image(error));
+        }
+
+        let sessions = try!(parser.parse_sessions());
+        let dpm = try!(parser.parse_dpm());
+
+        let mut files = Vec::with_capacity(parser.file_paths.len());
+
+        for path in &parser.file_paths {
+            match File::open(path) {
+                Ok(f) => files.push(f),
+                Err(e) => return Err(CdError::IoError(e)),
+            }
+        }
+
+        try!(parser.check_file_sizes(&sessions));
+
+        let indices = try!(parser.build_indices(&sessions));
+
+        Ok(Mds {
+            indices: indices,
+            files: files,
+            dpm: dpm,
+        })
+    }
+
+    fn bad_image(&self, msg: String) -> CdError {
+        CdError::BadImage(self.mds_path.clone(), msg)
+    }
+
+    /// Return `len` bytes at `offset` in the `.mds`, or an error if
+    /// the file is too short
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], CdError> {
+        if offset + len > self.mds.len() {
+            let error = format!("Truncated MDS file (offset 0x{:x})",
+                                offset);
+            return Err(self.bad_image(error));
+        }
+
+        Ok(&self.mds[offset..offset + len])
+    }
+
+    fn u8(&self, offset: usize) -> Result<u8, CdError> {
+        self.bytes(offset, 1).map(|b| b[0])
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, CdError> {
+        self.bytes(offset, 2).map(|b| b[0] as u16 | (b[1] as u16) << 8)
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, CdError> {
+        let lo = try!(self.u16(offset)) as u32;
+        let hi = try!(self.u16(offset + 2)) as u32;
+
+        Ok(lo | hi << 16)
+    }
+
+    fn u64(&self, offset: usize) -> Result<u64, CdError> {
+        let lo = try!(self.u32(offset)) as u64;
+        let hi = try!(self.u32(offset + 4)) as u64;
+
+        Ok(lo | hi << 32)
+    }
+
+    /// Parse the session blocks and their track blocks
+    fn parse_sessions(&mut self) -> Result<Vec<MdsSession>, CdError> {
+        let count = try!(self.u16(20)) as usize;
+        let offset = try!(self.u32(80)) as usize;
+
+        if count == 0 {
+            return Err(self.bad_image("Empty disc".to_string()));
+        }
+
+        let mut sessions: Vec<MdsSession> = Vec::with_capacity(count);
+
+        for s in 0..count {
+            let block = offset + s * SESSION_BLOCK_LEN;
+
+            let number = try!(self.u16(block + 8));
+            let end = try!(self.u32(block + 4)) as i32;
+            let blocks = try!(self.u8(block + 10)) as usize;
+            let tracks_offset = try!(self.u32(block + 20)) as usize;
+
+            let expected = sessions.len() as u16 + 1;
+
+            if number != expected {
+                let error = format!("Unexpected session number {}", number);
+                return Err(self.bad_image(error));
+            }
+
+            let mut tracks = Vec::new();
+
+            for b in 0..blocks {
+                let track_block = tracks_offset + b * TRACK_BLOCK_LEN;
+
+                // Points 0xa0 and above are the lead-in TOC entries
+                // for the session, we don't need them.
+                let point = try!(self.u8(track_block + 4));
+
+                if point == 0 || point > 99 {
+                    continue;
+                }
+
+                tracks.push(try!(self.parse_track(track_block)));
+            }
+
+            if tracks.is_empty() {
+                let error = format!("Session {} has no track", number);
+                return Err(self.bad_image(error));
+            }
+
+            sessions.push(MdsSession {
+                number: number as u8,
+                end: end,
+                tracks: tracks,
+            });
+        }
+
+        Ok(sessions)
+    }
+
+    /// Parse the track block at `offset`
+    fn parse_track(&mut self, offset: usize) -> Result<MdsTrack, CdError> {
+        let mode = try!(self.u8(offset));
+        let adr_ctl = try!(self.u8(offset + 2));
+        let point = try!(self.u8(offset + 4));
+        let extra_offset = try!(self.u32(offset + 12)) as usize;
+        let sector_size = try!(self.u16(offset + 16));
+        let start = try!(self.u32(offset + 36)) as i32;
+        let file_offset = try!(self.u64(offset + 40));
+        let file_count = try!(self.u32(offset + 48));
+        let footer_offset = try!(self.u32(offset + 52)) as usize;
+
+        let number = Bcd::from_binary(point).unwrap();
+
+        let (pregap, length) =
+            if extra_offset == 0 {
+                (0, 0)
+            } else {
+                (try!(self.u32(extra_offset)),
+                 try!(self.u32(extra_offset + 4)))
+            };
+
+        // The high nibble of the mode seems to contain flags, the low
+        // nibble gives the actual mode.
+        let format =
+            match mode & 0xf {
+                0x9 => TrackFormat::Audio,
+                0xa => TrackFormat::Mode1,
+                0xb | 0xc | 0xd => TrackFormat::Mode2Xa,
+                _ => {
+                    let error = format!("Track {}: unsupported mode 0x{:02x}",
+                                        number, mode);
+                    return Err(self.bad_image(error));
+                }
+            };
+
+        let data =
+            match (format, sector_size) {
+                (_, 2352) | (_, 2448) => DataType::Raw,
+                (TrackFormat::Mode1, 2048) => DataType::Mode1,
+                (TrackFormat::Mode2Xa, 2336) => DataType::Mode2,
+                _ => {
+                    let error = format!("Track {}: unsupported sector size \
+                                         {} for {:?}",
+                                        number, sector_size, format);
+                    return Err(self.bad_image(error));
+                }
+            };
+
+        if file_count > 1 {
+            let error = format!("Track {}: split data files are not \
+                                 supported", number);
+            return Err(self.bad_image(error));
+        }
+
+        let file = try!(self.data_file(footer_offset));
+
+        Ok(MdsTrack {
+            number: number,
+            format: format,
+            control: Control::from_bits_truncate(adr_ctl & 0xf),
+            data: data,
+            sector_size: sector_size,
+            start: start,
+            offset: file_offset,
+            pregap: pregap,
+            length: length,
+            file: file,
+        })
+    }
+
+    /// Resolve the data file referenced by the footer at `offset` and
+    /// return its position in `file_paths`
+    fn data_file(&mut self, offset: usize) -> Result<usize, CdError> {
+        let path =
+            if offset == 0 {
+                self.mds_path.with_extension("mdf")
+            } else {
+                let name_offset = try!(self.u32(offset)) as usize;
+                let wide = try!(self.u32(offset + 4)) != 0;
+
+                let name = try!(self.string(name_offset, wide));
+
+                if name.starts_with("*.") {
+                    // Same name as the .mds with a different extension
+                    self.mds_path.with_extension(&name[2..])
+                } else {
+                    match self.mds_path.parent() {
+                        Some(dir) => dir.join(name),
+                        None => PathBuf::from(name),
+                    }
+                }
+            };
+
+        match self.file_paths.iter().position(|p| *p == path) {
+            Some(i) => Ok(i),
+            None => {
+                self.file_paths.push(path);
+                Ok(self.file_paths.len() - 1)
+            }
+        }
+    }
+
+    /// Read the NUL-terminated string at `offset`, stored as UTF-16
+    /// if `wide` is true, as ASCII otherwise
+    fn string(&self, offset: usize, wide: bool) -> Result<String, CdError> {
+        let mut s = String::new();
+        let mut pos = offset;
+
+        loop {
+            let c =
+                if wide {
+                    let c = try!(self.u16(pos));
+                    pos += 2;
+                    c as u32
+                } else {
+                    let c = try!(self.u8(pos));
+                    pos += 1;
+                    c as u32
+                };
+
+            if c == 0 {
+                return Ok(s);
+            }
+
+            // Non-BMP characters are replaced, we don't expect them
+            // in file names
+            s.push(::std::char::from_u32(c).unwrap_or('\u{fffd}'));
+        }
+    }
+
+    /// Parse the Data Position Measurement block, if any
+    fn parse_dpm(&self) -> Result<Option<Dpm>, CdError> {
+        let offset = try!(self.u32(84)) as usize;
+
+        if offset == 0 {
+            return Ok(None);
+        }
+
+        let count = try!(self.u32(offset));
+
+        if count == 0 {
+            return Ok(None);
+        }
+
+        // There's normally only one DPM block, additional ones would
+        // be for other layers of DVDs.
+        let block = try!(self.u32(offset + 4)) as usize;
+
+        let start = try!(self.u32(block + 4));
+        let resolution = try!(self.u32(block + 8));
+        let entry_count = try!(self.u32(block + 12)) as usize;
+
+        let start =
+            match Msf::from_sector_index(start + 150) {
+                Some(m) if resolution > 0 => m,
+                _ => return Err(self.bad_image("Invalid DPM block"
+                                               .to_string())),
+            };
+
+        // Make sure we don't attempt to allocate a huge buffer
+        try!(self.bytes(block + 16, entry_count * 4));
+
+        let mut entries = Vec::with_capacity(entry_count);
+
+        for i in 0..entry_count {
+            entries.push(try!(self.u32(block + 16 + i * 4)));
+        }
+
+        Ok(Some(Dpm {
+            start: start,
+            resolution: resolution,
+            entries: entries,
+        }))
+    }
+
+    /// Make sure the data files are big enough to hold all the tracks
+    fn check_file_sizes(&self,
+                        sessions: &[MdsSession]) -> Result<(), CdError> {
+        for session in sessions {
+            for track in &session.tracks {
+                let path = &self.file_paths[track.file];
+
+                let len =
+                    match metadata(path) {
+                        Ok(m) => m.len(),
+                        Err(e) => return Err(CdError::IoError(e)),
+                    };
+
+                let end =
+                    track.offset +
+                    track.length as u64 * track.sector_size as u64;
+
+                if end > len {
+                    let error = format!("{} is too small for track {}",
+                                        path.display(), track.number);
+
+                    return Err(self.bad_image(error));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Build the index cache from the session list
+    fn build_indices(&self, sessions: &[MdsSession])
+                     -> Result<IndexCache<Storage>, CdError> {
+        let mut indices = Vec::new();
+        let mut lead_outs = Vec::new();
+
+        for session in sessions {
+            let mut previous: Option<&MdsTrack> = None;
+
+            for track in &session.tracks {
+                let start = try!(self.lba_msf(track.start));
+
+                let storage = |offset| {
+                    Storage::Mdf {
+                        file: track.file,
+                        offset: offset,
+                        sector_size: track.sector_size,
+                        data: track.data,
+                    }
+                };
+
+                let pregap_bytes =
+                    track.pregap as u64 * track.sector_size as u64;
+
+                let pregap =
+                    match previous {
+                        // Track 01's pregap always starts at 00:00:00
+                        None if session.number == 1 =>
+                            Some((Msf::zero(), Storage::PreGap)),
+                        // The pregap of the first track of the other
+                        // sessions is not stored
+                        None if track.pregap > 0 =>
+                            Some((try!(self.lba_msf(track.start -
+                                                    track.pregap as i32)),
+                                  Storage::PreGap)),
+                        None => None,
+                        Some(_) if track.pregap == 0 => None,
+                        Some(p) => {
+                            let pregap_start =
+                                try!(self.lba_msf(track.start -
+                                                  track.pregap as i32));
+
+                            // The pregap is normally stored right
+                            // before INDEX 01, after the previous
+                            // track's data
+                            let previous_end =
+                                p.offset +
+                                p.length as u64 * p.sector_size as u64;
+
+                            let stored =
+                                p.file == track.file &&
+                                track.offset >= pregap_bytes &&
+                                track.offset - pregap_bytes >= previous_end;
+
+                            if stored {
+                                Some((pregap_start,
+                                      storage(track.offset - pregap_bytes)))
+                            } else {
+                                Some((pregap_start, Storage::PreGap))
+                            }
+                        }
+                    };
+
+                if let Some((msf, storage)) = pregap {
+                    let mut index = Index::new(Bcd::zero(),
+                                               msf,
+                                               track.number,
+                                               track.format,
+                                               session.number,
+                                               storage);
+
+                    index.set_control(track.control);
+
+                    indices.push(index);
+                }
+
+                let mut index = Index::new(Bcd::one(),
+                                           start,
+                                           track.number,
+                                           track.format,
+                                           session.number,
+                                           storage(track.offset));
+
+                index.set_control(track.control);
+
+                indices.push(index);
+
+                previous = Some(track);
+            }
+
+            lead_outs.push(try!(self.lba_msf(session.end)));
+        }
+
+        IndexCache::new_multisession(self.mds_path.clone(),
+                                     indices,
+                                     lead_outs)
+    }
+
+    /// Convert an LBA into an absolute MSF
+    fn lba_msf(&self, lba: i32) -> Result<Msf, CdError> {
+        let sector = lba + 150;
+
+        if sector < 0 {
+            let error = format!("Invalid sector address {}", lba);
+            return Err(self.bad_image(error));
+        }
+
+        match Msf::from_sector_index(sector as u32) {
+            Some(m) => Ok(m),
+            None => {
+                let error = format!("Invalid sector address {}", lba);
+                Err(self.bad_image(error))
+            }
+        }
+    }
+}
+
+fn read_file(path: &Path, max_len: u64) -> Result<Vec<u8>, io::Error> {
+    let md = try!(metadata(path));
+
+    let len = md.len();
+
+    if len > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "MDS file is too big"));
+    }
+
+    let mut file = try!(File::open(path));
+
+    let mut mds = Vec::with_capacity(len as usize);
+
+    try!(file.read_to_end(&mut mds));
+
+    Ok(mds)
+}
+
+/// Length of the file header
+const HEADER_LEN: usize = 0x58;
+/// Length of a session block
+const SESSION_BLOCK_LEN: usize = 24;
+/// Length of a track block
+const TRACK_BLOCK_LEN: usize = 80;
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use Image;
+    use TrackFormat;
+    use toc::Control;
+    use bcd::Bcd;
+    use msf::Msf;
+    use sector::Sector;
+    use mds::{Mds, Storage, DataType};
+    use test_util::{temp_dir, write_file};
+
+    use super::MdsParser;
+
+    fn put16(m: &mut [u8], offset: usize, v: u16) {
+        m[offset] = v as u8;
+        m[offset + 1] = (v >> 8) as u8;
+    }
+
+    fn put32(m: &mut [u8], offset: usize, v: u32) {
+        put16(m, offset, v as u16);
+        put16(m, offset + 2, (v >> 16) as u16);
+    }
+
+    /// LBA of track 03's INDEX 01: session 1's lead-out at LBA 10,
+    /// the standard 1m30s lead-out and 1m lead-in then track 03's 2
+    /// second pregap
+    const TRACK03_LBA: u32 = 10 + 6750 + 4500 + 150;
+
+    /// Build an MDS descriptor for a two session disc:
+    ///
+    /// - Session 1: track 01 Mode 1 (2048 bytes per sector, 4
+    ///   sectors at LBA 0), track 02 audio with subchannel data
+    ///   (2448 bytes per sector, 2 sector stored pregap and 4
+    ///   sectors at LBA 6), lead-out at LBA 10.
+    /// - Session 2: track 03 Mode 2 (2336 bytes per sector, 3
+    ///   sectors), lead-out 3 sectors later.
+    ///
+    /// Each session also has a lead-in 0xa0 block which must be
+    /// ignored.
+    fn mds() -> Vec<u8> {
+        let sessions = 0x58;
+        let tracks1 = sessions + 2 * 24;
+        let tracks2 = tracks1 + 3 * 80;
+        let extra = tracks2 + 2 * 80;
+        let footer = extra + 3 * 8;
+        let name = footer + 16;
+
+        let mut m = vec![0; name + 6];
+
+        m[0..16].copy_from_slice(b"MEDIA DESCRIPTOR");
+        m[16] = 1;
+        m[17] = 3;
+        put16(&mut m, 20, 2);
+        put32(&mut m, 80, sessions as u32);
+
+        for &(s, start, end, blocks, first, tracks) in
+            &[(1, -150i32, 10, 3, 1, tracks1),
+              (2, TRACK03_LBA as i32 - 150, TRACK03_LBA + 3, 2, 3, tracks2)] {
+                let block = sessions + (s - 1) * 24;
+
+                put32(&mut m, block, start as u32);
+                put32(&mut m, block + 4, end);
+                put16(&mut m, block + 8, s as u16);
+                m[block + 10] = blocks;
+                m[block + 11] = 1;
+                put16(&mut m, block + 12, first);
+                put16(&mut m, block + 14, first + blocks as u16 - 2);
+                put32(&mut m, block + 20, tracks as u32);
+
+                // Lead-in entry
+                m[tracks + 2] = 0x14;
+                m[tracks + 4] = 0xa0;
+            }
+
+        // (block, mode, control, point, sector size, LBA, offset)
+        let t2_offset = 4 * 2048 + 2 * 2448;
+
+        let tracks = [(tracks1 + 80, 0xaa, 0x14, 1, 2048, 0, 0),
+                      (tracks1 + 160, 0xa9, 0x10, 2, 2448, 6, t2_offset),
+                      (tracks2 + 80, 0xac, 0x14, 3, 2336, TRACK03_LBA,
+                       t2_offset + 4 * 2448)];
+
+        for (i, &(block, mode, control, point, size, lba, offset))
+            in tracks.iter().enumerate() {
+                m[block] = mode;
+                m[block + 2] = control;
+                m[block + 4] = point;
+                put32(&mut m, block + 12, (extra + i * 8) as u32);
+                put16(&mut m, block + 16, size);
+                put32(&mut m, block + 36, lba);
+                put32(&mut m, block + 40, offset);
+                put32(&mut m, block + 48, 1);
+                put32(&mut m, block + 52, footer as u32);
+            }
+
+        // Pregap and length of each track
+        for (i, &(pregap, length)) in [(0, 4), (2, 4), (150, 3)].iter()
+            .enumerate() {
+                put32(&mut m, extra + i * 8, pregap);
+                put32(&mut m, extra + i * 8 + 4, length);
+            }
+
+        // The data file has the same name as the descriptor
+        put32(&mut m, footer, name as u32);
+        m[name..name + 6].copy_from_slice(b"*.mdf\0");
+
+        m
+    }
+
+    /// Sector data for the `mds()` layout. The pattern depends on the
+    /// position of the byte in the file.
+    fn mdf() -> Vec<u8> {
+        let len = 4 * 2048 + 6 * 2448 + 3 * 2336;
+
+        (0..len).map(|i| (i * 7 + i / 1024) as u8).collect()
+    }
+
+    fn msf(sector: u32) -> Msf {
+        Msf::from_sector_index(sector).unwrap()
+    }
+
+    fn parser(path: &Path) -> MdsParser {
+        MdsParser {
+            mds_path: path.to_path_buf(),
+            mds: mds(),
+            file_paths: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parse_sessions() {
+        let path = Path::new("/images/disc.mds");
+
+        let mut parser = parser(path);
+
+        let sessions = parser.parse_sessions().unwrap();
+
+        assert!(sessions.len() == 2);
+        assert!(sessions[0].number == 1);
+        assert!(sessions[0].end == 10);
+        assert!(sessions[0].tracks.len() == 2);
+        assert!(sessions[1].number == 2);
+        assert!(sessions[1].end == TRACK03_LBA as i32 + 3);
+        assert!(sessions[1].tracks.len() == 1);
+
+        let t1 = &sessions[0].tracks[0];
+
+        assert!(t1.number == Bcd::one());
+        assert!(t1.format == TrackFormat::Mode1);
+        assert!(t1.control == Control::from_format(TrackFormat::Mode1));
+        assert!(t1.data == DataType::Mode1);
+        assert!(t1.sector_size == 2048);
+        assert!(t1.start == 0);
+        assert!(t1.offset == 0);
+        assert!(t1.pregap == 0);
+        assert!(t1.length == 4);
+
+        let t2 = &sessions[0].tracks[1];
+
+        assert!(t2.number == Bcd::from_binary(2).unwrap());
+        assert!(t2.format == TrackFormat::Audio);
+        assert!(t2.control == Control::empty());
+        assert!(t2.data == DataType::Raw);
+        assert!(t2.sector_size == 2448);
+        assert!(t2.start == 6);
+        assert!(t2.offset == 4 * 2048 + 2 * 2448);
+        assert!(t2.pregap == 2);
+        assert!(t2.length == 4);
+
+        let t3 = &sessions[1].tracks[0];
+
+        assert!(t3.format == TrackFormat::Mode2Xa);
+        assert!(t3.data == DataType::Mode2);
+        assert!(t3.sector_size == 2336);
+        assert!(t3.start == TRACK03_LBA as i32);
+        assert!(t3.pregap == 150);
+        assert!(t3.length == 3);
+
+        // All the tracks use the same data file
+        assert!(t1.file == 0 && t2.file == 0 && t3.file == 0);
+        assert!(parser.file_paths == vec![path.with_extension("mdf")]);
+    }
+
+    #[test]
+    fn parse_track() {
+        let mut parser = parser(Path::new("disc.mds"));
+
+        // Unsupported mode
+        parser.mds[0x58 + 48 + 80] = 0xa8;
+
+        assert!(parser.parse_track(0x58 + 48 + 80).is_err());
+
+        // Unsupported sector size for a Mode 1 track
+        parser.mds[0x58 + 48 + 80] = 0xaa;
+        parser.mds[0x58 + 48 + 80 + 16] = 0x30;
+
+        assert!(parser.parse_track(0x58 + 48 + 80).is_err());
+
+        // Split data files
+        parser.mds[0x58 + 48 + 80 + 16] = 0x00;
+        parser.mds[0x58 + 48 + 80 + 48] = 2;
+
+        assert!(parser.parse_track(0x58 + 48 + 80).is_err());
+
+        parser.mds[0x58 + 48 + 80 + 48] = 1;
+
+        assert!(parser.parse_track(0x58 + 48 + 80).is_ok());
+    }
+
+    #[test]
+    fn build_indices() {
+        let mut parser = parser(Path::new("disc.mds"));
+
+        let sessions = parser.parse_sessions().unwrap();
+        let cache = parser.build_indices(&sessions).unwrap();
+
+        let t2_offset = 4 * 2048 + 2 * 2448;
+
+        // (sector, track, index, session, offset in the data file)
+        let expected = [(0, 1, 0, 1, None),
+                        (150, 1, 1, 1, Some(0)),
+                        // Track 02's pregap is stored right before
+                        // its INDEX 01
+                        (154, 2, 0, 1, Some(4 * 2048)),
+                        (156, 2, 1, 1, Some(t2_offset)),
+                        // The pregap of the first track of a session
+                        // isn't stored
+                        (TRACK03_LBA, 3, 0, 2, None),
+                        (TRACK03_LBA + 150, 3, 1, 2,
+                         Some(t2_offset + 4 * 2448))];
+
+        for (pos, &(sector, track, index, session, offset))
+            in expected.iter().enumerate() {
+                let i = cache.get(pos).unwrap();
+
+                assert!(i.sector_index() == sector);
+                assert!(i.track().binary() == track);
+                assert!(i.index().binary() == index);
+                assert!(i.session() == session);
+
+                match (i.private(), offset) {
+                    (&Storage::Mdf { offset: o, file: 0, .. }, Some(e)) =>
+                        assert!(o == e),
+                    (&Storage::PreGap, None) => (),
+                    _ => panic!("Unexpected storage for index {}", pos),
+                }
+            }
+
+        assert!(cache.get(expected.len()).is_none());
+        assert!(cache.session_lead_out(1).unwrap() == msf(160));
+        assert!(cache.session_lead_out(2).unwrap() ==
+                msf(TRACK03_LBA + 153));
+    }
+
+    #[test]
+    fn read_sectors() {
+        let dir = temp_dir("mds");
+        let data = mdf();
+
+        write_file(&dir, "disc.mdf", &data);
+
+        let path = write_file(&dir, "disc.mds", &mds());
+
+        let mut mds = Mds::new(&path).unwrap();
+        let mut sector = Sector::empty();
+
+        assert!(mds.dpm().is_none());
+        assert!(mds.toc().sessions.len() == 2);
+
+        // Track 01, cooked Mode 1
+        mds.read_sector(&mut sector, msf(151)).unwrap();
+
+        assert!(&sector.mode1_payload().unwrap()[..] == &data[2048..4096]);
+        assert!(!sector.has_subchannel());
+
+        // Track 02's pregap and data, with subchannel
+        for s in 154..160 {
+            let offset = 4 * 2048 + (s - 154) as usize * 2448;
+
+            mds.read_sector(&mut sector, msf(s)).unwrap();
+
+            let subchannel = sector.subchannel_interleaved().unwrap();
+
+            assert!(&sector.data_2352().unwrap()[..] ==
+                    &data[offset..offset + 2352]);
+            assert!(&subchannel[..] == &data[offset + 2352..offset + 2448]);
+            assert!(sector.metadata().index.binary() ==
+                    if s < 156 { 0 } else { 1 });
+        }
+
+        // Track 03 in session 2
+        let offset = 4 * 2048 + 6 * 2448 + 2336;
+
+        mds.read_sector(&mut sector, msf(TRACK03_LBA + 151)).unwrap();
+
+        assert!(sector.metadata().session == 2);
+        assert!(&sector.data_2352().unwrap()[16..] ==
+                &data[offset..offset + 2336]);
+    }
+}
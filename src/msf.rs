@@ -145,6 +145,12 @@ impl fmt::Display for Msf {
     }
 }
 
+impl fmt::Debug for Msf {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, fmt)
+    }
+}
+
 impl cmp::PartialOrd for Msf {
     fn partial_cmp(&self, other: &Msf) -> Option<cmp::Ordering> {
         let a = self.as_u32_bcd();
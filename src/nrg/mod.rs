@@ -0,0 +1,178 @@
+//! Nero Burning ROM (NRG) image format implementation
+//!
+//! An NRG image is a single file containing the sector data of every
+//! track followed by a list of "chunks" describing the layout of the
+//! disc. The position of the first chunk is stored in a footer at
+//! the very end of the file: "NERO" followed by a 32 bit offset for
+//! the original format, "NER5" followed by a 64 bit offset for the
+//! version 2 format introduced with Nero 5.5.
+//!
+//! Discs written in disc-at-once mode are described by a cue sheet
+//! (`CUES`/`CUEX` chunks) and a track list with the position of each
+//! track in the file (`DAOI`/`DAOX` chunks), one of each per
+//! session. Track-at-once discs only have a track list (`ETNF`/`ETN2`
+//! chunks). Every chunk contains big-endian values.
+//!
+//! Each track can be stored with a different sector size: 2048 or
+//! 2336 bytes for "cooked" data tracks, 2352 bytes for raw sectors
+//! and 2448 bytes for raw sectors followed by 96 bytes of subchannel
+//! data. Audio samples are stored big-endian.
+
+use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+use CdError;
+use Image;
+use internal::IndexCache;
+use sector::{Sector, SectorBuilder};
+use msf::Msf;
+use bcd::Bcd;
+use toc::Toc;
+
+use self::parser::NrgParser;
+
+mod parser;
+
+/// Nero image
+#[derive(Debug)]
+pub struct Nrg {
+    /// Cache of all the indices in the CD image
+    indices: IndexCache<Storage>,
+    /// The NRG file itself
+    file: File,
+}
+
+impl Nrg {
+    /// Open an `.nrg` file, parse its chunks and build a `Nrg`
+    /// instance.
+    pub fn new(path: &Path) -> Result<Nrg, CdError> {
+        NrgParser::build_nrg(path)
+    }
+}
+
+impl Image for Nrg {
+    fn image_format(&self) -> String {
+        "Nero".to_string()
+    }
+
+    fn read_sector(&mut self,
+                   sector: &mut Sector,
+                   msf: Msf) -> Result<(), CdError> {
+        let (index, metadata) = try!(self.indices.locate(msf));
+
+        let mut builder = SectorBuilder::new(sector);
+
+        match index.private() {
+            &Storage::Nrg { offset, sector_size, data } => {
+                let offset =
+                    offset +
+                    (msf.sector_index() - index.sector_index()) as u64 *
+                    sector_size as u64;
+
+                let file = &mut self.file;
+
+                let res =
+                    match data {
+                        DataType::Audio => builder.set_data_2352(|d| {
+                            try!(read_at(file, offset, d));
+
+                            // Convert the samples to little-endian
+                            for s in d.chunks_mut(2) {
+                                s.swap(0, 1);
+                            }
+
+                            Ok(())
+                        }),
+                        DataType::Raw =>
+                            builder.set_data_2352(
+                                |d| read_at(file, offset, d)),
+                        DataType::Mode1 =>
+                            builder.set_mode1_payload(
+                                |p| read_at(file, offset, p)),
+                        DataType::Mode2 =>
+                            builder.set_mode2_payload(
+                                |p| read_at(file, offset, p)),
+                        DataType::Mode2Form1 =>
+                            builder.set_mode2_form1_payload(
+                                |p| read_at(file, offset, p)),
+                    };
+
+                if let Err(e) = res {
+                    return Err(CdError::IoError(e));
+                }
+
+                // The subchannel data follows the 2352 bytes of
+                // sector data
+                if sector_size == 2448 {
+                    let res =
+                        builder.set_subchannel_interleaved(
+                            |s| read_at(file, offset + 2352, s));
+
+                    if let Err(e) = res {
+                        return Err(CdError::IoError(e));
+                    }
+                }
+            }
+            &Storage::PreGap => builder.set_blank(index.format()),
+        }
+
+        builder.set_metadata(metadata);
+
+        Ok(())
+    }
+
+    fn track_msf(&self, track: Bcd, track_msf: Msf) -> Result<Msf, CdError> {
+        self.indices.track_msf(track, track_msf)
+    }
+
+    fn toc(&self) -> Toc {
+        self.indices.toc()
+    }
+}
+
+/// Storage for an index
+#[derive(Debug, Clone, Copy)]
+enum Storage {
+    /// The index is stored in the NRG file
+    Nrg {
+        /// Offset of the index's first sector in the file
+        offset: u64,
+        /// Size of a sector in the file, including the subchannel
+        /// data if any
+        sector_size: u16,
+        /// Format of the sector data
+        data: DataType,
+    },
+    /// The index is a pre-gap, it's not stored in the image and must
+    /// be regenerated.
+    PreGap,
+}
+
+/// Format of the sector data stored in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DataType {
+    /// 2352 bytes of big-endian audio samples
+    Audio,
+    /// Full 2352 bytes of sector data
+    Raw,
+    /// 2048 bytes of Mode 1 user data
+    Mode1,
+    /// 2336 bytes of Mode 2 data (everything but the sync pattern
+    /// and header)
+    Mode2,
+    /// 2048 bytes of Mode 2 Form 1 user data
+    Mode2Form1,
+}
+
+/// Fill `buf` with the data located at `offset` in `file`
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    try!(file.seek(SeekFrom::Start(offset)));
+
+    file.read_exact(buf)
+}
+
+/// Max size for the chunk list, used to detect bogus input early
+/// without attempting to load a huge buffer to RAM.
+pub const NRG_MAX_CHUNKS_LENGTH: u64 = 16 * 1024 * 1024;
@@ -0,0 +1,1133 @@
+use std::path::{Path, PathBuf};
+use std::fs::File;
+
+use internal::{Index, IndexCache};
+use toc::Control;
+use CdError;
+use TrackFormat;
+use SessionFormat;
+
+use bcd::Bcd;
+use msf::Msf;
+
+use super::{Nrg, Storage, DataType, NRG_MAX_CHUNKS_LENGTH, read_at};
+
+pub struct NrgParser {
+    /// Path to the `.nrg` file
+    path: PathBuf,
+    /// True for the version 2 format ("NER5" footer)
+    v2: bool,
+    /// Offset of the first chunk in the file, the sector data is
+    /// stored before it
+    chunks_offset: u64,
+    /// Chunks in the order they appear in the file
+    chunks: Vec<Chunk>,
+}
+
+/// A single chunk from the chunk list
+struct Chunk {
+    id: [u8; 4],
+    data: Vec<u8>,
+}
+
+/// Entry in a cue sheet chunk
+struct CueEntry {
+    control: Control,
+    /// Track number in BCD, 0 for the lead-in and 0xaa for the
+    /// lead-out
+    track: u8,
+    index: Bcd,
+    /// Absolute sector index
+    sector: u32,
+}
+
+/// Session description
+struct NrgSession {
+    number: u8,
+    tracks: Vec<NrgTrack>,
+    /// Absolute sector index of the session's lead-out
+    lead_out: u32,
+}
+
+/// Track description
+struct NrgTrack {
+    number: Bcd,
+    format: TrackFormat,
+    control: Control,
+    data: DataType,
+    sector_size: u16,
+    /// Index numbers and their absolute sector index, in order
+    indices: Vec<(Bcd, u32)>,
+    /// Offset of the pregap in the file. If it's equal to
+    /// `start_offset` the pregap is not stored.
+    pregap_offset: u64,
+    /// Offset of INDEX 01 in the file
+    start_offset: u64,
+}
+
+impl NrgParser {
+    /// Open an `.nrg` file, parse its chunks and generate the CD
+    /// structure
+    pub fn build_nrg(path: &Path) -> Result<Nrg, CdError> {
+        let mut file =
+            match File::open(path) {
+                Ok(f) => f,
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        let mut parser = NrgParser {
+            path: PathBuf::from(path),
+            v2: false,
+            chunks_offset: 0,
+            chunks: Vec::new(),
+        };
+
+        try!(parser.read_chunks(&mut file));
+
+        try!(parser.check_media_type());
+
+        let has_chunk = |id| parser.chunks.iter().any(|c| &c.id == id);
+
+        let sessions =
+            if has_chunk(b"DAOX") || has_chunk(b"DAOI") {
+                try!(parser.dao_sessions())
+            } else if has_chunk(b"ETN2") || has_chunk(b"ETNF") {
+                try!(parser.tao_sessions())
+            } else {
+                return Err(parser.bad_image("Missing track list"
+                                            .to_string()));
+            };
+
+        let indices = try!(parser.build_indices(&sessions));
+
+        Ok(Nrg {
+            indices: indices,
+            file: file,
+        })
+    }
+
+    fn bad_image(&self, msg: String) -> CdError {
+        CdError::BadImage(self.path.clone(), msg)
+    }
+
+    /// Locate the chunk list using the footer and load it
+    fn read_chunks(&mut self, file: &mut File) -> Result<(), CdError> {
+        let len =
+            match file.metadata() {
+                Ok(m) => m.len(),
+                Err(e) => return Err(CdError::IoError(e)),
+            };
+
+        let mut footer = [0; 12];
+
+        if len < footer.len() as u64 {
+            return Err(self.bad_image("Not an NRG file".to_string()));
+        }
+
+        let footer_offset = len - footer.len() as u64;
+
+        if let Err(e) = read_at(file, footer_offset, &mut footer) {
+            return Err(CdError::IoError(e));
+        }
+
+        let footer_len =
+            if &footer[0..4] == b"NER5" {
+                self.v2 = true;
+                self.chunks_offset = be64(&footer[4..12]);
+                12
+            } else if &footer[4..8] == b"NERO" {
+                self.chunks_offset = be32(&footer[8..12]) as u64;
+                8
+            } else {
+                return Err(self.bad_image("Not an NRG file".to_string()));
+            };
+
+        let chunks_end = len - footer_len;
+
+        if self.chunks_offset > chunks_end ||
+            chunks_end - self.chunks_offset > NRG_MAX_CHUNKS_LENGTH {
+            let error = format!("Invalid chunk list offset 0x{:x}",
+                                self.chunks_offset);
+            return Err(self.bad_image(error));
+        }
+
+        let mut chunks =
+            vec![0; (chunks_end - self.chunks_offset) as usize];
+
+        if let Err(e) = read_at(file, self.chunks_offset, &mut chunks) {
+            return Err(CdError::IoError(e));
+        }
+
+        let mut pos = 0;
+
+        loop {
+            if pos + 8 > chunks.len() {
+                return Err(self.bad_image("Truncated chunk list"
+                                          .to_string()));
+            }
+
+            let id = *array_ref![chunks, pos, 4];
+            let size = be32(&chunks[pos + 4..pos + 8]) as usize;
+
+            if &id == b"END!" {
+                break;
+            }
+
+            pos += 8;
+
+            if size > chunks.len() - pos {
+                let error = format!("Truncated {} chunk",
+                                    String::from_utf8_lossy(&id));
+                return Err(self.bad_image(error));
+            }
+
+            self.chunks.push(Chunk {
+                id: id,
+                data: chunks[pos..pos + size].to_vec(),
+            });
+
+            pos += size;
+        }
+
+        Ok(())
+    }
+
+    /// Return all the chunks with the given `id` (version 2 format)
+    /// or `id_v1` (original format)
+    fn chunks(&self, id: &[u8; 4], id_v1: &[u8; 4]) -> Vec<&Chunk> {
+        let id = if self.v2 { id } else { id_v1 };
+
+        self.chunks.iter().filter(|c| &c.id == id).collect()
+    }
+
+    /// Make sure the image is a CD image using the MTYP chunk, if
+    /// present
+    fn check_media_type(&self) -> Result<(), CdError> {
+        let chunk =
+            match self.chunks.iter().find(|c| &c.id == b"MTYP") {
+                Some(c) => c,
+                None => return Ok(()),
+            };
+
+        if chunk.data.len() < 4 {
+            return Err(self.bad_image("Invalid MTYP chunk".to_string()));
+        }
+
+        let media_type = be32(&chunk.data);
+
+        // 0 means that the media type is unknown, otherwise it's a
+        // bitfield of compatible media types
+        if media_type != 0 && media_type & (MEDIA_CD | MEDIA_CDROM) == 0 {
+            let error = format!("Unsupported media type 0x{:x}",
+                                media_type);
+            return Err(self.bad_image(error));
+        }
+
+        Ok(())
+    }
+
+    /// Return the number of tracks in each session from the SINF
+    /// chunks
+    fn session_info(&self) -> Result<Vec<u32>, CdError> {
+        let mut sessions = Vec::new();
+
+        for chunk in self.chunks.iter().filter(|c| &c.id == b"SINF") {
+            if chunk.data.len() < 4 {
+                return Err(self.bad_image("Invalid SINF chunk"
+                                          .to_string()));
+            }
+
+            sessions.push(be32(&chunk.data));
+        }
+
+        Ok(sessions)
+    }
+
+    /// Split the `chunk` data into entries of `size` bytes following a
+    /// header of `header` bytes.
+    fn entries<'a>(&self,
+                   chunk: &'a Chunk,
+                   header: usize,
+                   size: usize) -> Result<Vec<&'a [u8]>, CdError> {
+        let len = chunk.data.len();
+
+        if len < header || (len - header) % size != 0 {
+            let error = format!("Invalid {} chunk length {}",
+                                String::from_utf8_lossy(&chunk.id), len);
+            return Err(self.bad_image(error));
+        }
+
+        Ok(chunk.data[header..].chunks(size).collect())
+    }
+
+    /// Parse a cue sheet chunk
+    fn parse_cue(&self, chunk: &Chunk) -> Result<Vec<CueEntry>, CdError> {
+        let mut cue = Vec::new();
+
+        for entry in try!(self.entries(chunk, 0, 8)) {
+            let (control, track, index) = (entry[0] >> 4, entry[1], entry[2]);
+
+            // We don't need the lead-in entries
+            if track == 0 {
+                continue;
+            }
+
+            let sector =
+                if self.v2 {
+                    // Signed LBA
+                    be32(&entry[4..8]) as i32 as i64 + 150
+                } else {
+                    // Absolute MSF, in binary
+                    let (m, s, f) = (entry[5], entry[6], entry[7]);
+
+                    if s >= 60 || f >= 75 {
+                        let error = format!("Invalid MSF {}:{}:{} in cue \
+                                             sheet", m, s, f);
+                        return Err(self.bad_image(error));
+                    }
+
+                    m as i64 * 60 * 75 + s as i64 * 75 + f as i64
+                };
+
+            let index =
+                match Bcd::from_bcd(index) {
+                    Some(i) if valid_sector(sector) => i,
+                    _ => {
+                        let error = format!("Invalid cue sheet entry for \
+                                             track {:02x}", track);
+                        return Err(self.bad_image(error));
+                    }
+                };
+
+            cue.push(CueEntry {
+                control: Control::from_bits_truncate(control),
+                track: track,
+                index: index,
+                sector: sector as u32,
+            });
+        }
+
+        Ok(cue)
+    }
+
+    /// Build the session list of a disc-at-once image using the cue
+    /// sheet and DAO chunks. There's one of each for every session.
+    fn dao_sessions(&self) -> Result<Vec<NrgSession>, CdError> {
+        let cues = self.chunks(b"CUEX", b"CUES");
+        let daos = self.chunks(b"DAOX", b"DAOI");
+
+        if cues.len() != daos.len() {
+            let error = format!("Found {} cue sheets for {} sessions",
+                                cues.len(), daos.len());
+            return Err(self.bad_image(error));
+        }
+
+        let sinf = try!(self.session_info());
+
+        let mut sessions = Vec::new();
+
+        for (cue, dao) in cues.into_iter().zip(daos) {
+            let number = sessions.len() as u8 + 1;
+
+            let cue = try!(self.parse_cue(cue));
+
+            let entry_size = if self.v2 { 42 } else { 30 };
+
+            let entries = try!(self.entries(dao, DAO_HEADER_LEN, entry_size));
+
+            let header = &dao.data[..DAO_HEADER_LEN];
+
+            // The session type uses the same encoding as the disc
+            // type in the lead-in's TOC
+            let format =
+                match be16(&header[18..20]) {
+                    0x10 => SessionFormat::Cdi,
+                    0x20 => SessionFormat::Cdxa,
+                    _ => SessionFormat::CddaCdRom,
+                };
+
+            let first_track = header[20];
+
+            if let Some(&count) = sinf.get(number as usize - 1) {
+                if count as usize != entries.len() {
+                    let error = format!("Session {} should have {} tracks, \
+                                         found {}",
+                                        number, count, entries.len());
+                    return Err(self.bad_image(error));
+                }
+            }
+
+            let mut tracks = Vec::new();
+
+            for (i, entry) in entries.into_iter().enumerate() {
+                let number =
+                    match Bcd::from_binary(first_track.wrapping_add(i as u8)) {
+                        Some(n) if n != Bcd::zero() => n,
+                        _ => {
+                            let error = format!("Invalid track number {}",
+                                                first_track as usize + i);
+                            return Err(self.bad_image(error));
+                        }
+                    };
+
+                let sector_size = be16(&entry[12..14]);
+                let mode = entry[14];
+
+                let (pregap_offset, start_offset) =
+                    if self.v2 {
+                        (be64(&entry[18..26]), be64(&entry[26..34]))
+                    } else {
+                        (be32(&entry[18..22]) as u64,
+                         be32(&entry[22..26]) as u64)
+                    };
+
+                let (format, data) =
+                    try!(self.track_mode(number, mode, sector_size, format));
+
+                let track_cue =
+                    cue.iter().filter(|e| e.track == number.bcd());
+
+                let control =
+                    match track_cue.clone().next() {
+                        Some(e) => e.control,
+                        None => {
+                            let error = format!("Track {} is missing from \
+                                                 the cue sheet", number);
+                            return Err(self.bad_image(error));
+                        }
+                    };
+
+                let indices = track_cue.map(|e| (e.index, e.sector)).collect();
+
+                if pregap_offset > start_offset {
+                    let error = format!("Track {}: invalid pregap offset",
+                                        number);
+                    return Err(self.bad_image(error));
+                }
+
+                if start_offset > self.chunks_offset {
+                    let error = format!("Track {}: invalid data offset",
+                                        number);
+                    return Err(self.bad_image(error));
+                }
+
+                tracks.push(NrgTrack {
+                    number: number,
+                    format: format,
+                    control: control,
+                    data: data,
+                    sector_size: sector_size,
+                    indices: indices,
+                    pregap_offset: pregap_offset,
+                    start_offset: start_offset,
+                });
+            }
+
+            let lead_out =
+                match cue.iter().find(|e| e.track == 0xaa) {
+                    Some(e) => e.sector,
+                    None => {
+                        let error = format!("Missing lead-out for session {}",
+                                            number);
+                        return Err(self.bad_image(error));
+                    }
+                };
+
+            sessions.push(NrgSession {
+                number: number,
+                tracks: tracks,
+                lead_out: lead_out,
+            });
+        }
+
+        Ok(sessions)
+    }
+
+    /// Build the session list of a track-at-once image. There's
+    /// normally one track list chunk per session, otherwise the SINF
+    /// chunks give the number of tracks in each session.
+    fn tao_sessions(&self) -> Result<Vec<NrgSession>, CdError> {
+        let chunks = self.chunks(b"ETN2", b"ETNF");
+
+        let entry_size = if self.v2 { 32 } else { 20 };
+
+        let mut lists = Vec::new();
+
+        for chunk in chunks {
+            lists.push(try!(self.entries(chunk, 0, entry_size)));
+        }
+
+        let sinf = try!(self.session_info());
+
+        if lists.len() == 1 && sinf.len() > 1 {
+            let total = sinf.iter().fold(0, |t, &c| t + c as usize);
+
+            if total != lists[0].len() {
+                let error = format!("Expected {} tracks, found {}",
+                                    total, lists[0].len());
+                return Err(self.bad_image(error));
+            }
+
+            let all = lists.pop().unwrap();
+            let mut pos = 0;
+
+            for &count in &sinf {
+                lists.push(all[pos..pos + count as usize].to_vec());
+                pos += count as usize;
+            }
+        }
+
+        let mut sessions = Vec::new();
+        let mut track_number = Bcd::one();
+        // Absolute sector index of the previous session's lead-out
+        let mut previous_end = 0;
+
+        for entries in lists {
+            let number = sessions.len() as u8 + 1;
+
+            let mut tracks = Vec::new();
+            let mut end = previous_end;
+
+            for entry in entries {
+                let (offset, size, mode, lba) =
+                    if self.v2 {
+                        (be64(&entry[0..8]), be64(&entry[8..16]),
+                         be32(&entry[16..20]), be32(&entry[20..24]))
+                    } else {
+                        (be32(&entry[0..4]) as u64, be32(&entry[4..8]) as u64,
+                         be32(&entry[8..12]), be32(&entry[12..16]))
+                    };
+
+                if mode > 0xff {
+                    let error = format!("Track {}: unsupported mode 0x{:x}",
+                                        track_number, mode);
+                    return Err(self.bad_image(error));
+                }
+
+                if offset > self.chunks_offset {
+                    let error = format!("Track {}: invalid data offset",
+                                        track_number);
+                    return Err(self.bad_image(error));
+                }
+
+                let mode = mode as u8;
+
+                let (format, data) =
+                    try!(self.track_mode(track_number,
+                                         mode,
+                                         0,
+                                         SessionFormat::CddaCdRom));
+
+                let sector_size = data_sector_size(mode).unwrap();
+
+                let start = lba as i64 + 150;
+
+                if !valid_sector(start) {
+                    let error = format!("Track {}: invalid LBA {}",
+                                        track_number, lba);
+                    return Err(self.bad_image(error));
+                }
+
+                let start = start as u32;
+
+                // The gaps aren't stored in track-at-once images
+                let pregap =
+                    if tracks.is_empty() {
+                        if number == 1 {
+                            Some(0)
+                        } else {
+                            Some(start.saturating_sub(150))
+                        }
+                    } else if start > end {
+                        Some(end)
+                    } else {
+                        None
+                    };
+
+                if start < end {
+                    let error = format!("Track {} overlaps the previous one",
+                                        track_number);
+                    return Err(self.bad_image(error));
+                }
+
+                let mut indices = Vec::new();
+
+                if let Some(p) = pregap {
+                    indices.push((Bcd::zero(), p));
+                }
+
+                indices.push((Bcd::one(), start));
+
+                tracks.push(NrgTrack {
+                    number: track_number,
+                    format: format,
+                    control: Control::from_format(format),
+                    data: data,
+                    sector_size: sector_size,
+                    indices: indices,
+                    pregap_offset: offset,
+                    start_offset: offset,
+                });
+
+                let track_end = start as u64 + size / sector_size as u64;
+
+                if track_end > i64::max_value() as u64 ||
+                    !valid_sector(track_end as i64) {
+                    let error = format!("Track {}: invalid size {}",
+                                        track_number, size);
+                    return Err(self.bad_image(error));
+                }
+
+                end = track_end as u32;
+
+                track_number =
+                    match Bcd::from_binary(track_number.binary() + 1) {
+                        Some(n) => n,
+                        None => return Err(self.bad_image("Too many tracks"
+                                                          .to_string())),
+                    };
+            }
+
+            if tracks.is_empty() {
+                let error = format!("Session {} has no tracks", number);
+                return Err(self.bad_image(error));
+            }
+
+            sessions.push(NrgSession {
+                number: number,
+                tracks: tracks,
+                lead_out: end,
+            });
+
+            previous_end = end;
+        }
+
+        Ok(sessions)
+    }
+
+    /// Decode a track's `mode` code. If `sector_size` is not 0 it's
+    /// checked against the sector size of the mode.
+    fn track_mode(&self,
+                  number: Bcd,
+                  mode: u8,
+                  sector_size: u16,
+                  session: SessionFormat)
+                  -> Result<(TrackFormat, DataType), CdError> {
+        let mode2 =
+            match session {
+                SessionFormat::Cdi => TrackFormat::Mode2CdI,
+                _ => TrackFormat::Mode2Xa,
+            };
+
+        let (format, data) =
+            match mode {
+                0x00 => (TrackFormat::Mode1, DataType::Mode1),
+                0x02 => (mode2, DataType::Mode2Form1),
+                0x03 => (mode2, DataType::Mode2),
+                0x05 | 0x0f => (TrackFormat::Mode1, DataType::Raw),
+                0x06 | 0x11 => (mode2, DataType::Raw),
+                0x07 | 0x10 => (TrackFormat::Audio, DataType::Audio),
+                _ => {
+                    let error = format!("Track {}: unsupported mode 0x{:02x}",
+                                        number, mode);
+                    return Err(self.bad_image(error));
+                }
+            };
+
+        let expected = data_sector_size(mode).unwrap();
+
+        if sector_size != 0 && sector_size != expected {
+            let error = format!("Track {}: invalid sector size {} for mode \
+                                 0x{:02x}", number, sector_size, mode);
+            return Err(self.bad_image(error));
+        }
+
+        Ok((format, data))
+    }
+
+    /// Build the index cache from the session list
+    fn build_indices(&self, sessions: &[NrgSession])
+                     -> Result<IndexCache<Storage>, CdError> {
+        let mut indices = Vec::new();
+        let mut lead_outs = Vec::new();
+
+        for session in sessions {
+            for (t, track) in session.tracks.iter().enumerate() {
+                let storage = |offset| {
+                    Storage::Nrg {
+                        offset: offset,
+                        sector_size: track.sector_size,
+                        data: track.data,
+                    }
+                };
+
+                let size = track.sector_size as u64;
+
+                let index01 =
+                    track.indices.iter().find(|&&(i, _)| i == Bcd::one());
+
+                let index01 =
+                    match index01 {
+                        Some(&(_, s)) => s,
+                        None => {
+                            let error = format!("Track {} has no INDEX 01",
+                                                track.number);
+                            return Err(self.bad_image(error));
+                        }
+                    };
+
+                // End of the track: the start of the next one or the
+                // session's lead-out
+                let end =
+                    match session.tracks.get(t + 1) {
+                        Some(next) => next.indices[0].1,
+                        None => session.lead_out,
+                    };
+
+                if end < index01 {
+                    let error = format!("Track {} ends before its INDEX 01",
+                                        track.number);
+                    return Err(self.bad_image(error));
+                }
+
+                let data_end =
+                    track.start_offset + (end - index01) as u64 * size;
+
+                if data_end > self.chunks_offset {
+                    let error = format!("Track {} extends past the end of \
+                                         the data", track.number);
+                    return Err(self.bad_image(error));
+                }
+
+                let mut push = |index, sector, storage| {
+                    // The sectors have been validated while parsing the
+                    // chunks
+                    let msf = Msf::from_sector_index(sector).unwrap();
+
+                    let mut index = Index::new(index,
+                                               msf,
+                                               track.number,
+                                               track.format,
+                                               session.number,
+                                               storage);
+
+                    index.set_control(track.control);
+
+                    indices.push(index);
+                };
+
+                if session.number == 1 && t == 0 && track.indices[0].1 > 0 {
+                    // Track 01's pregap always starts at 00:00:00
+                    push(Bcd::zero(), 0, Storage::PreGap);
+                }
+
+                // Number of pregap sectors stored in the file
+                let stored = (track.start_offset - track.pregap_offset) / size;
+
+                for &(index, sector) in &track.indices {
+                    if sector >= index01 {
+                        let offset =
+                            track.start_offset +
+                            (sector - index01) as u64 * size;
+
+                        push(index, sector, storage(offset));
+                        continue;
+                    }
+
+                    // Pregap, only the end of it might be stored
+                    let len = (index01 - sector) as u64;
+
+                    if stored >= len {
+                        push(index, sector, storage(track.start_offset -
+                                                    len * size));
+                    } else if stored > 0 {
+                        push(index, sector, Storage::PreGap);
+                        push(index,
+                             index01 - stored as u32,
+                             storage(track.pregap_offset));
+                    } else {
+                        push(index, sector, Storage::PreGap);
+                    }
+                }
+            }
+
+            match Msf::from_sector_index(session.lead_out) {
+                Some(m) => lead_outs.push(m),
+                None => {
+                    let error = format!("Invalid lead-out for session {}",
+                                        session.number);
+                    return Err(self.bad_image(error));
+                }
+            }
+        }
+
+        IndexCache::new_multisession(self.path.clone(),
+                                     indices,
+                                     lead_outs)
+    }
+}
+
+/// Return true if `sector` is a valid absolute sector index, that is
+/// if it fits in an MSF
+fn valid_sector(sector: i64) -> bool {
+    sector >= 0 &&
+        sector <= u32::max_value() as i64 &&
+        Msf::from_sector_index(sector as u32).is_some()
+}
+
+/// Return the size of a sector in the file for the given track mode
+fn data_sector_size(mode: u8) -> Option<u16> {
+    match mode {
+        0x00 | 0x02 => Some(2048),
+        0x03 => Some(2336),
+        0x05 | 0x06 | 0x07 => Some(2352),
+        0x0f | 0x10 | 0x11 => Some(2448),
+        _ => None,
+    }
+}
+
+fn be16(b: &[u8]) -> u16 {
+    (b[0] as u16) << 8 | b[1] as u16
+}
+
+fn be32(b: &[u8]) -> u32 {
+    (be16(&b[0..2]) as u32) << 16 | be16(&b[2..4]) as u32
+}
+
+fn be64(b: &[u8]) -> u64 {
+    (be32(&b[0..4]) as u64) << 32 | be32(&b[4..8]) as u64
+}
+
+/// Length of the header of the DAOI and DAOX chunks
+const DAO_HEADER_LEN: usize = 22;
+
+/// MTYP bit for CD media
+const MEDIA_CD: u32 = 0x00001;
+/// MTYP bit for CD-ROM media
+const MEDIA_CDROM: u32 = 0x00400;
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use Image;
+    use CdError;
+    use TrackFormat;
+    use bcd::Bcd;
+    use msf::Msf;
+    use sector::Sector;
+    use nrg::Nrg;
+    use test_util::{temp_dir, write_file};
+
+    fn put32(v: &mut Vec<u8>, x: u32) {
+        v.extend_from_slice(&[(x >> 24) as u8, (x >> 16) as u8,
+                              (x >> 8) as u8, x as u8]);
+    }
+
+    fn put64(v: &mut Vec<u8>, x: u64) {
+        put32(v, (x >> 32) as u32);
+        put32(v, x as u32);
+    }
+
+    /// Write an NRG file containing `data` followed by `chunks`
+    fn nrg(name: &str,
+           v2: bool,
+           data: &[u8],
+           chunks: &[(&[u8; 4], Vec<u8>)]) -> PathBuf {
+        let mut file = data.to_vec();
+        let offset = file.len() as u64;
+
+        for &(id, ref chunk) in chunks {
+            file.extend_from_slice(id);
+            put32(&mut file, chunk.len() as u32);
+            file.extend_from_slice(chunk);
+        }
+
+        file.extend_from_slice(b"END!\0\0\0\0");
+
+        if v2 {
+            file.extend_from_slice(b"NER5");
+            put64(&mut file, offset);
+        } else {
+            file.extend_from_slice(b"NERO");
+            put32(&mut file, offset as u32);
+        }
+
+        write_file(&temp_dir(name), "test.nrg", &file)
+    }
+
+    /// Build a cue sheet chunk from (control, track, index, sector)
+    /// entries
+    fn cue(v2: bool, entries: &[(u8, u8, u8, u32)]) -> Vec<u8> {
+        let mut chunk = Vec::new();
+
+        for &(control, track, index, sector) in entries {
+            chunk.extend_from_slice(&[control << 4 | 1, track, index, 0]);
+
+            if v2 {
+                put32(&mut chunk, (sector as i64 - 150) as u32);
+            } else {
+                chunk.extend_from_slice(&[0,
+                                          (sector / 4500) as u8,
+                                          (sector / 75 % 60) as u8,
+                                          (sector % 75) as u8]);
+            }
+        }
+
+        chunk
+    }
+
+    /// Build a DAO chunk from (sector size, mode, pregap offset, start
+    /// offset, end offset) entries
+    fn dao(v2: bool, tracks: &[(u16, u8, u64, u64, u64)]) -> Vec<u8> {
+        let mut chunk = vec![0; 22];
+
+        chunk[20] = 1;
+        chunk[21] = tracks.len() as u8;
+
+        for &(size, mode, pregap, start, end) in tracks {
+            // ISRC and unknown fields
+            chunk.extend_from_slice(&[0; 12]);
+            chunk.extend_from_slice(&[(size >> 8) as u8, size as u8]);
+            chunk.extend_from_slice(&[mode, 0, 0, 0]);
+
+            for &offset in &[pregap, start, end] {
+                if v2 {
+                    put64(&mut chunk, offset);
+                } else {
+                    put32(&mut chunk, offset as u32);
+                }
+            }
+        }
+
+        chunk
+    }
+
+    /// Build a TAO chunk from (offset, size, mode, lba) entries
+    fn etn(v2: bool, tracks: &[(u64, u64, u32, u32)]) -> Vec<u8> {
+        let mut chunk = Vec::new();
+
+        for &(offset, size, mode, lba) in tracks {
+            if v2 {
+                put64(&mut chunk, offset);
+                put64(&mut chunk, size);
+            } else {
+                put32(&mut chunk, offset as u32);
+                put32(&mut chunk, size as u32);
+            }
+
+            put32(&mut chunk, mode);
+            put32(&mut chunk, lba);
+
+            let padding = if v2 { 8 } else { 4 };
+
+            chunk.extend_from_slice(&vec![0; padding]);
+        }
+
+        chunk
+    }
+
+    /// Sector data: 3 Mode 1 sectors of 2048 bytes followed by 4
+    /// audio sectors
+    fn test_data() -> Vec<u8> {
+        let mut data = Vec::new();
+
+        for s in 0..3 {
+            data.extend_from_slice(&[0x10 + s; 2048]);
+        }
+
+        for i in 0..4 * 2352 {
+            data.push((i / 3) as u8);
+        }
+
+        data
+    }
+
+    fn msf(sector: u32) -> Msf {
+        Msf::from_sector_index(sector).unwrap()
+    }
+
+    fn is_bad_image(r: Result<Nrg, CdError>) -> bool {
+        match r {
+            Err(CdError::BadImage(_, _)) => true,
+            _ => false,
+        }
+    }
+
+    /// Disc-at-once image with a Mode 1 track and an audio track with
+    /// 2 sectors of stored pregap
+    fn dao_image(name: &str, v2: bool) -> PathBuf {
+        let (cue_id, dao_id) =
+            if v2 { (b"CUEX", b"DAOX") } else { (b"CUES", b"DAOI") };
+
+        let cue = cue(v2, &[(4, 0x00, 0x00, 0),
+                            (4, 0x01, 0x00, 0),
+                            (4, 0x01, 0x01, 150),
+                            (0, 0x02, 0x00, 153),
+                            (0, 0x02, 0x01, 155),
+                            (0, 0xaa, 0x01, 157)]);
+
+        let dao = dao(v2, &[(2048, 0x00, 0, 0, 6144),
+                            (2352, 0x07, 6144, 6144 + 2 * 2352,
+                             6144 + 4 * 2352)]);
+
+        nrg(name, v2, &test_data(), &[(cue_id, cue), (dao_id, dao)])
+    }
+
+    fn check_dao(path: &PathBuf) {
+        let mut nrg = Nrg::new(path).unwrap();
+
+        let toc = nrg.toc();
+
+        assert!(toc.sessions.len() == 1);
+        assert!(toc.lead_out() == msf(157));
+
+        let t1 = toc.track(Bcd::one()).unwrap();
+
+        assert!(t1.format == TrackFormat::Mode1);
+        assert!(t1.start == msf(150));
+
+        let t2 = toc.track(Bcd::from_binary(2).unwrap()).unwrap();
+
+        assert!(t2.format == TrackFormat::Audio);
+        assert!(t2.pregap_start() == msf(153));
+        assert!(t2.start == msf(155));
+
+        let mut sector = Sector::empty();
+
+        nrg.read_sector(&mut sector, msf(151)).unwrap();
+
+        assert!(sector.mode1_payload().unwrap()[..] == [0x11; 2048][..]);
+        assert!(sector.metadata().track == Bcd::one());
+
+        // Stored pregap, the audio samples are converted to
+        // little-endian
+        let data = test_data();
+
+        nrg.read_sector(&mut sector, msf(153)).unwrap();
+
+        assert!(sector.metadata().index == Bcd::zero());
+        assert!(sector.data_2352().unwrap()[0] == data[6144 + 1]);
+        assert!(sector.data_2352().unwrap()[1] == data[6144]);
+
+        nrg.read_sector(&mut sector, msf(156)).unwrap();
+
+        let offset = 6144 + 3 * 2352;
+
+        assert!(sector.metadata().index == Bcd::one());
+        assert!(sector.data_2352().unwrap()[2] == data[offset + 3]);
+        assert!(sector.data_2352().unwrap()[3] == data[offset + 2]);
+    }
+
+    #[test]
+    fn dao_v1() {
+        check_dao(&dao_image("nrg-dao-v1", false));
+    }
+
+    #[test]
+    fn dao_v2() {
+        check_dao(&dao_image("nrg-dao-v2", true));
+    }
+
+    #[test]
+    fn tao_v1() {
+        let etnf = etn(false, &[(0, 3 * 2048, 0x00, 0),
+                                (6144, 4 * 2352, 0x07, 5)]);
+
+        let path = nrg("nrg-tao-v1", false, &test_data(), &[(b"ETNF", etnf)]);
+
+        let mut nrg = Nrg::new(&path).unwrap();
+
+        let toc = nrg.toc();
+
+        assert!(toc.sessions.len() == 1);
+        assert!(toc.lead_out() == msf(159));
+
+        let t2 = toc.track(Bcd::from_binary(2).unwrap()).unwrap();
+
+        // The gap between the tracks isn't stored
+        assert!(t2.pregap_start() == msf(153));
+        assert!(t2.start == msf(155));
+
+        let mut sector = Sector::empty();
+
+        nrg.read_sector(&mut sector, msf(154)).unwrap();
+
+        assert!(sector.data_2352().unwrap()[..] == [0; 2352][..]);
+
+        nrg.read_sector(&mut sector, msf(155)).unwrap();
+
+        assert!(sector.data_2352().unwrap()[0] == test_data()[6145]);
+    }
+
+    #[test]
+    fn tao_v2_multisession() {
+        let etn1 = etn(true, &[(0, 3 * 2048, 0x00, 0)]);
+        let etn2 = etn(true, &[(6144, 4 * 2352, 0x07, 11253)]);
+
+        let path = nrg("nrg-tao-v2", true, &test_data(),
+                       &[(b"ETN2", etn1), (b"ETN2", etn2)]);
+
+        let mut nrg = Nrg::new(&path).unwrap();
+
+        let toc = nrg.toc();
+
+        assert!(toc.sessions.len() == 2);
+        assert!(toc.sessions[0].lead_out == msf(153));
+        assert!(toc.sessions[1].lead_out == msf(11407));
+
+        let t2 = &toc.sessions[1].tracks[0];
+
+        assert!(t2.number == Bcd::from_binary(2).unwrap());
+        assert!(t2.pregap_start() == msf(11253));
+        assert!(t2.start == msf(11403));
+
+        let mut sector = Sector::empty();
+
+        nrg.read_sector(&mut sector, msf(11403)).unwrap();
+
+        assert!(sector.metadata().session == 2);
+        assert!(sector.data_2352().unwrap()[0] == test_data()[6145]);
+    }
+
+    #[test]
+    fn out_of_range_sectors() {
+        // Track 01's INDEX 01 at 200:00:00
+        let cue_v1 = cue(false, &[(4, 0x01, 0x00, 0),
+                                  (4, 0x01, 0x01, 200 * 4500),
+                                  (4, 0xaa, 0x01, 200 * 4500 + 3)]);
+        let dao_v1 = dao(false, &[(2048, 0x00, 0, 0, 6144)]);
+
+        let path = nrg("nrg-bad-cue-v1", false, &test_data(),
+                       &[(b"CUES", cue_v1), (b"DAOI", dao_v1)]);
+
+        assert!(is_bad_image(Nrg::new(&path)));
+
+        // Huge LBA
+        let cue_v2 = cue(true, &[(4, 0x01, 0x00, 0),
+                                 (4, 0x01, 0x01, 0x7fffffff),
+                                 (4, 0xaa, 0x01, 150)]);
+        let dao_v2 = dao(true, &[(2048, 0x00, 0, 0, 6144)]);
+
+        let path = nrg("nrg-bad-cue-v2", true, &test_data(),
+                       &[(b"CUEX", cue_v2), (b"DAOX", dao_v2)]);
+
+        assert!(is_bad_image(Nrg::new(&path)));
+
+        // LBA overflowing once the 2 second offset is added
+        let etnf = etn(false, &[(0, 3 * 2048, 0x00, 0xffffffff)]);
+
+        let path = nrg("nrg-bad-lba", false, &test_data(),
+                       &[(b"ETNF", etnf)]);
+
+        assert!(is_bad_image(Nrg::new(&path)));
+
+        // Track size overflowing the end of the track
+        let etn2 = etn(true, &[(0, u64::max_value(), 0x00, 0)]);
+
+        let path = nrg("nrg-bad-size", true, &test_data(),
+                       &[(b"ETN2", etn2)]);
+
+        assert!(is_bad_image(Nrg::new(&path)));
+    }
+}
@@ -7,6 +7,12 @@ use TrackFormat;
 
 use msf::Msf;
 use bcd::Bcd;
+use ecc;
+use subchannel;
+use subchannel::SubChannel;
+use subchannel::{SubChannelP, SubChannelQ, SubChannelR, SubChannelS};
+use subchannel::{SubChannelT, SubChannelU, SubChannelV, SubChannelW};
+use ecc::{DataMode, Status};
 
 /// Sector metadata, contains informations about the position and
 /// format of a given sector.
@@ -36,6 +42,10 @@ pub struct Sector {
     /// Actual sector data, only the portions set in `ready` are
     /// currently valid.
     data: [u8; 2352],
+    /// Subchannel data in the deinterleaved layout (12 bytes per
+    /// channel, from P to W). Only valid if `SUBCHANNEL` is set in
+    /// `ready`.
+    subchannel: [u8; 96],
     /// Sector metadata
     metadata: Metadata,
 }
@@ -46,6 +56,7 @@ impl Sector {
         Sector {
             ready: DataReady::empty(),
             data: [0; 2352],
+            subchannel: [0; 96],
             metadata: Metadata {
                 msf: Msf::zero(),
                 track_msf: Msf::zero(),
@@ -58,13 +69,18 @@ impl Sector {
     }
 
     /// Retreive the entire sector data (except for the subchannel
-    /// data).
+    /// data). If the image format didn't provide the complete sector
+    /// the missing parts (sync pattern, header, EDC and ECC) are
+    /// regenerated from the metadata and the payload.
+    ///
+    /// Returns `CdError::BadFormat` if the sector doesn't contain
+    /// enough data to rebuild the missing parts.
     pub fn data_2352(&mut self) -> Result<&[u8; 2352], CdError> {
-        if self.ready.contains(DATA_2352) {
-            Ok(&self.data)
-        } else {
-            unimplemented!()
+        if !self.ready.contains(DATA_2352) {
+            try!(self.regenerate());
         }
+
+        Ok(&self.data)
     }
 
     /// Retreive the sector's metadata. This is *not* the subchannel
@@ -74,6 +90,109 @@ impl Sector {
         &self.metadata
     }
 
+    /// Return true if the image provided the subchannel data for this
+    /// sector
+    pub fn has_subchannel(&self) -> bool {
+        self.ready.contains(SUBCHANNEL)
+    }
+
+    /// Retrieve the 96 bytes of subchannel data in the deinterleaved
+    /// layout: 12 bytes for each channel, from P to W. Returns `None`
+    /// if the image didn't provide any subchannel data for this
+    /// sector.
+    pub fn subchannel_deinterleaved(&self) -> Option<&[u8; 96]> {
+        if self.has_subchannel() {
+            Some(&self.subchannel)
+        } else {
+            None
+        }
+    }
+
+    /// Retrieve the 96 bytes of subchannel data in the "raw"
+    /// interleaved layout: one bit of every channel per byte, P in the
+    /// MSB. Returns `None` if the image didn't provide any subchannel
+    /// data for this sector.
+    pub fn subchannel_interleaved(&self) -> Option<[u8; 96]> {
+        self.subchannel_deinterleaved().map(subchannel::interleave)
+    }
+
+    /// Return the 12 bytes of `channel` (0 for P up to 7 for W)
+    fn channel(&self, channel: usize) -> Option<[u8; 12]> {
+        self.subchannel_deinterleaved()
+            .map(|s| *array_ref![s, channel * 12, 12])
+    }
+
+    /// Retrieve the P subchannel data for this sector, if available
+    pub fn subchannel_p(&self) -> Option<SubChannelP> {
+        self.channel(0).map(SubChannelP::new)
+    }
+
+    /// Retrieve the Q subchannel data for this sector, if available
+    pub fn subchannel_q(&self) -> Option<SubChannelQ> {
+        self.channel(1).map(SubChannelQ::new)
+    }
+
+    /// Retrieve the R subchannel data for this sector, if available
+    pub fn subchannel_r(&self) -> Option<SubChannelR> {
+        self.channel(2).map(SubChannelR::new)
+    }
+
+    /// Retrieve the S subchannel data for this sector, if available
+    pub fn subchannel_s(&self) -> Option<SubChannelS> {
+        self.channel(3).map(SubChannelS::new)
+    }
+
+    /// Retrieve the T subchannel data for this sector, if available
+    pub fn subchannel_t(&self) -> Option<SubChannelT> {
+        self.channel(4).map(SubChannelT::new)
+    }
+
+    /// Retrieve the U subchannel data for this sector, if available
+    pub fn subchannel_u(&self) -> Option<SubChannelU> {
+        self.channel(5).map(SubChannelU::new)
+    }
+
+    /// Retrieve the V subchannel data for this sector, if available
+    pub fn subchannel_v(&self) -> Option<SubChannelV> {
+        self.channel(6).map(SubChannelV::new)
+    }
+
+    /// Retrieve the W subchannel data for this sector, if available
+    pub fn subchannel_w(&self) -> Option<SubChannelW> {
+        self.channel(7).map(SubChannelW::new)
+    }
+
+    /// Replace the Q subchannel data of this sector with `q`, used by
+    /// overlays patching the subchannel of an image. If the image
+    /// didn't provide any subchannel data the other channels are
+    /// zeroed.
+    pub fn set_subchannel_q(&mut self, q: &SubChannelQ) {
+        if !self.has_subchannel() {
+            self.subchannel = [0; 96];
+            self.ready.insert(SUBCHANNEL);
+        }
+
+        self.subchannel[12..24].copy_from_slice(q.raw());
+    }
+
+    /// Retrieve the 2048 bytes of user data of a CD-ROM Mode 1
+    /// sector. Returns `CdError::BadFormat` if this is not a Mode 1
+    /// sector.
+    ///
+    /// Contrary to `data_2352` this never needs to regenerate the
+    /// error detection and correction codes.
+    pub fn mode1_payload(&self) -> Result<&[u8; 2048], CdError> {
+        if self.metadata.format != TrackFormat::Mode1 {
+            return Err(CdError::BadFormat);
+        }
+
+        if !self.ready.contains(PAYLOAD) {
+            panic!("Missing payload for a track!");
+        }
+
+        Ok(array_ref![self.data, 16, 2048])
+    }
+
     /// Retrieve the CD-ROM XA Mode2 subheader. Returns
     /// `CdError::BadFormat` if this is not a CD-ROM XA sector.
     pub fn mode2_xa_subheader(&self) -> Result<XaSubHeader, CdError> {
@@ -110,12 +229,121 @@ impl Sector {
 
         Ok(payload)
     }
+
+    /// Check the integrity of the sector data using its error
+    /// detection and correction codes. Audio sectors are always
+    /// reported as `Status::Unprotected`.
+    pub fn check_integrity(&mut self) -> Result<Status, CdError> {
+        try!(self.data_2352());
+
+        match self.data_mode() {
+            Some(mode) => Ok(ecc::check(&self.data, mode)),
+            None => Ok(Status::Unprotected),
+        }
+    }
+
+    /// Attempt to repair the sector data using its error correction
+    /// codes. Returns the number of bytes which were corrected or
+    /// `CdError::CorruptedSector` if the sector is damaged beyond
+    /// repair (or if it's a Mode 2 Form 2 sector with a bad EDC).
+    ///
+    /// Audio sectors are returned unchanged.
+    pub fn correct_errors(&mut self) -> Result<u32, CdError> {
+        try!(self.data_2352());
+
+        let mode =
+            match self.data_mode() {
+                Some(m) => m,
+                None => return Ok(0),
+            };
+
+        match ecc::correct(&mut self.data, mode) {
+            Ok(n) => Ok(n),
+            Err(()) => Err(CdError::CorruptedSector),
+        }
+    }
+
+    /// Recompute the error detection and correction codes of a data
+    /// sector from its payload, replacing the ones provided by the
+    /// image. Audio sectors are returned unchanged.
+    pub fn regenerate_ecc(&mut self) -> Result<(), CdError> {
+        try!(self.data_2352());
+
+        if let Some(mode) = self.data_mode() {
+            ecc::generate(&mut self.data, mode);
+        }
+
+        Ok(())
+    }
+
+    /// Return the layout of the data in the sector, or `None` for
+    /// audio sectors. For Mode 2 sectors the subheader must be valid.
+    fn data_mode(&self) -> Option<DataMode> {
+        match self.metadata.format {
+            TrackFormat::Audio | TrackFormat::CdG => None,
+            TrackFormat::Mode1 => Some(DataMode::Mode1),
+            TrackFormat::Mode2Xa | TrackFormat::Mode2CdI => {
+                let subheader = XaSubHeader::new(array_ref![self.data, 16, 8]);
+
+                match subheader.form() {
+                    XaForm::Form1 => Some(DataMode::Mode2Form1),
+                    XaForm::Form2 => Some(DataMode::Mode2Form2),
+                }
+            }
+        }
+    }
+
+    /// Rebuild the portions of a data sector which haven't been
+    /// provided by the image backend: the sync pattern and header are
+    /// generated from the metadata and the error
+    /// detection/correction codes from the payload.
+    fn regenerate(&mut self) -> Result<(), CdError> {
+        if !self.ready.contains(METADATA | PAYLOAD) {
+            return Err(CdError::BadFormat);
+        }
+
+        let mode =
+            match self.data_mode() {
+                Some(m) => m,
+                // Audio sectors don't have any structure, there's
+                // nothing we can rebuild
+                None => return Err(CdError::BadFormat),
+            };
+
+        if !self.ready.contains(HEADER) {
+            let (m, s, f) = self.metadata.msf.into_bcd();
+
+            self.data[0..12].copy_from_slice(&SYNC_PATTERN);
+            self.data[12] = m.bcd();
+            self.data[13] = s.bcd();
+            self.data[14] = f.bcd();
+            self.data[15] =
+                match mode {
+                    DataMode::Mode1 => 1,
+                    _ => 2,
+                };
+
+            self.ready.insert(HEADER);
+        }
+
+        if !self.ready.contains(ECM) {
+            ecc::generate(&mut self.data, mode);
+
+            self.ready.insert(ECM);
+        }
+
+        Ok(())
+    }
 }
 
+/// Sync pattern found at the beginning of every CD-ROM data sector
+pub const SYNC_PATTERN: [u8; 12] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff,
+                                    0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
 impl Encodable for Sector {
     fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
 
-        s.emit_struct("Sector", 3, |s| {
+        s.emit_struct("Sector", 4, |s| {
             try!(s.emit_struct_field("ready", 0,
                                      |s| self.ready.encode(s)));
 
@@ -134,6 +362,17 @@ impl Encodable for Sector {
             try!(s.emit_struct_field("metadata", 2,
                                      |s| self.metadata.encode(s)));
 
+            try!(s.emit_struct_field(
+                "subchannel", 3,
+                |s| s.emit_seq(
+                    96,
+                    |s| {
+                        for (i, &b) in self.subchannel.iter().enumerate() {
+                            try!(s.emit_seq_elt(i, |s| b.encode(s)))
+                        }
+
+                        Ok(())
+                    })));
 
             Ok(())
         })
@@ -142,7 +381,7 @@ impl Encodable for Sector {
 
 impl Decodable for Sector {
     fn decode<D: Decoder>(d: &mut D) -> Result<Sector, D::Error> {
-        d.read_struct("Sector", 3, |d| {
+        d.read_struct("Sector", 4, |d| {
             let mut sector = Sector::empty();
 
             sector.ready =
@@ -171,6 +410,24 @@ impl Decodable for Sector {
                 try!(d.read_struct_field("metadata", 2,
                                          Decodable::decode));
 
+            try!(d.read_struct_field(
+                    "subchannel", 3,
+                    |d| {
+                        d.read_seq(|d, len| {
+                            if len != 96 {
+                                return Err(
+                                    d.error("wrong subchannel data length"));
+                            }
+
+                            for i in 0..len {
+                                sector.subchannel[i] =
+                                    try!(d.read_seq_elt(i, Decodable::decode));
+                            }
+
+                            Ok(len)
+                        })
+                    }));
+
             Ok(sector)
         })
     }
@@ -204,6 +461,8 @@ bitflags! {
         const DATA_2352 = HEADER.bits | PAYLOAD.bits | ECM.bits,
         /// Set when the metadata is valid
         const METADATA  = 0b00001000,
+        /// The 96 bytes of subchannel data
+        const SUBCHANNEL = 0b00010000,
     }
 }
 
@@ -220,6 +479,12 @@ bitflags! {
 ///
 /// The subheader starts at byte 16 of CD-ROM XA sectors, just after
 /// the CD-ROM header.
+///
+/// The subheader isn't protected by the error detection and
+/// correction codes of Form 2 sectors, it's stored twice instead.
+/// All the accessors use the first copy, `check` can be used to make
+/// sure both copies match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct XaSubHeader {
     subheader: [u8; 8],
 }
@@ -235,11 +500,120 @@ impl XaSubHeader {
 
     /// Return "form" of this sector
     pub fn form(&self) -> XaForm {
-        match self.subheader[2] & 0x20 != 0 {
+        match self.submode().contains(FORM2) {
             false => XaForm::Form1,
             true  => XaForm::Form2,
         }
     }
+
+    /// Return the file number. All the sectors of an interleaved file
+    /// share the same file number.
+    pub fn file_number(&self) -> u8 {
+        self.subheader[0]
+    }
+
+    /// Return the channel number, used to tell apart the streams
+    /// interleaved in a file (several audio tracks for instance)
+    pub fn channel_number(&self) -> u8 {
+        self.subheader[1]
+    }
+
+    /// Return the submode flags
+    pub fn submode(&self) -> XaSubmode {
+        XaSubmode::from_bits_truncate(self.subheader[2])
+    }
+
+    /// Return the raw coding information byte. Its meaning depends
+    /// on the type of sector, it's only defined for audio sectors.
+    pub fn coding_info(&self) -> u8 {
+        self.subheader[3]
+    }
+
+    /// Decode the coding information of an ADPCM audio sector.
+    /// Returns `None` if this is not an audio sector or if the coding
+    /// information uses reserved values.
+    pub fn audio_coding(&self) -> Option<XaAudioCoding> {
+        if !self.submode().contains(AUDIO) {
+            return None;
+        }
+
+        let coding = self.coding_info();
+
+        let stereo =
+            match coding & 3 {
+                0 => false,
+                1 => true,
+                _ => return None,
+            };
+
+        let sample_rate =
+            match (coding >> 2) & 3 {
+                0 => 37800,
+                1 => 18900,
+                _ => return None,
+            };
+
+        let bits_per_sample =
+            match (coding >> 4) & 3 {
+                0 => 4,
+                1 => 8,
+                _ => return None,
+            };
+
+        Some(XaAudioCoding {
+            stereo: stereo,
+            sample_rate: sample_rate,
+            bits_per_sample: bits_per_sample,
+            emphasis: coding & 0x40 != 0,
+        })
+    }
+
+    /// Make sure both copies of the subheader match. Returns
+    /// `CdError::CorruptedSector` otherwise.
+    pub fn check(&self) -> Result<(), CdError> {
+        if self.subheader[0..4] == self.subheader[4..8] {
+            Ok(())
+        } else {
+            Err(CdError::CorruptedSector)
+        }
+    }
+}
+
+bitflags! {
+    /// CD-ROM XA subheader submode flags
+    flags XaSubmode: u8 {
+        /// End of record: last sector of a logical record
+        const EOR      = 0b00000001,
+        /// Video sector
+        const VIDEO    = 0b00000010,
+        /// ADPCM audio sector
+        const AUDIO    = 0b00000100,
+        /// Data sector
+        const DATA     = 0b00001000,
+        /// Trigger: the application should be notified when this
+        /// sector is read
+        const TRIGGER  = 0b00010000,
+        /// Form 2 sector (Form 1 if not set)
+        const FORM2    = 0b00100000,
+        /// Real-time sector: it must be processed without delay, it
+        /// won't be read again in case of errors
+        const REALTIME = 0b01000000,
+        /// End of file: last sector of the file
+        const EOF      = 0b10000000,
+    }
+}
+
+/// Coding information of a CD-ROM XA ADPCM audio sector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XaAudioCoding {
+    /// True for stereo, false for mono
+    pub stereo: bool,
+    /// Sample rate in Hz: 37800 or 18900
+    pub sample_rate: u32,
+    /// Bits per sample: 4 or 8
+    pub bits_per_sample: u8,
+    /// True if emphasis is enabled
+    pub emphasis: bool,
 }
 
 /// CD-ROM XA Mode 2 sectors have two possible forms (advertised in
@@ -290,9 +664,340 @@ impl<'a> SectorBuilder<'a> {
         Ok(())
     }
 
+    /// Load the 2048 bytes of user data of a CD-ROM Mode 1 sector.
+    /// The sync pattern, header and error detection/correction codes
+    /// will be regenerated from the metadata if they're ever
+    /// requested. If the `loader` callback returns an error the
+    /// payload won't be tagged as valid.
+    pub fn set_mode1_payload<F, E>(&mut self, loader: F) -> Result<(), E>
+        where F: FnOnce(&mut [u8; 2048]) -> Result<(), E> {
+
+        try!(loader(array_mut_ref![self.sector.data, 16, 2048]));
+
+        self.sector.ready.insert(PAYLOAD);
+
+        Ok(())
+    }
+
+    /// Load the last 2336 bytes of a CD-ROM XA or CD-i Mode 2 sector
+    /// (everything but the sync pattern and header). The missing
+    /// header will be regenerated from the metadata if it's ever
+    /// requested. If the `loader` callback returns an error the
+    /// payload won't be tagged as valid.
+    pub fn set_mode2_payload<F, E>(&mut self, loader: F) -> Result<(), E>
+        where F: FnOnce(&mut [u8; 2336]) -> Result<(), E> {
+
+        try!(loader(array_mut_ref![self.sector.data, 16, 2336]));
+
+        // The EDC and ECC are part of those 2336 bytes
+        self.sector.ready.insert(PAYLOAD | ECM);
+
+        Ok(())
+    }
+
+    /// Load the 2048 bytes of user data of a CD-ROM XA Mode 2 Form 1
+    /// sector. The subheader is set to a plain data sector and the
+    /// sync pattern, header and error detection/correction codes will
+    /// be regenerated from the metadata if they're ever requested. If
+    /// the `loader` callback returns an error the payload won't be
+    /// tagged as valid.
+    pub fn set_mode2_form1_payload<F, E>(&mut self,
+                                         loader: F) -> Result<(), E>
+        where F: FnOnce(&mut [u8; 2048]) -> Result<(), E> {
+
+        try!(loader(array_mut_ref![self.sector.data, 24, 2048]));
+
+        // Both copies of the subheader: file 0, channel 0, submode
+        // DATA, no coding information
+        let subheader = [0, 0, 0x08, 0, 0, 0, 0x08, 0];
+
+        self.sector.data[16..24].copy_from_slice(&subheader);
+
+        self.sector.ready.insert(PAYLOAD);
+
+        Ok(())
+    }
+
+    /// Generate a blank sector of the given `format`, used to
+    /// synthesize the gaps which are not stored in the image: digital
+    /// silence for audio tracks and zeroed user data for CD-ROM
+    /// tracks. The header and error detection/correction codes of data
+    /// sectors will be regenerated if they're ever requested.
+    pub fn set_blank(&mut self, format: TrackFormat) {
+        let data = &mut self.sector.data;
+
+        for b in data.iter_mut() {
+            *b = 0;
+        }
+
+        match format {
+            TrackFormat::Audio | TrackFormat::CdG =>
+                self.sector.ready.insert(DATA_2352),
+            TrackFormat::Mode1 =>
+                self.sector.ready.insert(PAYLOAD),
+            TrackFormat::Mode2Xa | TrackFormat::Mode2CdI => {
+                // Mode 2 gaps are encoded as Form 2 sectors, in both
+                // copies of the subheader's submode.
+                data[18] = 0x20;
+                data[22] = 0x20;
+
+                self.sector.ready.insert(PAYLOAD);
+            }
+        }
+    }
+
+    /// Load the 96 bytes of subchannel data in the deinterleaved
+    /// layout (12 bytes per channel, from P to W). If the `loader`
+    /// callback returns an error the subchannel data won't be tagged
+    /// as valid.
+    pub fn set_subchannel_deinterleaved<F, E>(&mut self,
+                                              loader: F) -> Result<(), E>
+        where F: FnOnce(&mut [u8; 96]) -> Result<(), E> {
+
+        try!(loader(&mut self.sector.subchannel));
+
+        self.sector.ready.insert(SUBCHANNEL);
+
+        Ok(())
+    }
+
+    /// Load the 96 bytes of subchannel data in the "raw" interleaved
+    /// layout (one bit of every channel per byte, P in the MSB). If
+    /// the `loader` callback returns an error the subchannel data
+    /// won't be tagged as valid.
+    pub fn set_subchannel_interleaved<F, E>(&mut self,
+                                            loader: F) -> Result<(), E>
+        where F: FnOnce(&mut [u8; 96]) -> Result<(), E> {
+
+        let mut raw = [0; 96];
+
+        try!(loader(&mut raw));
+
+        self.sector.subchannel = subchannel::deinterleave(&raw);
+        self.sector.ready.insert(SUBCHANNEL);
+
+        Ok(())
+    }
+
     /// Set the metadata for the sector
     pub fn set_metadata(&mut self, metadata: Metadata) {
         self.sector.metadata = metadata;
         self.sector.ready.insert(METADATA);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Sector, SectorBuilder, Metadata, XaSubHeader};
+    use super::{AUDIO, FORM2, REALTIME, EOF};
+    use subchannel::{SubChannel, SubChannelQ};
+    use ecc::{check, DataMode, Status};
+    use msf::Msf;
+    use bcd::Bcd;
+    use TrackFormat;
+
+    fn metadata(msf: Msf, format: TrackFormat) -> Metadata {
+        Metadata {
+            msf: msf,
+            track_msf: Msf::zero(),
+            index: Bcd::one(),
+            track: Bcd::one(),
+            format: format,
+            session: 0,
+        }
+    }
+
+    #[test]
+    fn regenerate_mode1() {
+        let mut sector = Sector::empty();
+        let msf = Msf::from_sector_index(16 + 150).unwrap();
+
+        {
+            let mut builder = SectorBuilder::new(&mut sector);
+
+            builder.set_mode1_payload(|data| -> Result<(), ()> {
+                for (i, b) in data.iter_mut().enumerate() {
+                    *b = i as u8;
+                }
+
+                Ok(())
+            }).unwrap();
+
+            builder.set_metadata(metadata(msf, TrackFormat::Mode1));
+        }
+
+        assert!(sector.mode1_payload().unwrap()[0x42] == 0x42);
+
+        let data = *sector.data_2352().unwrap();
+
+        assert!(&data[0..16] == &[0x00, 0xff, 0xff, 0xff, 0xff, 0xff,
+                                  0xff, 0xff, 0xff, 0xff, 0xff, 0x00,
+                                  0x00, 0x02, 0x16, 0x01]);
+        assert!(data[16 + 0x42] == 0x42);
+        assert!(check(&data, DataMode::Mode1) == Status::Valid);
+    }
+
+    #[test]
+    fn regenerate_blank_mode2() {
+        let mut sector = Sector::empty();
+        let msf = Msf::from_sector_index(3).unwrap();
+
+        {
+            let mut builder = SectorBuilder::new(&mut sector);
+
+            builder.set_blank(TrackFormat::Mode2Xa);
+            builder.set_metadata(metadata(msf, TrackFormat::Mode2Xa));
+        }
+
+        let data = *sector.data_2352().unwrap();
+
+        assert!(&data[12..24] == &[0x00, 0x00, 0x03, 0x02,
+                                   0x00, 0x00, 0x20, 0x00,
+                                   0x00, 0x00, 0x20, 0x00]);
+        assert!(check(&data, DataMode::Mode2Form2) == Status::Valid);
+    }
+
+    #[test]
+    fn regenerate_mode2_form1() {
+        let mut sector = Sector::empty();
+        let msf = Msf::from_sector_index(200).unwrap();
+
+        {
+            let mut builder = SectorBuilder::new(&mut sector);
+
+            builder.set_mode2_form1_payload(|data| -> Result<(), ()> {
+                data[0] = 0x42;
+
+                Ok(())
+            }).unwrap();
+
+            builder.set_metadata(metadata(msf, TrackFormat::Mode2Xa));
+        }
+
+        let data = *sector.data_2352().unwrap();
+
+        assert!(&data[12..25] == &[0x00, 0x02, 0x50, 0x02,
+                                   0x00, 0x00, 0x08, 0x00,
+                                   0x00, 0x00, 0x08, 0x00,
+                                   0x42]);
+        assert!(check(&data, DataMode::Mode2Form1) == Status::Valid);
+    }
+
+    #[test]
+    fn regenerate_ecc() {
+        let mut sector = Sector::empty();
+        let msf = Msf::from_sector_index(16 + 150).unwrap();
+
+        let mut raw = [0; 2352];
+
+        raw[0..16].copy_from_slice(&[0x00, 0xff, 0xff, 0xff, 0xff, 0xff,
+                                     0xff, 0xff, 0xff, 0xff, 0xff, 0x00,
+                                     0x00, 0x02, 0x16, 0x01]);
+        raw[16] = 0x42;
+
+        {
+            let mut builder = SectorBuilder::new(&mut sector);
+
+            builder.set_data_2352(|data| -> Result<(), ()> {
+                *data = raw;
+
+                Ok(())
+            }).unwrap();
+
+            builder.set_metadata(metadata(msf, TrackFormat::Mode1));
+        }
+
+        assert!(sector.check_integrity().unwrap() == Status::BadEdc);
+
+        sector.regenerate_ecc().unwrap();
+
+        assert!(sector.check_integrity().unwrap() == Status::Valid);
+        assert!(sector.mode1_payload().unwrap()[0] == 0x42);
+    }
+
+    #[test]
+    fn regenerate_without_payload() {
+        let mut sector = Sector::empty();
+
+        SectorBuilder::new(&mut sector)
+            .set_metadata(metadata(Msf::zero(), TrackFormat::Mode1));
+
+        assert!(sector.data_2352().is_err());
+    }
+
+    #[test]
+    fn subchannel_layouts() {
+        let mut sector = Sector::empty();
+
+        assert!(sector.subchannel_q().is_none());
+
+        {
+            let mut builder = SectorBuilder::new(&mut sector);
+
+            // P set and Q's first byte set to 0x41, interleaved
+            builder.set_subchannel_interleaved(|s| -> Result<(), ()> {
+                for (i, b) in s.iter_mut().enumerate() {
+                    *b = 0x80;
+
+                    if i == 1 || i == 7 {
+                        *b |= 0x40;
+                    }
+                }
+
+                Ok(())
+            }).unwrap();
+        }
+
+        assert!(sector.subchannel_p().unwrap().valid());
+        assert!(sector.subchannel_q().unwrap().raw()[0] == 0x41);
+        assert!(sector.subchannel_w().unwrap().raw() == &[0; 12]);
+
+        let interleaved = sector.subchannel_interleaved().unwrap();
+
+        assert!(interleaved[0] == 0x80);
+        assert!(interleaved[1] == 0xc0);
+
+        // A new builder invalidates the subchannel data
+        SectorBuilder::new(&mut sector);
+
+        assert!(!sector.has_subchannel());
+
+        let q = SubChannelQ::new([0x42; 12]);
+
+        sector.set_subchannel_q(&q);
+
+        assert!(sector.subchannel_p().unwrap().raw() == &[0; 12]);
+        assert!(sector.subchannel_q().unwrap().raw() == &[0x42; 12]);
+    }
+
+    #[test]
+    fn xa_subheader() {
+        // XA audio, file 1, channel 3, 18.9kHz stereo 4 bit
+        let subheader = XaSubHeader::new(&[0x01, 0x03, 0x64, 0x05,
+                                           0x01, 0x03, 0x64, 0x05]);
+
+        assert!(subheader.file_number() == 1);
+        assert!(subheader.channel_number() == 3);
+        assert!(subheader.submode() == AUDIO | FORM2 | REALTIME);
+        assert!(!subheader.submode().contains(EOF));
+        assert!(subheader.check().is_ok());
+
+        let coding = subheader.audio_coding().unwrap();
+
+        assert!(coding.stereo);
+        assert!(coding.sample_rate == 18900);
+        assert!(coding.bits_per_sample == 4);
+        assert!(!coding.emphasis);
+
+        // Mismatched copies
+        let subheader = XaSubHeader::new(&[0x01, 0x03, 0x64, 0x05,
+                                           0x01, 0x02, 0x64, 0x05]);
+
+        assert!(subheader.check().is_err());
+
+        // Data sector
+        let subheader = XaSubHeader::new(&[0, 0, 0x08, 0, 0, 0, 0x08, 0]);
+
+        assert!(subheader.audio_coding().is_none());
+    }
+}
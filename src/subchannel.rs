@@ -32,6 +32,8 @@
 
 use bcd::Bcd;
 use msf::Msf;
+use toc::Control;
+use crc::crc16_q;
 
 use SessionFormat;
 
@@ -102,6 +104,59 @@ impl SubChannelQ {
         }
     }
 
+    /// Build the subchannel data for `data` with the `control` flags
+    /// and compute its CRC. Returns `None` if `data` is
+    /// `QData::Unsupported`.
+    pub fn from_data(control: Control, data: &QData) -> Option<SubChannelQ> {
+        let mut bytes = [0; 12];
+
+        // Control flags in the high nibble, mode in the low nibble
+        bytes[0] = (control.bits() << 4) | 1;
+
+        let msf_bytes = |msf: Msf| {
+            let (m, s, f) = msf.into_bcd();
+
+            [m.bcd(), s.bcd(), f.bcd()]
+        };
+
+        let (track, pointer, msf, ap) =
+            match *data {
+                QData::Mode1(track, index, msf, ap_msf) =>
+                    (track.bcd(), index.bcd(), msf, msf_bytes(ap_msf)),
+                QData::Mode1LeadOut(msf, ap_msf) =>
+                    (0xaa, 0x01, msf, msf_bytes(ap_msf)),
+                QData::Mode1Toc(ptrack, ap_msf, msf) =>
+                    (0, ptrack.bcd(), msf, msf_bytes(ap_msf)),
+                QData::Mode1TocFirstTrack(first, format, msf) => {
+                    let format =
+                        match format {
+                            SessionFormat::CddaCdRom => 0x00,
+                            SessionFormat::Cdi => 0x10,
+                            SessionFormat::Cdxa => 0x20,
+                        };
+
+                    (0, 0xa0, msf, [first.bcd(), format, 0])
+                }
+                QData::Mode1TocLastTrack(last, msf) =>
+                    (0, 0xa1, msf, [last.bcd(), 0, 0]),
+                QData::Mode1TocLeadOut(ap_msf, msf) =>
+                    (0, 0xa2, msf, msf_bytes(ap_msf)),
+                QData::Unsupported => return None,
+            };
+
+        bytes[1] = track;
+        bytes[2] = pointer;
+        bytes[3..6].copy_from_slice(&msf_bytes(msf));
+        bytes[7..10].copy_from_slice(&ap);
+
+        let crc = crc16_q(&bytes[..10]);
+
+        bytes[10] = (crc >> 8) as u8;
+        bytes[11] = crc as u8;
+
+        Some(SubChannelQ::new(bytes))
+    }
+
     /// Return true if this is a data track. For table of content
     /// sectors this flag applies to the target track.
     pub fn data(&self) -> bool {
@@ -157,6 +212,51 @@ impl SubChannelQ {
         (msb << 8) | lsb
     }
 
+    /// Return true if the CRC stored at the end of the subchannel
+    /// data matches its contents.
+    pub fn crc_valid(&self) -> bool {
+        crc16_q(&self.bytes[..10]) == self.crc()
+    }
+
+    /// Compare this subchannel data with the `expected` one (usually
+    /// synthesized from the disc structure) to figure out if it's
+    /// been deliberately altered or if it's just a read error.
+    ///
+    /// Copy protection schemes such as libcrypt flip a few bits of
+    /// both the relative and absolute MSF of some sectors and leave
+    /// the CRC invalid. A random read error is unlikely to leave the
+    /// rest of the frame intact while altering both MSFs.
+    pub fn compare(&self, expected: &SubChannelQ) -> QStatus {
+        if self.bytes == expected.bytes {
+            return QStatus::Valid;
+        }
+
+        if self.crc_valid() {
+            return QStatus::Unexpected;
+        }
+
+        let (b, e) = (&self.bytes, &expected.bytes);
+
+        // Control, mode, track, index and zero byte
+        let header_intact =
+            b[0..3] == e[0..3] && b[6] == e[6];
+
+        // Number of bits that differ in a range of bytes
+        let distance = |range: ::std::ops::Range<usize>| {
+            range.map(|i| (b[i] ^ e[i]).count_ones()).fold(0, |a, c| a + c)
+        };
+
+        let relative = distance(3..6);
+        let absolute = distance(7..10);
+
+        if header_intact && relative > 0 && absolute > 0 &&
+            relative + absolute <= 4 {
+            QStatus::Tampered
+        } else {
+            QStatus::Corrupted
+        }
+    }
+
     /// Parse the contents of this subchannel and return it as a
     /// `QData`. This method does not validate the sector's CRC but
     /// will return `QData::Unsupported` if it encounters a format
@@ -168,12 +268,6 @@ impl SubChannelQ {
             return QData::Unsupported
         }
 
-        let track =
-            match Bcd::from_bcd(self.bytes[1]) {
-                Some(b) => b,
-                None => return QData::Unsupported,
-            };
-
         let min =
             match Bcd::from_bcd(self.bytes[3]) {
                 Some(b) => b,
@@ -227,6 +321,21 @@ impl SubChannelQ {
                 None => return QData::Unsupported,
             };
 
+        if self.bytes[1] == 0xaa {
+            // We're in the lead-out
+            if self.bytes[2] != 0x01 {
+                return QData::Unsupported;
+            }
+
+            return QData::Mode1LeadOut(msf, ap_msf);
+        }
+
+        let track =
+            match Bcd::from_bcd(self.bytes[1]) {
+                Some(b) => b,
+                None => return QData::Unsupported,
+            };
+
         if track.bcd() == 0 {
             // We're in the lead-in, this is a TOC entry
             let pointer = self.bytes[2];
@@ -296,14 +405,18 @@ pub enum QData {
     /// * MSF of this sector relative to the beginning of the track
     ///   (index 01). In the prepap (index 00) it decreases until it
     ///   reaches index 01 at 00:00:00.
-    /// * MSF of this sector relative to the beginning of the user
-    ///   data area. This is *not* an absolute MSF, you have to add 2
-    ///   minutes (150 sectors) to get an absolute MSF.
+    /// * Absolute MSF of this sector. Track 01's pregap starts at
+    ///   00:00:00, its INDEX 01 is normally at 00:02:00.
     Mode1(Bcd, Bcd, Msf, Msf),
+    /// Mode 1 data in the lead-out area (track number 0xaa):
+    ///
+    /// * MSF of this sector relative to the beginning of the lead-out
+    /// * Absolute MSF of this sector
+    Mode1LeadOut(Msf, Msf),
     /// Mode 1 Table of content entry (in the lead-in):
     ///
     /// * Track number pointer
-    /// * Absolute MSF of Index 00 the track designed by the pointer
+    /// * Absolute MSF of INDEX 01 of the track designed by the pointer
     /// * MSF of this TOC entry in the lead-in
     Mode1Toc(Bcd, Msf, Msf),
     /// Mode 1 Table of content entry with pointer set to 0xa0:
@@ -327,6 +440,25 @@ pub enum QData {
     Unsupported,
 }
 
+/// Result of the comparison of the Q subchannel data of a sector with
+/// the data expected at that position, see `SubChannelQ::compare`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QStatus {
+    /// The data matches the expected one
+    Valid,
+    /// The data is valid (the CRC matches) but differs from the
+    /// expected one. It could be a mode 2 or 3 frame (catalog number
+    /// or ISRC) or the disc structure could be different from what we
+    /// expected.
+    Unexpected,
+    /// The CRC is invalid and the MSFs have been altered while the
+    /// rest of the frame is intact, most likely on purpose
+    /// (libcrypt-style copy protection).
+    Tampered,
+    /// The data is corrupted, most likely a read error
+    Corrupted,
+}
+
 /// This struct is used for subchannels where no special handling is
 /// implemented.
 pub struct SubChannelBasic {
@@ -337,8 +469,8 @@ pub struct SubChannelBasic {
 impl SubChannelBasic {
     /// Create a SubChannelBasic instance from 12 bytes of subchannel
     /// data.
-    pub fn new(raw: [u8; 12]) -> SubChannelQ {
-        SubChannelQ {
+    pub fn new(raw: [u8; 12]) -> SubChannelBasic {
+        SubChannelBasic {
             bytes: raw,
         }
     }
@@ -362,3 +494,172 @@ pub type SubChannelU = SubChannelBasic;
 pub type SubChannelV = SubChannelBasic;
 /// This struct contains the Subchannel W data for one sector.
 pub type SubChannelW = SubChannelBasic;
+
+/// Convert 96 bytes of "raw" interleaved subchannel data to the
+/// deinterleaved layout.
+///
+/// In the interleaved layout each byte contains one bit of every
+/// channel, P in the MSB down to W in the LSB, the way the data is
+/// stored on the disc. In the deinterleaved layout each channel is
+/// stored as 12 consecutive bytes, from P to W. Image formats use
+/// either one.
+pub fn deinterleave(interleaved: &[u8; 96]) -> [u8; 96] {
+    let mut deinterleaved = [0; 96];
+
+    for (i, &b) in interleaved.iter().enumerate() {
+        // Position of this bit within each channel
+        let byte = i / 8;
+        let bit = 7 - (i % 8);
+
+        for channel in 0..8 {
+            if b & (0x80 >> channel) != 0 {
+                deinterleaved[channel * 12 + byte] |= 1 << bit;
+            }
+        }
+    }
+
+    deinterleaved
+}
+
+/// Convert 96 bytes of deinterleaved subchannel data (12 bytes per
+/// channel, from P to W) to the "raw" interleaved layout. This is the
+/// reverse of `deinterleave`.
+pub fn interleave(deinterleaved: &[u8; 96]) -> [u8; 96] {
+    let mut interleaved = [0; 96];
+
+    for (i, b) in interleaved.iter_mut().enumerate() {
+        let byte = i / 8;
+        let bit = 7 - (i % 8);
+
+        for channel in 0..8 {
+            if deinterleaved[channel * 12 + byte] & (1 << bit) != 0 {
+                *b |= 0x80 >> channel;
+            }
+        }
+    }
+
+    interleaved
+}
+
+#[test]
+fn q_round_trip() {
+    let msf = |s| Msf::from_sector_index(s).unwrap();
+
+    // Track 01, INDEX 01, 00:00:00, absolute 00:02:00
+    let data = QData::Mode1(Bcd::one(), Bcd::one(), msf(0), msf(150));
+
+    let q = SubChannelQ::from_data(::toc::DATA, &data).unwrap();
+
+    assert!(q.raw() == &[0x41, 0x01, 0x01, 0x00, 0x00, 0x00,
+                         0x00, 0x00, 0x02, 0x00, 0x28, 0x32]);
+
+    match q.parse_data() {
+        QData::Mode1(t, i, m, a) =>
+            assert!(t == Bcd::one() && i == Bcd::one() &&
+                    m == msf(0) && a == msf(150)),
+        _ => panic!("Bad Q data"),
+    }
+
+    let data = QData::Mode1TocFirstTrack(Bcd::one(),
+                                         SessionFormat::Cdxa,
+                                         msf(3));
+
+    let q = SubChannelQ::from_data(::toc::DATA, &data).unwrap();
+
+    assert!(&q.raw()[..10] == &[0x41, 0x00, 0xa0, 0x00, 0x00, 0x03,
+                                0x00, 0x01, 0x20, 0x00]);
+
+    match q.parse_data() {
+        QData::Mode1TocFirstTrack(t, SessionFormat::Cdxa, m) =>
+            assert!(t == Bcd::one() && m == msf(3)),
+        _ => panic!("Bad TOC data"),
+    }
+
+    let data = QData::Mode1LeadOut(msf(10), msf(1000));
+
+    let q = SubChannelQ::from_data(Control::empty(), &data).unwrap();
+
+    assert!(&q.raw()[..10] == &[0x01, 0xaa, 0x01, 0x00, 0x00, 0x10,
+                                0x00, 0x00, 0x13, 0x25]);
+
+    match q.parse_data() {
+        QData::Mode1LeadOut(m, a) => assert!(m == msf(10) && a == msf(1000)),
+        _ => panic!("Bad lead-out data"),
+    }
+
+    assert!(SubChannelQ::from_data(Control::empty(),
+                                   &QData::Unsupported).is_none());
+}
+
+#[test]
+fn q_compare() {
+    let msf = |s| Msf::from_sector_index(s).unwrap();
+
+    // 03:08:05, a sector typically altered by libcrypt
+    let data = QData::Mode1(Bcd::one(), Bcd::one(), msf(13955), msf(14105));
+
+    let expected = SubChannelQ::from_data(::toc::DATA, &data).unwrap();
+
+    assert!(expected.crc_valid());
+    assert!(expected.compare(&expected) == QStatus::Valid);
+
+    // libcrypt flips a bit in the minutes of both MSFs
+    let mut raw = *expected.raw();
+    raw[3] ^= 0x04;
+    raw[7] ^= 0x20;
+    raw[11] ^= 0x01;
+
+    let tampered = SubChannelQ::new(raw);
+
+    assert!(!tampered.crc_valid());
+    assert!(tampered.compare(&expected) == QStatus::Tampered);
+
+    // Random corruption in the track number
+    let mut raw = *expected.raw();
+    raw[1] ^= 0x10;
+
+    assert!(SubChannelQ::new(raw).compare(&expected) == QStatus::Corrupted);
+
+    // Only one MSF altered
+    let mut raw = *expected.raw();
+    raw[5] ^= 0x01;
+
+    assert!(SubChannelQ::new(raw).compare(&expected) == QStatus::Corrupted);
+
+    // Valid frame, wrong position
+    let data = QData::Mode1(Bcd::one(), Bcd::one(), msf(13956), msf(14106));
+    let other = SubChannelQ::from_data(::toc::DATA, &data).unwrap();
+
+    assert!(other.compare(&expected) == QStatus::Unexpected);
+}
+
+#[test]
+fn interleaving() {
+    let mut deinterleaved = [0; 96];
+
+    // P set everywhere, a recognizable pattern in Q, R..W zeroed
+    for b in deinterleaved[0..12].iter_mut() {
+        *b = 0xff;
+    }
+
+    deinterleaved[12] = 0x41;
+
+    let interleaved = interleave(&deinterleaved);
+
+    // First byte: P and bit 7 of Q (0) set
+    assert!(interleaved[0] == 0x80);
+    // Second byte: P and bit 6 of Q (1) set
+    assert!(interleaved[1] == 0xc0);
+    assert!(interleaved[7] == 0xc0);
+    assert!(interleaved[8] == 0x80);
+
+    assert!(&deinterleave(&interleaved)[..] == &deinterleaved[..]);
+
+    let mut raw = [0; 96];
+
+    for (i, b) in raw.iter_mut().enumerate() {
+        *b = (i * 37) as u8;
+    }
+
+    assert!(&interleave(&deinterleave(&raw))[..] == &raw[..]);
+}
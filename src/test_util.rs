@@ -0,0 +1,35 @@
+//! Helpers shared by the unit tests which need actual image files
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Create an empty directory for the test `name`, removing any
+/// leftover from a previous run
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir()
+        .join(format!("cdimage-{}-{}", name, process::id()));
+
+    let _ = fs::remove_dir_all(&dir);
+
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+/// Create the file `name` in `dir` with the given contents and return
+/// its path
+pub fn write_file(dir: &Path, name: &str, data: &[u8]) -> PathBuf {
+    let path = dir.join(name);
+
+    fs::File::create(&path).unwrap().write_all(data).unwrap();
+
+    path
+}
+
+/// Return the path of the checked-in test file `name`
+pub fn testdata(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+}
@@ -0,0 +1,138 @@
+//! Table of contents (TOC) of a disc.
+//!
+//! The table of contents describes the layout of the disc: the
+//! sessions, the tracks they contain and where their indices are
+//! located. It's normally stored in the Q subchannel of the lead-in
+//! area of each session.
+
+use bcd::Bcd;
+use msf::Msf;
+
+use SessionFormat;
+use TrackFormat;
+
+/// Table of contents of a disc
+#[derive(Debug, Clone, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct Toc {
+    /// All the sessions on the disc, ordered by position
+    pub sessions: Vec<Session>,
+    /// Media Catalog Number (13 digit UPC/EAN barcode), if the image
+    /// format provides it
+    pub catalog: Option<String>,
+}
+
+impl Toc {
+    /// Return the MSF of the first sector in the lead-out of the last
+    /// session
+    pub fn lead_out(&self) -> Msf {
+        match self.sessions.last() {
+            Some(s) => s.lead_out,
+            // The backends refuse to open empty discs
+            None => Msf::zero(),
+        }
+    }
+
+    /// Return an iterator over all the tracks on the disc, ordered by
+    /// position
+    pub fn tracks<'a>(&'a self) -> Box<Iterator<Item=&'a Track> + 'a> {
+        Box::new(self.sessions.iter().flat_map(|s| s.tracks.iter()))
+    }
+
+    /// Return the description of `track` or `None` if there's no such
+    /// track on the disc
+    pub fn track(&self, track: Bcd) -> Option<&Track> {
+        self.tracks().find(|t| t.number == track)
+    }
+}
+
+/// Description of a single session
+#[derive(Debug, Clone, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct Session {
+    /// Session number
+    pub number: u8,
+    /// Session format
+    pub format: SessionFormat,
+    /// Tracks in this session, ordered by position
+    pub tracks: Vec<Track>,
+    /// MSF of the first sector in the lead-out of this session
+    pub lead_out: Msf,
+}
+
+impl Session {
+    /// Return the number of the first track in this session
+    pub fn first_track(&self) -> Bcd {
+        self.tracks[0].number
+    }
+
+    /// Return the number of the last track in this session
+    pub fn last_track(&self) -> Bcd {
+        self.tracks[self.tracks.len() - 1].number
+    }
+}
+
+/// Description of a single track
+#[derive(Debug, Clone, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct Track {
+    /// Track number
+    pub number: Bcd,
+    /// Track format
+    pub format: TrackFormat,
+    /// Control flags, as found in the Q subchannel
+    pub control: Control,
+    /// Absolute MSF of the track's INDEX 01
+    pub start: Msf,
+    /// Length of the track starting at INDEX 01, not counting the
+    /// pregap
+    pub length: Msf,
+    /// All the indices in this track (including the pregap, if any),
+    /// ordered by position
+    pub indices: Vec<TrackIndex>,
+    /// International Standard Recording Code (12 characters), if the
+    /// image format provides it
+    pub isrc: Option<String>,
+}
+
+impl Track {
+    /// Return the absolute MSF of the first sector of this track,
+    /// including the pregap
+    pub fn pregap_start(&self) -> Msf {
+        self.indices[0].msf
+    }
+}
+
+/// Description of a single index within a track
+#[derive(Debug, Clone, Copy, PartialEq, Eq,
+         RustcDecodable, RustcEncodable)]
+pub struct TrackIndex {
+    /// Index number
+    pub number: Bcd,
+    /// Absolute MSF of the first sector in this index
+    pub msf: Msf,
+}
+
+bitflags! {
+    /// Track control flags, stored in the 4 high bits of the first
+    /// byte of the Q subchannel.
+    #[derive(RustcDecodable, RustcEncodable)]
+    flags Control: u8 {
+        /// Audio track recorded with pre-emphasis
+        const PRE_EMPHASIS           = 0b0001,
+        /// Digital copy permitted
+        const DIGITAL_COPY_PERMITTED = 0b0010,
+        /// Data track
+        const DATA                   = 0b0100,
+        /// Four channel audio
+        const FOUR_CHANNEL           = 0b1000,
+    }
+}
+
+impl Control {
+    /// Return the default control flags for a track in `format`:
+    /// `DATA` for CD-ROM tracks, nothing for audio tracks
+    pub fn from_format(format: TrackFormat) -> Control {
+        match format {
+            TrackFormat::Audio | TrackFormat::CdG => Control::empty(),
+            _ => DATA,
+        }
+    }
+}